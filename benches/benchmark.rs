@@ -1,8 +1,9 @@
-use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
-use market_data::{MarketDataCache, MarketDataEntry};
+use criterion::{BatchSize, BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use market_data::{MarketDataCache, MarketDataEntry, TDigestPolicy};
 use rand::Rng;
+use std::hint::black_box;
 use std::sync::Arc;
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant, SystemTime};
 
 const NUM_BUCKETS: usize = 36000; // 1 hour data
 const BUCKET_NS: u64 = 100_000_000; // 100ms
@@ -13,10 +14,7 @@ fn generate_random_entry(time_offset: u64) -> MarketDataEntry {
     // 生成合理的买卖价差 (0.1-10.0)
     let spread = rng.gen_range(0.1..10.0);
 
-    MarketDataEntry {
-        utc_epoch_ns: time_offset,
-        spread,
-    }
+    MarketDataEntry::new(time_offset, spread)
 }
 
 // Initialize our cache
@@ -57,6 +55,60 @@ fn insert_benchmarks(c: &mut Criterion) {
         });
     }
 
+    // Dense quotes, 1000 entries per bucket, with and without pre-allocating buckets for them.
+    let entries: Vec<MarketDataEntry> = (0..100_000)
+        .map(|i| generate_random_entry(i as u64 * BUCKET_NS / 1000))
+        .collect();
+    group.throughput(Throughput::Elements(entries.len() as u64));
+    for hint in [0, 1000].iter() {
+        group.bench_with_input(
+            BenchmarkId::new("insert_dense_with_hint", hint),
+            hint,
+            |b, &hint| {
+                b.iter(|| {
+                    let mut cache =
+                        MarketDataCache::new(100, BUCKET_NS).with_expected_entries_per_bucket(hint);
+                    for entry in &entries {
+                        cache.insert(entry.clone());
+                    }
+                    cache
+                });
+            },
+        );
+    }
+
+    group.finish();
+}
+
+fn bulk_load_benchmarks(c: &mut Criterion) {
+    let mut group = c.benchmark_group("Bulk Load Operations");
+
+    // Sequential insert vs parallel bulk insert of the same data, 10 entries per bucket.
+    for size in [10_000, 100_000].iter() {
+        let entries: Vec<MarketDataEntry> = (0..*size)
+            .map(|i| generate_random_entry(i as u64 * BUCKET_NS / 10))
+            .collect();
+        group.throughput(Throughput::Elements(*size as u64));
+
+        group.bench_with_input(BenchmarkId::new("sequential", size), size, |b, _| {
+            b.iter(|| {
+                let mut cache = MarketDataCache::new(NUM_BUCKETS, BUCKET_NS);
+                for entry in &entries {
+                    cache.insert(entry.clone());
+                }
+                cache
+            });
+        });
+
+        group.bench_with_input(BenchmarkId::new("parallel", size), size, |b, _| {
+            b.iter(|| {
+                let mut cache = MarketDataCache::new(NUM_BUCKETS, BUCKET_NS);
+                cache.bulk_insert_parallel(entries.clone());
+                cache
+            });
+        });
+    }
+
     group.finish();
 }
 
@@ -102,6 +154,141 @@ fn query_benchmarks(c: &mut Criterion) {
     }
 }
 
+fn dense_query_benchmarks(c: &mut Criterion) {
+    let mut group = c.benchmark_group("Query Operations - dense dataset");
+
+    // 10_000 entries per bucket, and the range starts and ends in the middle of a bucket, so both partial buckets are
+    // scanned entry by entry.
+    let entries: Vec<MarketDataEntry> = (0..1_000_000)
+        .map(|i| generate_random_entry(i as u64 * BUCKET_NS / 10_000))
+        .collect();
+    let mut cache = MarketDataCache::new(100, BUCKET_NS);
+    cache.bulk_insert_parallel(entries);
+    let (start_time, end_time) = (BUCKET_NS / 2, 98 * BUCKET_NS + BUCKET_NS / 2);
+
+    group.bench_function("min_spread", |b| {
+        b.iter(|| cache.min_spread(black_box(start_time), black_box(end_time)));
+    });
+    group.bench_function("max_spread", |b| {
+        b.iter(|| cache.max_spread(black_box(start_time), black_box(end_time)));
+    });
+    group.bench_function("count_range", |b| {
+        b.iter(|| cache.count_range(black_box(start_time), black_box(end_time)));
+    });
+
+    group.finish();
+}
+
+fn query_while_inserting_benchmarks(c: &mut Criterion) {
+    let mut group = c.benchmark_group("Query While Inserting");
+
+    // Late quotes keep arriving into the buckets a percentile query covers, 100 entries per bucket.
+    let entries: Vec<MarketDataEntry> = (0..10_000)
+        .map(|i| generate_random_entry(i as u64 * BUCKET_NS / 100))
+        .collect();
+    let late_entries: Vec<MarketDataEntry> = (0..10)
+        .map(|i| generate_random_entry(BUCKET_NS + i as u64 * BUCKET_NS * 9))
+        .collect();
+    for policy in [TDigestPolicy::Lazy, TDigestPolicy::Incremental] {
+        group.bench_function(format!("{policy:?}"), |b| {
+            let mut cache = MarketDataCache::new(100, BUCKET_NS).with_tdigest_policy(policy);
+            cache.bulk_insert_parallel(entries.clone());
+            let end_time = 100 * BUCKET_NS - 1;
+            b.iter(|| {
+                for entry in &late_entries {
+                    cache.insert(entry.clone());
+                    cache.spread_percentiles(0, end_time);
+                }
+            });
+        });
+    }
+
+    group.finish();
+}
+
+fn eviction_benchmarks(c: &mut Criterion) {
+    let mut group = c.benchmark_group("Eviction");
+    group.sample_size(10);
+
+    // Worst case, evict 90 of 100 full buckets in one call, and partially clear a large boundary bucket.
+    let entries: Vec<MarketDataEntry> = (0..1_000_000)
+        .map(|i| generate_random_entry(i as u64 * BUCKET_NS / 10_000))
+        .collect();
+    let threshold = 90 * BUCKET_NS + BUCKET_NS / 2;
+    group.bench_function("remove_up_to many full buckets", |b| {
+        b.iter_batched(
+            || {
+                let mut cache = MarketDataCache::new(100, BUCKET_NS);
+                cache.bulk_insert_parallel(entries.clone());
+                cache
+            },
+            |mut cache| cache.remove_up_to(black_box(threshold)),
+            BatchSize::LargeInput,
+        );
+    });
+
+    group.finish();
+
+    // Inserts that keep moving a full window forward, 100 entries per bucket, so most of them evict one bucket without
+    // a batch, and only every 100th bucket evicts a batch of 100 with one.
+    let mut group = c.benchmark_group("Sliding Window Insert");
+    let entries: Vec<MarketDataEntry> = (0..100_000)
+        .map(|i| generate_random_entry(i as u64 * BUCKET_NS / 100))
+        .collect();
+    group.throughput(Throughput::Elements(entries.len() as u64));
+    for batch in [0, 100].iter() {
+        group.bench_with_input(
+            BenchmarkId::new("insert_with_eviction_batch", batch),
+            batch,
+            |b, &batch| {
+                b.iter_batched(
+                    || MarketDataCache::new(100, BUCKET_NS).with_eviction_batch(batch),
+                    |mut cache| {
+                        for entry in &entries {
+                            cache.insert(entry.clone());
+                        }
+                        cache
+                    },
+                    BatchSize::LargeInput,
+                );
+            },
+        );
+    }
+
+    group.finish();
+
+    // Per-insert latency of the same inserts, i.e. the eviction pauses averaged away by the throughput above. Each
+    // iteration is one run of all inserts, and reports the given percentile of their latencies instead of its total
+    // time.
+    let mut group = c.benchmark_group("Sliding Window Insert Latency");
+    for (name, quantile) in [("p50", 0.5), ("p99", 0.99), ("max", 1.0)] {
+        for batch in [0, 100].iter() {
+            group.bench_with_input(BenchmarkId::new(name, batch), batch, |b, &batch| {
+                b.iter_custom(|iters| {
+                    (0..iters)
+                        .map(|_| {
+                            let mut cache =
+                                MarketDataCache::new(100, BUCKET_NS).with_eviction_batch(batch);
+                            let mut latencies: Vec<Duration> = entries
+                                .iter()
+                                .map(|entry| {
+                                    let start = Instant::now();
+                                    cache.insert(black_box(entry.clone()));
+                                    start.elapsed()
+                                })
+                                .collect();
+                            latencies.sort_unstable();
+                            latencies[((latencies.len() - 1) as f64 * quantile) as usize]
+                        })
+                        .sum()
+                });
+            });
+        }
+    }
+
+    group.finish();
+}
+
 criterion_group! {
     name = benches;
     config = Criterion::default()
@@ -109,7 +296,11 @@ criterion_group! {
         .measurement_time(Duration::from_secs(10));
     targets =
         insert_benchmarks,
+        bulk_load_benchmarks,
         query_benchmarks,
+        dense_query_benchmarks,
+        query_while_inserting_benchmarks,
+        eviction_benchmarks,
 }
 
 criterion_main!(benches);
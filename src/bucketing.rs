@@ -0,0 +1,562 @@
+//! The pure bucketing and statistics logic, i.e. index math, running bucket statistics and a minimal spread only
+//! bucket. It only needs `core` and `alloc`, no file system, thread pool or logging, so it builds without the `std`
+//! feature, e.g. to feed entries and query them in an embedded environment. The rest of the crate builds on it with
+//! `std`.
+
+// System libraries.
+use alloc::vec::Vec;
+
+/// Find bucket index based on first bucket start ns, the query ns, and the duration of each bucket.
+/// Return None if the query ns is less than first bucket start ns, as it's impossible to find such index.
+pub fn find_bucket_index(
+    first_bucket_start_ns: u64,
+    query_ns: u64,
+    bucket_duration_ns: u64,
+) -> Option<usize> {
+    if query_ns < first_bucket_start_ns {
+        // Query time is before the first bucket
+        return None;
+    }
+
+    let elapsed_ns = query_ns - first_bucket_start_ns;
+    let index = (elapsed_ns / bucket_duration_ns) as usize;
+    Some(index)
+}
+
+/// Start time of the bucket containing the given timestamp, buckets are aligned to multiples of bucket_ns.
+pub fn bucket_start(ts: u64, bucket_ns: u64) -> u64 {
+    ts - ts % bucket_ns
+}
+
+/// Find min value in an f64 array. Return None if the input array is empty.
+pub fn f64_min(array: &[f64]) -> Option<&f64> {
+    array.iter().min_by(|a, b| a.partial_cmp(b).unwrap())
+}
+
+/// Find max value in an f64 array. Return None if the input array is empty.
+pub fn f64_max(array: &[f64]) -> Option<&f64> {
+    array.iter().max_by(|a, b| a.partial_cmp(b).unwrap())
+}
+
+/// Check whether two floats are equal within an absolute tolerance, e.g. to compare approximate statistics like TDigest
+/// percentiles or accumulated sums. Equal infinities are equal, and NaN is never equal to anything.
+pub fn approx_eq(a: f64, b: f64, eps: f64) -> bool {
+    a == b || (a - b).abs() <= eps
+}
+
+/// Exact quantile of an ascending sorted array, linearly interpolated between the two closest ranks. Return NaN if the
+/// input array is empty.
+pub fn sorted_quantile(sorted: &[f64], q: f64) -> f64 {
+    if sorted.is_empty() {
+        return f64::NAN;
+    }
+    let rank = q.clamp(0.0, 1.0) * (sorted.len() - 1) as f64;
+    // Rank is not negative, so truncating is floor, which core doesn't have for floats.
+    let lower = rank as usize;
+    let upper = if rank > lower as f64 {
+        lower + 1
+    } else {
+        lower
+    };
+    sorted[lower] + (sorted[upper] - sorted[lower]) * (rank - lower as f64)
+}
+
+/// Count, min, max and sums of a set of spreads, which are all that's needed for min, max, mean and variance queries.
+/// Min and max ignore non finite spreads, and they are f64::MAX and -f64::MAX without any finite spread.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SpreadStats {
+    pub count: usize,
+    pub min: f64,
+    pub max: f64,
+    pub sum: f64,
+    pub sum_squared: f64,
+}
+
+impl Default for SpreadStats {
+    fn default() -> Self {
+        Self {
+            count: 0,
+            min: f64::MAX,
+            max: -f64::MAX,
+            sum: 0.0,
+            sum_squared: 0.0,
+        }
+    }
+}
+
+impl SpreadStats {
+    pub fn from_spreads(spreads: impl IntoIterator<Item = f64>) -> Self {
+        let mut stats = Self::default();
+        spreads.into_iter().for_each(|spread| stats.insert(spread));
+        stats
+    }
+
+    pub fn insert(&mut self, spread: f64) {
+        self.count += 1;
+        if spread.is_finite() {
+            self.min = self.min.min(spread);
+            self.max = self.max.max(spread);
+        }
+        self.sum += spread;
+        self.sum_squared += spread * spread;
+    }
+
+    /// Same as [SpreadStats::from_spreads], but for a contiguous slice, scanned in [STATS_LANES] interleaved lanes. With
+    /// the `simd` feature on x86_64, the lanes are SSE2 registers, otherwise plain scalars doing exactly the same
+    /// operations, so both give bit for bit the same stats. Sums may differ from [SpreadStats::from_spreads] in the last
+    /// bits, as they're added up in a different order.
+    pub fn from_slice(spreads: &[f64]) -> Self {
+        let chunks = spreads.chunks_exact(STATS_LANES);
+        let remainder = chunks.remainder();
+        // Safe, as SSE2 is part of the x86_64 baseline, so no runtime detection is needed.
+        #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+        let lanes = unsafe { LaneStats::scan_sse2(chunks) };
+        #[cfg(not(all(feature = "simd", target_arch = "x86_64")))]
+        let lanes = LaneStats::scan_scalar(chunks);
+
+        let mut stats = lanes.finish(spreads.len() - remainder.len());
+        remainder.iter().for_each(|spread| stats.insert(*spread));
+        stats
+    }
+
+    /// Combine with the stats of another set of spreads.
+    pub fn merge(&mut self, other: &SpreadStats) {
+        self.count += other.count;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.sum += other.sum;
+        self.sum_squared += other.sum_squared;
+    }
+
+    /// Mean spread, NaN without any spread.
+    pub fn mean(&self) -> f64 {
+        if self.count == 0 {
+            return f64::NAN;
+        }
+        self.sum / self.count as f64
+    }
+}
+
+/// Number of interleaved lanes of [SpreadStats::from_slice], lane i takes every spread at an index i modulo this.
+const STATS_LANES: usize = 4;
+
+/// Per lane accumulators of [SpreadStats::from_slice].
+struct LaneStats {
+    min: [f64; STATS_LANES],
+    max: [f64; STATS_LANES],
+    sum: [f64; STATS_LANES],
+    sum_squared: [f64; STATS_LANES],
+}
+
+impl LaneStats {
+    #[cfg_attr(all(feature = "simd", target_arch = "x86_64"), allow(dead_code))]
+    fn scan_scalar(chunks: core::slice::ChunksExact<'_, f64>) -> Self {
+        let mut lanes = Self {
+            min: [f64::MAX; STATS_LANES],
+            max: [-f64::MAX; STATS_LANES],
+            sum: [0.0; STATS_LANES],
+            sum_squared: [0.0; STATS_LANES],
+        };
+        for chunk in chunks {
+            for (i, &spread) in chunk.iter().enumerate() {
+                // Non finite spreads are replaced by the identities of min and max, the same as the SIMD masks.
+                let finite = spread.abs() < f64::INFINITY;
+                let (low, high) = if finite {
+                    (spread, spread)
+                } else {
+                    (f64::MAX, -f64::MAX)
+                };
+                // Same operand order as _mm_min_pd(low, min) and _mm_max_pd(high, max).
+                lanes.min[i] = if low < lanes.min[i] {
+                    low
+                } else {
+                    lanes.min[i]
+                };
+                lanes.max[i] = if high > lanes.max[i] {
+                    high
+                } else {
+                    lanes.max[i]
+                };
+                lanes.sum[i] += spread;
+                lanes.sum_squared[i] += spread * spread;
+            }
+        }
+        lanes
+    }
+
+    /// Same as [LaneStats::scan_scalar], with lanes 0 and 1 in one SSE2 register and lanes 2 and 3 in another.
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    #[target_feature(enable = "sse2")]
+    fn scan_sse2(chunks: core::slice::ChunksExact<'_, f64>) -> Self {
+        use core::arch::x86_64::*;
+
+        let abs_mask = _mm_set1_pd(-0.0);
+        let infinity = _mm_set1_pd(f64::INFINITY);
+        let (min_identity, max_identity) = (_mm_set1_pd(f64::MAX), _mm_set1_pd(-f64::MAX));
+        let mut min = [min_identity; 2];
+        let mut max = [max_identity; 2];
+        let mut sum = [_mm_setzero_pd(); 2];
+        let mut sum_squared = [_mm_setzero_pd(); 2];
+        for chunk in chunks {
+            for (half, pair) in chunk.chunks_exact(2).enumerate() {
+                // Safe, as the pair holds 2 f64, and unaligned loads are used.
+                let spread = unsafe { _mm_loadu_pd(pair.as_ptr()) };
+                // All ones where |spread| < inf, i.e. finite, NaN compares false.
+                let finite = _mm_cmplt_pd(_mm_andnot_pd(abs_mask, spread), infinity);
+                let finite_spread = _mm_and_pd(finite, spread);
+                let low = _mm_or_pd(finite_spread, _mm_andnot_pd(finite, min_identity));
+                let high = _mm_or_pd(finite_spread, _mm_andnot_pd(finite, max_identity));
+                min[half] = _mm_min_pd(low, min[half]);
+                max[half] = _mm_max_pd(high, max[half]);
+                sum[half] = _mm_add_pd(sum[half], spread);
+                sum_squared[half] = _mm_add_pd(sum_squared[half], _mm_mul_pd(spread, spread));
+            }
+        }
+
+        let store = |registers: [__m128d; 2]| {
+            let mut lanes = [0.0; STATS_LANES];
+            // Safe, as lanes has room for both registers, and unaligned stores are used.
+            unsafe {
+                _mm_storeu_pd(lanes.as_mut_ptr(), registers[0]);
+                _mm_storeu_pd(lanes.as_mut_ptr().add(2), registers[1]);
+            }
+            lanes
+        };
+        Self {
+            min: store(min),
+            max: store(max),
+            sum: store(sum),
+            sum_squared: store(sum_squared),
+        }
+    }
+
+    /// Combine the lanes into the stats of the given number of scanned spreads, the same way for both scans.
+    fn finish(self, count: usize) -> SpreadStats {
+        let pairwise = |lanes: [f64; STATS_LANES]| (lanes[0] + lanes[2]) + (lanes[1] + lanes[3]);
+        SpreadStats {
+            count,
+            min: self
+                .min
+                .into_iter()
+                .fold(f64::MAX, |a, b| if b < a { b } else { a }),
+            max: self
+                .max
+                .into_iter()
+                .fold(-f64::MAX, |a, b| if b > a { b } else { a }),
+            sum: pairwise(self.sum),
+            sum_squared: pairwise(self.sum_squared),
+        }
+    }
+}
+
+/// Running stats of the entries of a bucket, shared by [SpreadBucket] and [crate::Bucket]: the [SpreadStats] of their
+/// spreads, plus sums over the offset of each entry from the bucket start, i.e. utc_epoch_ns - start_time_ns, for the
+/// trend of spreads over time.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct BucketStats {
+    pub spreads: SpreadStats,
+    pub sum_offset: f64,
+    pub sum_squared_offset: f64,
+    pub sum_offset_spread: f64,
+}
+
+impl BucketStats {
+    /// Stats of the entries of a bucket starting at start_time_ns, given as columns of the same length. Spread stats
+    /// come from [SpreadStats::from_slice], which is vectorized with the simd feature.
+    pub fn from_columns(start_time_ns: u64, timestamps: &[u64], spreads: &[f64]) -> Self {
+        let mut stats = Self {
+            spreads: SpreadStats::from_slice(spreads),
+            ..Default::default()
+        };
+        for (ts, spread) in timestamps.iter().zip(spreads) {
+            stats.add_offset((ts - start_time_ns) as f64, *spread);
+        }
+        stats
+    }
+
+    /// Add one entry, at the given offset from the bucket start.
+    pub fn insert(&mut self, offset: f64, spread: f64) {
+        self.spreads.insert(spread);
+        self.add_offset(offset, spread);
+    }
+
+    /// Combine with the stats of other entries of the same bucket.
+    pub fn merge(&mut self, other: &BucketStats) {
+        self.spreads.merge(&other.spreads);
+        self.sum_offset += other.sum_offset;
+        self.sum_squared_offset += other.sum_squared_offset;
+        self.sum_offset_spread += other.sum_offset_spread;
+    }
+
+    fn add_offset(&mut self, offset: f64, spread: f64) {
+        self.sum_offset += offset;
+        self.sum_squared_offset += offset * offset;
+        self.sum_offset_spread += offset * spread;
+    }
+}
+
+/// Whether a bucket covering [start_time_ns, end_time_ns) takes an entry at ts.
+pub fn bucket_contains(start_time_ns: u64, end_time_ns: u64, ts: u64) -> bool {
+    start_time_ns <= ts && ts < end_time_ns
+}
+
+/// Whether removing everything at or before threshold from a bucket covering [start_time_ns, end_time_ns) is up to the
+/// bucket itself. Before the bucket nothing is removed, and beyond it the whole bucket goes, which is up to its owner,
+/// e.g. by dropping the bucket.
+pub fn removes_within(start_time_ns: u64, end_time_ns: u64, threshold: u64) -> bool {
+    start_time_ns <= threshold && threshold <= end_time_ns
+}
+
+/// A spread only bucket covering [start_time_ns, end_time_ns), the embeddable counterpart of [crate::Bucket] without
+/// tdigests, locks or full depth. Entries are kept as columns of timestamps and spreads, in insertion order.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SpreadBucket {
+    pub start_time_ns: u64,
+    pub end_time_ns: u64,
+    pub stats: BucketStats,
+    pub timestamps: Vec<u64>,
+    pub spreads: Vec<f64>,
+}
+
+impl SpreadBucket {
+    pub fn new(start_time_ns: u64, end_time_ns: u64) -> Self {
+        Self {
+            start_time_ns,
+            end_time_ns,
+            ..Default::default()
+        }
+    }
+
+    /// Insert one spread. If the timestamp is not in the range of this bucket, insert will return false. Otherwise true.
+    pub fn insert(&mut self, utc_epoch_ns: u64, spread: f64) -> bool {
+        if !bucket_contains(self.start_time_ns, self.end_time_ns, utc_epoch_ns) {
+            return false;
+        }
+        self.stats
+            .insert((utc_epoch_ns - self.start_time_ns) as f64, spread);
+        self.timestamps.push(utc_epoch_ns);
+        self.spreads.push(spread);
+        true
+    }
+
+    /// If threshold is in the range of this bucket, remove everything at or before it, and return the number of entries
+    /// removed. Otherwise, return 0, see [removes_within].
+    pub fn remove_up_to(&mut self, threshold: u64) -> usize {
+        if !removes_within(self.start_time_ns, self.end_time_ns, threshold) {
+            return 0;
+        }
+        let original_count = self.stats.spreads.count;
+        let mut timestamps = self.timestamps.iter();
+        self.spreads
+            .retain(|_| timestamps.next().is_some_and(|ts| *ts > threshold));
+        self.timestamps.retain(|ts| *ts > threshold);
+        self.stats = BucketStats::from_columns(self.start_time_ns, &self.timestamps, &self.spreads);
+        original_count - self.stats.spreads.count
+    }
+
+    /// Stats of the spreads in [start, end], both inclusive. The cached stats are used if the range covers the bucket.
+    pub fn stats_in_between(&self, start: u64, end: u64) -> SpreadStats {
+        if start <= self.start_time_ns && self.end_time_ns <= end.saturating_add(1) {
+            return self.stats.spreads;
+        }
+        SpreadStats::from_spreads(
+            self.timestamps
+                .iter()
+                .zip(&self.spreads)
+                .filter(|(ts, _)| start <= **ts && **ts <= end)
+                .map(|(_, spread)| *spread),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    #[allow(clippy::useless_conversion)]
+    fn test_find_bucket_index() {
+        let first_bucket_start_ns = 10;
+        let bucket_duration_ns = 10;
+        let inputs = vec![0_u64, 5, 10, 15, 20, 25, 30];
+        let expected_outputs = vec![None, None, Some(0), Some(0), Some(1), Some(1), Some(2)];
+        for (input, expected) in inputs.into_iter().zip(expected_outputs.into_iter()) {
+            let output = find_bucket_index(first_bucket_start_ns, input, bucket_duration_ns);
+            assert_eq!(output, expected);
+        }
+    }
+
+    #[test]
+    fn test_bucket_start() {
+        assert_eq!(bucket_start(25, 10), 20);
+        assert_eq!(bucket_start(30, 10), 30);
+    }
+
+    #[test]
+    fn test_f64_max() {
+        let input = vec![1.0, 2.0, 3.0];
+        let max = f64_max(&input);
+        assert_eq!(max, Some(&3.0));
+
+        let input = vec![];
+        let max = f64_max(&input);
+        assert_eq!(max, None);
+    }
+
+    #[test]
+    fn test_f64_min() {
+        let input = vec![1.0, 2.0, 3.0];
+        let min = f64_min(&input);
+        assert_eq!(min, Some(&1.0));
+
+        let input = vec![];
+        let min = f64_min(&input);
+        assert_eq!(min, None);
+    }
+
+    #[test]
+    fn test_sorted_quantile() {
+        assert!(sorted_quantile(&[], 0.5).is_nan());
+        assert_eq!(sorted_quantile(&[3.0], 0.9), 3.0);
+        let sorted = [1.0, 2.0, 3.0, 4.0, 5.0];
+        assert_eq!(sorted_quantile(&sorted, 0.0), 1.0);
+        assert_eq!(sorted_quantile(&sorted, 0.5), 3.0);
+        assert_eq!(sorted_quantile(&sorted, 0.1), 1.4);
+        assert_eq!(sorted_quantile(&sorted, 1.0), 5.0);
+    }
+
+    #[test]
+    fn test_approx_eq() {
+        assert!(approx_eq(0.1 + 0.2, 0.3, 1e-12));
+        assert!(!approx_eq(1.0, 1.1, 1e-3));
+        assert!(approx_eq(f64::INFINITY, f64::INFINITY, 0.0));
+        assert!(!approx_eq(f64::NAN, f64::NAN, 1.0));
+    }
+
+    /// Deterministic spreads of both signs and a wide range of magnitudes, with every 50th one not finite.
+    fn test_spreads(n: usize) -> Vec<f64> {
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        (0..n)
+            .map(|i| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                match i % 50 {
+                    7 => f64::NAN,
+                    23 => f64::INFINITY,
+                    _ => (state % 2_000_001) as f64 / 1000.0 - 1000.0,
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_spread_stats_from_slice() {
+        assert_eq!(SpreadStats::from_slice(&[]), SpreadStats::default());
+        assert_eq!(
+            SpreadStats::from_slice(&[3.0, f64::NEG_INFINITY, -1.0]),
+            SpreadStats::from_spreads([3.0, f64::NEG_INFINITY, -1.0])
+        );
+
+        let finite: Vec<f64> = test_spreads(1003)
+            .into_iter()
+            .filter(|s| s.is_finite())
+            .collect();
+        let (fast, slow) = (
+            SpreadStats::from_slice(&finite),
+            SpreadStats::from_spreads(finite.iter().copied()),
+        );
+        assert_eq!(
+            (fast.count, fast.min, fast.max),
+            (slow.count, slow.min, slow.max)
+        );
+        assert!(approx_eq(fast.sum, slow.sum, 1e-6));
+        assert!(approx_eq(fast.sum_squared, slow.sum_squared, 1e-3));
+
+        // Non finite spreads are left out of min and max, but still counted and summed.
+        let stats = SpreadStats::from_slice(&test_spreads(1003));
+        assert_eq!(stats.count, 1003);
+        assert!(stats.min.is_finite() && stats.max.is_finite());
+        assert!(stats.sum.is_nan());
+    }
+
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    #[test]
+    fn test_sse2_matches_scalar() {
+        let bits = |stats: SpreadStats| {
+            (
+                stats.min.to_bits(),
+                stats.max.to_bits(),
+                stats.sum.to_bits(),
+                stats.sum_squared.to_bits(),
+            )
+        };
+        let mut spreads = test_spreads(4099);
+        spreads.extend([-0.0, 0.0, -0.0, 0.0]);
+        for spreads in [
+            spreads.clone(),
+            spreads.into_iter().filter(|s| s.is_finite()).collect(),
+        ] {
+            let chunks = spreads.chunks_exact(STATS_LANES);
+            let scalar = LaneStats::scan_scalar(chunks.clone()).finish(0);
+            let sse2 = unsafe { LaneStats::scan_sse2(chunks) }.finish(0);
+            assert_eq!(bits(scalar), bits(sse2));
+        }
+    }
+
+    #[test]
+    fn test_spread_bucket() {
+        let mut bucket = SpreadBucket::new(10, 20);
+        assert!(!bucket.insert(20, 1.0));
+        for (ts, spread) in [(10, 3.0), (12, f64::NAN), (15, 1.0), (19, 2.0)] {
+            assert!(bucket.insert(ts, spread));
+        }
+        assert_eq!(bucket.stats.spreads.count, 4);
+        assert_eq!(
+            (bucket.stats.spreads.min, bucket.stats.spreads.max),
+            (1.0, 3.0)
+        );
+
+        let stats = bucket.stats_in_between(13, 19);
+        assert_eq!((stats.count, stats.min, stats.max), (2, 1.0, 2.0));
+        assert_eq!(stats.mean(), 1.5);
+
+        // Nothing is removed outside of the bucket, the whole of it is up to its owner.
+        assert_eq!(bucket.remove_up_to(5), 0);
+        assert_eq!(bucket.remove_up_to(21), 0);
+        assert_eq!(bucket.remove_up_to(12), 2);
+        assert_eq!(bucket.stats.spreads, bucket.stats_in_between(0, 100));
+        assert_eq!(bucket.stats.spreads.sum, 3.0);
+        // Offsets 5 and 9 are left.
+        assert_eq!(bucket.stats.sum_offset, 14.0);
+        assert_eq!(bucket.stats.sum_offset_spread, 5.0 * 1.0 + 9.0 * 2.0);
+        assert!(SpreadStats::default().mean().is_nan());
+    }
+
+    #[test]
+    fn test_bucket_stats() {
+        let (timestamps, spreads) = ([10, 12, 15, 19], [3.0, 1.0, f64::INFINITY, 2.0]);
+        let mut inserted = BucketStats::default();
+        timestamps
+            .iter()
+            .zip(spreads)
+            .for_each(|(ts, spread)| inserted.insert((ts - 10) as f64, spread));
+        assert_eq!(
+            BucketStats::from_columns(10, &timestamps, &spreads),
+            inserted
+        );
+        assert_eq!(
+            (inserted.spreads.max, inserted.sum_squared_offset),
+            (3.0, 4.0 + 25.0 + 81.0)
+        );
+
+        let mut merged = BucketStats::from_columns(10, &timestamps[..2], &spreads[..2]);
+        merged.merge(&BucketStats::from_columns(
+            10,
+            &timestamps[2..],
+            &spreads[2..],
+        ));
+        assert_eq!(merged, inserted);
+    }
+}
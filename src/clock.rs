@@ -0,0 +1,76 @@
+//! Where "now" comes from for [crate::MarketDataCache::evict_older_than] and the rolling queries. The system clock is the
+//! default, a [ManualClock] is for tests and for feeds that carry their own clock, e.g. replaying recorded data.
+
+// System libraries.
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A source of the current time in ns since the unix epoch.
+pub trait Clock: fmt::Debug + Send + Sync {
+    fn now_ns(&self) -> u64;
+}
+
+/// The system wall clock.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_ns(&self) -> u64 {
+        // A clock before 1970 or after 2554 is not something we can do anything about.
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_nanos().min(u64::MAX as u128) as u64)
+    }
+}
+
+/// A clock that only moves when told to. Share it with the cache in an [std::sync::Arc] and keep a handle to drive it.
+#[derive(Debug, Default)]
+pub struct ManualClock {
+    now_ns: AtomicU64,
+}
+
+impl ManualClock {
+    pub fn new(now_ns: u64) -> Self {
+        Self {
+            now_ns: AtomicU64::new(now_ns),
+        }
+    }
+
+    pub fn set(&self, now_ns: u64) {
+        self.now_ns.store(now_ns, Ordering::Relaxed);
+    }
+
+    /// Move the clock forward, saturating at u64::MAX.
+    pub fn advance(&self, ns: u64) {
+        self.now_ns
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |now| {
+                Some(now.saturating_add(ns))
+            })
+            .unwrap();
+    }
+}
+
+impl Clock for ManualClock {
+    fn now_ns(&self) -> u64 {
+        self.now_ns.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clocks() {
+        let clock = ManualClock::new(10);
+        clock.advance(5);
+        assert_eq!(clock.now_ns(), 15);
+        clock.set(3);
+        clock.advance(u64::MAX);
+        assert_eq!(clock.now_ns(), u64::MAX);
+
+        // This test is written after 2020.
+        assert!(SystemClock.now_ns() > 1_577_836_800_000_000_000);
+    }
+}
@@ -0,0 +1,35 @@
+//! Errors reported by [crate::MarketDataCache] operations that can fail because of their input.
+
+// Third party libraries.
+use thiserror::Error;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum MarketDataError {
+    #[error(
+        "bucket [{start_time_ns}, {end_time_ns}) is not aligned to the cache buckets of {bucket_ns} ns"
+    )]
+    MisalignedBucket {
+        start_time_ns: u64,
+        end_time_ns: u64,
+        bucket_ns: u64,
+    },
+    #[error(
+        "bucket starting at {start_time_ns} is older than the cache window starting at {cache_start_ns}"
+    )]
+    BucketTooOld {
+        start_time_ns: u64,
+        cache_start_ns: u64,
+    },
+    #[error("cache window for timestamp {0} overflows u64")]
+    WindowOverflow(u64),
+    #[error("entry at {0} is rejected by its bucket")]
+    EntryRejected(u64),
+    #[error("invalid serialized tdigest: {0}")]
+    InvalidTDigest(String),
+    #[error("invalid json input: {0}")]
+    InvalidJson(String),
+    #[error("failed to read input: {0}")]
+    Io(String),
+    #[error("timestamp {0} is negative, timestamps are unsigned nanoseconds since the epoch")]
+    NegativeTimestamp(i64),
+}
@@ -1,4 +1,41 @@
+//! In-memory cache of market data spreads. The [bucketing] core only needs `alloc`, everything else needs the default
+//! `std` feature.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+pub mod bucketing;
+#[cfg(feature = "std")]
+pub mod clock;
+#[cfg(feature = "std")]
+pub mod error;
+#[cfg(feature = "python")]
+pub mod python;
+#[cfg(feature = "std")]
+pub mod sketch;
+#[cfg(feature = "std")]
+pub mod source;
+#[cfg(feature = "std")]
 pub mod types;
+#[cfg(feature = "std")]
 pub mod utils;
 
-pub use types::{BidAsk, Bucket, MarketDataCache, MarketDataEntry};
+#[cfg(feature = "std")]
+pub use clock::{Clock, ManualClock, SystemClock};
+#[cfg(feature = "std")]
+pub use error::MarketDataError;
+#[cfg(feature = "std")]
+pub use sketch::{DDSketch, QuantileSketch};
+#[cfg(feature = "std")]
+pub use source::{JsonFileSource, QuoteSource, RawQuote};
+#[cfg(feature = "std")]
+pub use types::{
+    AggBucket, AlertDirection, AlertFn, BidAsk, BookOrder, Bucket, BucketSketch, BucketStore,
+    BucketSummary, CachedMarketDataCache, EntryStorage, EvictFn, EvictHandler, InsertOutcome,
+    InvariantViolation, LoadOptions, LoadReport, MarketDataCache, MarketDataEntry,
+    MarketDataEntryFull, OneSidedPolicy, PercentileResult, QueryCache, QueryKind, QueryResult,
+    RangeSummary, Regime, SamplingPolicy, SerializableDigest, SkipCounts, SkipReason, SpreadAlert,
+    SpreadConvention, SpreadHistogram, StabilityComponents, TDigestPolicy, WindowComparison,
+    WinsorizePolicy,
+};
@@ -1,8 +1,5 @@
 //! Just a sample main implementation. I used the provided json file to do some basic testing.
 
-mod types;
-mod utils;
-
 // System libraries.
 use log::{LevelFilter, info};
 
@@ -10,7 +7,7 @@ use log::{LevelFilter, info};
 use rayon::ThreadPoolBuilder;
 
 // Project libraries.
-use crate::types::MarketDataCache;
+use market_data::MarketDataCache;
 
 fn main() {
     env_logger::builder()
@@ -27,10 +24,8 @@ fn main() {
     dbg!(&cache.count());
     dbg!(&cache.buckets.len());
 
-    let lock = cache.buckets[0].read().unwrap();
-    let start_time = lock.start_time_ns;
-    let lock = cache.buckets.back().unwrap().read().unwrap();
-    let end_time = lock.end_time_ns - 10000;
+    let (start_time, end_time) = cache.time_bounds().unwrap();
+    let end_time = end_time - 10000;
 
     dbg!(&cache.spread_percentiles(start_time, end_time));
     dbg!(cache.count());
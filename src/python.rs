@@ -0,0 +1,101 @@
+//! Python bindings, behind the `python` feature. [PyMarketDataCache] wraps a [MarketDataCache] as the Python class
+//! `MarketDataCache` of the `market_data` module, with timestamps as Python ints and spreads as Python floats. Build the
+//! extension module with `cargo rustc --release --features python --crate-type cdylib`.
+
+// Third party libraries.
+use pyo3::prelude::*;
+
+// Project libraries.
+use crate::types::{InsertOutcome, MarketDataCache, MarketDataEntry};
+
+/// A [MarketDataCache] for Python, see there for the semantics of each method.
+#[pyclass(name = "MarketDataCache")]
+pub struct PyMarketDataCache {
+    cache: MarketDataCache,
+}
+
+#[pymethods]
+impl PyMarketDataCache {
+    #[new]
+    fn new(num_buckets: usize, bucket_ns: u64) -> Self {
+        Self {
+            cache: MarketDataCache::new(num_buckets, bucket_ns),
+        }
+    }
+
+    /// Insert a quote, and tell whether it's stored.
+    fn insert(&mut self, utc_epoch_ns: u64, spread: f64) -> bool {
+        matches!(
+            self.cache
+                .insert(MarketDataEntry::new(utc_epoch_ns, spread)),
+            InsertOutcome::Inserted | InsertOutcome::EvictedThenInserted { .. }
+        )
+    }
+
+    fn count(&self) -> usize {
+        self.cache.count()
+    }
+
+    fn count_range(&self, start_time: u64, end_time: u64) -> usize {
+        self.cache.count_range(start_time, end_time)
+    }
+
+    fn min_spread(&self, start_time: u64, end_time: u64) -> f64 {
+        self.cache.min_spread(start_time, end_time)
+    }
+
+    fn max_spread(&self, start_time: u64, end_time: u64) -> f64 {
+        self.cache.max_spread(start_time, end_time)
+    }
+
+    /// The 10th, 50th and 90th percentiles as a tuple.
+    fn spread_percentiles(&self, start_time: u64, end_time: u64) -> (f64, f64, f64) {
+        self.cache.spread_percentiles(start_time, end_time)
+    }
+}
+
+#[pymodule]
+fn market_data(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyMarketDataCache>()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_python_smoke() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let module = PyModule::new(py, "market_data").unwrap();
+            market_data(&module).unwrap();
+            let cache = module
+                .getattr("MarketDataCache")
+                .unwrap()
+                .call1((10usize, 10u64))
+                .unwrap();
+            for i in 0..100u64 {
+                let inserted: bool = cache
+                    .call_method1("insert", (i, i as f64))
+                    .unwrap()
+                    .extract()
+                    .unwrap();
+                assert!(inserted);
+            }
+            let call = |name: &str| cache.call_method1(name, (30u64, 70u64)).unwrap();
+            assert_eq!(call("count_range").extract::<usize>().unwrap(), 41);
+            assert_eq!(call("min_spread").extract::<f64>().unwrap(), 30.0);
+            assert_eq!(call("max_spread").extract::<f64>().unwrap(), 70.0);
+            let (p10, p50, p90): (f64, f64, f64) = call("spread_percentiles").extract().unwrap();
+            assert!(p10 < p50 && p50 < p90);
+            assert_eq!(
+                cache
+                    .call_method0("count")
+                    .unwrap()
+                    .extract::<usize>()
+                    .unwrap(),
+                100
+            );
+        });
+    }
+}
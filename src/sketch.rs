@@ -0,0 +1,244 @@
+//! Streaming quantile sketches. Buckets cache a [TDigest] of their spreads, and a [DDSketch] too if the cache is built
+//! with [crate::MarketDataCache::with_bucket_sketch], for a relative error guarantee on the quantiles. Any other
+//! [QuantileSketch] can be built over a time range with [crate::MarketDataCache::spread_quantile_with].
+
+// System libraries.
+use std::collections::BTreeMap;
+
+// Third party libraries.
+use tdigest::TDigest;
+
+// Project libraries.
+use crate::utils::merge_digests_safe;
+
+/// A mergeable summary of values that estimates their quantiles.
+pub trait QuantileSketch {
+    fn insert(&mut self, v: f64);
+    /// Combine with the sketch of another set of values.
+    fn merge(&mut self, other: &Self);
+    /// Estimate the q quantile, q in [0, 1]. Return NaN if nothing is inserted.
+    fn quantile(&self, q: f64) -> f64;
+}
+
+impl QuantileSketch for TDigest {
+    /// Note that a single insert re-compresses the digest, prefer merging values in batches, e.g. with
+    /// [TDigest::merge_unsorted], where possible.
+    fn insert(&mut self, v: f64) {
+        *self = self.merge_unsorted(vec![v]);
+    }
+
+    fn merge(&mut self, other: &Self) {
+        if other.is_empty() {
+            return;
+        }
+        if self.is_empty() {
+            *self = other.clone();
+            return;
+        }
+        if let Some(merged) = merge_digests_safe(vec![self.clone(), other.clone()]) {
+            *self = merged;
+        }
+    }
+
+    fn quantile(&self, q: f64) -> f64 {
+        if self.is_empty() {
+            return f64::NAN;
+        }
+        self.estimate_quantile(q)
+    }
+}
+
+/// A DDSketch, which guarantees that every quantile estimate is within relative_accuracy of the exact value, for both
+/// positive and negative values. Values are counted in logarithmically sized bins, so memory grows with the log of the
+/// value range rather than the number of values. Non finite values are ignored.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DDSketch {
+    relative_accuracy: f64,
+    gamma: f64,
+    positive: BTreeMap<i32, u64>,
+    negative: BTreeMap<i32, u64>,
+    zero_count: u64,
+    count: u64,
+}
+
+impl Default for DDSketch {
+    /// 1% relative accuracy.
+    fn default() -> Self {
+        Self::new(0.01)
+    }
+}
+
+impl DDSketch {
+    /// Relative accuracy should be in (0, 1), e.g. 0.01 for estimates within 1% of the exact quantiles. Panics
+    /// otherwise, as no bin size can guarantee it.
+    pub fn new(relative_accuracy: f64) -> Self {
+        assert!(
+            0.0 < relative_accuracy && relative_accuracy < 1.0,
+            "relative accuracy of a DDSketch must be in (0, 1), got {relative_accuracy}"
+        );
+        Self {
+            relative_accuracy,
+            gamma: (1.0 + relative_accuracy) / (1.0 - relative_accuracy),
+            positive: BTreeMap::new(),
+            negative: BTreeMap::new(),
+            zero_count: 0,
+            count: 0,
+        }
+    }
+
+    pub fn relative_accuracy(&self) -> f64 {
+        self.relative_accuracy
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Forget all values, keeping the relative accuracy.
+    pub fn clear(&mut self) {
+        *self = Self::new(self.relative_accuracy);
+    }
+
+    /// Estimate the heap memory held by the bins in bytes, not counting the tree nodes' own overhead.
+    pub fn heap_bytes(&self) -> usize {
+        (self.positive.len() + self.negative.len()) * (size_of::<i32>() + size_of::<u64>())
+    }
+
+    /// Index of the bin holding a positive magnitude, i.e. (gamma^(key - 1), gamma^key].
+    fn key(&self, magnitude: f64) -> i32 {
+        (magnitude.ln() / self.gamma.ln()).ceil() as i32
+    }
+
+    /// The representative magnitude of a bin, within relative_accuracy of every magnitude in it.
+    fn value(&self, key: i32) -> f64 {
+        2.0 * self.gamma.powi(key) / (self.gamma + 1.0)
+    }
+}
+
+impl QuantileSketch for DDSketch {
+    fn insert(&mut self, v: f64) {
+        if !v.is_finite() {
+            return;
+        }
+        if v == 0.0 {
+            self.zero_count += 1;
+        } else {
+            let key = self.key(v.abs());
+            let bins = if v > 0.0 {
+                &mut self.positive
+            } else {
+                &mut self.negative
+            };
+            *bins.entry(key).or_insert(0) += 1;
+        }
+        self.count += 1;
+    }
+
+    /// Both sketches should have the same relative accuracy, otherwise the bins of other are taken as if they were ours.
+    fn merge(&mut self, other: &Self) {
+        for (key, n) in &other.positive {
+            *self.positive.entry(*key).or_insert(0) += n;
+        }
+        for (key, n) in &other.negative {
+            *self.negative.entry(*key).or_insert(0) += n;
+        }
+        self.zero_count += other.zero_count;
+        self.count += other.count;
+    }
+
+    fn quantile(&self, q: f64) -> f64 {
+        if self.count == 0 {
+            return f64::NAN;
+        }
+        let rank = (q.clamp(0.0, 1.0) * (self.count - 1) as f64) as u64;
+        // Walk from the most negative value up, the largest magnitudes of negative values come first.
+        let mut seen = 0;
+        for (key, n) in self.negative.iter().rev() {
+            seen += n;
+            if seen > rank {
+                return -self.value(*key);
+            }
+        }
+        seen += self.zero_count;
+        if seen > rank {
+            return 0.0;
+        }
+        for (key, n) in &self.positive {
+            seen += n;
+            if seen > rank {
+                return self.value(*key);
+            }
+        }
+        // Only reached if bins don't add up to count, i.e. sketches of different accuracies are merged.
+        self.positive
+            .keys()
+            .next_back()
+            .map_or(0.0, |key| self.value(*key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::approx_eq;
+
+    #[test]
+    fn test_ddsketch_relative_error() {
+        let mut sketch = DDSketch::new(0.01);
+        let values: Vec<f64> = (1..=1000).map(|i| i as f64 * 0.37).collect();
+        values.iter().for_each(|v| sketch.insert(*v));
+        sketch.insert(f64::NAN);
+        assert_eq!(sketch.count(), 1000);
+        for q in [0.0, 0.1, 0.5, 0.9, 0.99, 1.0] {
+            let exact = values[(q * 999.0) as usize];
+            let estimate = sketch.quantile(q);
+            assert!(
+                (estimate - exact).abs() <= exact * 0.01,
+                "{q}: {estimate} vs {exact}"
+            );
+        }
+        assert!(DDSketch::default().quantile(0.5).is_nan());
+
+        sketch.clear();
+        assert_eq!(sketch.count(), 0);
+        assert_eq!(sketch.relative_accuracy(), 0.01);
+    }
+
+    #[test]
+    #[should_panic(expected = "relative accuracy")]
+    fn test_ddsketch_invalid_accuracy() {
+        DDSketch::new(1.0);
+    }
+
+    #[test]
+    fn test_ddsketch_merge_signed() {
+        let mut negative = DDSketch::default();
+        let mut rest = DDSketch::default();
+        for v in [-4.0, -2.0, -1.0] {
+            negative.insert(v);
+        }
+        for v in [0.0, 1.0, 2.0, 4.0] {
+            rest.insert(v);
+        }
+        negative.merge(&rest);
+        assert_eq!(negative.count(), 7);
+        assert!(approx_eq(negative.quantile(0.0), -4.0, 0.04));
+        assert_eq!(negative.quantile(0.5), 0.0);
+        assert!(approx_eq(negative.quantile(1.0), 4.0, 0.04));
+    }
+
+    #[test]
+    fn test_tdigest_sketch() {
+        let mut sketch = TDigest::default();
+        assert!(QuantileSketch::quantile(&sketch, 0.5).is_nan());
+        let mut other = TDigest::default();
+        for v in 1..=50 {
+            sketch.insert(v as f64);
+            other.insert((v + 50) as f64);
+        }
+        QuantileSketch::merge(&mut sketch, &other);
+        QuantileSketch::merge(&mut sketch, &TDigest::default());
+        assert_eq!(sketch.count(), 100.0);
+        assert!(approx_eq(QuantileSketch::quantile(&sketch, 0.5), 50.5, 1.0));
+    }
+}
@@ -0,0 +1,90 @@
+//! Where raw quotes come from. [crate::MarketDataCache::ingest] validates and inserts quotes from any [QuoteSource], so a
+//! websocket or message queue consumer only needs to hand over its raw quotes one by one, and the validation logic is
+//! shared with the json file loader.
+
+// System libraries.
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+
+// Third party libraries.
+use serde_json::Value;
+
+/// One unvalidated quote, in the same json shape as an item of `market_data_entries` in the input file, i.e. an object
+/// with `utc_epoch_ns`, and either `bids` and `asks`, or a precomputed `spread`.
+pub type RawQuote = Value;
+
+/// A stream of raw quotes, returns None when it's exhausted.
+pub trait QuoteSource {
+    fn next_raw(&mut self) -> Option<RawQuote>;
+}
+
+/// Reads all quotes of a json file, which holds them in a top level `market_data_entries` array.
+#[derive(Debug)]
+pub struct JsonFileSource {
+    entries: std::vec::IntoIter<Value>,
+}
+
+impl JsonFileSource {
+    pub fn open(file_path: &str) -> io::Result<Self> {
+        Self::from_reader(BufReader::new(File::open(file_path)?))
+    }
+
+    /// Same as [JsonFileSource::open], but reads the json document from any reader, e.g. a string already in memory.
+    pub fn from_reader<R: Read>(reader: R) -> io::Result<Self> {
+        let mut json: Value = serde_json::from_reader(reader)?;
+        match json["market_data_entries"].take() {
+            Value::Array(entries) => Ok(Self::from_values(entries)),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "missing market_data_entries array in json",
+            )),
+        }
+    }
+
+    /// Same as [JsonFileSource::open], but for quotes already parsed as json values.
+    pub fn from_values(entries: Vec<Value>) -> Self {
+        Self {
+            entries: entries.into_iter(),
+        }
+    }
+}
+
+impl QuoteSource for JsonFileSource {
+    fn next_raw(&mut self) -> Option<RawQuote> {
+        self.entries.next()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_json_file_source() {
+        let path = std::env::temp_dir().join("market_data_test_json_file_source.json");
+        std::fs::write(
+            &path,
+            r#"{"market_data_entries": [{"utc_epoch_ns": 1}, {"utc_epoch_ns": 2}]}"#,
+        )
+        .unwrap();
+        let mut source = JsonFileSource::open(path.to_str().unwrap()).unwrap();
+        assert_eq!(source.next_raw(), Some(json!({"utc_epoch_ns": 1})));
+        assert_eq!(source.next_raw(), Some(json!({"utc_epoch_ns": 2})));
+        assert_eq!(source.next_raw(), None);
+
+        std::fs::write(&path, r#"{"entries": []}"#).unwrap();
+        assert!(JsonFileSource::open(path.to_str().unwrap()).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_json_source_from_reader() {
+        let json = r#"{"market_data_entries": [{"utc_epoch_ns": 1}]}"#;
+        let mut source = JsonFileSource::from_reader(json.as_bytes()).unwrap();
+        assert_eq!(source.next_raw(), Some(json!({"utc_epoch_ns": 1})));
+        assert_eq!(source.next_raw(), None);
+
+        assert!(JsonFileSource::from_reader("not json".as_bytes()).is_err());
+    }
+}
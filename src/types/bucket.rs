@@ -1,18 +1,24 @@
 //! [Bucket] is our smallest cache unit, it holds the cached result of small amount of time.
 
 // System libraries.
-use std::cell::RefCell;
+use std::sync::OnceLock;
 
 // Third party libraries.
+use rayon::prelude::*;
 use tdigest::TDigest;
 
 // Project libraries.
-use crate::types::{Bucket, MarketDataEntry};
-use crate::utils::{f64_max, f64_min};
-
-// Should be safe, as we have a RwLock outside of each Bucket.
-unsafe impl Send for Bucket {}
-unsafe impl Sync for Bucket {}
+use crate::bucketing::{BucketStats, SpreadStats, bucket_contains, removes_within};
+use crate::error::MarketDataError;
+use crate::sketch::{DDSketch, QuantileSketch};
+use crate::types::{
+    BidAsk, Bucket, BucketSummary, EntryColumns, MarketDataEntry, MarketDataEntryFull,
+    TDigestPolicy,
+};
+use crate::utils::{merge_digests_safe, merge_spreads, tdigest_or_null};
+
+/// Buckets with at least this many entries recalculate their stats in parallel.
+const PAR_RECALCULATE_THRESHOLD: usize = 1 << 16;
 
 impl Bucket {
     /// A [Bucket] is defined by its start and end time, represented by u64 in ns.
@@ -21,11 +27,30 @@ impl Bucket {
             start_time_ns,
             end_time_ns,
             count: 0,
+            version: 0,
             // We will use a lazy calculation, so most of the time, tdigest will remain None.
-            tdigest: RefCell::new(None),
+            tdigest: OnceLock::new(),
+            stale_tdigest: None,
             min_spread: f64::MAX,
             max_spread: -f64::MAX,
-            entries: Vec::new(),
+            sum_spread: 0.0,
+            sum_squared_spread: 0.0,
+            sum_offset: 0.0,
+            sum_squared_offset: 0.0,
+            sum_offset_spread: 0.0,
+            entries: EntryColumns::default(),
+            last_inserted_ns: None,
+            depth: Vec::new(),
+            ddsketch: None,
+        }
+    }
+
+    /// Same as [Bucket::new], but pre-allocate space for the given number of entries, to avoid repeated reallocation for
+    /// instruments with a predictable quote rate.
+    pub fn with_capacity(start_time_ns: u64, end_time_ns: u64, capacity: usize) -> Self {
+        Self {
+            entries: EntryColumns::with_capacity(capacity),
+            ..Self::new(start_time_ns, end_time_ns)
         }
     }
 
@@ -33,63 +58,280 @@ impl Bucket {
     /// return false. Otherwise true.
     pub fn insert(&mut self, market_data_entry: MarketDataEntry) -> bool {
         // A quick check the new data indeed belongs to this bucket.
-        if !(self.start_time_ns <= market_data_entry.utc_epoch_ns
-            && market_data_entry.utc_epoch_ns < self.end_time_ns)
-        {
+        if !bucket_contains(
+            self.start_time_ns,
+            self.end_time_ns,
+            market_data_entry.utc_epoch_ns,
+        ) {
             return false;
         }
         // We'll use lazy calculation here.
-        self.tdigest = RefCell::new(None);
-        self.count += 1;
+        self.touch();
         let spread = market_data_entry.spread;
 
         // Update our cache results.
-        self.min_spread = self.min_spread.min(spread);
-        self.max_spread = self.max_spread.max(spread);
+        let mut stats = self.stats();
+        stats.insert(
+            (market_data_entry.utc_epoch_ns - self.start_time_ns) as f64,
+            spread,
+        );
+        self.set_stats(stats);
+        if let Some(ddsketch) = &mut self.ddsketch {
+            ddsketch.insert(spread);
+        }
 
         // Original values will be used when we only want to select a part of this bucket's data, so still need to store
         // them.
+        self.last_inserted_ns = Some(market_data_entry.utc_epoch_ns);
         self.entries.push(market_data_entry);
 
         true
     }
 
+    /// Same as [Bucket::insert], but merge the new spread into the cached tdigest instead of dropping it, see
+    /// [crate::TDigestPolicy::Incremental]. Nothing is calculated if the tdigest is not cached yet.
+    pub fn insert_incremental(&mut self, market_data_entry: MarketDataEntry) -> bool {
+        let spread = market_data_entry.spread;
+        let cached = self.tdigest.take();
+        if !self.insert(market_data_entry) {
+            self.tdigest = cached.map_or_else(OnceLock::new, OnceLock::from);
+            return false;
+        }
+        if let Some((_, tdigest)) = cached {
+            self.tdigest = OnceLock::from((self.version, merge_spreads(&tdigest, vec![spread])));
+        }
+        true
+    }
+
+    /// Same as [Bucket::insert_incremental], but the entry is dropped once it's counted, see
+    /// [crate::EntryStorage::StatsOnly]. The tdigest is always kept, as it can't be calculated from entries later.
+    pub fn insert_stats_only(&mut self, market_data_entry: MarketDataEntry) -> bool {
+        self.extend_stats_only(vec![market_data_entry]).len() == 1
+    }
+
+    /// Same as [Bucket::insert_stats_only] for many entries, whose spreads are merged into the tdigest at once. Return
+    /// the spreads of the entries in the range of this bucket, i.e. the stored ones.
+    pub fn extend_stats_only(&mut self, entries: Vec<MarketDataEntry>) -> Vec<f64> {
+        let cached = self.tdigest.take();
+        let spreads: Vec<f64> = entries
+            .into_iter()
+            .filter_map(|entry| {
+                let spread = entry.spread;
+                self.insert(entry).then_some(spread)
+            })
+            .collect();
+        if spreads.is_empty() {
+            self.tdigest = cached.map_or_else(OnceLock::new, OnceLock::from);
+            return spreads;
+        }
+        self.entries.clear();
+        let tdigest = cached.map_or_else(|| TDigest::new_with_size(100), |(_, tdigest)| tdigest);
+        self.tdigest = OnceLock::from((self.version, merge_spreads(&tdigest, spreads.clone())));
+        spreads
+    }
+
+    /// Keep a [DDSketch] of the given relative accuracy from now on, started from the entries already in the bucket. A
+    /// no-op if the bucket already keeps one.
+    pub fn track_ddsketch(&mut self, relative_accuracy: f64) {
+        if self.ddsketch.is_none() {
+            let mut ddsketch = DDSketch::new(relative_accuracy);
+            self.entries
+                .spreads()
+                .iter()
+                .for_each(|v| ddsketch.insert(*v));
+            self.ddsketch = Some(ddsketch);
+        }
+    }
+
+    /// Drop all entries, keeping the stats and the tdigest calculated from them, see [crate::EntryStorage::StatsOnly].
+    pub fn drop_entries(&mut self) {
+        self.ensure_tdigest();
+        self.stale_tdigest = None;
+        self.entries.clear();
+        self.depth.clear();
+    }
+
+    /// Same as [Bucket::insert], or [Bucket::insert_incremental] with [TDigestPolicy::Incremental], but also keep all
+    /// bid/ask levels of the entry. Return false if the entry is not in the range of this bucket, or it has no spread
+    /// because one side of its book is empty.
+    pub fn insert_full(
+        &mut self,
+        market_data_entry: MarketDataEntryFull,
+        tdigest_policy: TDigestPolicy,
+    ) -> bool {
+        let Some(entry) = market_data_entry.to_entry() else {
+            return false;
+        };
+        let inserted = match tdigest_policy {
+            TDigestPolicy::Lazy => self.insert(entry),
+            TDigestPolicy::Incremental => self.insert_incremental(entry),
+        };
+        if !inserted {
+            return false;
+        }
+        self.depth.push(market_data_entry);
+        true
+    }
+
     /// If threshold is in the range of [Bucket] start and end timestamp, then remove everything happens before
     /// threshold and return the number of elements removed. Otherwise, return 0.
     pub fn remove_up_to(&mut self, threshold: u64) -> usize {
-        if threshold < self.start_time_ns || threshold > self.end_time_ns {
-            // If <, everything should be kept, if >, then the whole bucket should be removed from our cache.
+        if !removes_within(self.start_time_ns, self.end_time_ns, threshold) {
+            // Everything should be kept, or the whole bucket should be removed from our cache.
             return 0;
         }
 
         let original_count = self.count;
         // Filter out.
-        self.entries.retain(|entry| entry.utc_epoch_ns > threshold);
+        self.entries.retain_by_time(|ts| ts > threshold);
+        self.depth.retain(|entry| entry.utc_epoch_ns > threshold);
+        self.compact_if_sparse();
+
+        self.recalculate_stats();
+        original_count - self.count
+    }
 
-        // Update count, min and max.
-        self.count = self.entries.len();
-        let spreads: Vec<f64> = self
-            .entries
+    /// Timestamp of the first entry out of the range of this bucket, if any. [Bucket::insert] never adds one, but
+    /// entries are pub, e.g. for a bucket built by a secondary feed.
+    pub fn first_out_of_range(&self) -> Option<u64> {
+        self.entries
+            .timestamps()
             .iter()
-            .map(|entry| entry.spread)
-            .filter(|v| v.is_finite()) // Filter out NaN、inf
-            .collect();
+            .chain(self.depth.iter().map(|entry| &entry.utc_epoch_ns))
+            .copied()
+            .find(|ts| !bucket_contains(self.start_time_ns, self.end_time_ns, *ts))
+    }
+
+    /// Free the space reserved for entries beyond the current ones.
+    pub fn compact(&mut self) {
+        self.entries.shrink_to_fit();
+        self.depth.shrink_to_fit();
+    }
+
+    /// Same as [Bucket::compact], but only if more than half of the reserved space is unused, e.g. after most of the
+    /// bucket is evicted. A partially removed bucket may still take late entries, so a few removed ones aren't worth a
+    /// reallocation on every eviction.
+    fn compact_if_sparse(&mut self) {
+        if self.entries.capacity() > 2 * self.entries.len() {
+            self.compact();
+        }
+    }
+
+    /// Same as [Bucket::remove_up_to], but return the removed entries, in the order they were inserted.
+    pub fn drain_up_to(&mut self, threshold: u64) -> Vec<MarketDataEntry> {
+        if !removes_within(self.start_time_ns, self.end_time_ns, threshold) {
+            return Vec::new();
+        }
+
+        let removed = self.entries.drain_by_time(|ts| ts <= threshold);
+        self.depth.retain(|entry| entry.utc_epoch_ns > threshold);
+        self.compact_if_sparse();
 
-        if self.count > 0 {
-            self.min_spread = *f64_min(&spreads).unwrap();
-            self.max_spread = *f64_max(&spreads).unwrap();
+        self.recalculate_stats();
+        removed
+    }
+
+    /// The running stats of the entries, kept in the pub fields of [Bucket], see [BucketStats].
+    fn stats(&self) -> BucketStats {
+        BucketStats {
+            spreads: SpreadStats {
+                count: self.count,
+                min: self.min_spread,
+                max: self.max_spread,
+                sum: self.sum_spread,
+                sum_squared: self.sum_squared_spread,
+            },
+            sum_offset: self.sum_offset,
+            sum_squared_offset: self.sum_squared_offset,
+            sum_offset_spread: self.sum_offset_spread,
+        }
+    }
+
+    fn set_stats(&mut self, stats: BucketStats) {
+        self.count = stats.spreads.count;
+        self.min_spread = stats.spreads.min;
+        self.max_spread = stats.spreads.max;
+        self.sum_spread = stats.spreads.sum;
+        self.sum_squared_spread = stats.spreads.sum_squared;
+        self.sum_offset = stats.sum_offset;
+        self.sum_squared_offset = stats.sum_squared_offset;
+        self.sum_offset_spread = stats.sum_offset_spread;
+    }
+
+    /// Some entries are removed, update count, min, max and sums from the remaining entries.
+    fn recalculate_stats(&mut self) {
+        let start_time_ns = self.start_time_ns;
+        let (timestamps, spreads) = (self.entries.timestamps(), self.entries.spreads());
+        let stats = if timestamps.len() < PAR_RECALCULATE_THRESHOLD {
+            BucketStats::from_columns(start_time_ns, timestamps, spreads)
         } else {
-            self.min_spread = f64::MAX;
-            self.max_spread = -f64::MAX;
+            timestamps
+                .par_chunks(PAR_RECALCULATE_THRESHOLD)
+                .zip(spreads.par_chunks(PAR_RECALCULATE_THRESHOLD))
+                .map(|(timestamps, spreads)| {
+                    BucketStats::from_columns(start_time_ns, timestamps, spreads)
+                })
+                .reduce(BucketStats::default, |mut a, b| {
+                    a.merge(&b);
+                    a
+                })
+        };
+        self.set_stats(stats);
+        if let Some(ddsketch) = &mut self.ddsketch {
+            ddsketch.clear();
+            self.entries
+                .spreads()
+                .iter()
+                .for_each(|v| ddsketch.insert(*v));
         }
 
         // Lazy calculation again.
-        self.tdigest = RefCell::new(None);
-        original_count - self.count
+        self.invalidate();
+    }
+
+    /// Merge another [Bucket] covering the same time range into this one, e.g. data bucketed by another feed. Counts,
+    /// min, max, sums and DDSketches are combined directly. Cached tdigests are merged if both buckets have one, and
+    /// the other one's is taken as is if this bucket is empty, otherwise it's left for lazy calculation. Return false
+    /// without merging anything if time ranges are different, or the other bucket holds an entry out of its range, see
+    /// [Bucket::first_out_of_range].
+    pub fn merge(&mut self, other: &Bucket) -> bool {
+        if self.start_time_ns != other.start_time_ns
+            || self.end_time_ns != other.end_time_ns
+            || other.first_out_of_range().is_some()
+        {
+            return false;
+        }
+        let was_empty = self.count == 0;
+
+        let mut stats = self.stats();
+        stats.merge(&other.stats());
+        self.set_stats(stats);
+        self.entries.extend(other.entries.iter());
+        self.depth.extend(other.depth.iter().cloned());
+        match (&mut self.ddsketch, &other.ddsketch) {
+            (Some(ddsketch), Some(other_ddsketch)) => ddsketch.merge(other_ddsketch),
+            (Some(ddsketch), None) => other
+                .entries
+                .spreads()
+                .iter()
+                .for_each(|v| ddsketch.insert(*v)),
+            _ => {}
+        }
+
+        let merged = match (self.current_tdigest(), other.current_tdigest()) {
+            (Some(a), Some(b)) => merge_digests_safe(vec![a.clone(), b.clone()]),
+            (None, Some(b)) if was_empty => Some(b.clone()),
+            _ => None,
+        };
+        self.invalidate();
+        if let Some(merged) = merged {
+            self.tdigest = OnceLock::from((self.version, merged));
+        }
+        true
     }
 
     /// Get everything between [threshold time, bucket end time].
-    pub fn get_start_from(&self, threshold: u64) -> Vec<&MarketDataEntry> {
+    pub fn get_start_from(&self, threshold: u64) -> Vec<MarketDataEntry> {
         if self.start_time_ns <= threshold && threshold <= self.end_time_ns {
             self.entries
                 .iter()
@@ -100,13 +342,26 @@ impl Bucket {
         }
     }
 
+    /// Same as [Bucket::get_start_from], but only the spreads, read from the spread column without building entries.
+    pub fn spreads_start_from(&self, threshold: u64) -> Vec<f64> {
+        if self.start_time_ns <= threshold && threshold <= self.end_time_ns {
+            self.entries.spreads_where(|ts| ts >= threshold).collect()
+        } else {
+            Vec::new()
+        }
+    }
+
     /// Count number of elements in between [threshold time, bucket end time].
     pub fn count_start_from(&self, threshold: u64) -> usize {
-        self.get_start_from(threshold).len()
+        if self.start_time_ns <= threshold && threshold <= self.end_time_ns {
+            self.count_timestamps(|ts| ts >= threshold)
+        } else {
+            0
+        }
     }
 
     /// Get everything between [bucket start time, threshold].
-    pub fn get_end_before(&self, threshold: u64) -> Vec<&MarketDataEntry> {
+    pub fn get_end_before(&self, threshold: u64) -> Vec<MarketDataEntry> {
         if self.start_time_ns <= threshold && threshold <= self.end_time_ns {
             self.entries
                 .iter()
@@ -117,26 +372,163 @@ impl Bucket {
         }
     }
 
+    /// Same as [Bucket::get_end_before], but only the spreads.
+    pub fn spreads_end_before(&self, threshold: u64) -> Vec<f64> {
+        if self.start_time_ns <= threshold && threshold <= self.end_time_ns {
+            self.entries.spreads_where(|ts| ts <= threshold).collect()
+        } else {
+            Vec::new()
+        }
+    }
+
     /// Count number of elements in between [bucket start time, threshold].
     pub fn count_end_before(&self, threshold: u64) -> usize {
-        self.get_end_before(threshold).len()
+        if self.start_time_ns <= threshold && threshold <= self.end_time_ns {
+            self.count_timestamps(|ts| ts <= threshold)
+        } else {
+            0
+        }
+    }
+
+    /// Count the entries whose timestamp satisfies the predicate, only reading the timestamp column.
+    fn count_timestamps<F: Fn(u64) -> bool>(&self, f: F) -> usize {
+        self.entries
+            .timestamps()
+            .iter()
+            .filter(|ts| f(**ts))
+            .count()
     }
 
-    /// Lazy calculate of TDigest.
+    /// Lazy calculate of TDigest. Concurrent readers of the same bucket calculate it only once per version, and the cached
+    /// one is returned as long as the entries don't change. After inserts, the stale one is caught up with the inserted
+    /// spreads rather than calculated from all entries again.
     pub fn get_tdigest(&self) -> TDigest {
-        let mut tdigest_opt = self.tdigest.borrow_mut();
-        if let Some(tdigest) = &*tdigest_opt {
-            return tdigest.clone();
+        self.ensure_tdigest();
+        match self.current_tdigest() {
+            Some(tdigest) => tdigest.clone(),
+            // A tdigest of an older version is never returned, even if it's put there by hand.
+            None => self.calculate_tdigest(),
+        }
+    }
+
+    /// Calculate the tdigest now if it's not cached yet, e.g. once inserts settle, so queries only read it. Unlike
+    /// [Bucket::get_tdigest], nothing is cloned.
+    pub fn ensure_tdigest(&self) {
+        self.tdigest
+            .get_or_init(|| (self.version, self.calculate_tdigest()));
+    }
+
+    /// The version the cached tdigest is calculated at, or None if there's no cached tdigest.
+    pub fn tdigest_version(&self) -> Option<u64> {
+        self.tdigest.get().map(|(version, _)| *version)
+    }
+
+    /// The cached tdigest if it's calculated at the current version.
+    fn current_tdigest(&self) -> Option<&TDigest> {
+        self.tdigest
+            .get()
+            .filter(|(version, _)| *version == self.version)
+            .map(|(_, tdigest)| tdigest)
+    }
+
+    fn calculate_tdigest(&self) -> TDigest {
+        let spreads = self.entries.spreads();
+        // Every insert appends one entry and bumps the version once, so the spreads inserted since the stale tdigest
+        // are the last ones.
+        if let Some((version, stale)) = &self.stale_tdigest {
+            let inserted = self.version.wrapping_sub(*version) as usize;
+            if inserted <= spreads.len() {
+                return merge_spreads(stale, spreads[spreads.len() - inserted..].to_vec());
+            }
         }
+        merge_spreads(&TDigest::new_with_size(100), spreads.to_vec())
+    }
 
-        let spreads = self.entries.iter().map(|e| e.spread).collect();
-        let new_tdigest = TDigest::new_with_size(100).merge_unsorted(spreads);
-        *tdigest_opt = Some(new_tdigest.clone());
-        new_tdigest
+    /// An entry is inserted, bump the version. The cached tdigest is kept as the stale one, to be caught up on the next
+    /// query.
+    fn touch(&mut self) {
+        self.version = self.version.wrapping_add(1);
+        if let Some(cached) = self.tdigest.take() {
+            self.stale_tdigest = Some(cached);
+        }
+    }
+
+    /// Entries are removed or merged, bump the version and drop the cached tdigests, as they can't be caught up.
+    fn invalidate(&mut self) {
+        self.version = self.version.wrapping_add(1);
+        self.tdigest = OnceLock::new();
+        self.stale_tdigest = None;
+    }
+
+    /// Serialize the tdigest of this bucket, calculating it if it's not cached yet, e.g. to offload a cold bucket to disk.
+    /// The tdigest of an empty bucket is written as null.
+    pub fn serialize_tdigest(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        // Serializing a tdigest never fails, it's plain numbers.
+        tdigest_or_null::serialize(
+            &self.get_tdigest(),
+            &mut serde_json::Serializer::new(&mut bytes),
+        )
+        .unwrap();
+        bytes
+    }
+
+    /// Restore the cached tdigest from [Bucket::serialize_tdigest] output. The bucket must hold the same entries as
+    /// when it's serialized, and an already cached tdigest is kept as it's the same.
+    pub fn load_tdigest(&self, bytes: &[u8]) -> Result<(), MarketDataError> {
+        let mut deserializer = serde_json::Deserializer::from_slice(bytes);
+        let tdigest = tdigest_or_null::deserialize(&mut deserializer)
+            .and_then(|tdigest| deserializer.end().map(|_| tdigest))
+            .map_err(|e| MarketDataError::InvalidTDigest(e.to_string()))?;
+        let _ = self.tdigest.set((self.version, tdigest));
+        Ok(())
+    }
+
+    /// Copy the cached statistics of this bucket, without touching its entries.
+    pub fn summary(&self) -> BucketSummary {
+        let (min_spread, max_spread, mean_spread) = if self.count == 0 {
+            (f64::NAN, f64::NAN, f64::NAN)
+        } else {
+            (
+                self.min_spread,
+                self.max_spread,
+                self.sum_spread / self.count as f64,
+            )
+        };
+        BucketSummary {
+            start_time_ns: self.start_time_ns,
+            end_time_ns: self.end_time_ns,
+            count: self.count,
+            min_spread,
+            max_spread,
+            mean_spread,
+        }
+    }
+
+    /// Estimate the heap memory held by this bucket in bytes, based on vector capacities. Centroids of the cached tdigest
+    /// are not exposed, so the tdigest is counted as if it's full, which makes this an upper bound for it.
+    pub fn heap_bytes(&self) -> usize {
+        let entries = self.entries.heap_bytes();
+        let depth = self.depth.capacity() * size_of::<MarketDataEntryFull>()
+            + self
+                .depth
+                .iter()
+                .map(|e| (e.bids.capacity() + e.asks.capacity()) * size_of::<BidAsk>())
+                .sum::<usize>();
+        // Each centroid holds a mean and a weight.
+        let tdigest = self
+            .tdigest
+            .get()
+            .into_iter()
+            .chain(&self.stale_tdigest)
+            .map(|(_, t)| t.max_size() * 2 * size_of::<f64>())
+            .sum::<usize>();
+        let ddsketch = self.ddsketch.as_ref().map_or(0, DDSketch::heap_bytes);
+        entries + depth + tdigest + ddsketch
     }
 
     /// Get the samples in between start and end, and both of the threshold are in the same bucket.
-    pub fn get_in_between(&self, start: u64, end: u64) -> Vec<&MarketDataEntry> {
+    pub fn get_in_between(&self, start: u64, end: u64) -> Vec<MarketDataEntry> {
         if !(self.start_time_ns <= start && start <= end && end <= self.end_time_ns) {
             return Vec::new();
         }
@@ -146,15 +538,35 @@ impl Bucket {
             .collect()
     }
 
+    /// Same as [Bucket::get_in_between], but only the spreads.
+    pub fn spreads_in_between(&self, start: u64, end: u64) -> Vec<f64> {
+        if !(self.start_time_ns <= start && start <= end && end <= self.end_time_ns) {
+            return Vec::new();
+        }
+        self.entries.spreads_between(start, end).collect()
+    }
+
+    /// Same as [Bucket::get_in_between], but for the full depth entries.
+    pub fn get_full_in_between(&self, start: u64, end: u64) -> Vec<&MarketDataEntryFull> {
+        self.depth
+            .iter()
+            .filter(|entry| start <= entry.utc_epoch_ns && entry.utc_epoch_ns <= end)
+            .collect()
+    }
+
     /// Count the samples in between start and end, and both of the threshold are in the same bucket.
     pub fn count_in_between(&self, start: u64, end: u64) -> usize {
-        self.get_in_between(start, end).len()
+        if !(self.start_time_ns <= start && start <= end && end <= self.end_time_ns) {
+            return 0;
+        }
+        self.count_timestamps(|ts| start <= ts && ts <= end)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::utils::{approx_eq, f64_max, f64_min};
 
     #[test]
     fn test_default_bucket() {
@@ -162,26 +574,50 @@ mod tests {
         assert_eq!(bucket.count, 0);
         assert_eq!(bucket.start_time_ns, 0);
         assert_eq!(bucket.end_time_ns, 0);
-        assert!(bucket.tdigest.borrow().is_none());
+        assert!(bucket.tdigest.get().is_none());
     }
 
     #[test]
+    #[allow(clippy::neg_multiply)]
     fn test_new_bucket() {
         let bucket = Bucket::new(10, 100);
         assert_eq!(bucket.count, 0);
         assert_eq!(bucket.start_time_ns, 10);
         assert_eq!(bucket.end_time_ns, 100);
-        assert!(bucket.tdigest.borrow().is_none());
+        assert!(bucket.tdigest.get().is_none());
         assert_eq!(bucket.min_spread, f64::MAX);
         assert_eq!(bucket.max_spread, -1.0 * f64::MAX);
     }
 
+    #[test]
+    fn test_bucket_with_capacity() {
+        let bucket = Bucket::with_capacity(10, 100, 64);
+        assert_eq!(bucket.count, 0);
+        assert_eq!(bucket.start_time_ns, 10);
+        assert_eq!(bucket.end_time_ns, 100);
+        assert!(bucket.entries.capacity() >= 64);
+    }
+
+    #[test]
+    fn test_remove_up_to_compacts_sparse() {
+        let mut bucket = Bucket::with_capacity(0, 100, 64);
+        (0..40).for_each(|i| assert!(bucket.insert(MarketDataEntry::new(i, 1.0))));
+
+        // Most of the reserved space is still used, keep it.
+        assert_eq!(bucket.remove_up_to(4), 5);
+        assert!(bucket.entries.capacity() >= 64);
+
+        assert_eq!(bucket.remove_up_to(30), 26);
+        assert_eq!(bucket.entries.capacity(), 9);
+    }
+
     #[test]
     fn test_insert() {
         let market_data_entries: Vec<MarketDataEntry> = (0..20)
             .map(|i| MarketDataEntry {
                 utc_epoch_ns: i,
                 spread: i as f64,
+                ..Default::default()
             })
             .collect();
         let mut bucket = Bucket::new(0, 10);
@@ -196,7 +632,7 @@ mod tests {
         assert_eq!(bucket.count, 10);
         assert_eq!(bucket.min_spread, 0.0);
         assert_eq!(bucket.max_spread, 9.0);
-        assert!(bucket.tdigest.borrow().is_none());
+        assert!(bucket.tdigest.get().is_none());
     }
 
     #[test]
@@ -205,6 +641,7 @@ mod tests {
             .map(|i| MarketDataEntry {
                 utc_epoch_ns: i,
                 spread: i as f64,
+                ..Default::default()
             })
             .collect();
         let mut bucket = Bucket::new(5, 20);
@@ -226,7 +663,23 @@ mod tests {
         assert_eq!(bucket.count, 9);
         assert_eq!(bucket.max_spread, 19.0);
         assert_eq!(bucket.min_spread, 11.0);
-        assert!(bucket.tdigest.borrow().is_none());
+        assert!(bucket.tdigest.get().is_none());
+    }
+
+    #[test]
+    fn test_sums() {
+        let mut bucket = Bucket::new(5, 20);
+        for i in 0..20 {
+            bucket.insert(MarketDataEntry::new(i, i as f64));
+        }
+        // Spreads 5 to 19.
+        assert_eq!(bucket.sum_spread, 180.0);
+        assert_eq!(bucket.sum_squared_spread, 2440.0);
+
+        // Spreads 11 to 19 are left.
+        bucket.remove_up_to(10);
+        assert_eq!(bucket.sum_spread, 135.0);
+        assert_eq!(bucket.sum_squared_spread, 2085.0);
     }
 
     #[test]
@@ -235,6 +688,7 @@ mod tests {
             .map(|i| MarketDataEntry {
                 utc_epoch_ns: i,
                 spread: i as f64,
+                ..Default::default()
             })
             .collect();
         let mut bucket = Bucket::new(0, 20);
@@ -258,6 +712,7 @@ mod tests {
             .map(|i| MarketDataEntry {
                 utc_epoch_ns: i,
                 spread: i as f64,
+                ..Default::default()
             })
             .collect();
         let mut bucket = Bucket::new(0, 20);
@@ -281,6 +736,7 @@ mod tests {
             .map(|i| MarketDataEntry {
                 utc_epoch_ns: i,
                 spread: i as f64,
+                ..Default::default()
             })
             .collect();
         let mut bucket = Bucket::new(0, 20);
@@ -306,21 +762,247 @@ mod tests {
             .map(|i| MarketDataEntry {
                 utc_epoch_ns: i,
                 spread: i as f64,
+                ..Default::default()
             })
             .collect();
         let mut bucket = Bucket::new(0, 20);
         for entry in market_data_entries {
             bucket.insert(entry);
         }
-        assert!(bucket.tdigest.borrow().is_none());
+        assert!(bucket.tdigest.get().is_none());
         let tdigest = bucket.get_tdigest();
         let ten_th = tdigest.estimate_quantile(0.1);
         assert_eq!(ten_th, 1.5);
-        assert!(bucket.tdigest.borrow().is_some());
+        assert!(bucket.tdigest.get().is_some());
         bucket.insert(MarketDataEntry {
             utc_epoch_ns: 1,
             spread: 1.0,
+            ..Default::default()
         });
-        assert!(bucket.tdigest.borrow().is_none());
+        assert!(bucket.tdigest.get().is_none());
+    }
+
+    #[test]
+    fn test_insert_full() {
+        use crate::types::BidAsk;
+
+        let mut bucket = Bucket::new(0, 20);
+        for i in 0..20 {
+            let entry = MarketDataEntryFull {
+                utc_epoch_ns: i,
+                bids: vec![BidAsk {
+                    price: 100.0,
+                    amount: 1.0,
+                }],
+                asks: vec![BidAsk {
+                    price: 100.0 + i as f64,
+                    amount: 1.0,
+                }],
+            };
+            assert!(bucket.insert_full(entry, TDigestPolicy::Lazy));
+        }
+        let one_sided = MarketDataEntryFull {
+            utc_epoch_ns: 5,
+            bids: vec![],
+            asks: vec![BidAsk {
+                price: 100.0,
+                amount: 1.0,
+            }],
+        };
+        assert!(!bucket.insert_full(one_sided, TDigestPolicy::Lazy));
+
+        assert_eq!(bucket.count, 20);
+        assert_eq!(bucket.max_spread, 19.0);
+        assert_eq!(bucket.get_full_in_between(5, 9).len(), 5);
+
+        bucket.remove_up_to(9);
+        assert_eq!(bucket.depth.len(), bucket.entries.len());
+        assert_eq!(bucket.depth[0].utc_epoch_ns, 10);
+    }
+
+    #[test]
+    fn test_drain_up_to() {
+        let market_data_entries: Vec<MarketDataEntry> =
+            (0..20).map(|i| MarketDataEntry::new(i, i as f64)).collect();
+        let mut bucket = Bucket::new(5, 20);
+        for entry in market_data_entries {
+            bucket.insert(entry);
+        }
+
+        assert!(bucket.drain_up_to(30).is_empty());
+        assert!(bucket.drain_up_to(3).is_empty());
+
+        let drained = bucket.drain_up_to(10);
+        let drained_times: Vec<u64> = drained.iter().map(|entry| entry.utc_epoch_ns).collect();
+        assert_eq!(drained_times, vec![5, 6, 7, 8, 9, 10]);
+        assert_eq!(bucket.count, 9);
+        assert_eq!(bucket.min_spread, 11.0);
+        assert_eq!(bucket.max_spread, 19.0);
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut a = Bucket::new(0, 20);
+        let mut b = Bucket::new(0, 20);
+        for i in 0..10 {
+            a.insert(MarketDataEntry::new(i, i as f64));
+            b.insert(MarketDataEntry::new(i + 10, (i + 10) as f64));
+        }
+        a.get_tdigest();
+        b.get_tdigest();
+
+        assert!(!a.merge(&Bucket::new(0, 10)));
+        let mut out_of_range = b.clone();
+        out_of_range.entries.push(MarketDataEntry::new(25, 1.0));
+        assert_eq!(out_of_range.first_out_of_range(), Some(25));
+        assert!(!a.merge(&out_of_range));
+        assert_eq!(a.count, 10);
+        assert_eq!(b.first_out_of_range(), None);
+        assert!(a.merge(&b));
+        assert_eq!(a.count, 20);
+        assert_eq!(a.entries.len(), 20);
+        assert_eq!(a.min_spread, 0.0);
+        assert_eq!(a.max_spread, 19.0);
+        assert!(approx_eq(a.sum_spread, 190.0, 1e-9));
+        assert_eq!(a.current_tdigest().unwrap().count(), 20.0);
+        assert!(approx_eq(a.get_tdigest().estimate_quantile(0.5), 9.5, 1e-9));
+    }
+
+    #[test]
+    fn test_summary() {
+        let mut bucket = Bucket::new(0, 10);
+        assert!(bucket.summary().mean_spread.is_nan());
+
+        bucket.insert(MarketDataEntry::new(1, 1.0));
+        bucket.insert(MarketDataEntry::new(2, 3.0));
+        let summary = bucket.summary();
+        assert_eq!((summary.start_time_ns, summary.end_time_ns), (0, 10));
+        assert_eq!(summary.count, 2);
+        assert_eq!(
+            (summary.min_spread, summary.max_spread, summary.mean_spread),
+            (1.0, 3.0, 2.0)
+        );
+    }
+
+    #[test]
+    fn test_heap_bytes() {
+        // Only the timestamp and spread columns are allocated for entries without amounts or meta.
+        let entry_bytes = size_of::<u64>() + size_of::<f64>();
+        let mut bucket = Bucket::with_capacity(0, 10, 8);
+        assert_eq!(bucket.heap_bytes(), 8 * entry_bytes);
+
+        bucket.insert(MarketDataEntry::new(1, 1.0));
+        bucket.get_tdigest();
+        assert_eq!(
+            bucket.heap_bytes(),
+            8 * entry_bytes + 100 * 2 * size_of::<f64>()
+        );
+    }
+
+    #[test]
+    fn test_tdigest_version() {
+        let mut bucket = Bucket::new(0, 100);
+        bucket.insert(MarketDataEntry::new(1, 1.0));
+        let version = bucket.version;
+        assert_eq!(bucket.tdigest_version(), None);
+
+        // The first query caches the tdigest, and the second one reuses it.
+        let first = bucket.get_tdigest();
+        assert_eq!(bucket.tdigest_version(), Some(version));
+        assert_eq!(bucket.get_tdigest(), first);
+        assert_eq!(bucket.tdigest_version(), Some(version));
+
+        // An insert bumps the version, so the next query merges the new spread into the stale tdigest.
+        bucket.insert(MarketDataEntry::new(2, 3.0));
+        assert_eq!(bucket.version, version + 1);
+        assert_eq!(bucket.tdigest_version(), None);
+        assert_eq!(bucket.stale_tdigest, Some((version, first.clone())));
+        let caught_up = bucket.get_tdigest();
+        assert_eq!(caught_up.count(), 2.0);
+        assert_eq!(caught_up.max(), 3.0);
+        assert_eq!(bucket.tdigest_version(), Some(version + 1));
+
+        // A removal can't be caught up, so it drops both.
+        bucket.insert(MarketDataEntry::new(3, 5.0));
+        bucket.remove_up_to(1);
+        assert_eq!(bucket.stale_tdigest, None);
+        assert_eq!(bucket.get_tdigest().count(), 2.0);
+        assert_eq!(bucket.get_tdigest().min(), 3.0);
+
+        // A tdigest of an older version is ignored.
+        bucket.tdigest = OnceLock::from((version, first));
+        assert_eq!(bucket.get_tdigest().count(), 2.0);
+        assert_eq!(bucket.get_tdigest().max(), 5.0);
+    }
+
+    #[test]
+    fn test_tdigest_round_trip() {
+        let mut bucket = Bucket::new(0, 100);
+        for i in 0..100 {
+            bucket.insert(MarketDataEntry::new(i, i as f64));
+        }
+        let bytes = bucket.serialize_tdigest();
+        let expected = bucket.get_tdigest();
+
+        // Drop the cached tdigest, as if it's spilled to disk.
+        bucket.tdigest = OnceLock::new();
+        bucket.load_tdigest(&bytes).unwrap();
+        assert_eq!(bucket.current_tdigest(), Some(&expected));
+        assert_eq!(
+            bucket.get_tdigest().estimate_quantile(0.5),
+            expected.estimate_quantile(0.5)
+        );
+
+        assert!(matches!(
+            Bucket::new(0, 100).load_tdigest(b"not a tdigest"),
+            Err(MarketDataError::InvalidTDigest(_))
+        ));
+
+        let empty = Bucket::new(0, 100);
+        let bytes = empty.serialize_tdigest();
+        assert_eq!(bytes, b"null");
+        empty.load_tdigest(&bytes).unwrap();
+        assert!(empty.get_tdigest().is_empty());
+    }
+
+    #[test]
+    fn test_insert_incremental() {
+        let mut bucket = Bucket::new(0, 1000);
+        for i in 0..100 {
+            bucket.insert(MarketDataEntry::new(i, (i % 10) as f64));
+        }
+        bucket.get_tdigest();
+        for i in 100..1000 {
+            assert!(bucket.insert_incremental(MarketDataEntry::new(i, (i % 17) as f64)));
+            assert!(bucket.tdigest.get().is_some());
+        }
+        assert!(!bucket.insert_incremental(MarketDataEntry::new(1000, 1.0)));
+        assert!(bucket.tdigest.get().is_some());
+
+        let incremental = bucket.get_tdigest();
+        bucket.tdigest = OnceLock::new();
+        let from_scratch = bucket.get_tdigest();
+        assert_eq!(incremental.count(), from_scratch.count());
+        for q in [0.1, 0.5, 0.9] {
+            let (a, b) = (
+                incremental.estimate_quantile(q),
+                from_scratch.estimate_quantile(q),
+            );
+            assert!(approx_eq(a, b, 0.5), "q {q}: {a} vs {b}");
+        }
+    }
+
+    #[test]
+    fn test_remove_up_to_large_bucket() {
+        let total = PAR_RECALCULATE_THRESHOLD as u64 * 2;
+        let mut bucket = Bucket::new(0, total);
+        for i in 0..total {
+            bucket.insert(MarketDataEntry::new(i, (i % 10) as f64));
+        }
+        assert_eq!(bucket.remove_up_to(total / 4 - 1), total as usize / 4);
+        assert_eq!(bucket.count, total as usize * 3 / 4);
+        assert_eq!((bucket.min_spread, bucket.max_spread), (0.0, 9.0));
+        let expected_sum: f64 = bucket.entries.iter().map(|e| e.spread).sum();
+        assert!(approx_eq(bucket.sum_spread, expected_sum, 1e-6));
     }
 }
@@ -0,0 +1,200 @@
+//! [EntryColumns] keeps the entries of a bucket as parallel arrays, and hands out [MarketDataEntry]s built on demand.
+
+// Project libraries.
+use crate::types::{EntryColumns, EntryExtras, MarketDataEntry};
+
+/// Extras of an entry without amounts or meta, which are not stored.
+const DEFAULT_EXTRAS: EntryExtras = (0.0, 0.0, None);
+
+impl EntryColumns {
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            utc_epoch_ns: Vec::with_capacity(capacity),
+            spreads: Vec::with_capacity(capacity),
+            extras: Vec::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.utc_epoch_ns.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.utc_epoch_ns.is_empty()
+    }
+
+    /// Number of entries that fit without reallocating the timestamp and spread columns.
+    pub fn capacity(&self) -> usize {
+        self.utc_epoch_ns.capacity().min(self.spreads.capacity())
+    }
+
+    /// Timestamps of all entries, in insertion order.
+    pub fn timestamps(&self) -> &[u64] {
+        &self.utc_epoch_ns
+    }
+
+    /// Spreads of all entries, in the same order as [EntryColumns::timestamps].
+    pub fn spreads(&self) -> &[f64] {
+        &self.spreads
+    }
+
+    /// Same as [EntryColumns::spreads], but mutable. Cached statistics of the owning bucket are not updated.
+    pub fn spreads_mut(&mut self) -> &mut [f64] {
+        &mut self.spreads
+    }
+
+    /// Spreads of the entries with a timestamp in [start, end], both inclusive.
+    pub fn spreads_between(&self, start: u64, end: u64) -> impl Iterator<Item = f64> + '_ {
+        self.spreads_where(move |ts| start <= ts && ts <= end)
+    }
+
+    /// Spreads of the entries whose timestamp satisfies the predicate, only reading the timestamp and spread columns.
+    pub fn spreads_where<F: Fn(u64) -> bool>(&self, f: F) -> impl Iterator<Item = f64> {
+        self.utc_epoch_ns
+            .iter()
+            .zip(&self.spreads)
+            .filter(move |(ts, _)| f(**ts))
+            .map(|(_, spread)| *spread)
+    }
+
+    /// Get the entry at the given position in insertion order.
+    pub fn get(&self, idx: usize) -> Option<MarketDataEntry> {
+        (idx < self.len()).then(|| self.entry(idx))
+    }
+
+    fn entry(&self, idx: usize) -> MarketDataEntry {
+        let (bid_amount, ask_amount, meta) =
+            self.extras.get(idx).copied().unwrap_or(DEFAULT_EXTRAS);
+        MarketDataEntry {
+            utc_epoch_ns: self.utc_epoch_ns[idx],
+            spread: self.spreads[idx],
+            bid_amount,
+            ask_amount,
+            meta,
+        }
+    }
+
+    /// Iterate over copies of all entries, in insertion order.
+    pub fn iter(
+        &self,
+    ) -> impl DoubleEndedIterator<Item = MarketDataEntry> + ExactSizeIterator + '_ {
+        (0..self.len()).map(|idx| self.entry(idx))
+    }
+
+    pub fn push(&mut self, entry: MarketDataEntry) {
+        let extras = (entry.bid_amount, entry.ask_amount, entry.meta);
+        if !self.extras.is_empty() || extras != DEFAULT_EXTRAS {
+            // From the first entry with extras on, the ones before it get the defaults.
+            self.extras.resize(self.len(), DEFAULT_EXTRAS);
+            self.extras.push(extras);
+        }
+        self.utc_epoch_ns.push(entry.utc_epoch_ns);
+        self.spreads.push(entry.spread);
+    }
+
+    /// Keep only the entries whose timestamp satisfies the predicate, in the same order.
+    pub fn retain_by_time<F: Fn(u64) -> bool>(&mut self, f: F) {
+        let keep: Vec<bool> = self.utc_epoch_ns.iter().map(|ts| f(*ts)).collect();
+        let mut flags = keep.iter();
+        self.spreads.retain(|_| *flags.next().unwrap());
+        let mut flags = keep.iter();
+        self.extras.retain(|_| *flags.next().unwrap());
+        let mut flags = keep.iter();
+        self.utc_epoch_ns.retain(|_| *flags.next().unwrap());
+    }
+
+    /// Remove the entries whose timestamp satisfies the predicate, and return them in insertion order.
+    pub fn drain_by_time<F: Fn(u64) -> bool>(&mut self, f: F) -> Vec<MarketDataEntry> {
+        let removed = self.iter().filter(|entry| f(entry.utc_epoch_ns)).collect();
+        self.retain_by_time(|ts| !f(ts));
+        removed
+    }
+
+    /// Remove all entries, keeping the allocated space.
+    pub fn clear(&mut self) {
+        self.utc_epoch_ns.clear();
+        self.spreads.clear();
+        self.extras.clear();
+    }
+
+    /// Free the space reserved for entries beyond the current ones.
+    pub fn shrink_to_fit(&mut self) {
+        self.utc_epoch_ns.shrink_to_fit();
+        self.spreads.shrink_to_fit();
+        self.extras.shrink_to_fit();
+    }
+
+    /// Heap memory held by the columns in bytes, based on their capacities.
+    pub fn heap_bytes(&self) -> usize {
+        self.utc_epoch_ns.capacity() * size_of::<u64>()
+            + self.spreads.capacity() * size_of::<f64>()
+            + self.extras.capacity() * size_of::<EntryExtras>()
+    }
+}
+
+impl Extend<MarketDataEntry> for EntryColumns {
+    fn extend<I: IntoIterator<Item = MarketDataEntry>>(&mut self, iter: I) {
+        iter.into_iter().for_each(|entry| self.push(entry));
+    }
+}
+
+impl FromIterator<MarketDataEntry> for EntryColumns {
+    fn from_iter<I: IntoIterator<Item = MarketDataEntry>>(iter: I) -> Self {
+        let mut columns = Self::default();
+        columns.extend(iter);
+        columns
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_columns_round_trip() {
+        let mut columns = EntryColumns::default();
+        columns.push(MarketDataEntry::new(0, 1.0).with_meta(1));
+        assert_eq!(columns.get(0).unwrap().meta, Some(1));
+
+        let mut columns = EntryColumns::with_capacity(4);
+        columns.push(MarketDataEntry::new(3, 1.0));
+        columns.push(MarketDataEntry::new(1, 2.0));
+        // Only allocated from the first entry with extras.
+        assert_eq!(columns.heap_bytes(), 4 * 16);
+        columns.push(
+            MarketDataEntry::new(2, 3.0)
+                .with_amounts(5.0, 6.0)
+                .with_meta(7),
+        );
+        assert_eq!(columns.len(), 3);
+        assert_eq!(columns.timestamps(), &[3, 1, 2]);
+        assert_eq!(columns.spreads(), &[1.0, 2.0, 3.0]);
+        let last = columns.get(2).unwrap();
+        assert_eq!(
+            (last.bid_amount, last.ask_amount, last.meta),
+            (5.0, 6.0, Some(7))
+        );
+        assert_eq!(columns.get(0).unwrap().meta, None);
+        assert!(columns.get(3).is_none());
+        assert_eq!(
+            columns.spreads_between(2, 3).collect::<Vec<_>>(),
+            vec![1.0, 3.0]
+        );
+
+        let removed = columns.drain_by_time(|ts| ts <= 2);
+        assert_eq!(
+            removed.iter().map(|e| e.utc_epoch_ns).collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+        assert_eq!(removed[1].meta, Some(7));
+        assert_eq!(
+            columns.iter().map(|e| e.utc_epoch_ns).collect::<Vec<_>>(),
+            vec![3]
+        );
+
+        let collected: EntryColumns = removed.into_iter().collect();
+        assert_eq!(collected.iter().next_back().unwrap().bid_amount, 5.0);
+        columns.clear();
+        assert!(columns.is_empty());
+    }
+}
@@ -0,0 +1,127 @@
+//! Helpers on the entry types, mainly converting full depth entries to the spread only ones we use for statistics.
+
+// Project libraries.
+use crate::types::{MarketDataEntry, MarketDataEntryFull, SpreadConvention};
+
+impl MarketDataEntry {
+    /// An entry with only its spread known, top of book amounts are 0.
+    pub fn new(utc_epoch_ns: u64, spread: f64) -> Self {
+        Self {
+            utc_epoch_ns,
+            spread,
+            bid_amount: 0.0,
+            ask_amount: 0.0,
+            meta: None,
+        }
+    }
+
+    /// Tag the entry with caller defined metadata.
+    pub fn with_meta(mut self, meta: u64) -> Self {
+        self.meta = Some(meta);
+        self
+    }
+
+    /// Set amounts of the best bid and ask.
+    pub fn with_amounts(mut self, bid_amount: f64, ask_amount: f64) -> Self {
+        self.bid_amount = bid_amount;
+        self.ask_amount = ask_amount;
+        self
+    }
+
+    /// Top of book imbalance, (bid amount - ask amount) / (bid amount + ask amount), in [-1, 1] for valid amounts.
+    /// Return NaN if both amounts are 0, e.g. when they are unknown.
+    pub fn imbalance(&self) -> f64 {
+        let total = self.bid_amount + self.ask_amount;
+        if total == 0.0 {
+            return f64::NAN;
+        }
+        (self.bid_amount - self.ask_amount) / total
+    }
+}
+
+impl MarketDataEntryFull {
+    /// Spread is calculated lazily from the best ask and bid. Return None if either side of the book is empty.
+    pub fn spread(&self) -> Option<f64> {
+        let best_ask = self.asks.first()?;
+        let best_bid = self.bids.first()?;
+        Some(best_ask.price - best_bid.price)
+    }
+
+    /// Collapse to the spread only entry. Return None if spread is not available.
+    pub fn to_entry(&self) -> Option<MarketDataEntry> {
+        let spread = self.spread()?;
+        Some(
+            MarketDataEntry::new(self.utc_epoch_ns, spread)
+                .with_amounts(self.bids[0].amount, self.asks[0].amount),
+        )
+    }
+}
+
+impl SpreadConvention {
+    /// Calculate the spread of the best ask and bid prices in this convention.
+    pub fn spread(self, best_ask: f64, best_bid: f64) -> f64 {
+        match self {
+            Self::AskMinusBid => best_ask - best_bid,
+            Self::BidMinusAsk => best_bid - best_ask,
+            Self::AbsoluteValue => (best_ask - best_bid).abs(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::BidAsk;
+
+    #[test]
+    fn test_imbalance() {
+        let entry = MarketDataEntry::new(0, 1.0).with_amounts(3.0, 1.0);
+        assert_eq!(entry.imbalance(), 0.5);
+        let entry = MarketDataEntry::new(0, 1.0).with_amounts(1.0, 3.0);
+        assert_eq!(entry.imbalance(), -0.5);
+        assert!(MarketDataEntry::new(0, 1.0).imbalance().is_nan());
+    }
+
+    #[test]
+    fn test_spread() {
+        let mut entry = MarketDataEntryFull {
+            utc_epoch_ns: 10,
+            bids: vec![
+                BidAsk {
+                    price: 99.0,
+                    amount: 1.0,
+                },
+                BidAsk {
+                    price: 98.0,
+                    amount: 2.0,
+                },
+            ],
+            asks: vec![
+                BidAsk {
+                    price: 101.5,
+                    amount: 1.0,
+                },
+                BidAsk {
+                    price: 102.0,
+                    amount: 3.0,
+                },
+            ],
+        };
+        assert_eq!(entry.spread(), Some(2.5));
+        let spread_only = entry.to_entry().unwrap();
+        assert_eq!(spread_only.utc_epoch_ns, 10);
+        assert_eq!(spread_only.spread, 2.5);
+        assert_eq!(spread_only.imbalance(), 0.0);
+
+        entry.asks.clear();
+        assert_eq!(entry.spread(), None);
+        assert!(entry.to_entry().is_none());
+    }
+
+    #[test]
+    fn test_spread_convention() {
+        assert_eq!(SpreadConvention::AskMinusBid.spread(101.0, 100.0), 1.0);
+        assert_eq!(SpreadConvention::BidMinusAsk.spread(101.0, 100.0), -1.0);
+        assert_eq!(SpreadConvention::AbsoluteValue.spread(100.0, 101.0), 1.0);
+    }
+}
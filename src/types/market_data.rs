@@ -1,591 +1,6742 @@
 //! Our main logic of this in-memory cache structure. A [MarketDataCache] consists of a Deque of continues [Bucket]s,
 //! with O(1) time for pop front, push back, and indexing. Also, each [Bucket] object is warped in a [RwLock] for faster
 //! multithreading access. Counter itself is Atomic as it's expected that this value will be updated often.
+//!
+//! Everything that reshapes the deque, i.e. inserts that evict old buckets and removals, takes `&mut self`, while
+//! queries take `&self`. So a query's bucket indices can't be invalidated by a concurrent eviction, the borrow checker
+//! rules it out. To share a cache between a writer and readers, wrap it in e.g. `Arc<RwLock<MarketDataCache>>`, readers
+//! then see a consistent deque for the whole query.
 
 // System libraries.
 use log::{info, warn};
-use std::collections::VecDeque;
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, BinaryHeap, HashMap};
+use std::fmt;
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{BufReader, Read};
+use std::ops::Range;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, RwLock};
 
 // Third party libraries.
+use rayon::ThreadPool;
 use rayon::prelude::*;
 use serde_json::Value;
 use tdigest::TDigest;
 
 // Project libraries.
-use crate::types::{Bucket, MarketDataCache, MarketDataEntry};
-use crate::utils::{calculate_ave_price, find_bucket_index, parse_bid_ask_array};
+use crate::bucketing::{SpreadStats, bucket_start};
+use crate::clock::{Clock, SystemClock};
+use crate::error::MarketDataError;
+use crate::sketch::{DDSketch, QuantileSketch};
+use crate::source::{JsonFileSource, QuoteSource, RawQuote};
+use crate::types::{
+    AggBucket, AlertDirection, AlertFn, BookOrder, Bucket, BucketSketch, BucketStore,
+    BucketSummary, EntryStorage, EvictFn, EvictHandler, InsertOutcome, InvariantViolation,
+    LoadOptions, LoadReport, MarketDataCache, MarketDataEntry, MarketDataEntryFull, OneSidedPolicy,
+    PercentileResult, RangeSummary, Regime, SamplingPolicy, SerializableDigest, SkipCounts,
+    SkipReason, SpreadAlert, SpreadHistogram, StabilityComponents, TDigestPolicy, WindowComparison,
+    WinsorizePolicy,
+};
+use crate::utils::{
+    calculate_ave_price, find_bucket_index, float_gcd, is_book_sorted, merge_digests_safe,
+    merge_spreads, parse_bid_ask_array, sort_book, sorted_quantile, try_from_signed,
+};
 
-impl MarketDataCache {
-    /// A [MarketDataCache] object can hold data in the last num_buckets * bucket_ns ns.
-    pub fn new(num_buckets: usize, bucket_ns: u64) -> Self {
-        let buckets = VecDeque::with_capacity(num_buckets);
-        Self {
-            buckets,
-            bucket_ns,
-            num_buckets,
-            count: AtomicUsize::new(0),
+impl InsertOutcome {
+    /// Outcome of a stored entry, whose insert evicted the given number of entries to slide the cache window.
+    fn stored(evicted: usize) -> Self {
+        if evicted == 0 {
+            Self::Inserted
+        } else {
+            Self::EvictedThenInserted { evicted }
         }
     }
+}
 
-    /// Pre-populate with data for testing. This method will assume bucket size of 100ms and 36000 buckets, which is
-    /// 1 hour of data. This method also handles some errors in input data, e.g. missing expected json fields, apparent
-    /// outliers, etc.
-    pub fn with_file(file_path: &str) -> Self {
-        info!("Reading json file {file_path}");
-        let file = File::open(file_path).unwrap();
-        let reader = BufReader::new(file);
-
-        // Some entries in input json are invalid, so first read everything as raw json values and filter them out later.
-        let json: Value = serde_json::from_reader(reader).unwrap();
-        let entries = json["market_data_entries"].as_array().unwrap();
-        let mut market_data_entries = vec![];
-
-        for (i, entry) in entries.iter().enumerate() {
-            // Handle timestamp.
-            let utc_epoch_ns = match entry.get("utc_epoch_ns") {
-                // This timestamp is 2009 Jan 3, time of the first bitcoin block.
-                Some(Value::Number(n)) if n.as_i64().unwrap() <= 1230940800000000000 => {
-                    warn!("Skipping entry {i} due to invalid timestamp {n}");
-                    continue;
-                }
-                Some(Value::Number(n)) => {
-                    if let Some(ts) = n.as_u64() {
-                        ts
-                    } else {
-                        warn!("Skipping entry {i} due to non-u64 timestamp {n}");
-                        continue;
-                    }
-                }
-                _ => {
-                    warn!("Skipping entry {i} due to missing timestamp in json");
-                    continue;
-                }
-            };
-
-            // Handle bids.
-            // Note that the raw data is already sorted from highest to lowest.
-            let bids = match entry.get("bids") {
-                Some(Value::Array(arr)) => parse_bid_ask_array(arr),
-                _ => {
-                    warn!("Skipping entry {i} due to missing bids array in json");
-                    continue;
-                }
-            };
+impl SpreadHistogram {
+    pub fn new(bin_width: f64) -> Self {
+        Self {
+            bin_width,
+            counts: HashMap::new(),
+        }
+    }
 
-            // Handle asks.
-            // Note that the raw data is already sorted, from lowest to highest.
-            let asks = match entry.get("asks") {
-                Some(Value::Array(arr)) => parse_bid_ask_array(arr),
-                _ => {
-                    warn!("Skipping entry {i} due to missing asks array in json");
-                    continue;
-                }
-            };
+    fn bin(&self, spread: f64) -> i64 {
+        (spread / self.bin_width).floor() as i64
+    }
 
-            if bids.is_empty() || asks.is_empty() {
-                warn!("Skipping entry {i} due to empty bids or asks array");
-                continue;
-            }
-            let spread = asks[0].price - bids[0].price;
+    /// Count a spread, non finite spreads are ignored.
+    pub fn add(&mut self, spread: f64) {
+        if spread.is_finite() {
+            *self.counts.entry(self.bin(spread)).or_insert(0) += 1;
+        }
+    }
 
-            // Safe unwrap here, because we already checked 0.
-            let ave_bid = calculate_ave_price(&bids).unwrap();
-            let ave_ask = calculate_ave_price(&asks).unwrap();
-            if spread.abs() >= ave_ask * 0.03 || spread.abs() > ave_bid * 0.03 {
-                warn!(
-                    "Skipping entry {i} due to outlier, spread is {spread} but ave bid is {ave_bid} and ave ask is {ave_ask}"
-                );
-                continue;
+    /// Uncount a spread that was added before.
+    pub fn remove(&mut self, spread: f64) {
+        if !spread.is_finite() {
+            return;
+        }
+        let bin = self.bin(spread);
+        if let Some(count) = self.counts.get_mut(&bin) {
+            *count -= 1;
+            if *count == 0 {
+                self.counts.remove(&bin);
             }
-            market_data_entries.push(MarketDataEntry {
-                utc_epoch_ns,
-                spread: asks[0].price - bids[0].price,
-            });
         }
+    }
 
-        info!(
-            "Finished reading json file, {} raw entries are identified and {} are valid",
-            entries.len(),
-            market_data_entries.len()
-        );
-
-        // 1 hour data, and each bucket is 100ms.
-        let mut cache = Self::new(36000, 100_000_000);
-        for entry in market_data_entries {
-            cache.insert(entry);
+    /// Add the counts of another histogram with the same bin width.
+    pub fn merge(&mut self, other: &SpreadHistogram) {
+        for (bin, count) in &other.counts {
+            *self.counts.entry(*bin).or_insert(0) += count;
         }
-        cache
     }
 
-    /// Insert an entry into the cache.
-    pub fn insert(&mut self, data: MarketDataEntry) {
-        if self.buckets.is_empty() {
-            // Need to initialize all buckets.
-            // We use aligned bucket start time for easier implementation.
-            let remainder = data.utc_epoch_ns % self.bucket_ns;
-            let aligned_start_time_ns = data.utc_epoch_ns - remainder;
-            for i in 0..self.num_buckets {
-                self.buckets.push_back(Arc::new(RwLock::new(Bucket::new(
-                    aligned_start_time_ns + self.bucket_ns * i as u64,
-                    aligned_start_time_ns + self.bucket_ns * (i + 1) as u64,
-                ))));
-            }
-        }
+    /// Center of the most populated bin, the lowest one on a tie. NaN if nothing is counted.
+    pub fn mode(&self) -> f64 {
+        self.counts
+            .iter()
+            .max_by(|(bin_a, count_a), (bin_b, count_b)| {
+                count_a.cmp(count_b).then(bin_b.cmp(bin_a))
+            })
+            .map_or(f64::NAN, |(bin, _)| (*bin as f64 + 0.5) * self.bin_width)
+    }
+}
 
-        self.count.fetch_add(1, Ordering::SeqCst);
-        let first_bucket_start_ns = {
-            let first_bucket = self.buckets[0].read().unwrap();
-            first_bucket.start_time_ns
-        };
+impl fmt::Debug for EvictHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EvictHandler")
+    }
+}
 
-        // Find the desired bucket to insert into.
-        let bucket_idx =
-            match find_bucket_index(first_bucket_start_ns, data.utc_epoch_ns, self.bucket_ns) {
-                Some(idx) => idx,
-                None => return,
-            };
+impl fmt::Debug for SpreadAlert {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SpreadAlert")
+            .field("threshold", &self.threshold)
+            .field("direction", &self.direction)
+            .finish_non_exhaustive()
+    }
+}
 
-        if bucket_idx >= self.buckets.len() {
-            // So the new data is out of our cache time, need to delete some old data now!
-            let total_cache_time_in_ns = self.num_buckets as u64 * self.bucket_ns;
-            let cache_start_time_ns = first_bucket_start_ns;
-            let threshold = cache_start_time_ns + self.bucket_ns * (bucket_idx + 1) as u64
-                - total_cache_time_in_ns;
-            self.remove_up_to(threshold);
-        }
-        // self.buckets changed, so need to re calculate index!
-        let first_bucket_start_ns = {
-            let first_bucket = self.buckets[0].read().unwrap();
-            first_bucket.start_time_ns
+impl SkipCounts {
+    fn add(&mut self, reason: SkipReason) {
+        let count = match reason {
+            SkipReason::InvalidTimestamp => &mut self.invalid_timestamp,
+            SkipReason::MissingBook => &mut self.missing_book,
+            SkipReason::EmptyBook => &mut self.empty_book,
+            SkipReason::UnsortedBook => &mut self.unsorted_book,
+            SkipReason::Outlier => &mut self.outlier,
         };
-        let bucket_idx =
-            find_bucket_index(first_bucket_start_ns, data.utc_epoch_ns, self.bucket_ns).unwrap();
+        *count += 1;
+    }
+}
 
-        // Get write lock on the target bucket.
-        let bucket = &self.buckets[bucket_idx];
-        let mut bucket_lock = bucket.write().unwrap();
-        bucket_lock.insert(data);
+impl SpreadAlert {
+    /// Tell whether the given spread is beyond the threshold, in the direction of this alert.
+    fn is_triggered(&self, spread: f64) -> bool {
+        match self.direction {
+            AlertDirection::Above => spread > self.threshold,
+            AlertDirection::Below => spread < self.threshold,
+        }
     }
+}
 
-    /// Remove all entries older or the same age as the specified time.
-    /// This function is only used for some periodic cleanup.
-    /// Returns the number of entries deleted.
-    pub fn remove_up_to(&mut self, time: u64) -> usize {
-        let original_count = self.count.load(Ordering::SeqCst);
-        let mut bucket_end_time = {
-            let first_bucket = self.buckets[0].read().unwrap();
-            first_bucket.end_time_ns
-        };
-        while bucket_end_time <= time {
-            // Delete the whole bucket.
-            let popped = self.buckets.pop_front().unwrap();
-            let removed_count = {
-                let popped_bucket = popped.read().unwrap();
-                popped_bucket.count
-            };
-            self.count.fetch_sub(removed_count, Ordering::SeqCst);
+/// A query time range after being validated and clamped to the time span covered by our [Bucket]s. Start and end time
+/// are inclusive, and always fall into the buckets pointed by start_idx and end_idx.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct ResolvedRange {
+    start_idx: usize,
+    end_idx: usize,
+    start_time: u64,
+    end_time: u64,
+}
 
-            bucket_end_time = {
-                let new_first = self.buckets.front().unwrap().read().unwrap();
-                new_first.end_time_ns
-            };
-        }
+/// Loader warnings for skipped entries. After max_warnings are logged, the rest are only counted, so a file with millions
+/// of malformed entries doesn't produce gigabytes of logs.
+#[derive(Debug, Default)]
+struct WarningLimiter {
+    max_warnings: usize,
+    emitted: usize,
+    suppressed: usize,
+}
 
-        // Now, cannot just delete the whole next Bucket, but only a small portion of its data.
-        let deleted = {
-            let mut first_bucket = self.buckets[0].write().unwrap();
-            first_bucket.remove_up_to(time)
-        };
-        self.count.fetch_sub(deleted, Ordering::SeqCst);
+impl WarningLimiter {
+    fn new(max_warnings: usize) -> Self {
+        Self {
+            max_warnings,
+            ..Default::default()
+        }
+    }
 
-        // We deleted some old buckets, time to insert new buckets to keep our total cache duration unchanged.
-        while self.buckets.len() < self.num_buckets {
-            // Get the end time of the last bucket.
-            let last_end = {
-                let last_bucket = self.buckets.back().unwrap().read().unwrap();
-                last_bucket.end_time_ns
-            };
+    /// Log the warning if we haven't reached the limit yet, otherwise just count it.
+    fn warn(&mut self, message: fmt::Arguments) {
+        if self.emitted < self.max_warnings {
+            warn!("{message}");
+            self.emitted += 1;
+        } else {
+            self.suppressed += 1;
+        }
+    }
 
-            self.buckets.push_back(Arc::new(RwLock::new(Bucket::new(
-                last_end,
-                last_end + self.bucket_ns,
-            ))));
+    /// One aggregated warning for everything suppressed.
+    fn summarize(&self) {
+        if self.suppressed > 0 {
+            warn!(
+                "{} more entries were skipped, per entry warnings are suppressed after {}",
+                self.suppressed, self.max_warnings
+            );
         }
-        original_count - self.count.load(Ordering::SeqCst)
     }
+}
 
-    /// Get the total number of entries in the cache.
-    pub fn count(&self) -> usize {
-        self.count.load(Ordering::SeqCst)
+/// A bucket ranked by a key for [MarketDataCache::top_buckets_by_max_spread] and alike. A greater key ranks higher, and
+/// the earlier bucket ranks higher on a tie, so rankings are deterministic.
+#[derive(Clone, Copy, Debug)]
+struct RankedBucket {
+    key: f64,
+    summary: BucketSummary,
+}
+
+impl Ord for RankedBucket {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.key
+            .total_cmp(&other.key)
+            .then(other.summary.start_time_ns.cmp(&self.summary.start_time_ns))
     }
+}
 
-    /// Get the number of entries in the given time range, including both ends.
-    /// start_time and end_time may be any time within the last 1 hour.
-    pub fn count_range(&self, start_time: u64, end_time: u64) -> usize {
-        // No sanity check here because we assumed start and end time are valid.
-        // Get the start time of the first bucket.
-        let cache_start_time_ns = {
-            let first_bucket = self.buckets[0].read().unwrap();
-            first_bucket.start_time_ns
-        };
+impl PartialOrd for RankedBucket {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
 
-        let start_idx = find_bucket_index(cache_start_time_ns, start_time, self.bucket_ns).unwrap();
-        let end_idx = find_bucket_index(cache_start_time_ns, end_time, self.bucket_ns).unwrap();
+impl PartialEq for RankedBucket {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
 
-        // If start and end points to the same bucket.
-        if start_idx == end_idx {
-            return self.buckets[start_idx]
-                .read()
-                .unwrap()
-                .count_in_between(start_time, end_time);
-        }
+impl Eq for RankedBucket {}
 
-        let mut cnt = 0;
+/// Push a bucket into a min heap holding the top k buckets so far, dropping the lowest ranked one if it's full.
+fn push_top_k(heap: &mut BinaryHeap<Reverse<RankedBucket>>, ranked: RankedBucket, k: usize) {
+    heap.push(Reverse(ranked));
+    if heap.len() > k {
+        heap.pop();
+    }
+}
 
-        // Handle the starting bucket, partial data.
-        cnt += {
-            let bucket = self.buckets[start_idx].read().unwrap();
-            bucket.count_start_from(start_time)
-        };
+/// Centered moments of (time, spread) pairs for the least squares trend, see [MarketDataCache::spread_trend_slope].
+/// Times are ns offsets from a common origin. Means and centered sums combine stably across buckets, unlike raw sums of
+/// squared epoch timestamps.
+#[derive(Clone, Copy, Debug, Default)]
+struct TrendMoments {
+    n: f64,
+    mean_t: f64,
+    mean_s: f64,
+    /// Sum of (t - mean_t)^2.
+    m2_t: f64,
+    /// Sum of (t - mean_t) * (s - mean_s).
+    c_ts: f64,
+}
 
-        // Handle the middle, complete bucket. Use rayon to speedup.
-        if start_idx + 1 < end_idx {
-            cnt += (start_idx + 1..end_idx)
-                .into_par_iter()
-                .map(|i| {
-                    let bucket = self.buckets[i].read().unwrap();
-                    bucket.count
-                })
-                .sum::<usize>();
+impl TrendMoments {
+    /// Moments from the raw sums of n pairs, with times as offsets u from a local origin, which itself is origin_t from
+    /// the common origin.
+    fn from_sums(
+        n: usize,
+        origin_t: f64,
+        sum_u: f64,
+        sum_s: f64,
+        sum_uu: f64,
+        sum_us: f64,
+    ) -> Self {
+        if n == 0 {
+            return Self::default();
         }
-
-        // Handle the ending bucket, partial data.
-        if start_idx != end_idx {
-            cnt += {
-                let bucket = self.buckets[end_idx].read().unwrap();
-                bucket.count_end_before(end_time)
-            };
+        let n = n as f64;
+        let (mean_u, mean_s) = (sum_u / n, sum_s / n);
+        Self {
+            n,
+            mean_t: origin_t + mean_u,
+            mean_s,
+            m2_t: sum_uu - sum_u * mean_u,
+            c_ts: sum_us - sum_u * mean_s,
         }
-
-        cnt
     }
 
-    /// Get the 10th, 50th, and 90th percentiles of the spread in the given time range.
-    /// Spread is defined as the difference between the lowest ask price and highest bid price.
-    /// start_time and end_time may be any time within the last 1 hour.
-    pub fn spread_percentiles(&self, start_time: u64, end_time: u64) -> (f64, f64, f64) {
-        // No sanity check here because we assumed start and end time are valid.
-        let cache_start_time_ns = {
-            let first_bucket = self.buckets[0].read().unwrap();
-            first_bucket.start_time_ns
-        };
-
-        let start_idx = find_bucket_index(cache_start_time_ns, start_time, self.bucket_ns).unwrap();
-        let end_idx = find_bucket_index(cache_start_time_ns, end_time, self.bucket_ns).unwrap();
+    /// Moments of the entries, with times as offsets from origin_ns.
+    fn from_entries(entries: &[MarketDataEntry], origin_ns: u64) -> Self {
+        let (sum_u, sum_s, sum_uu, sum_us) =
+            entries.iter().fold((0.0, 0.0, 0.0, 0.0), |sums, entry| {
+                let u = (entry.utc_epoch_ns - origin_ns) as f64;
+                (
+                    sums.0 + u,
+                    sums.1 + entry.spread,
+                    sums.2 + u * u,
+                    sums.3 + u * entry.spread,
+                )
+            });
+        Self::from_sums(entries.len(), 0.0, sum_u, sum_s, sum_uu, sum_us)
+    }
 
-        // If start and end points to the same bucket.
-        if start_idx == end_idx {
-            let bucket = self.buckets[start_idx].read().unwrap();
-            let entries: Vec<f64> = bucket
-                .get_in_between(start_time, end_time)
-                .iter()
-                .map(|e| e.spread)
-                .collect();
-            let tdigest = TDigest::new_with_size(entries.len()).merge_unsorted(entries);
-            return (
-                tdigest.estimate_quantile(0.1),
-                tdigest.estimate_quantile(0.5),
-                tdigest.estimate_quantile(0.9),
-            );
+    fn combine(self, other: Self) -> Self {
+        if self.n == 0.0 {
+            return other;
+        }
+        if other.n == 0.0 {
+            return self;
+        }
+        let n = self.n + other.n;
+        let (delta_t, delta_s) = (other.mean_t - self.mean_t, other.mean_s - self.mean_s);
+        let weight = self.n * other.n / n;
+        Self {
+            n,
+            mean_t: self.mean_t + delta_t * other.n / n,
+            mean_s: self.mean_s + delta_s * other.n / n,
+            m2_t: self.m2_t + other.m2_t + delta_t * delta_t * weight,
+            c_ts: self.c_ts + other.c_ts + delta_t * delta_s * weight,
         }
+    }
+}
 
-        let mut tdigests = Vec::new();
+/// Clamp spreads of all entries to the quantile bounds of the policy, calculated exactly from all their spreads.
+fn winsorize(entries: &mut [MarketDataEntry], policy: WinsorizePolicy) {
+    // NaN spreads, e.g. of one sided books, have no rank, and are kept as they are.
+    let mut spreads: Vec<f64> = entries
+        .iter()
+        .map(|e| e.spread)
+        .filter(|spread| !spread.is_nan())
+        .collect();
+    if spreads.is_empty() {
+        return;
+    }
+    spreads.sort_by(f64::total_cmp);
+    let lower = sorted_quantile(&spreads, policy.lower_q);
+    let upper = sorted_quantile(&spreads, policy.upper_q);
+    for entry in entries.iter_mut() {
+        entry.spread = entry.spread.clamp(lower, upper);
+    }
+}
 
-        // Handle the starting bucket, partial data.
-        {
-            let bucket = self.buckets[start_idx].read().unwrap();
-            let entries = bucket.get_start_from(start_time);
-            if !entries.is_empty() {
-                let spreads: Vec<f64> = entries.iter().map(|e| e.spread).collect();
-                tdigests.push(TDigest::new_with_size(1000).merge_unsorted(spreads));
-            }
-        }
+/// Number of valid quotes [MarketDataCache::ingest] collects before inserting them.
+const INGEST_BATCH_SIZE: usize = 10_000;
+/// Most buckets [LoadOptions::fit_window] grows the window to, so a single stray timestamp can't allocate an unbounded
+/// window.
+const MAX_FIT_WINDOW_BUCKETS: usize = 1 << 20;
 
-        // Handle the middle, complete buckets. Use rayon to speedup.
-        let middle_tdigests: Vec<_> = (start_idx + 1..end_idx)
-            .into_par_iter()
-            .map(|i| {
-                let bucket = self.buckets[i].read().unwrap();
-                bucket.get_tdigest()
-            })
-            .collect();
-        tdigests.extend(middle_tdigests);
+/// Centroids of a merged range tdigest per merged part, the same as the cached tdigest of a bucket.
+const MERGED_TDIGEST_SIZE_PER_PART: usize = 100;
+/// Bounds of the merged range tdigest size, see [merged_tdigest_size].
+const MERGED_TDIGEST_SIZE_RANGE: (usize, usize) = (1000, 10_000);
 
-        // Handle the last bucket, partial data.
-        if start_idx != end_idx {
-            let bucket = self.buckets[end_idx].read().unwrap();
-            let entries = bucket.get_end_before(end_time);
-            if !entries.is_empty() {
-                let spreads: Vec<f64> = entries.iter().map(|e| e.spread).collect();
-                tdigests.push(TDigest::new_with_size(1000).merge_unsorted(spreads));
-            }
-        }
+/// Number of centroids of a tdigest merged from the given number of parts, proportional to it, within
+/// [MERGED_TDIGEST_SIZE_RANGE]. A fixed size under-represents the tails of ranges spanning many buckets, as the centroids
+/// of all buckets are compressed into it, while a larger digest costs more memory and merge time, linear in its size.
+/// The upper bound keeps a whole window query at a few hundred KB, where extra centroids barely move the percentiles.
+fn merged_tdigest_size(num_parts: usize) -> usize {
+    let (min, max) = MERGED_TDIGEST_SIZE_RANGE;
+    num_parts
+        .saturating_mul(MERGED_TDIGEST_SIZE_PER_PART)
+        .clamp(min, max)
+}
 
-        let merged = TDigest::merge_digests(tdigests);
+/// The 10th, 50th and 90th percentiles of a range tdigest, all NaN if the range has no spread.
+fn p10_p50_p90(tdigest: Option<TDigest>) -> (f64, f64, f64) {
+    tdigest.map_or((f64::NAN, f64::NAN, f64::NAN), |tdigest| {
         (
-            merged.estimate_quantile(0.1),
-            merged.estimate_quantile(0.5),
-            merged.estimate_quantile(0.9),
+            tdigest.estimate_quantile(0.1),
+            tdigest.estimate_quantile(0.5),
+            tdigest.estimate_quantile(0.9),
         )
+    })
+}
+
+/// Merge the tdigests of the parts of a range, skipping empty ones, into a digest of [merged_tdigest_size]. None if all
+/// of them are empty, see [merge_digests_safe].
+fn merge_non_empty(mut tdigests: Vec<TDigest>) -> Option<TDigest> {
+    tdigests.retain(|tdigest| !tdigest.is_empty());
+    if tdigests.len() > 1 {
+        // The merged digest takes the size of the first one, and empty digests are skipped for everything else.
+        tdigests.insert(
+            0,
+            TDigest::new_with_size(merged_tdigest_size(tdigests.len())),
+        );
     }
+    merge_digests_safe(tdigests)
+}
 
-    /// Get the minimum spread in the given time range.
-    /// start_time and end_time may be any time within the last 1 hour.
-    pub fn min_spread(&self, start_time: u64, end_time: u64) -> f64 {
-        let cache_start_time_ns = {
-            let first_bucket = self.buckets[0].read().unwrap();
-            first_bucket.start_time_ns
-        };
+impl SerializableDigest {
+    /// Merge the digests of several ranges, e.g. the same time range from caches of different processes.
+    pub fn merge<I: IntoIterator<Item = SerializableDigest>>(digests: I) -> Self {
+        let tdigests = digests.into_iter().map(|digest| digest.tdigest).collect();
+        Self {
+            tdigest: merge_non_empty(tdigests).unwrap_or_default(),
+        }
+    }
 
-        let start_idx = find_bucket_index(cache_start_time_ns, start_time, self.bucket_ns).unwrap();
-        let end_idx = find_bucket_index(cache_start_time_ns, end_time, self.bucket_ns).unwrap();
-        let mut min = f64::MAX;
+    /// Number of spreads in the digest.
+    pub fn count(&self) -> usize {
+        self.tdigest.count() as usize
+    }
 
-        // If start and end points to the same bucket.
-        if start_idx == end_idx {
-            let bucket = self.buckets[start_idx].read().unwrap();
-            let entries = bucket.get_in_between(start_time, end_time);
-            if !entries.is_empty() {
-                let bucket_min = entries
-                    .iter()
-                    .map(|e| e.spread)
-                    .min_by(|a, b| a.partial_cmp(b).unwrap())
-                    .unwrap();
-                return min.min(bucket_min);
-            } else {
-                return min;
-            }
+    pub fn is_empty(&self) -> bool {
+        self.tdigest.is_empty()
+    }
+
+    /// Estimate the q quantile of the spreads, q in [0, 1]. Return NaN if the digest is empty.
+    pub fn quantile(&self, q: f64) -> f64 {
+        if self.tdigest.is_empty() {
+            return f64::NAN;
         }
+        self.tdigest.estimate_quantile(q)
+    }
+}
 
-        // Handle the starting bucket, partial data.
-        {
-            let bucket = self.buckets[start_idx].read().unwrap();
-            let entries = bucket.get_start_from(start_time);
-            if !entries.is_empty() {
-                let bucket_min = entries
-                    .iter()
-                    .map(|e| e.spread)
-                    .min_by(|a, b| a.partial_cmp(b).unwrap())
-                    .unwrap();
-                min = min.min(bucket_min);
-            }
+/// Estimate the fraction of values in a non empty tdigest below the threshold, by bisecting its quantiles, as the tdigest
+/// crate has no cdf.
+fn tdigest_fraction_below(tdigest: &TDigest, threshold: f64) -> f64 {
+    let (mut low, mut high) = (0.0, 1.0);
+    for _ in 0..32 {
+        let q = (low + high) / 2.0;
+        if tdigest.estimate_quantile(q) < threshold {
+            low = q;
+        } else {
+            high = q;
         }
+    }
+    (low + high) / 2.0
+}
 
-        // Handle the middle, complete buckets. Use rayon to speedup.
-        let middle_part_min = (start_idx + 1..end_idx)
-            .into_par_iter()
-            .map(|i| {
-                let bucket = self.buckets[i].read().unwrap();
-                bucket.min_spread
-            })
-            .min_by(|a, b| a.partial_cmp(b).unwrap())
-            .unwrap_or(f64::MAX);
-        min = min.min(middle_part_min);
+/// Make the bucket keep the sketch chosen by [MarketDataCache::with_bucket_sketch] before anything is stored in it.
+fn track_bucket_sketch(bucket: &mut Bucket, bucket_sketch: BucketSketch) {
+    if let BucketSketch::DDSketch(relative_accuracy) = bucket_sketch {
+        bucket.track_ddsketch(relative_accuracy);
+    }
+}
 
-        // Handle the last bucket, partial data.
-        if start_idx != end_idx {
-            let bucket = self.buckets[end_idx].read().unwrap();
-            let entries = bucket.get_end_before(end_time);
-            if !entries.is_empty() {
-                let bucket_min = entries
-                    .iter()
-                    .map(|e| e.spread)
-                    .min_by(|a, b| a.partial_cmp(b).unwrap())
-                    .unwrap();
-                min = min.min(bucket_min);
-            }
+/// Number of quantiles compared by [MarketDataCache::divergence_from_baseline].
+const DIVERGENCE_QUANTILES: usize = 100;
+
+/// Two sample Kolmogorov-Smirnov statistic of two ascending sorted samples, i.e. the largest distance between their
+/// empirical distribution functions. Return NaN if either sample is empty.
+fn ks_statistic(a: &[f64], b: &[f64]) -> f64 {
+    if a.is_empty() || b.is_empty() {
+        return f64::NAN;
+    }
+    let (mut i, mut j, mut max_distance) = (0, 0, 0.0_f64);
+    while i < a.len() && j < b.len() {
+        // Step over all values equal to the smaller head in both samples, so ties move both distributions together.
+        let x = a[i].min(b[j]);
+        while i < a.len() && a[i] <= x {
+            i += 1;
+        }
+        while j < b.len() && b[j] <= x {
+            j += 1;
         }
+        let distance = (i as f64 / a.len() as f64 - j as f64 / b.len() as f64).abs();
+        max_distance = max_distance.max(distance);
+    }
+    max_distance
+}
 
-        min
+impl MarketDataCache {
+    /// A [MarketDataCache] object can hold data in the last num_buckets * bucket_ns ns.
+    pub fn new(num_buckets: usize, bucket_ns: u64) -> Self {
+        Self {
+            buckets: BucketStore::new_dense(num_buckets),
+            bucket_ns,
+            num_buckets,
+            count: AtomicUsize::new(0),
+            storage: EntryStorage::SpreadOnly,
+            latest_entry: None,
+            expected_entries_per_bucket: 0,
+            tdigest_policy: TDigestPolicy::Lazy,
+            sampling: SamplingPolicy::KeepAll,
+            sampling_offered: 0,
+            par_threshold: 16,
+            version: 0,
+            global_min: f64::MAX,
+            global_max: -f64::MAX,
+            evict_handler: None,
+            spread_alert: None,
+            mode_histogram: None,
+            clock: Arc::new(SystemClock),
+            thread_pool: None,
+            max_extra_buckets: 0,
+            eviction_batch: 0,
+            bucket_sketch: BucketSketch::TDigest,
+        }
     }
 
-    // Get the maximum spread in the given time range.
-    // start_time and end_time may be any time within the last 1 hour.
-    pub fn max_spread(&self, start_time: u64, end_time: u64) -> f64 {
-        let cache_start_time_ns = {
-            let first_bucket = self.buckets[0].read().unwrap();
-            first_bucket.start_time_ns
-        };
+    /// Pre-allocate space for the given number of entries whenever a bucket is created. Useful for high frequency
+    /// instruments with a predictable quote rate, default is 0 which means no pre-allocation.
+    pub fn with_expected_entries_per_bucket(mut self, expected_entries_per_bucket: usize) -> Self {
+        self.expected_entries_per_bucket = expected_entries_per_bucket;
+        self
+    }
 
-        let start_idx = find_bucket_index(cache_start_time_ns, start_time, self.bucket_ns).unwrap();
-        let end_idx = find_bucket_index(cache_start_time_ns, end_time, self.bucket_ns).unwrap();
-        let mut max = -f64::MAX;
+    /// Choose how inserts keep the cached bucket tdigests up to date, default is [TDigestPolicy::Lazy].
+    pub fn with_tdigest_policy(mut self, tdigest_policy: TDigestPolicy) -> Self {
+        self.tdigest_policy = tdigest_policy;
+        self
+    }
 
-        // If start and end points to the same bucket.
-        if start_idx == end_idx {
-            let bucket = self.buckets[start_idx].read().unwrap();
-            let entries = bucket.get_in_between(start_time, end_time);
-            if !entries.is_empty() {
-                let bucket_max = entries
-                    .iter()
-                    .map(|e| e.spread)
-                    .max_by(|a, b| a.partial_cmp(b).unwrap())
-                    .unwrap();
-                return max.max(bucket_max);
-            }
-        }
+    /// Downsample bursty feeds on insert, default is [SamplingPolicy::KeepAll]. Only [MarketDataCache::insert] and
+    /// [MarketDataCache::insert_full] sample, bulk loads keep every entry.
+    pub fn with_sampling(mut self, sampling: SamplingPolicy) -> Self {
+        self.sampling = sampling;
+        self
+    }
 
-        // Handle the starting bucket, partial data.
-        {
-            let bucket = self.buckets[start_idx].read().unwrap();
-            let entries = bucket.get_start_from(start_time);
-            if !entries.is_empty() {
-                let bucket_max = entries
-                    .iter()
-                    .map(|e| e.spread)
-                    .max_by(|a, b| a.partial_cmp(b).unwrap())
-                    .unwrap();
-                max = max.max(bucket_max);
-            }
+    /// Handle the middle buckets of a query sequentially if there are fewer of them than par_threshold, default is 16.
+    /// Use 0 to always go parallel, and usize::MAX to never.
+    pub fn with_par_threshold(mut self, par_threshold: usize) -> Self {
+        self.par_threshold = par_threshold;
+        self
+    }
+
+    /// Count the spreads of the whole window in bins of bin_width, kept up to date on every insert and eviction, so
+    /// [MarketDataCache::approximate_mode] is O(bins). Costs a hash map update per insert, and evictions need to move the
+    /// evicted entries out like [MarketDataCache::drain_up_to]. Should be set before any insert, entries already in the
+    /// cache are not counted. Ignored if bin_width is not a positive number, or on a cache from
+    /// [MarketDataCache::new_stats_only], as it can't tell which spreads an eviction removes.
+    pub fn with_mode_histogram(mut self, bin_width: f64) -> Self {
+        if bin_width > 0.0 && bin_width.is_finite() && !self.is_stats_only() {
+            self.mode_histogram = Some(SpreadHistogram::new(bin_width));
         }
+        self
+    }
 
-        // Handle the middle, complete buckets. Use rayon to speedup.
-        let middle_part_max = (start_idx + 1..end_idx)
-            .into_par_iter()
-            .map(|i| {
-                let bucket = self.buckets[i].read().unwrap();
-                bucket.max_spread
-            })
-            .max_by(|a, b| a.partial_cmp(b).unwrap())
-            .unwrap_or_else(|| -f64::MAX);
-        max = max.max(middle_part_max);
+    /// Use the given clock for "now" instead of the system clock, e.g. a [crate::clock::ManualClock] in tests, or a clock
+    /// following the feed's own timestamps.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Run parallel queries, bulk loads and evictions in the given rayon pool instead of the global one, e.g. when the
+    /// crate is embedded in an application that configures the global pool itself. The pool can be shared by caches.
+    pub fn with_thread_pool(mut self, pool: Arc<ThreadPool>) -> Self {
+        self.thread_pool = Some(pool);
+        self
+    }
+
+    /// Allow [MarketDataCache::prepend_historical] to add up to the given number of buckets before the window, default is
+    /// 0 which means backfilled entries older than the window are dropped.
+    pub fn with_max_extra_buckets(mut self, max_extra_buckets: usize) -> Self {
+        self.max_extra_buckets = max_extra_buckets;
+        self
+    }
+
+    /// Evict old buckets in batches instead of on every insert that moves the window forward, for fewer but larger
+    /// eviction pauses. The window grows by up to eviction_batch buckets beyond num_buckets, and once a new entry is
+    /// beyond that, it's evicted back to num_buckets at once. So queries may see up to eviction_batch buckets of data
+    /// older than num_buckets * bucket_ns. Default is 0 which evicts whenever the window moves.
+    pub fn with_eviction_batch(mut self, eviction_batch: usize) -> Self {
+        self.eviction_batch = eviction_batch;
+        self
+    }
+
+    /// Choose the quantile sketch buckets keep for [MarketDataCache::spread_quantile], default is
+    /// [BucketSketch::TDigest], the tdigest every bucket caches anyway. [BucketSketch::DDSketch] keeps a [DDSketch] in
+    /// every bucket as well, which costs a bin update per insert, and panics unless its relative accuracy is in (0, 1).
+    /// Should be set before any insert, buckets with entries already start their sketch from them on their next insert.
+    pub fn with_bucket_sketch(mut self, bucket_sketch: BucketSketch) -> Self {
+        if let BucketSketch::DDSketch(relative_accuracy) = bucket_sketch {
+            DDSketch::new(relative_accuracy);
+        }
+        self.bucket_sketch = bucket_sketch;
+        self
+    }
+
+    /// Same as [MarketDataCache::new], but only keeps buckets that have data, see [BucketStore]. Meant for instruments
+    /// that rarely quote, where most buckets of the window would be empty, queries return the same results as a dense
+    /// cache.
+    pub fn new_sparse(num_buckets: usize, bucket_ns: u64) -> Self {
+        Self {
+            buckets: BucketStore::new_sparse(bucket_ns),
+            num_buckets,
+            ..Self::new(0, bucket_ns)
+        }
+    }
+
+    /// Same as [MarketDataCache::new], but buckets only keep their count, min, max, sums and tdigest, and drop the entries,
+    /// for the smallest memory footprint, see [EntryStorage::StatsOnly]. Queries can't look inside a bucket, so a partial
+    /// bucket at either end of a range counts as a whole, i.e. ranges are widened to whole buckets. Counts, min, max,
+    /// mean, variance and trend slope of ranges aligned to buckets are exact, percentiles come from the cached tdigests, as
+    /// do the counts of [MarketDataCache::count_spread_above] and [MarketDataCache::count_spread_below] in buckets
+    /// straddling the threshold. Queries over single entries, e.g. [MarketDataCache::entries_in_range] or
+    /// [MarketDataCache::spread_mode], find nothing. Evictions are per bucket too, a bucket is kept until all of it is evicted.
+    pub fn new_stats_only(num_buckets: usize, bucket_ns: u64) -> Self {
+        Self {
+            storage: EntryStorage::StatsOnly,
+            ..Self::new(num_buckets, bucket_ns)
+        }
+    }
+
+    /// Same as [MarketDataCache::new], but keeps all bid/ask levels of entries inserted by
+    /// [MarketDataCache::insert_full].
+    pub fn new_full_depth(num_buckets: usize, bucket_ns: u64) -> Self {
+        Self {
+            storage: EntryStorage::FullDepth,
+            ..Self::new(num_buckets, bucket_ns)
+        }
+    }
+
+    /// Pre-populate with data for testing. This method will assume bucket size of 100ms and 36000 buckets, which is
+    /// 1 hour of data. This method also handles some errors in input data, e.g. missing expected json fields, apparent
+    /// outliers, etc.
+    pub fn with_file(file_path: &str) -> Self {
+        Self::with_file_options(file_path, LoadOptions::default())
+    }
+
+    /// Same as [MarketDataCache::with_file], with options to control the loading process.
+    pub fn with_file_options(file_path: &str, options: LoadOptions) -> Self {
+        info!("Reading json file {file_path}");
+        let reader = BufReader::new(File::open(file_path).unwrap());
+        Self::with_json_reader(reader, options).unwrap()
+    }
+
+    /// Same as [MarketDataCache::with_file], but parses a json document already in memory, e.g. fetched over HTTP.
+    pub fn with_json_str(json: &str) -> Result<Self, MarketDataError> {
+        Self::with_json_reader(json.as_bytes(), LoadOptions::default())
+    }
+
+    /// Same as [MarketDataCache::with_file_options], but reads the json document from any reader. Fails if the input is
+    /// not json or has no `market_data_entries` array, invalid quotes inside it are skipped as usual.
+    pub fn with_json_reader<R: Read>(
+        reader: R,
+        options: LoadOptions,
+    ) -> Result<Self, MarketDataError> {
+        let source = JsonFileSource::from_reader(reader)
+            .map_err(|e| MarketDataError::InvalidJson(e.to_string()))?;
+        let mut cache = Self::file_cache();
+        cache.ingest(source, options);
+        Ok(cache)
+    }
+
+    /// The empty cache json files are loaded into.
+    fn file_cache() -> Self {
+        // 1 hour data, and each bucket is 100ms.
+        Self::new(36000, 100_000_000)
+    }
+
+    /// Validate all raw quotes of a source and insert the valid ones, e.g. to feed the cache from a websocket or a message
+    /// queue with the same checks as the json file loader. Invalid quotes are skipped with a warning. Valid quotes are
+    /// inserted in batches of [INGEST_BATCH_SIZE] while reading, so an endless source is inserted as it goes, with bounded
+    /// memory. Winsorizing and fit_window need all quotes before inserting any, so with either of them the whole source
+    /// is read first, and it must end.
+    pub fn ingest<S: QuoteSource>(&mut self, source: S, options: LoadOptions) -> LoadReport {
+        let buffered = options.winsorize.is_some() || options.fit_window;
+        let mut batch = Vec::new();
+        // Later batches may evict earlier ones, so count what each batch stores rather than the change of our count.
+        let mut inserted = 0;
+        let report = Self::read_quotes(source, &options, |entry| {
+            batch.push(entry);
+            if !buffered && batch.len() >= INGEST_BATCH_SIZE {
+                inserted += self.bulk_insert_parallel(std::mem::take(&mut batch));
+            }
+        });
+        if let Some(policy) = options.winsorize {
+            winsorize(&mut batch, policy);
+        }
+        let exceeds_window = !self.fit_window_to(report.data_span, options.fit_window);
+        inserted += self.bulk_insert_parallel(batch);
+        LoadReport {
+            inserted,
+            exceeds_window,
+            ..report
+        }
+    }
+
+    /// Run the same parsing and validation as [MarketDataCache::with_file] on a json file, but discard the entries and
+    /// only report on them, e.g. as a pre-flight check of a large file before loading it in production. Exceeds_window
+    /// tells whether they'd fit in the cache window of [MarketDataCache::with_file], and inserted is always 0.
+    pub fn validate_file(file_path: &str) -> Result<LoadReport, MarketDataError> {
+        info!("Validating json file {file_path}");
+        let file = File::open(file_path).map_err(|e| MarketDataError::Io(e.to_string()))?;
+        let source = JsonFileSource::from_reader(BufReader::new(file))
+            .map_err(|e| MarketDataError::InvalidJson(e.to_string()))?;
+        let options = LoadOptions::default();
+        // Only the reported time span matters for the window, so nothing is kept.
+        let report = Self::read_quotes(source, &options, |_| {});
+        let exceeds_window =
+            !Self::file_cache().fit_window_to(report.data_span, options.fit_window);
+        Ok(LoadReport {
+            exceeds_window,
+            ..report
+        })
+    }
+
+    /// Validate all raw quotes of a source, hand the valid ones to on_valid in source order as they're read, and report on
+    /// them. Inserting is up to on_valid, so inserted and exceeds_window are left unset.
+    fn read_quotes<S: QuoteSource>(
+        mut source: S,
+        options: &LoadOptions,
+        mut on_valid: impl FnMut(MarketDataEntry),
+    ) -> LoadReport {
+        let mut warnings = WarningLimiter::new(options.max_warnings);
+        let mut total = 0;
+        let mut valid = 0;
+        let mut previous_ns: Option<u64> = None;
+        let mut out_of_order = 0;
+        let mut skipped_by_reason = SkipCounts::default();
+        let mut data_span: Option<(u64, u64)> = None;
+        while let Some(raw) = source.next_raw() {
+            match Self::validate_raw_quote(total, &raw, options, &mut warnings) {
+                Ok(entry) => {
+                    let ts = entry.utc_epoch_ns;
+                    if previous_ns.is_some_and(|previous_ns| ts <= previous_ns) {
+                        out_of_order += 1;
+                    }
+                    previous_ns = Some(ts);
+                    data_span = Some(
+                        data_span.map_or((ts, ts), |(first, last)| (first.min(ts), last.max(ts))),
+                    );
+                    valid += 1;
+                    on_valid(entry);
+                }
+                Err(reason) => skipped_by_reason.add(reason),
+            }
+            total += 1;
+        }
+        if out_of_order > 0 {
+            warn!("{out_of_order} quotes are not in strictly increasing timestamp order");
+        }
+
+        warnings.summarize();
+        info!("Finished reading quotes, {total} raw entries are identified and {valid} are valid");
+
+        LoadReport {
+            total,
+            skipped: total - valid,
+            skipped_by_reason,
+            timestamps_monotonic: out_of_order == 0,
+            out_of_order,
+            data_span,
+            ..Default::default()
+        }
+    }
+
+    /// Check if entries spanning from the first to the last timestamp of data_span fit in one cache window, and warn if
+    /// they don't, as only the latest window of them is kept. If grow is set and the cache is empty, grow the window to
+    /// fit them instead, up to [MAX_FIT_WINDOW_BUCKETS] buckets. Return whether they fit afterwards.
+    fn fit_window_to(&mut self, data_span: Option<(u64, u64)>, grow: bool) -> bool {
+        let Some((first_ns, last_ns)) = data_span else {
+            return true;
+        };
+        let needed_buckets = (last_ns / self.bucket_ns - first_ns / self.bucket_ns) as usize + 1;
+        if needed_buckets <= self.num_buckets {
+            return true;
+        }
+        if grow && self.buckets.is_empty() {
+            let grown_buckets = needed_buckets
+                .min(MAX_FIT_WINDOW_BUCKETS)
+                .max(self.num_buckets);
+            info!(
+                "Growing cache window from {} to {grown_buckets} buckets to fit all entries",
+                self.num_buckets
+            );
+            self.num_buckets = grown_buckets;
+            if needed_buckets <= grown_buckets {
+                return true;
+            }
+        }
+        warn!(
+            "Entries span {needed_buckets} buckets from {first_ns} to {last_ns}, but the cache window only has {} \
+             buckets, entries older than the latest window are evicted",
+            self.num_buckets
+        );
+        false
+    }
+
+    /// Turn the i-th raw quote into an entry, or return why it's skipped if it's invalid. Outliers are invalid unless
+    /// they are winsorized later, unsorted books are handled according to book_order, and the spread follows
+    /// spread_convention. A quote may carry a precomputed numeric `spread` instead of `bids` and `asks`, which is taken
+    /// as is. There is no book to check it against, so it's never an outlier, and the spread convention is up to the
+    /// feed.
+    fn validate_raw_quote(
+        i: usize,
+        entry: &RawQuote,
+        options: &LoadOptions,
+        warnings: &mut WarningLimiter,
+    ) -> Result<MarketDataEntry, SkipReason> {
+        // Outliers are only known after all spreads are seen when winsorizing, so keep them and clamp later.
+        let drop_outliers = options.winsorize.is_none();
+        let book_order = options.book_order;
+        // Handle timestamp.
+        // Timestamps are unsigned, a signed one from upstream is converted, and rejected if negative.
+        let utc_epoch_ns = match entry.get("utc_epoch_ns") {
+            Some(Value::Number(n)) => {
+                let ts = match (n.as_u64(), n.as_i64()) {
+                    (Some(ts), _) => Ok(ts),
+                    (None, Some(ts)) => try_from_signed(ts),
+                    (None, None) => {
+                        warnings.warn(format_args!(
+                            "Skipping entry {i} due to non-integer timestamp {n}"
+                        ));
+                        return Err(SkipReason::InvalidTimestamp);
+                    }
+                };
+                match ts {
+                    // This timestamp is 2009 Jan 3, time of the first bitcoin block.
+                    Ok(ts) if ts > 1230940800000000000 => ts,
+                    Ok(ts) => {
+                        warnings.warn(format_args!(
+                            "Skipping entry {i} due to invalid timestamp {ts}"
+                        ));
+                        return Err(SkipReason::InvalidTimestamp);
+                    }
+                    Err(e) => {
+                        warnings.warn(format_args!("Skipping entry {i}: {e}"));
+                        return Err(SkipReason::InvalidTimestamp);
+                    }
+                }
+            }
+            _ => {
+                warnings.warn(format_args!(
+                    "Skipping entry {i} due to missing timestamp in json"
+                ));
+                return Err(SkipReason::InvalidTimestamp);
+            }
+        };
+
+        // Newer feeds emit the spread directly, without the book.
+        if let Some(spread) = entry.get("spread").and_then(Value::as_f64) {
+            return Ok(MarketDataEntry::new(utc_epoch_ns, spread));
+        }
+
+        // Handle bids.
+        // Note that the raw data is already sorted from highest to lowest, unless book_order says otherwise.
+        let mut bids = match entry.get("bids") {
+            Some(Value::Array(arr)) => parse_bid_ask_array(arr),
+            _ => {
+                warnings.warn(format_args!(
+                    "Skipping entry {i} due to missing bids array in json"
+                ));
+                return Err(SkipReason::MissingBook);
+            }
+        };
+
+        // Handle asks.
+        // Note that the raw data is already sorted, from lowest to highest.
+        let mut asks = match entry.get("asks") {
+            Some(Value::Array(arr)) => parse_bid_ask_array(arr),
+            _ => {
+                warnings.warn(format_args!(
+                    "Skipping entry {i} due to missing asks array in json"
+                ));
+                return Err(SkipReason::MissingBook);
+            }
+        };
+
+        if bids.is_empty() || asks.is_empty() {
+            return match options.one_sided {
+                OneSidedPolicy::Skip => {
+                    warnings.warn(format_args!(
+                        "Skipping entry {i} due to empty bids or asks array"
+                    ));
+                    Err(SkipReason::EmptyBook)
+                }
+                OneSidedPolicy::RecordSentinel(spread) => {
+                    Ok(MarketDataEntry::new(utc_epoch_ns, spread))
+                }
+                OneSidedPolicy::RecordNaN => Ok(MarketDataEntry::new(utc_epoch_ns, f64::NAN)),
+            };
+        }
+        if book_order != BookOrder::Trust && !is_book_sorted(&bids, &asks) {
+            if book_order == BookOrder::Skip {
+                warnings.warn(format_args!(
+                    "Skipping entry {i} due to unsorted bids or asks array"
+                ));
+                return Err(SkipReason::UnsortedBook);
+            }
+            sort_book(&mut bids, &mut asks);
+        }
+        let spread = options
+            .spread_convention
+            .spread(asks[0].price, bids[0].price);
+
+        // Safe unwrap here, because we already checked 0.
+        let ave_bid = calculate_ave_price(&bids).unwrap();
+        let ave_ask = calculate_ave_price(&asks).unwrap();
+        if drop_outliers && (spread.abs() >= ave_ask * 0.03 || spread.abs() > ave_bid * 0.03) {
+            warnings.warn(format_args!(
+                "Skipping entry {i} due to outlier, spread is {spread} but ave bid is {ave_bid} and ave ask is {ave_ask}"
+            ));
+            return Err(SkipReason::Outlier);
+        }
+        Ok(MarketDataEntry::new(utc_epoch_ns, spread).with_amounts(bids[0].amount, asks[0].amount))
+    }
+
+    /// Insert an entry into the cache, and tell whether it's stored. An entry before the cache window is dropped, and it's
+    /// not counted.
+    pub fn insert(&mut self, data: MarketDataEntry) -> InsertOutcome {
+        let (bucket_idx, evicted) = match self.prepare_insert(data.utc_epoch_ns) {
+            Ok(prepared) => prepared,
+            Err(outcome) => return outcome,
+        };
+
+        // Get write lock on the target bucket.
+        let bucket_sketch = self.bucket_sketch;
+        let bucket = self
+            .buckets
+            .materialize(bucket_idx, self.expected_entries_per_bucket);
+        let mut bucket_lock = bucket.write().unwrap();
+        track_bucket_sketch(&mut bucket_lock, bucket_sketch);
+        let inserted = match (self.storage, self.tdigest_policy) {
+            (EntryStorage::StatsOnly, _) => bucket_lock.insert_stats_only(data.clone()),
+            (_, TDigestPolicy::Lazy) => bucket_lock.insert(data.clone()),
+            (_, TDigestPolicy::Incremental) => bucket_lock.insert_incremental(data.clone()),
+        };
+        if !inserted {
+            return InsertOutcome::Rejected;
+        }
+        drop(bucket_lock);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        if let Some(histogram) = &mut self.mode_histogram {
+            histogram.add(data.spread);
+        }
+        self.global_min = self.global_min.min(data.spread);
+        self.global_max = self.global_max.max(data.spread);
+        self.check_spread_alert(&data);
+        self.latest_entry = Some(data);
+        InsertOutcome::stored(evicted)
+    }
+
+    /// Same as [MarketDataCache::insert], but failures that are not about the entry being too old are errors, i.e. the
+    /// cache window overflows u64, or the bucket rejects the entry. So ingestion code can propagate them with `?`, and
+    /// only has to handle [InsertOutcome::BeforeWindow] as a regular outcome.
+    pub fn try_insert(&mut self, data: MarketDataEntry) -> Result<InsertOutcome, MarketDataError> {
+        let ts = data.utc_epoch_ns;
+        match self.insert(data) {
+            InsertOutcome::WindowOverflow => Err(MarketDataError::WindowOverflow(ts)),
+            InsertOutcome::Rejected => Err(MarketDataError::EntryRejected(ts)),
+            outcome => Ok(outcome),
+        }
+    }
+
+    /// Insert a full depth entry into the cache. Its spread is calculated from the best ask and bid, and entries with an
+    /// empty side of book are ignored. Unless the cache keeps [EntryStorage::FullDepth], it's the same as
+    /// [MarketDataCache::insert] with the spread.
+    pub fn insert_full(&mut self, data: MarketDataEntryFull) -> InsertOutcome {
+        let Some(entry) = data.to_entry() else {
+            return InsertOutcome::Rejected;
+        };
+        if self.storage != EntryStorage::FullDepth {
+            return self.insert(entry);
+        }
+
+        let (bucket_idx, evicted) = match self.prepare_insert(data.utc_epoch_ns) {
+            Ok(prepared) => prepared,
+            Err(outcome) => return outcome,
+        };
+        let bucket_sketch = self.bucket_sketch;
+        let bucket = self
+            .buckets
+            .materialize(bucket_idx, self.expected_entries_per_bucket);
+        let mut bucket_lock = bucket.write().unwrap();
+        track_bucket_sketch(&mut bucket_lock, bucket_sketch);
+        if !bucket_lock.insert_full(data, self.tdigest_policy) {
+            return InsertOutcome::Rejected;
+        }
+        drop(bucket_lock);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        if let Some(histogram) = &mut self.mode_histogram {
+            histogram.add(entry.spread);
+        }
+        self.global_min = self.global_min.min(entry.spread);
+        self.global_max = self.global_max.max(entry.spread);
+        self.check_spread_alert(&entry);
+        self.latest_entry = Some(entry);
+        InsertOutcome::stored(evicted)
+    }
+
+    /// Call the [SpreadAlert], if any, with a just stored entry whose spread is beyond its threshold.
+    fn check_spread_alert(&mut self, entry: &MarketDataEntry) {
+        if let Some(alert) = &mut self.spread_alert
+            && alert.is_triggered(entry.spread)
+        {
+            (alert.callback)(entry);
+        }
+    }
+
+    /// Tell whether the [SamplingPolicy] keeps an entry with the given timestamp, going into the bucket at bucket_idx.
+    fn sample(&mut self, bucket_idx: usize, ts: u64) -> bool {
+        match self.sampling {
+            SamplingPolicy::KeepAll => true,
+            SamplingPolicy::EveryNth(n) => {
+                let offered = self.sampling_offered;
+                self.sampling_offered = offered.wrapping_add(1);
+                offered.is_multiple_of(n.max(1))
+            }
+            SamplingPolicy::MinInterval(interval_ns) => {
+                // A missing bucket in sparse mode has nothing inserted yet.
+                let Some(bucket) = self.buckets.stored(bucket_idx) else {
+                    return true;
+                };
+                bucket
+                    .read()
+                    .unwrap()
+                    .last_inserted_ns
+                    .is_none_or(|last_ns| last_ns.abs_diff(ts) >= interval_ns)
+            }
+        }
+    }
+
+    /// Insert entries that may be older than the cache window, e.g. when backfilling after the window is established, as
+    /// [MarketDataCache::insert] drops them. Empty buckets are added before the window to hold them, until the window
+    /// has max_extra_buckets more than num_buckets, see [MarketDataCache::with_max_extra_buckets]. The added buckets are
+    /// the oldest ones, so they're the first to be evicted once the window moves forward. Entries older than that, or
+    /// after the window, are dropped, and the latest entry is kept. An empty cache starts its window from the entries,
+    /// the same as [MarketDataCache::bulk_insert_parallel]. Return the number of inserted entries.
+    pub fn prepend_historical(&mut self, mut entries: Vec<MarketDataEntry>) -> usize {
+        let Some((mut window_start_ns, window_end_ns)) = self.time_bounds() else {
+            return self.bulk_insert_parallel(entries);
+        };
+        let first_ns = entries.iter().map(|entry| entry.utc_epoch_ns).min();
+        if let Some(first_ns) = first_ns.filter(|&first_ns| first_ns < window_start_ns) {
+            let room =
+                (self.num_buckets + self.max_extra_buckets).saturating_sub(self.buckets.len());
+            let needed = (window_start_ns - first_ns).div_ceil(self.bucket_ns);
+            let added = needed.min(room as u64);
+            if added > 0 {
+                // Bucket indices change.
+                self.bump_version();
+            }
+            for _ in 0..added {
+                self.buckets.push_empty_front(
+                    window_start_ns - self.bucket_ns,
+                    window_start_ns,
+                    self.expected_entries_per_bucket,
+                );
+                window_start_ns -= self.bucket_ns;
+            }
+            if added < needed {
+                warn!(
+                    "Dropping backfilled entries before {window_start_ns}, the window can only grow by {} buckets",
+                    self.max_extra_buckets
+                );
+            }
+        }
+
+        entries.retain(|entry| {
+            window_start_ns <= entry.utc_epoch_ns && entry.utc_epoch_ns < window_end_ns
+        });
+        // Everything fits in the window, so nothing is evicted, but the latest entry mustn't go back in time.
+        let latest_entry = self.latest_entry.take();
+        let inserted = self.bulk_insert_parallel(entries);
+        self.latest_entry = match (latest_entry, self.latest_entry.take()) {
+            (Some(latest), Some(inserted)) if inserted.utc_epoch_ns >= latest.utc_epoch_ns => {
+                Some(inserted)
+            }
+            (latest, inserted) => latest.or(inserted),
+        };
+        inserted
+    }
+
+    /// Get ready to insert an entry with the given timestamp, initialize buckets for the first insert, offer it to the
+    /// [SamplingPolicy], and evict old data if the timestamp is out of our cache time. Return the index of the bucket
+    /// to insert into and the number of evicted entries, or why the entry has to be dropped. Sampling is decided before
+    /// eviction, so a sampled out entry never slides the window. Count is left to the caller, as nothing is stored yet.
+    fn prepare_insert(&mut self, ts: u64) -> Result<(usize, usize), InsertOutcome> {
+        self.bump_version();
+        if self.buckets.is_empty() && !self.init_buckets(ts) {
+            return Err(InsertOutcome::WindowOverflow);
+        }
+
+        let (first_bucket_start_ns, _) = self.buckets.time_bounds().unwrap();
+
+        // Find the desired bucket to insert into.
+        let bucket_idx = find_bucket_index(first_bucket_start_ns, ts, self.bucket_ns)
+            .ok_or(InsertOutcome::BeforeWindow)?;
+        // Entries that can't fit in any window are not offered to sampling, the same as entries before the window.
+        if bucket_idx >= self.buckets.len()
+            && self.bucket_end(first_bucket_start_ns, bucket_idx).is_none()
+        {
+            warn!("Skipping timestamp {ts}, cache window overflows u64");
+            return Err(InsertOutcome::WindowOverflow);
+        }
+        // A bucket beyond the window has nothing inserted yet.
+        if !self.sample(bucket_idx, ts) {
+            return Err(InsertOutcome::Sampled);
+        }
+
+        if bucket_idx < self.buckets.len() {
+            return Ok((bucket_idx, 0));
+        }
+        let count_before = self.count.load(Ordering::Relaxed);
+        if !self.make_room_for(ts) {
+            return Err(InsertOutcome::WindowOverflow);
+        }
+        let evicted = count_before - self.count.load(Ordering::Relaxed);
+        // self.buckets changed, so need to re calculate index!
+        let (first_bucket_start_ns, _) = self.buckets.time_bounds().unwrap();
+        let bucket_idx = find_bucket_index(first_bucket_start_ns, ts, self.bucket_ns)
+            .ok_or(InsertOutcome::BeforeWindow)?;
+        Ok((bucket_idx, evicted))
+    }
+
+    /// Insert a batch of entries, e.g. when loading a file. Eviction mutates our deque, so it's done once up front to
+    /// make room for the latest entry. After that, buckets are independent of each other, so entries are grouped by
+    /// their bucket index and each group is inserted in parallel under its own bucket write lock. Entries that are
+    /// older than the cache window after eviction are dropped, the same as they would be evicted by one by one insert.
+    /// Return the number of stored entries, which doesn't count the ones evicted to make room for them.
+    pub fn bulk_insert_parallel(&mut self, mut entries: Vec<MarketDataEntry>) -> usize {
+        if entries.is_empty() {
+            return 0;
+        }
+        // Stable sort, so entries with the same timestamp keep their original order.
+        self.install(|| entries.par_sort_by_key(|entry| entry.utc_epoch_ns));
+        self.bump_version();
+
+        if self.buckets.is_empty() && !self.init_buckets(entries[0].utc_epoch_ns) {
+            return 0;
+        }
+        // Drop the entries that can't fit in a cache window, so the latest remaining entry always has room.
+        while let Some(last) = entries.last() {
+            if self.make_room_for(last.utc_epoch_ns) {
+                break;
+            }
+            entries.pop();
+        }
+        if entries.is_empty() {
+            return 0;
+        }
+
+        let (first_bucket_start_ns, _) = self.buckets.time_bounds().unwrap();
+        // The latest entry always fits in the cache after making room for it.
+        self.latest_entry = entries.last().cloned();
+
+        // Group sorted entries by bucket index.
+        let mut groups: Vec<(usize, Vec<MarketDataEntry>)> = Vec::new();
+        for entry in entries {
+            let Some(idx) =
+                find_bucket_index(first_bucket_start_ns, entry.utc_epoch_ns, self.bucket_ns)
+            else {
+                continue;
+            };
+            match groups.last_mut() {
+                Some((last_idx, group)) if *last_idx == idx => group.push(entry),
+                _ => groups.push((idx, vec![entry])),
+            }
+        }
+
+        // Create the buckets in sparse mode up front, as that changes the store.
+        let capacity = self.expected_entries_per_bucket;
+        let groups: Vec<(Arc<RwLock<Bucket>>, Vec<MarketDataEntry>)> = groups
+            .into_iter()
+            .map(|(idx, group)| (Arc::clone(self.buckets.materialize(idx, capacity)), group))
+            .collect();
+        // (number of stored entries, min spread, max spread, histogram of stored spreads) of each group.
+        let bin_width = self.mode_histogram.as_ref().map(|h| h.bin_width);
+        let identity = || (0, f64::MAX, -f64::MAX, bin_width.map(SpreadHistogram::new));
+        let tally =
+            |(count, min, max, mut histogram): (usize, f64, f64, Option<SpreadHistogram>),
+             spread: f64| {
+                if let Some(histogram) = &mut histogram {
+                    histogram.add(spread);
+                }
+                (count + 1, min.min(spread), max.max(spread), histogram)
+            };
+        let (stats_only, bucket_sketch) =
+            (self.storage == EntryStorage::StatsOnly, self.bucket_sketch);
+        let (inserted, min, max, histogram) = self.install(|| {
+            groups
+                .into_par_iter()
+                .map(|(bucket, group)| {
+                    let mut bucket = bucket.write().unwrap();
+                    track_bucket_sketch(&mut bucket, bucket_sketch);
+                    if stats_only {
+                        return bucket
+                            .extend_stats_only(group)
+                            .into_iter()
+                            .fold(identity(), tally);
+                    }
+                    group
+                        .into_iter()
+                        .filter_map(|entry| {
+                            let spread = entry.spread;
+                            bucket.insert(entry).then_some(spread)
+                        })
+                        .fold(identity(), tally)
+                })
+                .reduce(identity, |mut a, b| {
+                    if let (Some(histogram), Some(other)) = (&mut a.3, &b.3) {
+                        histogram.merge(other);
+                    }
+                    (a.0 + b.0, a.1.min(b.1), a.2.max(b.2), a.3)
+                })
+        });
+        self.count.fetch_add(inserted, Ordering::Relaxed);
+        if let (Some(global), Some(histogram)) = (&mut self.mode_histogram, histogram) {
+            global.merge(&histogram);
+        }
+        self.global_min = self.global_min.min(min);
+        self.global_max = self.global_max.max(max);
+        inserted
+    }
+
+    /// Merge an already aggregated [Bucket], e.g. from a secondary feed, into the live bucket with the same time range,
+    /// instead of replaying its entries one by one. The bucket must be aligned to our buckets, not older than our cache
+    /// window, and only hold entries in its own range. A bucket newer than the window moves the window forward, the
+    /// same as inserting its entries would.
+    pub fn merge_bucket(&mut self, other: &Bucket) -> Result<(), MarketDataError> {
+        self.bump_version();
+        if !other.start_time_ns.is_multiple_of(self.bucket_ns)
+            || other.end_time_ns.checked_sub(other.start_time_ns) != Some(self.bucket_ns)
+        {
+            return Err(MarketDataError::MisalignedBucket {
+                start_time_ns: other.start_time_ns,
+                end_time_ns: other.end_time_ns,
+                bucket_ns: self.bucket_ns,
+            });
+        }
+        if let Some(ts) = other.first_out_of_range() {
+            return Err(MarketDataError::EntryRejected(ts));
+        }
+        if self.buckets.is_empty() && !self.init_buckets(other.start_time_ns) {
+            return Err(MarketDataError::WindowOverflow(other.start_time_ns));
+        }
+        if !self.make_room_for(other.start_time_ns) {
+            return Err(MarketDataError::WindowOverflow(other.start_time_ns));
+        }
+
+        let (cache_start_ns, _) = self.time_bounds().unwrap();
+        let Some(bucket_idx) =
+            find_bucket_index(cache_start_ns, other.start_time_ns, self.bucket_ns)
+        else {
+            return Err(MarketDataError::BucketTooOld {
+                start_time_ns: other.start_time_ns,
+                cache_start_ns,
+            });
+        };
+
+        let (stats_only, bucket_sketch) = (self.is_stats_only(), self.bucket_sketch);
+        if stats_only {
+            // Our tdigest can't be calculated from entries after the merge.
+            other.ensure_tdigest();
+        }
+        let mut bucket = self
+            .buckets
+            .materialize(bucket_idx, self.expected_entries_per_bucket)
+            .write()
+            .unwrap();
+        track_bucket_sketch(&mut bucket, bucket_sketch);
+        if !bucket.merge(other) {
+            // Our buckets are aligned to bucket_ns as well, so this only happens if the cache itself is not aligned.
+            return Err(MarketDataError::MisalignedBucket {
+                start_time_ns: other.start_time_ns,
+                end_time_ns: other.end_time_ns,
+                bucket_ns: self.bucket_ns,
+            });
+        }
+        if stats_only {
+            bucket.drop_entries();
+        }
+        self.count.fetch_add(other.count, Ordering::Relaxed);
+        if let Some(histogram) = &mut self.mode_histogram {
+            other.entries.iter().for_each(|e| histogram.add(e.spread));
+        }
+        self.global_min = self.global_min.min(other.min_spread);
+        self.global_max = self.global_max.max(other.max_spread);
+        Ok(())
+    }
+
+    /// Initialize all buckets. We use aligned bucket start time for easier implementation, so the first bucket starts at
+    /// the bucket_ns boundary right before the given timestamp.
+    /// Return false without creating any bucket if the cache window would overflow u64.
+    fn init_buckets(&mut self, first_ns: u64) -> bool {
+        let aligned_start_time_ns = bucket_start(first_ns, self.bucket_ns);
+        if self.window_end(aligned_start_time_ns).is_none() {
+            warn!(
+                "Cannot create buckets starting from {aligned_start_time_ns}, cache window overflows u64"
+            );
+            return false;
+        }
+        for i in 0..self.num_buckets {
+            self.buckets.push_empty(
+                aligned_start_time_ns + self.bucket_ns * i as u64,
+                aligned_start_time_ns + self.bucket_ns * (i + 1) as u64,
+                self.expected_entries_per_bucket,
+            );
+        }
+        true
+    }
+
+    /// End time of a cache window starting from the given time, or None if it overflows u64.
+    fn window_end(&self, start_time_ns: u64) -> Option<u64> {
+        self.bucket_ns
+            .checked_mul(self.num_buckets as u64)
+            .and_then(|total_cache_time_in_ns| start_time_ns.checked_add(total_cache_time_in_ns))
+    }
+
+    /// End time of the bucket at the given index from the first bucket, or None if it overflows u64.
+    fn bucket_end(&self, first_bucket_start_ns: u64, bucket_idx: usize) -> Option<u64> {
+        self.bucket_ns
+            .checked_mul(bucket_idx as u64 + 1)
+            .and_then(|elapsed_ns| first_bucket_start_ns.checked_add(elapsed_ns))
+    }
+
+    /// If the given timestamp is out of our cache time, delete some old data, so that the last bucket contains it, or
+    /// grow the window to it within the eviction batch, see [MarketDataCache::with_eviction_batch]. Return false
+    /// without touching the cache if the window needed for the timestamp would overflow u64.
+    fn make_room_for(&mut self, ts: u64) -> bool {
+        let (first_bucket_start_ns, _) = self.buckets.time_bounds().unwrap();
+        let bucket_idx = match find_bucket_index(first_bucket_start_ns, ts, self.bucket_ns) {
+            Some(idx) if idx >= self.buckets.len() => idx,
+            _ => return true,
+        };
+
+        // The new last bucket is the one containing ts, and the new window ends with it.
+        let Some(new_end_time_ns) = self.bucket_end(first_bucket_start_ns, bucket_idx) else {
+            warn!("Skipping timestamp {ts}, cache window overflows u64");
+            return false;
+        };
+        // Within the eviction batch, grow the window instead.
+        if bucket_idx < self.num_buckets + self.eviction_batch {
+            while self.buckets.len() <= bucket_idx {
+                let (_, last_end) = self.buckets.time_bounds().unwrap();
+                self.buckets.push_empty(
+                    last_end,
+                    last_end + self.bucket_ns,
+                    self.expected_entries_per_bucket,
+                );
+            }
+            return true;
+        }
+        let total_cache_time_in_ns = self.num_buckets as u64 * self.bucket_ns;
+        self.remove_up_to(new_end_time_ns - total_cache_time_in_ns);
+        true
+    }
+
+    /// Drop all entries but keep the configuration, e.g. bucket size, number of buckets and storage mode. The bucket
+    /// deque keeps its allocation, and the next insert starts a new window from its timestamp.
+    pub fn reset(&mut self) {
+        self.bump_version();
+        self.buckets.clear();
+        self.count.store(0, Ordering::Relaxed);
+        self.latest_entry = None;
+        self.sampling_offered = 0;
+        self.global_min = f64::MAX;
+        self.global_max = -f64::MAX;
+        if let Some(histogram) = &mut self.mode_histogram {
+            histogram.counts.clear();
+        }
+    }
+
+    /// Reset the cache and reload it from the given entries, e.g. to rebuild the cache after a reconnect. Equivalent to
+    /// [MarketDataCache::reset] followed by [MarketDataCache::bulk_insert_parallel].
+    pub fn reset_with(&mut self, entries: impl IntoIterator<Item = MarketDataEntry>) {
+        self.reset();
+        self.bulk_insert_parallel(entries.into_iter().collect());
+    }
+
+    /// Hand every entry evicted from now on to the given handler before it's dropped, e.g. to persist cold data. It's
+    /// called by [MarketDataCache::remove_up_to], including the evictions of inserts that slide the cache window, with
+    /// the evicted entries in timestamp order, and not called if nothing is evicted. [MarketDataCache::drain_up_to]
+    /// returns the entries to the caller instead, and [MarketDataCache::reset] drops them without calling it. Never called
+    /// on a cache from [MarketDataCache::new_stats_only], as it keeps no entries to hand over.
+    pub fn set_evict_handler(&mut self, handler: Box<EvictFn>) {
+        self.evict_handler = Some(EvictHandler(handler));
+    }
+
+    /// Stop handing evicted entries over, and return the handler if one is set.
+    pub fn clear_evict_handler(&mut self) -> Option<EvictHandler> {
+        self.evict_handler.take()
+    }
+
+    /// Call the given callback with every entry stored from now on whose spread is strictly above the threshold, e.g. to
+    /// trip a circuit breaker on a blown out market. It's checked by [MarketDataCache::insert] and
+    /// [MarketDataCache::insert_full] after the entry is stored, so dropped entries never fire it. Replaces any alert
+    /// set before.
+    pub fn set_spread_alert(&mut self, threshold: f64, cb: Box<AlertFn>) {
+        self.set_spread_alert_with(threshold, AlertDirection::Above, cb);
+    }
+
+    /// Same as [MarketDataCache::set_spread_alert], but fire on either side of the threshold, e.g.
+    /// [AlertDirection::Below] to catch a crossed market.
+    pub fn set_spread_alert_with(
+        &mut self,
+        threshold: f64,
+        direction: AlertDirection,
+        cb: Box<AlertFn>,
+    ) {
+        self.spread_alert = Some(SpreadAlert {
+            threshold,
+            direction,
+            callback: cb,
+        });
+    }
+
+    /// Stop checking inserted spreads, and return the alert if one is set.
+    pub fn clear_spread_alert(&mut self) -> Option<SpreadAlert> {
+        self.spread_alert.take()
+    }
+
+    /// Remove all entries older or the same age as the specified time.
+    /// This function is only used for some periodic cleanup.
+    /// Returns the number of entries deleted. It's a no-op on an empty cache.
+    pub fn remove_up_to(&mut self, time: u64) -> usize {
+        if self.buckets.is_empty() {
+            return 0;
+        }
+        // With an evict handler or a mode histogram, the entries need to be moved out for them. Stats only buckets have no
+        // entries to move.
+        if !self.is_stats_only() && (self.evict_handler.is_some() || self.mode_histogram.is_some())
+        {
+            let evicted = self.drain_up_to(time);
+            if !evicted.is_empty()
+                && let Some(handler) = &mut self.evict_handler
+            {
+                (handler.0)(&evicted);
+            }
+            return evicted.len();
+        }
+        let original_count = self.count.load(Ordering::Relaxed);
+        // Extremes of the buckets we remove from, to tell whether the running extremes might be removed.
+        // Popped buckets are summed up and dropped in parallel, as evicting many full buckets at once is dominated by
+        // freeing their entries.
+        let popped = self.pop_front_up_to(time);
+        let (removed_count, mut removed_min, mut removed_max) = self.install(|| {
+            popped
+                .into_par_iter()
+                .map(|popped| {
+                    let popped_bucket = popped.read().unwrap();
+                    (
+                        popped_bucket.count,
+                        popped_bucket.min_spread,
+                        popped_bucket.max_spread,
+                    )
+                })
+                .reduce(
+                    || (0, f64::MAX, -f64::MAX),
+                    |a, b| (a.0 + b.0, a.1.min(b.1), a.2.max(b.2)),
+                )
+        });
+        self.count.fetch_sub(removed_count, Ordering::Relaxed);
+        if self.buckets.is_empty() {
+            self.update_running_extremes(removed_min, removed_max);
+            return original_count - self.count.load(Ordering::Relaxed);
+        }
+
+        // Now, cannot just delete the whole next Bucket, but only a small portion of its data. Without entries, it's kept
+        // whole.
+        let partial_bucket = self.buckets.stored(0).filter(|_| !self.is_stats_only());
+        let deleted = partial_bucket.map_or(0, |first_bucket| {
+            let mut first_bucket = first_bucket.write().unwrap();
+            removed_min = removed_min.min(first_bucket.min_spread);
+            removed_max = removed_max.max(first_bucket.max_spread);
+            // A large bucket recalculates its stats in parallel.
+            let first_bucket: &mut Bucket = &mut first_bucket;
+            self.install(|| first_bucket.remove_up_to(time))
+        });
+        self.count.fetch_sub(deleted, Ordering::Relaxed);
+
+        self.refill_buckets();
+        self.update_running_extremes(removed_min, removed_max);
+        original_count - self.count.load(Ordering::Relaxed)
+    }
+
+    /// Remove all entries older than max_age_ns according to the cache's clock, i.e. with a timestamp before now -
+    /// max_age_ns, see [MarketDataCache::with_clock]. Returns the number of entries deleted, 0 if now is less than
+    /// max_age_ns after the epoch.
+    pub fn evict_older_than(&mut self, max_age_ns: u64) -> usize {
+        match self.clock.now_ns().checked_sub(max_age_ns) {
+            Some(cutoff) if cutoff > 0 => self.remove_up_to(cutoff - 1),
+            _ => 0,
+        }
+    }
+
+    /// Same as [MarketDataCache::remove_up_to], but return the removed entries in timestamp order, e.g. for audit
+    /// logging. Use [MarketDataCache::remove_up_to] if only the number of removed entries matters, as it doesn't need to
+    /// move the entries out. A cache from [MarketDataCache::new_stats_only] evicts the same, but has no entries to return.
+    pub fn drain_up_to(&mut self, time: u64) -> Vec<MarketDataEntry> {
+        if self.buckets.is_empty() {
+            return Vec::new();
+        }
+        let mut drained = Vec::new();
+        // Taken from the bucket stats, as stats only buckets have no entries.
+        let (mut removed_min, mut removed_max) = (f64::MAX, -f64::MAX);
+        for popped in self.pop_front_up_to(time) {
+            let popped_bucket = popped.read().unwrap();
+            self.count.fetch_sub(popped_bucket.count, Ordering::Relaxed);
+            removed_min = removed_min.min(popped_bucket.min_spread);
+            removed_max = removed_max.max(popped_bucket.max_spread);
+            drained.extend(popped_bucket.entries.iter());
+        }
+        if self.buckets.is_empty() {
+            self.update_running_extremes(removed_min, removed_max);
+            if let Some(histogram) = &mut self.mode_histogram {
+                drained.iter().for_each(|e| histogram.remove(e.spread));
+            }
+            return drained;
+        }
+
+        // Now, cannot just delete the whole next Bucket, but only a small portion of its data. Without entries, it's kept
+        // whole.
+        let mut partial = self
+            .buckets
+            .stored(0)
+            .filter(|_| !self.is_stats_only())
+            .map_or_else(Vec::new, |first_bucket| {
+                let mut first_bucket = first_bucket.write().unwrap();
+                let first_bucket: &mut Bucket = &mut first_bucket;
+                self.install(|| first_bucket.drain_up_to(time))
+            });
+        self.count.fetch_sub(partial.len(), Ordering::Relaxed);
+        for entry in &partial {
+            removed_min = removed_min.min(entry.spread);
+            removed_max = removed_max.max(entry.spread);
+        }
+        drained.append(&mut partial);
+
+        self.refill_buckets();
+        self.update_running_extremes(removed_min, removed_max);
+        if let Some(histogram) = &mut self.mode_histogram {
+            drained.iter().for_each(|e| histogram.remove(e.spread));
+        }
+        // Entries in each bucket are in insertion order, and buckets are in time order. Stable sort keeps the insertion
+        // order for entries with the same timestamp.
+        drained.sort_by_key(|entry| entry.utc_epoch_ns);
+        drained
+    }
+
+    /// Some entries whose spreads are within [removed_min, removed_max] are removed. If the running extremes might be
+    /// among them, rescan the min and max cached in the remaining buckets, otherwise they're still valid.
+    fn update_running_extremes(&mut self, removed_min: f64, removed_max: f64) {
+        if removed_min > self.global_min && removed_max < self.global_max {
+            return;
+        }
+        let (min, max) = self
+            .buckets
+            .iter()
+            .fold((f64::MAX, -f64::MAX), |(min, max), bucket| {
+                let bucket = bucket.read().unwrap();
+                (min.min(bucket.min_spread), max.max(bucket.max_spread))
+            });
+        self.global_min = min;
+        self.global_max = max;
+    }
+
+    /// Pop all buckets that end before or at the specified time, and return them. If every bucket is popped, the cache
+    /// starts over from the bucket containing the specified time, or stays empty if that window would overflow u64.
+    /// Buckets must not be empty when calling this function. Without entries, a bucket whose last nanosecond is the
+    /// specified time is popped too, as there is nothing left to keep of it.
+    fn pop_front_up_to(&mut self, time: u64) -> Vec<Arc<RwLock<Bucket>>> {
+        self.bump_version();
+        let pop_time = if self.is_stats_only() {
+            time.saturating_add(1)
+        } else {
+            time
+        };
+        let popped = self.buckets.pop_front_up_to(pop_time);
+        if self.buckets.is_empty() {
+            self.init_buckets(time);
+        }
+        popped
+    }
+
+    /// We deleted some old buckets, time to insert new buckets to keep our total cache duration unchanged.
+    fn refill_buckets(&mut self) {
+        while self.buckets.len() < self.num_buckets {
+            // Get the end time of the last bucket.
+            let (_, last_end) = self.buckets.time_bounds().unwrap();
+            let Some(new_end) = last_end.checked_add(self.bucket_ns) else {
+                warn!("Cannot add bucket after {last_end}, cache window overflows u64");
+                return;
+            };
+
+            self.buckets
+                .push_empty(last_end, new_end, self.expected_entries_per_bucket);
+        }
+    }
+
+    /// Estimate the memory used by this cache in bytes, e.g. to choose num_buckets and bucket_ns for a memory budget. It's
+    /// the size of the cache itself, the bucket deque, every bucket behind its [Arc] and [RwLock], and the heap memory
+    /// of every bucket, see [Bucket::heap_bytes]. Allocator overheads are not included.
+    pub fn estimated_bytes(&self) -> usize {
+        size_of::<Self>() + self.buckets.estimated_bytes()
+    }
+
+    /// Check the internal invariants of the cache, as a debugging aid: consecutive buckets are contiguous, the count is
+    /// the sum of the bucket counts, and every entry falls within its bucket. Return all violations found, in bucket
+    /// order with the count mismatch last. Missing buckets of a sparse cache are skipped.
+    pub fn validate_invariants(&self) -> Result<(), Vec<InvariantViolation>> {
+        let mut violations = Vec::new();
+        let mut bucket_sum = 0;
+        let mut previous: Option<(usize, u64)> = None;
+        for idx in 0..self.buckets.len() {
+            let Some(bucket) = self.buckets.stored(idx) else {
+                previous = None;
+                continue;
+            };
+            let bucket = bucket.read().unwrap();
+            if let Some((previous_idx, end_time_ns)) = previous
+                && end_time_ns != bucket.start_time_ns
+            {
+                violations.push(InvariantViolation::NotContiguous {
+                    idx: previous_idx,
+                    end_time_ns,
+                    next_start_time_ns: bucket.start_time_ns,
+                });
+            }
+            previous = Some((idx, bucket.end_time_ns));
+            bucket_sum += bucket.count;
+            violations.extend(
+                bucket
+                    .entries
+                    .iter()
+                    .filter(|e| {
+                        e.utc_epoch_ns < bucket.start_time_ns
+                            || e.utc_epoch_ns >= bucket.end_time_ns
+                    })
+                    .map(|e| InvariantViolation::EntryOutOfBucket {
+                        idx,
+                        utc_epoch_ns: e.utc_epoch_ns,
+                    }),
+            );
+        }
+        let count = self.count();
+        if count != bucket_sum {
+            violations.push(InvariantViolation::CountMismatch { count, bucket_sum });
+        }
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(violations)
+        }
+    }
+
+    /// Calculate the missing tdigests of all non-empty buckets in parallel, e.g. after a bulk load or between bursts of
+    /// inserts, so percentile queries find every middle bucket tdigest ready and don't pay for it. Only read locks are
+    /// taken, see [Bucket::ensure_tdigest].
+    pub fn warm_digests(&self) {
+        let buckets: Vec<&Arc<RwLock<Bucket>>> = self.buckets.iter().collect();
+        self.install(|| {
+            buckets.into_par_iter().for_each(|bucket| {
+                let bucket = bucket.read().unwrap();
+                if bucket.count > 0 {
+                    bucket.ensure_tdigest();
+                }
+            })
+        });
+    }
+
+    /// Free the space every bucket reserves beyond its current entries, e.g. after a burst of quotes, or when
+    /// [MarketDataCache::with_expected_entries_per_bucket] over-estimates the quote rate. Buckets grow again on insert.
+    pub fn compact(&mut self) {
+        for bucket in self.buckets.iter() {
+            bucket.write().unwrap().compact();
+        }
+    }
+
+    /// Get the time span covered by this cache as (cache start ns, cache end ns), start inclusive and end exclusive.
+    /// Return None before the first insert, as buckets are not initialized yet.
+    pub fn time_bounds(&self) -> Option<(u64, u64)> {
+        self.buckets.time_bounds()
+    }
+
+    /// Get the timestamps of the earliest and latest entries actually in the cache, both inclusive, e.g. to label the x
+    /// axis of a chart. Unlike [MarketDataCache::time_bounds], leading and trailing empty buckets are not included.
+    /// Return None if the cache has no entry.
+    pub fn data_time_span(&self) -> Option<(u64, u64)> {
+        let first_ns = self.buckets.iter().find_map(|bucket| {
+            let bucket = bucket.read().unwrap();
+            bucket.entries.timestamps().iter().copied().min()
+        })?;
+        let last_ns = self.buckets.iter().rev().find_map(|bucket| {
+            let bucket = bucket.read().unwrap();
+            bucket.entries.timestamps().iter().copied().max()
+        })?;
+        Some((first_ns, last_ns))
+    }
+
+    /// Check whether the given timestamp falls into the time span covered by this cache.
+    pub fn contains(&self, ts: u64) -> bool {
+        self.time_bounds()
+            .is_some_and(|(start_time, end_time)| start_time <= ts && ts < end_time)
+    }
+
+    /// Get the summary of the bucket containing the given timestamp, e.g. to inspect the cached statistics around an event.
+    /// Return None if the timestamp is outside the time span of this cache.
+    pub fn bucket_at(&self, ts: u64) -> Option<BucketSummary> {
+        let (cache_start_time_ns, _) = self.time_bounds()?;
+        if !self.contains(ts) {
+            return None;
+        }
+        let idx = find_bucket_index(cache_start_time_ns, ts, self.bucket_ns)?;
+        self.summary_at(cache_start_time_ns, idx)
+    }
+
+    /// Get the summary of the bucket at the given index, 0 being the oldest bucket. Return None if the index is out of
+    /// range.
+    pub fn bucket_summary_at_index(&self, idx: usize) -> Option<BucketSummary> {
+        let (cache_start_time_ns, _) = self.time_bounds()?;
+        self.summary_at(cache_start_time_ns, idx)
+    }
+
+    /// Get the summaries of the buckets in the given index range, 0 being the oldest bucket. Indices past the newest
+    /// bucket are ignored.
+    pub fn bucket_summaries_by_index(&self, range: Range<usize>) -> Vec<BucketSummary> {
+        let Some((cache_start_time_ns, _)) = self.time_bounds() else {
+            return Vec::new();
+        };
+        let end = range.end.min(self.buckets.len());
+        (range.start..end)
+            .filter_map(|idx| self.summary_at(cache_start_time_ns, idx))
+            .collect()
+    }
+
+    /// Call a function with the summary of every bucket, from the oldest to the newest, including empty ones. Locking is
+    /// handled here, so callers don't depend on how buckets are stored or synchronized.
+    pub fn for_each_bucket<F: FnMut(&BucketSummary)>(&self, mut f: F) {
+        let Some((cache_start_time_ns, _)) = self.time_bounds() else {
+            return;
+        };
+        for idx in 0..self.buckets.len() {
+            if let Some(summary) = self.summary_at(cache_start_time_ns, idx) {
+                f(&summary);
+            }
+        }
+    }
+
+    /// Same as [MarketDataCache::for_each_bucket], but collect what the function returns for every bucket.
+    pub fn map_buckets<T, F: Fn(&BucketSummary) -> T>(&self, f: F) -> Vec<T> {
+        let mut result = Vec::with_capacity(self.buckets.len());
+        self.for_each_bucket(|summary| result.push(f(summary)));
+        result
+    }
+
+    /// Call a function with the summary of every bucket overlapping with the given time range, from the oldest to the
+    /// newest, so large ranges can be aggregated incrementally without collecting anything. Stats of the first and last
+    /// buckets only cover the entries in range, while their start and end times are still the bucket's. The function is
+    /// called under a bucket read lock, see [MarketDataCache] for what it may do.
+    pub fn stream_range<F: FnMut(BucketSummary)>(&self, start_time: u64, end_time: u64, mut f: F) {
+        let Some(range) = self.resolve_range(start_time, end_time) else {
+            return;
+        };
+        let (cache_start_time_ns, _) = self.time_bounds().unwrap();
+        for idx in range.start_idx..=range.end_idx {
+            let bucket = self.buckets[idx].read().unwrap();
+            let bucket_start_ns = cache_start_time_ns + self.bucket_ns * idx as u64;
+            let partial = idx == range.start_idx || idx == range.end_idx;
+            let summary = if partial && !self.is_stats_only() {
+                let stats = SpreadStats::from_spreads(
+                    bucket
+                        .entries
+                        .iter()
+                        .filter(|e| {
+                            range.start_time <= e.utc_epoch_ns && e.utc_epoch_ns <= range.end_time
+                        })
+                        .map(|e| e.spread),
+                );
+                let nan_if_empty = |v: f64| if stats.count == 0 { f64::NAN } else { v };
+                BucketSummary {
+                    start_time_ns: bucket_start_ns,
+                    end_time_ns: bucket_start_ns + self.bucket_ns,
+                    count: stats.count,
+                    min_spread: nan_if_empty(stats.min),
+                    max_spread: nan_if_empty(stats.max),
+                    mean_spread: stats.mean(),
+                }
+            } else {
+                BucketSummary {
+                    start_time_ns: bucket_start_ns,
+                    end_time_ns: bucket_start_ns + self.bucket_ns,
+                    ..bucket.summary()
+                }
+            };
+            f(summary);
+        }
+    }
+
+    /// Get the k buckets with the widest max spread among those overlapping with the given time range, e.g. to report
+    /// the widest markets of a day. Only cached bucket stats are read, so it's cheap. Empty buckets are ignored. Sorted
+    /// from the widest, and the earlier bucket comes first on a tie.
+    pub fn top_buckets_by_max_spread(
+        &self,
+        start_time: u64,
+        end_time: u64,
+        k: usize,
+    ) -> Vec<BucketSummary> {
+        self.top_buckets_by(start_time, end_time, k, |summary| summary.max_spread)
+    }
+
+    /// Same as [MarketDataCache::top_buckets_by_max_spread], but rank buckets by their number of entries.
+    pub fn top_buckets_by_count(
+        &self,
+        start_time: u64,
+        end_time: u64,
+        k: usize,
+    ) -> Vec<BucketSummary> {
+        self.top_buckets_by(start_time, end_time, k, |summary| summary.count as f64)
+    }
+
+    /// Rank the non-empty buckets overlapping with the given time range by a key, and return the top k of them, see
+    /// [RankedBucket]. Each rayon job keeps its own bounded heap, and the heaps are merged at the end.
+    fn top_buckets_by<F>(
+        &self,
+        start_time: u64,
+        end_time: u64,
+        k: usize,
+        key: F,
+    ) -> Vec<BucketSummary>
+    where
+        F: Fn(&BucketSummary) -> f64 + Sync,
+    {
+        let Some(range) = self.resolve_range(start_time, end_time) else {
+            return Vec::new();
+        };
+        if k == 0 {
+            return Vec::new();
+        }
+        let (cache_start_time_ns, _) = self.time_bounds().unwrap();
+        let heap = self.install(|| {
+            (range.start_idx..=range.end_idx)
+                .into_par_iter()
+                .filter_map(|idx| self.summary_at(cache_start_time_ns, idx))
+                .filter(|summary| summary.count > 0)
+                .fold(BinaryHeap::new, |mut heap, summary| {
+                    let ranked = RankedBucket {
+                        key: key(&summary),
+                        summary,
+                    };
+                    push_top_k(&mut heap, ranked, k);
+                    heap
+                })
+                .reduce(BinaryHeap::new, |mut heap, other| {
+                    for Reverse(ranked) in other {
+                        push_top_k(&mut heap, ranked, k);
+                    }
+                    heap
+                })
+        });
+        // Ascending order of Reverse is descending order of rank.
+        heap.into_sorted_vec()
+            .into_iter()
+            .map(|Reverse(ranked)| ranked.summary)
+            .collect()
+    }
+
+    /// Get the summary of the bucket at the given index, given the start time of the cache.
+    fn summary_at(&self, cache_start_time_ns: u64, idx: usize) -> Option<BucketSummary> {
+        let bucket = self.buckets.get(idx)?.read().unwrap();
+        // A missing bucket in sparse mode doesn't know its own time range.
+        let bucket_start_ns = cache_start_time_ns + self.bucket_ns * idx as u64;
+        Some(BucketSummary {
+            start_time_ns: bucket_start_ns,
+            end_time_ns: bucket_start_ns + self.bucket_ns,
+            ..bucket.summary()
+        })
+    }
+
+    /// Validate a query range and clamp it to the time span of our buckets. Return None if the cache is empty, start time
+    /// is after end time, or the range doesn't overlap with the cache at all.
+    fn resolve_range(&self, start_time: u64, end_time: u64) -> Option<ResolvedRange> {
+        if start_time > end_time {
+            return None;
+        }
+        let (cache_start_time_ns, cache_end_time_ns) = self.time_bounds()?;
+        if end_time < cache_start_time_ns || start_time >= cache_end_time_ns {
+            return None;
+        }
+
+        // Bucket end time is exclusive, so the last valid ns is one before it.
+        let start_time = start_time.max(cache_start_time_ns);
+        let end_time = end_time.min(cache_end_time_ns - 1);
+        Some(ResolvedRange {
+            start_idx: find_bucket_index(cache_start_time_ns, start_time, self.bucket_ns)?,
+            end_idx: find_bucket_index(cache_start_time_ns, end_time, self.bucket_ns)?,
+            start_time,
+            end_time,
+        })
+    }
+
+    /// Get the number of buckets a query over the given time range touches, e.g. to decide whether to chunk a big query.
+    /// The range is clamped to the cache the same way as the queries do, so it's 0 on an empty cache, an invalid range,
+    /// or a range that doesn't overlap with the cache.
+    pub fn buckets_in_range(&self, start_time: u64, end_time: u64) -> usize {
+        self.resolve_range(start_time, end_time)
+            .map_or(0, |range| range.end_idx - range.start_idx + 1)
+    }
+
+    /// Get all full depth entries in the given time range, including both ends, in bucket order. Always empty with
+    /// [EntryStorage::SpreadOnly].
+    pub fn full_entries_in_range(
+        &self,
+        start_time: u64,
+        end_time: u64,
+    ) -> Vec<MarketDataEntryFull> {
+        let Some(range) = self.resolve_range(start_time, end_time) else {
+            return Vec::new();
+        };
+        (range.start_idx..=range.end_idx)
+            .flat_map(|i| {
+                let bucket = self.buckets[i].read().unwrap();
+                bucket
+                    .get_full_in_between(range.start_time, range.end_time)
+                    .into_iter()
+                    .cloned()
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    /// Get the number of entries, sum of spreads and sum of squared spreads in a resolved range. Middle buckets use their
+    /// cached sums, only the partial buckets at both ends need to be scanned.
+    fn range_moments(&self, range: ResolvedRange) -> (usize, f64, f64) {
+        let moments_of = |spreads: Vec<f64>| {
+            let stats = SpreadStats::from_slice(&spreads);
+            (stats.count, stats.sum, stats.sum_squared)
+        };
+        let bucket_moments =
+            |bucket: &Bucket| (bucket.count, bucket.sum_spread, bucket.sum_squared_spread);
+        let sum_moments =
+            |a: (usize, f64, f64), b: (usize, f64, f64)| (a.0 + b.0, a.1 + b.1, a.2 + b.2);
+
+        // Whole buckets only, so the cached sums are enough.
+        if self.is_stats_only() {
+            return self
+                .map_buckets_in(range.start_idx..range.end_idx + 1, bucket_moments)
+                .into_iter()
+                .fold((0, 0.0, 0.0), sum_moments);
+        }
+
+        // If start and end points to the same bucket.
+        if range.start_idx == range.end_idx {
+            let bucket = self.buckets[range.start_idx].read().unwrap();
+            return moments_of(bucket.spreads_in_between(range.start_time, range.end_time));
+        }
+
+        let (mut n, mut sum, mut sum_sq) = {
+            let bucket = self.buckets[range.start_idx].read().unwrap();
+            moments_of(bucket.spreads_start_from(range.start_time))
+        };
+
+        // Handle the middle, complete buckets.
+        let (middle_n, middle_sum, middle_sum_sq) = self
+            .map_middle_buckets(range.start_idx, range.end_idx, bucket_moments)
+            .into_iter()
+            .fold((0, 0.0, 0.0), sum_moments);
+        n += middle_n;
+        sum += middle_sum;
+        sum_sq += middle_sum_sq;
+
+        let (last_n, last_sum, last_sum_sq) = {
+            let bucket = self.buckets[range.end_idx].read().unwrap();
+            moments_of(bucket.spreads_end_before(range.end_time))
+        };
+        (n + last_n, sum + last_sum, sum_sq + last_sum_sq)
+    }
+
+    /// Get the population variance of spreads in the given time range. Middle buckets use their cached sums, so they're
+    /// O(1). Return NaN if no spread is in range.
+    pub fn spread_variance(&self, start_time: u64, end_time: u64) -> f64 {
+        self.spread_mean_variance(start_time, end_time).1
+    }
+
+    /// Mean and population variance of spreads in the given time range, both NaN if no spread is in range.
+    fn spread_mean_variance(&self, start_time: u64, end_time: u64) -> (f64, f64) {
+        let Some(range) = self.resolve_range(start_time, end_time) else {
+            return (f64::NAN, f64::NAN);
+        };
+        let (n, sum, sum_sq) = self.range_moments(range);
+        if n == 0 {
+            return (f64::NAN, f64::NAN);
+        }
+        let mean = sum / n as f64;
+        // Rounding error may push the variance slightly below zero.
+        (mean, (sum_sq / n as f64 - mean * mean).max(0.0))
+    }
+
+    /// Get mean spread, spread variance and the longest quote gap in the given time range, the components of
+    /// [MarketDataCache::stability_score]. The gap fraction is relative to the requested range, not the clamped one, so a
+    /// range mostly outside of the cache reads as gappy.
+    pub fn stability_components(&self, start_time: u64, end_time: u64) -> StabilityComponents {
+        let (mean_spread, spread_variance) = self.spread_mean_variance(start_time, end_time);
+        let longest_gap_ns = self.longest_gap(start_time, end_time);
+        let range_ns = end_time.saturating_sub(start_time).saturating_add(1);
+        StabilityComponents {
+            mean_spread,
+            spread_variance,
+            longest_gap_ns,
+            gap_fraction: longest_gap_ns as f64 / range_ns as f64,
+        }
+    }
+
+    /// Get a composite market quality score in the given time range, lower is more stable:
+    ///
+    /// `mean_spread * (1 + spread_variance / mean_spread^2 + gap_fraction)`
+    ///
+    /// i.e. the mean spread, inflated by the squared coefficient of variation and the longest quote gap as a fraction of
+    /// the range. Both penalties are unitless, so the score is in spread units and comparable across instruments quoted
+    /// in the same currency. Return NaN if no spread is in range, or the mean spread is zero.
+    pub fn stability_score(&self, start_time: u64, end_time: u64) -> f64 {
+        let components = self.stability_components(start_time, end_time);
+        let mean = components.mean_spread;
+        if mean == 0.0 {
+            return f64::NAN;
+        }
+        mean * (1.0 + components.spread_variance / (mean * mean) + components.gap_fraction)
+    }
+
+    /// Get the least squares slope of spread against time in the given time range, in spread units per second, e.g.
+    /// positive when the market is widening. Middle buckets use their cached sums, so they're O(1). Return NaN if there
+    /// are fewer than 2 distinct timestamps in range.
+    pub fn spread_trend_slope(&self, start_time: u64, end_time: u64) -> f64 {
+        let Some(range) = self.resolve_range(start_time, end_time) else {
+            return f64::NAN;
+        };
+        let bucket_moments = |bucket: &Bucket, origin_ns: u64| {
+            TrendMoments::from_sums(
+                bucket.count,
+                (bucket.start_time_ns - origin_ns) as f64,
+                bucket.sum_offset,
+                bucket.sum_spread,
+                bucket.sum_squared_offset,
+                bucket.sum_offset_spread,
+            )
+        };
+        // All times are offsets from the range start.
+        let origin_ns = range.start_time;
+        let moments = if self.is_stats_only() {
+            // Whole buckets only, so times are offsets from the start of the first one instead.
+            let origin_ns = self.buckets[range.start_idx].read().unwrap().start_time_ns;
+            self.map_buckets_in(range.start_idx..range.end_idx + 1, |bucket| {
+                bucket_moments(bucket, origin_ns)
+            })
+            .into_iter()
+            .fold(TrendMoments::default(), TrendMoments::combine)
+        } else if range.start_idx == range.end_idx {
+            let bucket = self.buckets[range.start_idx].read().unwrap();
+            TrendMoments::from_entries(
+                &bucket.get_in_between(range.start_time, range.end_time),
+                origin_ns,
+            )
+        } else {
+            let first = {
+                let bucket = self.buckets[range.start_idx].read().unwrap();
+                TrendMoments::from_entries(&bucket.get_start_from(range.start_time), origin_ns)
+            };
+            let middle = self.map_middle_buckets(range.start_idx, range.end_idx, |bucket| {
+                bucket_moments(bucket, origin_ns)
+            });
+            let last = {
+                let bucket = self.buckets[range.end_idx].read().unwrap();
+                TrendMoments::from_entries(&bucket.get_end_before(range.end_time), origin_ns)
+            };
+            middle
+                .into_iter()
+                .fold(first, TrendMoments::combine)
+                .combine(last)
+        };
+        if moments.n < 2.0 || moments.m2_t == 0.0 {
+            return f64::NAN;
+        }
+        moments.c_ts / moments.m2_t * 1e9
+    }
+
+    /// How unusual the latest quote is compared to the trailing window: (latest spread - mean) / standard deviation, with
+    /// mean and population standard deviation over [latest timestamp - window_ns, latest timestamp]. Return NaN if there
+    /// is no entry yet, or standard deviation is zero.
+    pub fn latest_spread_zscore(&self, window_ns: u64) -> f64 {
+        let Some(latest) = &self.latest_entry else {
+            return f64::NAN;
+        };
+        let start_time = latest.utc_epoch_ns.saturating_sub(window_ns);
+        let Some(range) = self.resolve_range(start_time, latest.utc_epoch_ns) else {
+            return f64::NAN;
+        };
+
+        let (n, sum, sum_sq) = self.range_moments(range);
+        if n == 0 {
+            return f64::NAN;
+        }
+        let mean = sum / n as f64;
+        // Rounding error may push the variance slightly below zero.
+        let std_dev = (sum_sq / n as f64 - mean * mean).max(0.0).sqrt();
+        if std_dev == 0.0 {
+            return f64::NAN;
+        }
+        (latest.spread - mean) / std_dev
+    }
+
+    /// Get all entries in the given time range, including both ends, in timestamp order. Entries with the same timestamp
+    /// keep their insertion order. Note that entries are rebuilt from the bucket columns, this is the base of queries
+    /// that need to walk through quotes one by one. Use [MarketDataCache::visit_entries] to walk through them without
+    /// collecting them.
+    pub fn entries_in_range(&self, start_time: u64, end_time: u64) -> Vec<MarketDataEntry> {
+        let mut result = Vec::new();
+        self.for_each_entry(start_time, end_time, |entry| result.push(entry));
+        result
+    }
+
+    /// Call a function with every entry in the given time range, in the same order as
+    /// [MarketDataCache::entries_in_range], but without collecting them, e.g. for custom aggregations over millions of
+    /// entries. Buckets store entries as columns, so each entry is rebuilt from them one at a time and lent to the
+    /// function, not borrowed from the bucket. The function is called under a bucket read lock, see [MarketDataCache]
+    /// for what it may do.
+    pub fn visit_entries<F: FnMut(&MarketDataEntry)>(
+        &self,
+        start_time: u64,
+        end_time: u64,
+        mut f: F,
+    ) {
+        self.for_each_entry(start_time, end_time, |entry| f(&entry));
+    }
+
+    /// Rebuild every entry in the given time range from the bucket columns, and hand it to a function in timestamp
+    /// order, see [MarketDataCache::entries_in_range].
+    fn for_each_entry<F: FnMut(MarketDataEntry)>(&self, start_time: u64, end_time: u64, mut f: F) {
+        let Some(range) = self.resolve_range(start_time, end_time) else {
+            return;
+        };
+        for i in range.start_idx..=range.end_idx {
+            let bucket = self.buckets[i].read().unwrap();
+            let timestamps = bucket.entries.timestamps();
+            let mut rows: Vec<u32> = (0..timestamps.len() as u32)
+                .filter(|row| {
+                    (range.start_time..=range.end_time).contains(&timestamps[*row as usize])
+                })
+                .collect();
+            // Buckets are in time order, so only need to sort within each bucket. The sort is stable, so entries with
+            // the same timestamp keep their insertion order.
+            rows.sort_by_key(|row| timestamps[*row as usize]);
+            rows.into_iter()
+                .filter_map(|row| bucket.entries.get(row as usize))
+                .for_each(&mut f);
+        }
+    }
+
+    /// Get the spread as of ts, i.e. of the most recent quote at or before ts, e.g. to mark to market at a fixed instant.
+    /// Of several quotes at the same timestamp, the last inserted one wins. The bucket containing ts is searched first,
+    /// then earlier buckets are walked back until one has a quote. Entries in a bucket are in insertion order, not
+    /// necessarily sorted, so each visited bucket is scanned. Return None if there is no quote at or before ts.
+    pub fn spread_as_of(&self, ts: u64) -> Option<f64> {
+        let (cache_start_time_ns, _) = self.time_bounds()?;
+        let idx = find_bucket_index(cache_start_time_ns, ts, self.bucket_ns)?;
+        let idx = idx.min(self.buckets.len() - 1);
+        (0..=idx)
+            .rev()
+            .filter_map(|i| self.buckets.stored(i))
+            .find_map(|bucket| {
+                let bucket = bucket.read().unwrap();
+                bucket
+                    .entries
+                    .iter()
+                    .filter(|e| e.utc_epoch_ns <= ts)
+                    .reduce(|latest, e| {
+                        if e.utc_epoch_ns >= latest.utc_epoch_ns {
+                            e
+                        } else {
+                            latest
+                        }
+                    })
+                    .map(|e| e.spread)
+            })
+    }
+
+    /// Get the most recent n entries in timestamp order, e.g. for a live ticker, or everything if there are fewer than n
+    /// entries. Buckets are walked from the newest one and the walk stops as soon as n entries are collected.
+    pub fn last_n_entries(&self, n: usize) -> Vec<MarketDataEntry> {
+        let mut chunks = Vec::new();
+        let mut remaining = n;
+        for bucket in self.buckets.iter().rev() {
+            if remaining == 0 {
+                break;
+            }
+            let bucket = bucket.read().unwrap();
+            let mut entries: Vec<MarketDataEntry> = bucket.entries.iter().collect();
+            entries.sort_by_key(|e| e.utc_epoch_ns);
+            let skip = entries.len().saturating_sub(remaining);
+            remaining -= entries.len() - skip;
+            chunks.push(entries.split_off(skip));
+        }
+        chunks.into_iter().rev().flatten().collect()
+    }
+
+    /// Get the largest time gap in ns between two consecutive quotes in the given time range. Gaps between range bounds
+    /// and the first or last quote are not counted. Return 0 if there are less than two quotes in range.
+    pub fn longest_gap(&self, start_time: u64, end_time: u64) -> u64 {
+        self.entries_in_range(start_time, end_time)
+            .windows(2)
+            .map(|pair| pair[1].utc_epoch_ns - pair[0].utc_epoch_ns)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Get all silent periods in the given time range, as (gap start, gap end) pairs, where gap start and end are the
+    /// timestamps of two consecutive quotes more than threshold_ns apart. Empty if there are less than two quotes.
+    pub fn gaps_exceeding(
+        &self,
+        start_time: u64,
+        end_time: u64,
+        threshold_ns: u64,
+    ) -> Vec<(u64, u64)> {
+        self.entries_in_range(start_time, end_time)
+            .windows(2)
+            .map(|pair| (pair[0].utc_epoch_ns, pair[1].utc_epoch_ns))
+            .filter(|(gap_start, gap_end)| gap_end - gap_start > threshold_ns)
+            .collect()
+    }
+
+    /// Get the log returns of the spread between consecutive quotes in the given time range, i.e. ln(spread\[i\] /
+    /// spread\[i - 1\]) in timestamp order. The log of a zero or negative ratio is undefined, so a pair where either spread
+    /// is zero or negative (e.g. a crossed book) is omitted if skip_non_positive is set, otherwise it gives NaN, which
+    /// keeps the result aligned with the quotes.
+    pub fn spread_log_returns(
+        &self,
+        start_time: u64,
+        end_time: u64,
+        skip_non_positive: bool,
+    ) -> Vec<f64> {
+        self.entries_in_range(start_time, end_time)
+            .windows(2)
+            .filter_map(|pair| {
+                let (previous, current) = (pair[0].spread, pair[1].spread);
+                if previous > 0.0 && current > 0.0 {
+                    Some((current / previous).ln())
+                } else if skip_non_positive {
+                    None
+                } else {
+                    Some(f64::NAN)
+                }
+            })
+            .collect()
+    }
+
+    /// Get the time in ns the spread spent in each band over the given time range, e.g. to see how long the spread was
+    /// tight rather than how many quotes were. Band i is [band_edges\[i\], band_edges\[i + 1\]), except that the last
+    /// band includes its upper edge as well. Each quote's spread lasts until the next quote, and the last one until
+    /// end_time, clamped to the cache. Time before the first quote in range, and spreads outside all bands, are not
+    /// counted. Return an empty vector if band_edges has less than two edges or isn't strictly increasing.
+    pub fn time_in_bands(&self, start_time: u64, end_time: u64, band_edges: &[f64]) -> Vec<u64> {
+        if band_edges.len() < 2 || !band_edges.windows(2).all(|pair| pair[0] < pair[1]) {
+            return Vec::new();
+        }
+        let mut result = vec![0; band_edges.len() - 1];
+        let Some((_, cache_end_time_ns)) = self.time_bounds() else {
+            return result;
+        };
+        let end_time = end_time.min(cache_end_time_ns - 1);
+
+        let entries = self.entries_in_range(start_time, end_time);
+        let until = entries
+            .iter()
+            .skip(1)
+            .map(|e| e.utc_epoch_ns)
+            .chain(std::iter::once(end_time));
+        for (entry, until) in entries.iter().zip(until) {
+            let spread = entry.spread;
+            if !(band_edges[0] <= spread && spread <= band_edges[band_edges.len() - 1]) {
+                continue;
+            }
+            let band = band_edges
+                .partition_point(|edge| *edge <= spread)
+                .min(result.len())
+                - 1;
+            result[band] += until - entry.utc_epoch_ns;
+        }
+        result
+    }
+
+    /// Get the spread level below which the market spent q of the time in the given time range, e.g. 0.9 for the spread
+    /// not exceeded 90% of the time. Unlike the percentile queries, which weight every quote equally, each spread is
+    /// weighted by how long it lasted, the same way as [MarketDataCache::time_in_bands]: until the next quote, and the
+    /// last one until end_time, clamped to the cache. Result is the smallest spread whose cumulative duration reaches q
+    /// of the total. Non finite spreads are ignored. Return NaN if q is not in [0, 1], or no time is covered by a spread.
+    pub fn spread_time_quantile(&self, start_time: u64, end_time: u64, q: f64) -> f64 {
+        if !(0.0..=1.0).contains(&q) {
+            return f64::NAN;
+        }
+        let Some((_, cache_end_time_ns)) = self.time_bounds() else {
+            return f64::NAN;
+        };
+        let end_time = end_time.min(cache_end_time_ns - 1);
+
+        let entries = self.entries_in_range(start_time, end_time);
+        let until = entries
+            .iter()
+            .skip(1)
+            .map(|e| e.utc_epoch_ns)
+            .chain(std::iter::once(end_time));
+        let mut weighted: Vec<(f64, u64)> = entries
+            .iter()
+            .zip(until)
+            .map(|(entry, until)| (entry.spread, until - entry.utc_epoch_ns))
+            .filter(|(spread, duration)| spread.is_finite() && *duration > 0)
+            .collect();
+        let total: u64 = weighted.iter().map(|(_, duration)| duration).sum();
+        if total == 0 {
+            return f64::NAN;
+        }
+        weighted.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        let target = q * total as f64;
+        let mut cumulative = 0;
+        for (spread, duration) in &weighted {
+            cumulative += duration;
+            if cumulative as f64 >= target {
+                return *spread;
+            }
+        }
+        // Only reached through rounding, the cumulative duration ends at total.
+        weighted[weighted.len() - 1].0
+    }
+
+    /// Get the maximal runs of consecutive quotes with the same spread in the given time range, in time order, e.g. to
+    /// detect stale quotes. Each run is (spread, number of quotes, duration in ns), where the spread is the first one of
+    /// the run, and a quote joins it if its spread is within tolerance of that, 0.0 for exact equality. The same as
+    /// [MarketDataCache::spread_time_quantile], a quote lasts until the next one, and the last one until end_time, so a
+    /// run lasts from its first quote to the first quote of the next run. NaN spreads never join a run.
+    pub fn spread_runs(
+        &self,
+        start_time: u64,
+        end_time: u64,
+        tolerance: f64,
+    ) -> Vec<(f64, usize, u64)> {
+        let Some((_, cache_end_time_ns)) = self.time_bounds() else {
+            return Vec::new();
+        };
+        let end_time = end_time.min(cache_end_time_ns - 1);
+
+        // (spread, number of quotes, first timestamp) of each run.
+        let mut runs: Vec<(f64, usize, u64)> = Vec::new();
+        self.visit_entries(start_time, end_time, |e| match runs.last_mut() {
+            Some((spread, count, _)) if (e.spread - *spread).abs() <= tolerance => *count += 1,
+            _ => runs.push((e.spread, 1, e.utc_epoch_ns)),
+        });
+        let until: Vec<u64> = runs
+            .iter()
+            .skip(1)
+            .map(|(_, _, first_ns)| *first_ns)
+            .chain(std::iter::once(end_time))
+            .collect();
+        runs.into_iter()
+            .zip(until)
+            .map(|((spread, count, first_ns), until)| (spread, count, until - first_ns))
+            .collect()
+    }
+
+    /// Get the version of the cache content, it changes on every update through the cache methods, e.g. to tell whether
+    /// a memoized query result is still valid. Writes to buckets that bypass the cache methods are not tracked.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    fn bump_version(&mut self) {
+        self.version = self.version.wrapping_add(1);
+    }
+
+    /// Get the minimum spread of the whole cache window. It's kept up to date on every update instead of being calculated
+    /// from the buckets, so it's O(1), e.g. for a live display. f64::MAX if the cache is empty.
+    pub fn running_min_spread(&self) -> f64 {
+        self.global_min
+    }
+
+    /// Same as [MarketDataCache::running_min_spread], but the maximum spread, -f64::MAX if the cache is empty.
+    pub fn running_max_spread(&self) -> f64 {
+        self.global_max
+    }
+
+    /// Get the total number of entries in the cache.
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Get the number of entries in the given time range, including both ends.
+    /// start_time and end_time may be any time within the last 1 hour. The range is clamped to the cache, and it's 0 on an
+    /// empty cache or a range that doesn't overlap with the cache.
+    pub fn count_range(&self, start_time: u64, end_time: u64) -> usize {
+        let Some(range) = self.resolve_range(start_time, end_time) else {
+            return 0;
+        };
+        let (start_idx, end_idx) = (range.start_idx, range.end_idx);
+        let (start_time, end_time) = (range.start_time, range.end_time);
+        // Whole buckets only, so the cached counts are enough.
+        if self.is_stats_only() || self.is_bucket_aligned(&range) {
+            return self
+                .map_buckets_in(start_idx..end_idx + 1, |bucket| bucket.count)
+                .into_iter()
+                .sum();
+        }
+
+        // If start and end points to the same bucket.
+        if start_idx == end_idx {
+            return self.buckets[start_idx]
+                .read()
+                .unwrap()
+                .count_in_between(start_time, end_time);
+        }
+
+        let mut cnt = 0;
+
+        // Handle the starting bucket, partial data.
+        cnt += {
+            let bucket = self.buckets[start_idx].read().unwrap();
+            bucket.count_start_from(start_time)
+        };
+
+        // Handle the middle, complete bucket.
+        cnt += self
+            .map_middle_buckets(start_idx, end_idx, |bucket| bucket.count)
+            .into_iter()
+            .sum::<usize>();
+
+        // Handle the ending bucket, partial data.
+        if start_idx != end_idx {
+            cnt += {
+                let bucket = self.buckets[end_idx].read().unwrap();
+                bucket.count_end_before(end_time)
+            };
+        }
+
+        cnt
+    }
+
+    /// Get the number of entries in the given time range with a spread strictly above the threshold, e.g. to alert on
+    /// wide markets. Same range semantics as [MarketDataCache::count_range].
+    pub fn count_spread_above(&self, start_time: u64, end_time: u64, threshold: f64) -> usize {
+        self.count_spread_beyond(start_time, end_time, threshold, true)
+    }
+
+    /// Same as [MarketDataCache::count_spread_above], but count spreads strictly below the threshold.
+    pub fn count_spread_below(&self, start_time: u64, end_time: u64, threshold: f64) -> usize {
+        self.count_spread_beyond(start_time, end_time, threshold, false)
+    }
+
+    /// Count the entries in range with a spread above the threshold, or below it if above is false. A middle bucket is
+    /// skipped without a scan when its cached min and max spread show that all or none of its entries count, partial
+    /// buckets are always scanned. Stats only buckets can't be scanned, so every bucket is taken whole, and the count of
+    /// one straddling the threshold is estimated from its tdigest.
+    fn count_spread_beyond(
+        &self,
+        start_time: u64,
+        end_time: u64,
+        threshold: f64,
+        above: bool,
+    ) -> usize {
+        let Some(range) = self.resolve_range(start_time, end_time) else {
+            return 0;
+        };
+        let counts = |spread: f64| {
+            if above {
+                spread > threshold
+            } else {
+                spread < threshold
+            }
+        };
+        self.install(|| {
+            (range.start_idx..=range.end_idx)
+                .into_par_iter()
+                .map(|i| {
+                    let bucket = self.buckets[i].read().unwrap();
+                    if self.is_stats_only() || (i != range.start_idx && i != range.end_idx) {
+                        let (all, none) = if above {
+                            (
+                                bucket.min_spread > threshold,
+                                bucket.max_spread <= threshold,
+                            )
+                        } else {
+                            (
+                                bucket.max_spread < threshold,
+                                bucket.min_spread >= threshold,
+                            )
+                        };
+                        if all {
+                            return bucket.count;
+                        }
+                        if none {
+                            return 0;
+                        }
+                    }
+                    if self.is_stats_only() {
+                        let below = tdigest_fraction_below(&bucket.get_tdigest(), threshold);
+                        let fraction = if above { 1.0 - below } else { below };
+                        return (fraction * bucket.count as f64).round() as usize;
+                    }
+                    bucket
+                        .entries
+                        .iter()
+                        .filter(|e| {
+                            range.start_time <= e.utc_epoch_ns
+                                && e.utc_epoch_ns <= range.end_time
+                                && counts(e.spread)
+                        })
+                        .count()
+                })
+                .sum()
+        })
+    }
+
+    /// Get the 10th, 50th, and 90th percentiles of the spread in the given time range.
+    /// Spread is defined as the difference between the lowest ask price and highest bid price.
+    /// start_time and end_time may be any time within the last 1 hour. Gives NaN for all three on an empty cache, a range
+    /// that doesn't overlap with the cache, or a range without any entry, e.g. one covering only empty buckets.
+    pub fn spread_percentiles(&self, start_time: u64, end_time: u64) -> (f64, f64, f64) {
+        p10_p50_p90(self.spread_tdigest(start_time, end_time))
+    }
+
+    /// Get the lower_q and upper_q quantiles of spread of each bucket overlapping the given time range, as (bucket start
+    /// ns, lower, upper) in time order, e.g. to shade a p10 to p90 band over a chart. Unlike
+    /// [MarketDataCache::spread_percentiles], buckets are not merged. Complete buckets use their cached tdigests, and
+    /// the first and last buckets only their entries in range. Buckets are estimated in parallel. Empty buckets are
+    /// skipped, or filled with NaN if fill_empty is set, for a chart with a fixed x axis. Return an empty vector if the
+    /// quantiles are not within [0, 1] or lower_q > upper_q.
+    pub fn percentile_band_series(
+        &self,
+        start_time: u64,
+        end_time: u64,
+        lower_q: f64,
+        upper_q: f64,
+        fill_empty: bool,
+    ) -> Vec<(u64, f64, f64)> {
+        if !(0.0 <= lower_q && lower_q <= upper_q && upper_q <= 1.0) {
+            return Vec::new();
+        }
+        let Some(range) = self.resolve_range(start_time, end_time) else {
+            return Vec::new();
+        };
+        let (cache_start_time_ns, _) = self.time_bounds().unwrap();
+        self.install(|| {
+            (range.start_idx..=range.end_idx)
+                .into_par_iter()
+                .filter_map(|idx| {
+                    let bucket = self.buckets[idx].read().unwrap();
+                    let bucket_start_ns = cache_start_time_ns + self.bucket_ns * idx as u64;
+                    let partial = idx == range.start_idx || idx == range.end_idx;
+                    let tdigest = if partial && !self.is_stats_only() {
+                        let spreads: Vec<f64> = bucket
+                            .entries
+                            .iter()
+                            .filter(|e| {
+                                range.start_time <= e.utc_epoch_ns
+                                    && e.utc_epoch_ns <= range.end_time
+                            })
+                            .map(|e| e.spread)
+                            .collect();
+                        (!spreads.is_empty())
+                            .then(|| merge_spreads(&TDigest::new_with_size(spreads.len()), spreads))
+                    } else {
+                        (bucket.count > 0).then(|| bucket.get_tdigest())
+                    };
+                    match tdigest {
+                        Some(tdigest) => Some((
+                            bucket_start_ns,
+                            tdigest.estimate_quantile(lower_q),
+                            tdigest.estimate_quantile(upper_q),
+                        )),
+                        None => fill_empty.then_some((bucket_start_ns, f64::NAN, f64::NAN)),
+                    }
+                })
+                .collect()
+        })
+    }
+
+    /// Same as [MarketDataCache::spread_percentiles], but skip the first skip_ns of the range as warmup, e.g. the noisy
+    /// quotes right after market open. The skip is relative to start_time, so the range becomes
+    /// [start_time + skip_ns, end_time]. Gives NaN for all three unless skip_ns < end_time - start_time.
+    pub fn spread_percentiles_skip(
+        &self,
+        start_time: u64,
+        end_time: u64,
+        skip_ns: u64,
+    ) -> (f64, f64, f64) {
+        match end_time.checked_sub(start_time) {
+            Some(duration) if skip_ns < duration => {
+                self.spread_percentiles(start_time + skip_ns, end_time)
+            }
+            _ => (f64::NAN, f64::NAN, f64::NAN),
+        }
+    }
+
+    /// Get the given quantile of the spread for each of num_windows consecutive windows of window_ns, the last of which
+    /// ends at the end of the cache, e.g. the median spread of each of the last 60 minutes. Results are ordered from the
+    /// oldest window to the newest. Windows are independent of each other, so they're handled in parallel. A window
+    /// without any quote, including one before the cache start, gets NaN.
+    pub fn sliding_quantile(&self, window_ns: u64, num_windows: usize, q: f64) -> Vec<f64> {
+        let Some((_, cache_end_time_ns)) = self.time_bounds() else {
+            return vec![f64::NAN; num_windows];
+        };
+        self.install(|| {
+            (0..num_windows)
+                .into_par_iter()
+                .map(|i| {
+                    // Window i ends (num_windows - i - 1) windows before the cache end, and both ends are inclusive.
+                    let windows_after = (num_windows - i - 1) as u64;
+                    let Some(end_time) = window_ns
+                        .checked_mul(windows_after)
+                        .and_then(|elapsed_ns| cache_end_time_ns.checked_sub(elapsed_ns + 1))
+                    else {
+                        return f64::NAN;
+                    };
+                    let start_time = (end_time + 1).saturating_sub(window_ns);
+                    match self.spread_tdigest(start_time, end_time) {
+                        Some(tdigest) if !tdigest.is_empty() => tdigest.estimate_quantile(q),
+                        _ => f64::NAN,
+                    }
+                })
+                .collect()
+        })
+    }
+
+    /// Get the given quantile of the spread in [event - half_window_ns, event + half_window_ns] around each event, e.g. for
+    /// an event study of news or trades. Results are aligned with events. Windows are clamped to the cache, and a window
+    /// without any quote gets NaN. Events are independent of each other, so they're handled in parallel.
+    pub fn quantile_around(&self, events: &[u64], half_window_ns: u64, q: f64) -> Vec<f64> {
+        self.install(|| {
+            events
+                .par_iter()
+                .map(|&event| {
+                    let start_time = event.saturating_sub(half_window_ns);
+                    let end_time = event.saturating_add(half_window_ns);
+                    match self.spread_tdigest(start_time, end_time) {
+                        Some(tdigest) if !tdigest.is_empty() => tdigest.estimate_quantile(q),
+                        _ => f64::NAN,
+                    }
+                })
+                .collect()
+        })
+    }
+
+    /// Get the merged tdigest of the spreads in the given time range instead of only its quantiles, e.g. to combine ranges
+    /// from several processes with [SerializableDigest::merge]. The digest is empty if the range doesn't have any entry.
+    pub fn range_digest(&self, start_time: u64, end_time: u64) -> SerializableDigest {
+        SerializableDigest {
+            tdigest: self
+                .spread_tdigest(start_time, end_time)
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Merge the spreads in the given time range into one [TDigest], using the cached tdigests of complete buckets. None
+    /// on an empty cache, a range that doesn't overlap with the cache, or a range without any entry.
+    fn spread_tdigest(&self, start_time: u64, end_time: u64) -> Option<TDigest> {
+        let range = self.resolve_range(start_time, end_time)?;
+        if self.is_stats_only() {
+            let tdigests = self.map_buckets_in(range.start_idx..range.end_idx + 1, |bucket| {
+                (bucket.count > 0).then(|| bucket.get_tdigest())
+            });
+            return merge_non_empty(tdigests.into_iter().flatten().collect());
+        }
+
+        // Empty middle buckets are skipped, rather than building an empty tdigest for each.
+        self.merge_range_tdigest(range, |middle| {
+            self.map_buckets_in(middle, |bucket| {
+                (bucket.count > 0).then(|| bucket.get_tdigest())
+            })
+            .into_iter()
+            .flatten()
+            .collect()
+        })
+    }
+
+    /// Merge the spreads in a resolved range into one [TDigest]. The partial buckets at both ends are scanned, while
+    /// the tdigests of the middle, complete buckets come from middle_tdigests, given their indices. None if the range
+    /// doesn't have any entry.
+    fn merge_range_tdigest<F>(&self, range: ResolvedRange, middle_tdigests: F) -> Option<TDigest>
+    where
+        F: FnOnce(Range<usize>) -> Vec<TDigest>,
+    {
+        let (start_idx, end_idx) = (range.start_idx, range.end_idx);
+        let (start_time, end_time) = (range.start_time, range.end_time);
+
+        // If start and end points to the same bucket.
+        if start_idx == end_idx {
+            let bucket = self.buckets[start_idx].read().unwrap();
+            let entries = bucket.spreads_in_between(start_time, end_time);
+            return (!entries.is_empty())
+                .then(|| merge_spreads(&TDigest::new_with_size(entries.len()), entries));
+        }
+
+        let mut tdigests = Vec::new();
+
+        // Handle the starting bucket, partial data.
+        {
+            let bucket = self.buckets[start_idx].read().unwrap();
+            let spreads = bucket.spreads_start_from(start_time);
+            if !spreads.is_empty() {
+                tdigests.push(merge_spreads(&TDigest::new_with_size(1000), spreads));
+            }
+        }
+
+        // Handle the middle, complete buckets.
+        tdigests.extend(middle_tdigests(start_idx + 1..end_idx));
+
+        // Handle the last bucket, partial data.
+        {
+            let bucket = self.buckets[end_idx].read().unwrap();
+            let spreads = bucket.spreads_end_before(end_time);
+            if !spreads.is_empty() {
+                tdigests.push(merge_spreads(&TDigest::new_with_size(1000), spreads));
+            }
+        }
+
+        merge_non_empty(tdigests)
+    }
+
+    /// Same as [MarketDataCache::spread_percentiles], but if the range has at most max_exact entries, calculate the
+    /// percentiles exactly from the sorted spreads instead of estimating them by TDigest. Small ranges are cheap to sort
+    /// and are where TDigest's approximation error shows the most. The result tells which method is used, an empty range
+    /// gives NaN percentiles and is not approximate.
+    pub fn spread_percentiles_exact_if_small(
+        &self,
+        start_time: u64,
+        end_time: u64,
+        max_exact: usize,
+    ) -> PercentileResult {
+        if self.count_range(start_time, end_time) > max_exact {
+            let (p10, p50, p90) = self.spread_percentiles(start_time, end_time);
+            return PercentileResult {
+                p10,
+                p50,
+                p90,
+                approximate: true,
+            };
+        }
+
+        // NaN spreads, e.g. of one sided books, have no rank, and are left out the same as by the tdigests.
+        let mut spreads: Vec<f64> = match self.resolve_range(start_time, end_time) {
+            Some(range) => self
+                .map_entries(range, |e| e.spread)
+                .into_iter()
+                .filter(|spread| !spread.is_nan())
+                .collect(),
+            None => Vec::new(),
+        };
+        spreads.sort_by(f64::total_cmp);
+        PercentileResult {
+            p10: sorted_quantile(&spreads, 0.1),
+            p50: sorted_quantile(&spreads, 0.5),
+            p90: sorted_quantile(&spreads, 0.9),
+            approximate: false,
+        }
+    }
+
+    /// Estimate the q quantile of spread in the given time range, q in [0, 1], with the sketch buckets keep, see
+    /// [MarketDataCache::with_bucket_sketch]. Middle buckets use their cached sketch, only the partial buckets at both
+    /// ends need to be scanned. Return NaN if no spread is in range.
+    pub fn spread_quantile(&self, start_time: u64, end_time: u64, q: f64) -> f64 {
+        let BucketSketch::DDSketch(relative_accuracy) = self.bucket_sketch else {
+            return self
+                .spread_tdigest(start_time, end_time)
+                .map_or(f64::NAN, |tdigest| tdigest.estimate_quantile(q));
+        };
+        let Some(range) = self.resolve_range(start_time, end_time) else {
+            return f64::NAN;
+        };
+        self.install(|| {
+            (range.start_idx..=range.end_idx)
+                .into_par_iter()
+                .map(|i| {
+                    let bucket = self.buckets[i].read().unwrap();
+                    let partial = i == range.start_idx || i == range.end_idx;
+                    match &bucket.ddsketch {
+                        Some(ddsketch) if !partial || self.is_stats_only() => ddsketch.clone(),
+                        _ => {
+                            let mut ddsketch = DDSketch::new(relative_accuracy);
+                            bucket
+                                .entries
+                                .iter()
+                                .filter(|e| {
+                                    range.start_time <= e.utc_epoch_ns
+                                        && e.utc_epoch_ns <= range.end_time
+                                })
+                                .for_each(|e| ddsketch.insert(e.spread));
+                            ddsketch
+                        }
+                    }
+                })
+                .reduce(
+                    || DDSketch::new(relative_accuracy),
+                    |mut a, b| {
+                        a.merge(&b);
+                        a
+                    },
+                )
+                .quantile(q)
+        })
+    }
+
+    /// Get the q quantile of spread in the given time range with any [QuantileSketch], e.g. a [DDSketch] of an accuracy
+    /// other than the one buckets keep. A sketch is built for each bucket overlapping the range in parallel, then
+    /// they're merged. Unlike [MarketDataCache::spread_quantile], nothing is cached in the buckets, so every call scans
+    /// all entries in range. Return NaN if no spread is in range.
+    pub fn spread_quantile_with<S>(&self, start_time: u64, end_time: u64, q: f64) -> f64
+    where
+        S: QuantileSketch + Default + Send,
+    {
+        let Some(range) = self.resolve_range(start_time, end_time) else {
+            return f64::NAN;
+        };
+        self.install(|| {
+            (range.start_idx..=range.end_idx)
+                .into_par_iter()
+                .map(|i| {
+                    let bucket = self.buckets[i].read().unwrap();
+                    let mut sketch = S::default();
+                    bucket
+                        .entries
+                        .iter()
+                        .filter(|e| {
+                            range.start_time <= e.utc_epoch_ns && e.utc_epoch_ns <= range.end_time
+                        })
+                        .for_each(|e| sketch.insert(e.spread));
+                    sketch
+                })
+                .reduce(S::default, |mut a, b| {
+                    a.merge(&b);
+                    a
+                })
+                .quantile(q)
+        })
+    }
+
+    /// Same as [MarketDataCache::spread_percentiles], but for a batch of time ranges. Results are returned in the same
+    /// order as the input ranges. A range that is invalid (start after end) or doesn't overlap with the cache gets
+    /// (NaN, NaN, NaN).
+    ///
+    /// Ranges in a report often overlap, so the tdigest of every complete bucket is fetched only once and shared by all
+    /// ranges covering it. Then each range is handled in parallel, as they are independent of each other.
+    pub fn spread_percentiles_multi(&self, ranges: &[(u64, u64)]) -> Vec<(f64, f64, f64)> {
+        if self.is_stats_only() {
+            return ranges
+                .iter()
+                .map(|&(start_time, end_time)| self.spread_percentiles(start_time, end_time))
+                .collect();
+        }
+        let resolved: Vec<Option<ResolvedRange>> = ranges
+            .iter()
+            .map(|&(start_time, end_time)| self.resolve_range(start_time, end_time))
+            .collect();
+
+        // Union of all middle, complete buckets, so each of them is only locked and read once. Empty ones have no tdigest.
+        let middle_indices: BTreeSet<usize> = resolved
+            .iter()
+            .flatten()
+            .flat_map(|range| range.start_idx + 1..range.end_idx)
+            .collect();
+        let middle_tdigests: HashMap<usize, TDigest> = self.install(|| {
+            middle_indices
+                .into_par_iter()
+                .filter_map(|i| {
+                    let bucket = self.buckets[i].read().unwrap();
+                    (bucket.count > 0).then(|| (i, bucket.get_tdigest()))
+                })
+                .collect()
+        });
+
+        self.install(|| {
+            resolved
+                .par_iter()
+                .map(|range| {
+                    let Some(range) = range else {
+                        return (f64::NAN, f64::NAN, f64::NAN);
+                    };
+                    // The middle, complete buckets are already calculated above.
+                    let merged = self.merge_range_tdigest(*range, |middle| {
+                        middle
+                            .filter_map(|i| middle_tdigests.get(&i).cloned())
+                            .collect()
+                    });
+                    p10_p50_p90(merged)
+                })
+                .collect()
+        })
+    }
+
+    /// Same as [MarketDataCache::summarize], over the last window_ns up to now according to the cache's clock, i.e.
+    /// [now - window_ns, now], e.g. for a live dashboard. Unlike [MarketDataCache::latest_spread_zscore], the window
+    /// follows the clock rather than the latest quote, so it empties out when the feed goes quiet.
+    pub fn rolling_summary(&self, window_ns: u64) -> RangeSummary {
+        let now = self.clock.now_ns();
+        self.summarize(now.saturating_sub(window_ns), now)
+    }
+
+    /// Same as [MarketDataCache::summarize], but serialized as a JSON object, e.g. for a web frontend. NaN fields of an
+    /// empty range are null.
+    pub fn summarize_json(&self, start_time: u64, end_time: u64) -> String {
+        // Serializing a struct of plain numbers never fails.
+        serde_json::to_string(&self.summarize(start_time, end_time)).unwrap()
+    }
+
+    /// Get count, min, max, mean and the 10th, 50th, 90th percentiles of spread in the given time range, in one
+    /// traversal of the three parts of the range. Each bucket is locked only once, and count, min, max and sum are
+    /// accumulated together with the tdigests. Compared to calling each query separately, this halves bucket traversals
+    /// and lock acquisitions.
+    pub fn summarize(&self, start_time: u64, end_time: u64) -> RangeSummary {
+        let empty = RangeSummary {
+            count: 0,
+            min: f64::NAN,
+            max: f64::NAN,
+            mean: f64::NAN,
+            p10: f64::NAN,
+            p50: f64::NAN,
+            p90: f64::NAN,
+        };
+        let Some(range) = self.resolve_range(start_time, end_time) else {
+            return empty;
+        };
+
+        // (count, min, max, sum) of a region.
+        type Stats = (usize, f64, f64, f64);
+        let combine = |a: Stats, b: Stats| (a.0 + b.0, a.1.min(b.1), a.2.max(b.2), a.3 + b.3);
+        let identity: Stats = (0, f64::MAX, -f64::MAX, 0.0);
+        let partial = |spreads: Vec<f64>, tdigest_size: usize| {
+            let spread_stats = SpreadStats::from_slice(&spreads);
+            let stats = combine(
+                identity,
+                (
+                    spread_stats.count,
+                    spread_stats.min,
+                    spread_stats.max,
+                    spread_stats.sum,
+                ),
+            );
+            let tdigest = (!spreads.is_empty())
+                .then(|| merge_spreads(&TDigest::new_with_size(tdigest_size), spreads));
+            (stats, tdigest)
+        };
+
+        let whole = |bucket: &Bucket| {
+            let bucket_stats = (
+                bucket.count,
+                bucket.min_spread,
+                bucket.max_spread,
+                bucket.sum_spread,
+            );
+            (
+                bucket_stats,
+                (bucket.count > 0).then(|| bucket.get_tdigest()),
+            )
+        };
+
+        let mut tdigests = Vec::new();
+        let mut stats = identity;
+        if self.is_stats_only() {
+            // Buckets hold no entries, so take the ones at both ends as whole.
+            for (bucket_stats, tdigest) in
+                self.map_buckets_in(range.start_idx..range.end_idx + 1, whole)
+            {
+                stats = combine(stats, bucket_stats);
+                tdigests.extend(tdigest);
+            }
+        } else if range.start_idx == range.end_idx {
+            // If start and end points to the same bucket.
+            let bucket = self.buckets[range.start_idx].read().unwrap();
+            let spreads = bucket.spreads_in_between(range.start_time, range.end_time);
+            let size = spreads.len();
+            let (bucket_stats, tdigest) = partial(spreads, size);
+            stats = combine(stats, bucket_stats);
+            tdigests.extend(tdigest);
+        } else {
+            // Handle the starting bucket, partial data.
+            {
+                let bucket = self.buckets[range.start_idx].read().unwrap();
+                let (bucket_stats, tdigest) =
+                    partial(bucket.spreads_start_from(range.start_time), 1000);
+                stats = combine(stats, bucket_stats);
+                tdigests.extend(tdigest);
+            }
+
+            // Handle the middle, complete buckets.
+            let middle = self.map_middle_buckets(range.start_idx, range.end_idx, whole);
+            for (bucket_stats, tdigest) in middle {
+                stats = combine(stats, bucket_stats);
+                tdigests.extend(tdigest);
+            }
+
+            // Handle the last bucket, partial data.
+            {
+                let bucket = self.buckets[range.end_idx].read().unwrap();
+                let (bucket_stats, tdigest) =
+                    partial(bucket.spreads_end_before(range.end_time), 1000);
+                stats = combine(stats, bucket_stats);
+                tdigests.extend(tdigest);
+            }
+        }
+
+        let (count, min, max, sum) = stats;
+        let Some(merged) = merge_non_empty(tdigests).filter(|_| count > 0) else {
+            return empty;
+        };
+        RangeSummary {
+            count,
+            min,
+            max,
+            mean: sum / count as f64,
+            p10: merged.estimate_quantile(0.1),
+            p50: merged.estimate_quantile(0.5),
+            p90: merged.estimate_quantile(0.9),
+        }
+    }
+
+    /// Group the buckets overlapping with the given time range into windows of agg_ns, aligned to multiples of agg_ns,
+    /// e.g. to report at 1s resolution from 100ms buckets. Complete buckets contribute their cached stats and tdigests,
+    /// and only the partial buckets at both ends of the range are scanned. Windows are ordered from the oldest, and
+    /// include empty ones. Return nothing if agg_ns is not a positive multiple of the bucket size.
+    pub fn aggregate_to(&self, agg_ns: u64, start_time: u64, end_time: u64) -> Vec<AggBucket> {
+        if agg_ns == 0 || !agg_ns.is_multiple_of(self.bucket_ns) {
+            return Vec::new();
+        }
+        let Some(range) = self.resolve_range(start_time, end_time) else {
+            return Vec::new();
+        };
+        let (cache_start_time_ns, _) = self.time_bounds().unwrap();
+
+        // (window start, count, min, max, sum, tdigest) of each bucket in range.
+        let buckets: Vec<(u64, usize, f64, f64, f64, Option<TDigest>)> = self.install(|| {
+            (range.start_idx..=range.end_idx)
+                .into_par_iter()
+                .map(|idx| {
+                    let bucket = self.buckets[idx].read().unwrap();
+                    let bucket_start_ns = cache_start_time_ns + self.bucket_ns * idx as u64;
+                    let window_start_ns = bucket_start(bucket_start_ns, agg_ns);
+                    let complete = self.is_stats_only()
+                        || (range.start_time <= bucket_start_ns
+                            && bucket_start_ns + self.bucket_ns - 1 <= range.end_time);
+                    if complete {
+                        return (
+                            window_start_ns,
+                            bucket.count,
+                            bucket.min_spread,
+                            bucket.max_spread,
+                            bucket.sum_spread,
+                            (bucket.count > 0).then(|| bucket.get_tdigest()),
+                        );
+                    }
+                    let spreads = bucket.spreads_in_between(
+                        range.start_time.max(bucket_start_ns),
+                        range.end_time.min(bucket_start_ns + self.bucket_ns - 1),
+                    );
+                    let stats = SpreadStats::from_slice(&spreads);
+                    let tdigest = (!spreads.is_empty())
+                        .then(|| merge_spreads(&TDigest::new_with_size(spreads.len()), spreads));
+                    (
+                        window_start_ns,
+                        stats.count,
+                        stats.min,
+                        stats.max,
+                        stats.sum,
+                        tdigest,
+                    )
+                })
+                .collect()
+        });
+
+        let mut windows = Vec::new();
+        for window in buckets.chunk_by(|a, b| a.0 == b.0) {
+            let count: usize = window.iter().map(|bucket| bucket.1).sum();
+            let non_empty = window.iter().filter(|bucket| bucket.1 > 0);
+            let min = non_empty
+                .clone()
+                .map(|bucket| bucket.2)
+                .fold(f64::NAN, f64::min);
+            let max = non_empty
+                .clone()
+                .map(|bucket| bucket.3)
+                .fold(f64::NAN, f64::max);
+            let sum: f64 = non_empty.map(|bucket| bucket.4).sum();
+            let tdigests = window
+                .iter()
+                .filter_map(|bucket| bucket.5.clone())
+                .collect();
+            windows.push(AggBucket {
+                start_time_ns: window[0].0,
+                count,
+                min,
+                max,
+                mean: if count == 0 {
+                    f64::NAN
+                } else {
+                    sum / count as f64
+                },
+                p50: merge_non_empty(tdigests)
+                    .map_or(f64::NAN, |tdigest| tdigest.estimate_quantile(0.5)),
+            });
+        }
+        windows
+    }
+
+    /// Classify the spreads of the given time range against the whole cache window, with a threshold of 1 IQR, see
+    /// [MarketDataCache::classify_regime_with].
+    pub fn classify_regime(&self, start_time: u64, end_time: u64) -> Option<Regime> {
+        self.classify_regime_with(start_time, end_time, 1.0)
+    }
+
+    /// Classify the spreads of the given time range against the whole cache window. The median spread of the range is
+    /// compared to the median of the window: more than iqr_multiple interquartile ranges of the window below it is
+    /// [Regime::Tight], above it is [Regime::Wide], and [Regime::Normal] otherwise. Medians and quartiles are estimated by
+    /// TDigest. Return None if the range has no spread, or iqr_multiple is negative or NaN.
+    pub fn classify_regime_with(
+        &self,
+        start_time: u64,
+        end_time: u64,
+        iqr_multiple: f64,
+    ) -> Option<Regime> {
+        if iqr_multiple.is_nan() || iqr_multiple < 0.0 {
+            return None;
+        }
+        let (cache_start_time_ns, cache_end_time_ns) = self.time_bounds()?;
+        let (median, whole) = self.install(|| {
+            rayon::join(
+                || self.spread_tdigest(start_time, end_time),
+                || self.spread_tdigest(cache_start_time_ns, cache_end_time_ns - 1),
+            )
+        });
+        let median = median?.estimate_quantile(0.5);
+        let whole = whole?;
+        let center = whole.estimate_quantile(0.5);
+        let threshold =
+            iqr_multiple * (whole.estimate_quantile(0.75) - whole.estimate_quantile(0.25));
+        Some(if median < center - threshold {
+            Regime::Tight
+        } else if median > center + threshold {
+            Regime::Wide
+        } else {
+            Regime::Normal
+        })
+    }
+
+    /// Measure how far the spreads of a time range are from a baseline distribution, e.g. a digest of a calm period
+    /// deserialized from disk, for anomaly detection. The metric approximates the Wasserstein-1 (earth mover's) distance,
+    /// the mean absolute difference between the quantiles of the two distributions, over a grid of
+    /// [DIVERGENCE_QUANTILES] evenly spaced quantiles. It's in the unit of spreads, 0 for identical distributions, and
+    /// grows with both shifts and changes of shape. Return NaN if the range or the baseline is empty.
+    pub fn divergence_from_baseline(
+        &self,
+        start_time: u64,
+        end_time: u64,
+        baseline: &TDigest,
+    ) -> f64 {
+        if baseline.is_empty() {
+            return f64::NAN;
+        }
+        let Some(tdigest) = self.spread_tdigest(start_time, end_time) else {
+            return f64::NAN;
+        };
+        // Midpoints of the grid cells, so both tails are weighted the same as everything in between.
+        let total: f64 = (0..DIVERGENCE_QUANTILES)
+            .map(|i| {
+                let q = (i as f64 + 0.5) / DIVERGENCE_QUANTILES as f64;
+                (tdigest.estimate_quantile(q) - baseline.estimate_quantile(q)).abs()
+            })
+            .sum();
+        total / DIVERGENCE_QUANTILES as f64
+    }
+
+    /// Compare spreads of two time ranges, e.g. before and after an event, see [WindowComparison]. Both windows are
+    /// summarized in parallel, like [MarketDataCache::summarize], and the Kolmogorov-Smirnov statistic is calculated
+    /// exactly from their sorted spreads. Non finite spreads are left out of the statistic.
+    pub fn compare_windows(&self, a: (u64, u64), b: (u64, u64)) -> WindowComparison {
+        let sorted_spreads = |(start_time, end_time): (u64, u64)| {
+            let Some(range) = self.resolve_range(start_time, end_time) else {
+                return Vec::new();
+            };
+            let mut spreads: Vec<f64> = self
+                .map_entries(range, |e| e.spread)
+                .into_iter()
+                .filter(|spread| spread.is_finite())
+                .collect();
+            spreads.sort_by(f64::total_cmp);
+            spreads
+        };
+        let ((summary_a, spreads_a), (summary_b, spreads_b)) = self.install(|| {
+            rayon::join(
+                || (self.summarize(a.0, a.1), sorted_spreads(a)),
+                || (self.summarize(b.0, b.1), sorted_spreads(b)),
+            )
+        });
+        let nan_if_empty = |v: f64| {
+            if summary_a.count == 0 || summary_b.count == 0 {
+                f64::NAN
+            } else {
+                v
+            }
+        };
+        WindowComparison {
+            a: summary_a,
+            b: summary_b,
+            count_delta: summary_b.count as i64 - summary_a.count as i64,
+            mean_delta: nan_if_empty(summary_b.mean - summary_a.mean),
+            p50_delta: nan_if_empty(summary_b.p50 - summary_a.p50),
+            ks_statistic: ks_statistic(&spreads_a, &spreads_b),
+        }
+    }
+
+    /// Count the spreads in a resolved range in bins of bin_width, keyed by the bin index, i.e. floor(spread /
+    /// bin_width). Each bucket is binned in parallel and the histograms are summed up. Non finite spreads are ignored.
+    fn spread_histogram(&self, range: ResolvedRange, bin_width: f64) -> HashMap<i64, usize> {
+        self.install(|| {
+            (range.start_idx..=range.end_idx)
+                .into_par_iter()
+                .map(|i| {
+                    let bucket = self.buckets[i].read().unwrap();
+                    let mut histogram = HashMap::new();
+                    for entry in bucket.entries.iter().filter(|e| {
+                        range.start_time <= e.utc_epoch_ns
+                            && e.utc_epoch_ns <= range.end_time
+                            && e.spread.is_finite()
+                    }) {
+                        *histogram
+                            .entry((entry.spread / bin_width).floor() as i64)
+                            .or_insert(0) += 1;
+                    }
+                    histogram
+                })
+                .reduce(HashMap::new, |mut a, b| {
+                    for (bin, count) in b {
+                        *a.entry(bin).or_insert(0) += count;
+                    }
+                    a
+                })
+        })
+    }
+
+    /// Apply a function to each of the middle buckets strictly between start_idx and end_idx, and return the results in
+    /// bucket order. Buckets are handled with rayon, unless there are fewer of them than par_threshold.
+    fn map_middle_buckets<T, F>(&self, start_idx: usize, end_idx: usize, f: F) -> Vec<T>
+    where
+        T: Send,
+        F: Fn(&Bucket) -> T + Sync,
+    {
+        self.map_buckets_in(start_idx + 1..end_idx.max(start_idx + 1), f)
+    }
+
+    /// Same as [MarketDataCache::map_middle_buckets], but for the given bucket indices.
+    fn map_buckets_in<T, F>(&self, indices: Range<usize>, f: F) -> Vec<T>
+    where
+        T: Send,
+        F: Fn(&Bucket) -> T + Sync,
+    {
+        if indices.len() < self.par_threshold {
+            return indices
+                .map(|i| f(&self.buckets[i].read().unwrap()))
+                .collect();
+        }
+        self.install(|| {
+            indices
+                .into_par_iter()
+                .map(|i| f(&self.buckets[i].read().unwrap()))
+                .collect()
+        })
+    }
+
+    /// Whether buckets drop their entries, see [MarketDataCache::new_stats_only]. Queries then treat the partial buckets
+    /// at both ends of a range as whole ones.
+    fn is_stats_only(&self) -> bool {
+        self.storage == EntryStorage::StatsOnly
+    }
+
+    /// Whether the range starts at the start of its first bucket and ends at the last nanosecond of its last bucket, i.e.
+    /// it covers whole buckets only.
+    fn is_bucket_aligned(&self, range: &ResolvedRange) -> bool {
+        let Some((cache_start_time_ns, _)) = self.time_bounds() else {
+            return false;
+        };
+        range.start_time == cache_start_time_ns + self.bucket_ns * range.start_idx as u64
+            && range.end_time
+                == cache_start_time_ns + self.bucket_ns * (range.end_idx as u64 + 1) - 1
+    }
+
+    /// Run op in our own thread pool, so the rayon calls inside it use that pool, see [MarketDataCache::with_thread_pool].
+    /// Without one, op runs on the current thread and rayon uses the global pool.
+    fn install<R, OP>(&self, op: OP) -> R
+    where
+        R: Send,
+        OP: FnOnce() -> R + Send,
+    {
+        match &self.thread_pool {
+            Some(pool) => pool.install(op),
+            None => op(),
+        }
+    }
+
+    /// Apply a function to every entry in a resolved range, buckets are handled in parallel. Values are returned in
+    /// bucket order, and in insertion order within each bucket.
+    fn map_entries<T, F>(&self, range: ResolvedRange, f: F) -> Vec<T>
+    where
+        T: Send,
+        F: Fn(&MarketDataEntry) -> T + Sync,
+    {
+        self.install(|| {
+            (range.start_idx..=range.end_idx)
+                .into_par_iter()
+                .flat_map_iter(|i| {
+                    let bucket = self.buckets[i].read().unwrap();
+                    bucket
+                        .entries
+                        .iter()
+                        .filter(|e| {
+                            range.start_time <= e.utc_epoch_ns && e.utc_epoch_ns <= range.end_time
+                        })
+                        .map(|e| f(&e))
+                        .collect::<Vec<_>>()
+                })
+                .collect()
+        })
+    }
+
+    /// Get the mean spread in the given time range after trimming the lowest and highest trim_fraction of spreads, which
+    /// is robust to outliers. Bounds are the trim_fraction and 1 - trim_fraction quantiles of the merged tdigest, and
+    /// spreads within them, inclusive, are averaged. Return NaN if trim_fraction is not in [0, 0.5), or no spread is in
+    /// range.
+    pub fn spread_trimmed_mean(&self, start_time: u64, end_time: u64, trim_fraction: f64) -> f64 {
+        if !(0.0..0.5).contains(&trim_fraction) {
+            return f64::NAN;
+        }
+        let Some(range) = self.resolve_range(start_time, end_time) else {
+            return f64::NAN;
+        };
+        // Nothing to trim with 0, and tdigest estimates of the extremes are not exact.
+        let (lower, upper) = if trim_fraction == 0.0 {
+            (f64::NEG_INFINITY, f64::INFINITY)
+        } else {
+            let Some(tdigest) = self.spread_tdigest(start_time, end_time) else {
+                return f64::NAN;
+            };
+            (
+                tdigest.estimate_quantile(trim_fraction),
+                tdigest.estimate_quantile(1.0 - trim_fraction),
+            )
+        };
+        let kept: Vec<f64> = self
+            .map_entries(range, |e| e.spread)
+            .into_iter()
+            .filter(|spread| lower <= *spread && *spread <= upper)
+            .collect();
+        if kept.is_empty() {
+            return f64::NAN;
+        }
+        kept.iter().sum::<f64>() / kept.len() as f64
+    }
+
+    /// Get the mean top of book imbalance in the given time range, see [MarketDataEntry::imbalance]. Entries without
+    /// known amounts are ignored. Return NaN if no entry has an imbalance.
+    pub fn mean_imbalance(&self, start_time: u64, end_time: u64) -> f64 {
+        let Some(range) = self.resolve_range(start_time, end_time) else {
+            return f64::NAN;
+        };
+        let imbalances: Vec<f64> = self
+            .map_entries(range, |e| e.imbalance())
+            .into_iter()
+            .filter(|v| !v.is_nan())
+            .collect();
+        if imbalances.is_empty() {
+            return f64::NAN;
+        }
+        imbalances.iter().sum::<f64>() / imbalances.len() as f64
+    }
+
+    /// Get the 10th, 50th, and 90th percentiles of top of book imbalance in the given time range. Entries without known
+    /// amounts are ignored. Return NaN for all of them if no entry has an imbalance.
+    pub fn imbalance_percentiles(&self, start_time: u64, end_time: u64) -> (f64, f64, f64) {
+        let Some(range) = self.resolve_range(start_time, end_time) else {
+            return (f64::NAN, f64::NAN, f64::NAN);
+        };
+        let imbalances: Vec<f64> = self
+            .map_entries(range, |e| e.imbalance())
+            .into_iter()
+            .filter(|v| !v.is_nan())
+            .collect();
+        if imbalances.is_empty() {
+            return (f64::NAN, f64::NAN, f64::NAN);
+        }
+        let tdigest = TDigest::new_with_size(1000).merge_unsorted(imbalances);
+        (
+            tdigest.estimate_quantile(0.1),
+            tdigest.estimate_quantile(0.5),
+            tdigest.estimate_quantile(0.9),
+        )
+    }
+
+    /// Get the most common spread in the given time range, e.g. for instruments quoting at tick size multiples. Spreads
+    /// are binned by bin_width, and the center of the most populated bin is returned, the lowest one on a tie. Return
+    /// NaN if there is no spread in range, or bin_width is not a positive number.
+    pub fn spread_mode(&self, start_time: u64, end_time: u64, bin_width: f64) -> f64 {
+        if !(bin_width > 0.0 && bin_width.is_finite()) {
+            return f64::NAN;
+        }
+        let Some(range) = self.resolve_range(start_time, end_time) else {
+            return f64::NAN;
+        };
+        self.spread_histogram(range, bin_width)
+            .into_iter()
+            .max_by(|(bin_a, count_a), (bin_b, count_b)| {
+                count_a.cmp(count_b).then(bin_b.cmp(bin_a))
+            })
+            .map_or(f64::NAN, |(bin, _)| (bin as f64 + 0.5) * bin_width)
+    }
+
+    /// Get the most common spread of the whole cache window from the online histogram set up by
+    /// [MarketDataCache::with_mode_histogram], in O(bins). The center of the most populated bin is returned, the lowest
+    /// one on a tie, the same as [MarketDataCache::spread_mode] over the whole window. Return NaN without a histogram, or
+    /// if the cache has no spread.
+    pub fn approximate_mode(&self) -> f64 {
+        self.mode_histogram
+            .as_ref()
+            .map_or(f64::NAN, SpreadHistogram::mode)
+    }
+
+    /// Infer the tick size of the instrument from the spreads in the given time range, e.g. to validate data quality.
+    /// Distinct spreads are collected, and the greatest common divisor of their differences is the tick. Spreads within a
+    /// relative tolerance of 1e-9 of the largest one count as the same, and so do remainders in the GCD. Non finite
+    /// spreads are ignored. Return None if there are fewer than 3 distinct spreads, as a single difference says little.
+    pub fn infer_tick_size(&self, start_time: u64, end_time: u64) -> Option<f64> {
+        let mut spreads: Vec<f64> = Vec::new();
+        self.visit_entries(start_time, end_time, |e| {
+            if e.spread.is_finite() {
+                spreads.push(e.spread);
+            }
+        });
+        spreads.sort_by(f64::total_cmp);
+        let largest = spreads
+            .iter()
+            .map(|spread| spread.abs())
+            .fold(0.0, f64::max);
+        let tolerance = (largest * 1e-9).max(f64::MIN_POSITIVE);
+        spreads.dedup_by(|b, a| *b - *a <= tolerance);
+        if spreads.len() < 3 {
+            return None;
+        }
+        spreads
+            .windows(2)
+            .map(|pair| pair[1] - pair[0])
+            .reduce(|tick, diff| float_gcd(tick, diff, tolerance))
+    }
+
+    /// Get the mean spread by phase within a period in the given time range, e.g. with a phase_ns of 1 second, whether
+    /// spreads are wider at the top of each second. An entry falls into phase bin `(ts % phase_ns) * num_phases /
+    /// phase_ns`. Return one mean per bin, NaN for a bin without entries, or an empty Vec if phase_ns or num_phases is 0.
+    pub fn spread_by_phase(
+        &self,
+        start_time: u64,
+        end_time: u64,
+        phase_ns: u64,
+        num_phases: usize,
+    ) -> Vec<f64> {
+        if phase_ns == 0 || num_phases == 0 {
+            return Vec::new();
+        }
+        let mut bins = vec![(0usize, 0.0); num_phases];
+        self.visit_entries(start_time, end_time, |e| {
+            // In u128, as the product may overflow u64 for a long period.
+            let phase = (e.utc_epoch_ns % phase_ns) as u128 * num_phases as u128 / phase_ns as u128;
+            let (count, sum) = &mut bins[phase as usize];
+            *count += 1;
+            *sum += e.spread;
+        });
+        bins.into_iter()
+            .map(|(count, sum)| {
+                if count == 0 {
+                    f64::NAN
+                } else {
+                    sum / count as f64
+                }
+            })
+            .collect()
+    }
+
+    /// Get the minimum spread in the given time range.
+    /// start_time and end_time may be any time within the last 1 hour. Gives f64::MAX if there's nothing in the range.
+    pub fn min_spread(&self, start_time: u64, end_time: u64) -> f64 {
+        let Some(range) = self.resolve_range(start_time, end_time) else {
+            return f64::MAX;
+        };
+        let (start_idx, end_idx) = (range.start_idx, range.end_idx);
+        let (start_time, end_time) = (range.start_time, range.end_time);
+        let mut min = f64::MAX;
+        if self.is_stats_only() {
+            return self
+                .map_buckets_in(start_idx..end_idx + 1, |bucket| bucket.min_spread)
+                .into_iter()
+                .fold(min, f64::min);
+        }
+
+        // If start and end points to the same bucket.
+        if start_idx == end_idx {
+            let bucket = self.buckets[start_idx].read().unwrap();
+            let spreads = bucket.spreads_in_between(start_time, end_time);
+            if !spreads.is_empty() {
+                let bucket_min = SpreadStats::from_slice(&spreads).min;
+                return min.min(bucket_min);
+            } else {
+                return min;
+            }
+        }
+
+        // Handle the starting bucket, partial data.
+        {
+            let bucket = self.buckets[start_idx].read().unwrap();
+            let spreads = bucket.spreads_start_from(start_time);
+            if !spreads.is_empty() {
+                let bucket_min = SpreadStats::from_slice(&spreads).min;
+                min = min.min(bucket_min);
+            }
+        }
+
+        // Handle the middle, complete buckets.
+        let middle_part_min = self
+            .map_middle_buckets(start_idx, end_idx, |bucket| bucket.min_spread)
+            .into_iter()
+            .min_by(|a, b| a.partial_cmp(b).unwrap())
+            .unwrap_or(f64::MAX);
+        min = min.min(middle_part_min);
+
+        // Handle the last bucket, partial data.
+        if start_idx != end_idx {
+            let bucket = self.buckets[end_idx].read().unwrap();
+            let spreads = bucket.spreads_end_before(end_time);
+            if !spreads.is_empty() {
+                let bucket_min = SpreadStats::from_slice(&spreads).min;
+                min = min.min(bucket_min);
+            }
+        }
+
+        min
+    }
+
+    // Get the maximum spread in the given time range.
+    // start_time and end_time may be any time within the last 1 hour. Gives -f64::MAX if there's nothing in the range.
+    pub fn max_spread(&self, start_time: u64, end_time: u64) -> f64 {
+        let Some(range) = self.resolve_range(start_time, end_time) else {
+            return -f64::MAX;
+        };
+        let (start_idx, end_idx) = (range.start_idx, range.end_idx);
+        let (start_time, end_time) = (range.start_time, range.end_time);
+        let mut max = -f64::MAX;
+        if self.is_stats_only() {
+            return self
+                .map_buckets_in(start_idx..end_idx + 1, |bucket| bucket.max_spread)
+                .into_iter()
+                .fold(max, f64::max);
+        }
+
+        // If start and end points to the same bucket.
+        if start_idx == end_idx {
+            let bucket = self.buckets[start_idx].read().unwrap();
+            let spreads = bucket.spreads_in_between(start_time, end_time);
+            if !spreads.is_empty() {
+                let bucket_max = SpreadStats::from_slice(&spreads).max;
+                return max.max(bucket_max);
+            }
+        }
+
+        // Handle the starting bucket, partial data.
+        {
+            let bucket = self.buckets[start_idx].read().unwrap();
+            let spreads = bucket.spreads_start_from(start_time);
+            if !spreads.is_empty() {
+                let bucket_max = SpreadStats::from_slice(&spreads).max;
+                max = max.max(bucket_max);
+            }
+        }
+
+        // Handle the middle, complete buckets.
+        let middle_part_max = self
+            .map_middle_buckets(start_idx, end_idx, |bucket| bucket.max_spread)
+            .into_iter()
+            .max_by(|a, b| a.partial_cmp(b).unwrap())
+            .unwrap_or(-f64::MAX);
+        max = max.max(middle_part_max);
 
         // Handle the last bucket, partial data.
         if start_idx != end_idx {
             let bucket = self.buckets[end_idx].read().unwrap();
-            let entries = bucket.get_end_before(end_time);
-            if !entries.is_empty() {
-                let bucket_max = entries
-                    .iter()
-                    .map(|e| e.spread)
-                    .max_by(|a, b| a.partial_cmp(b).unwrap())
-                    .unwrap();
+            let spreads = bucket.spreads_end_before(end_time);
+            if !spreads.is_empty() {
+                let bucket_max = SpreadStats::from_slice(&spreads).max;
                 max = max.max(bucket_max);
             }
         }
 
-        max
+        max
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::types::{BidAsk, SpreadConvention};
+    use crate::utils::approx_eq;
+
+    #[test]
+    fn test_new_market_data_cache() {
+        let mut cache = MarketDataCache::new(10, 10);
+        let entry = MarketDataEntry {
+            utc_epoch_ns: 0,
+            spread: 1.0,
+            ..Default::default()
+        };
+
+        cache.insert(entry);
+        assert_eq!(cache.count(), 1);
+
+        for (i, bucket) in cache.buckets.iter().enumerate() {
+            let read_lock = bucket.read().unwrap();
+            assert_eq!(read_lock.start_time_ns, i as u64 * 10);
+            assert_eq!(read_lock.end_time_ns, (i + 1) as u64 * 10);
+        }
+        assert_eq!(cache.buckets.len(), 10);
+    }
+
+    #[test]
+    fn test_remove_up_to() {
+        let mut cache = MarketDataCache::new(4, 10);
+        let entries: Vec<MarketDataEntry> = (0..16)
+            .map(|i| MarketDataEntry {
+                utc_epoch_ns: i * 5,
+                spread: i as f64,
+                ..Default::default()
+            })
+            .collect();
+        for entry in entries {
+            cache.insert(entry);
+        }
+        assert_eq!(cache.count(), 7);
+        cache.remove_up_to(60);
+        assert_eq!(cache.count(), 3);
+    }
+
+    #[test]
+    fn test_count_range() {
+        let mut cache = MarketDataCache::new(4, 10);
+        let entries: Vec<MarketDataEntry> = (0..16)
+            .map(|i| MarketDataEntry {
+                utc_epoch_ns: i * 5,
+                spread: i as f64,
+                ..Default::default()
+            })
+            .collect();
+        for entry in entries {
+            cache.insert(entry);
+        }
+        let count = cache.count_range(45, 60);
+        assert_eq!(count, 4);
+    }
+
+    #[test]
+    fn test_count_range_bucket_edges() {
+        // Aligned to buckets, and one ns inside at either end.
+        let mut cache = MarketDataCache::new(10, 10);
+        for i in 0..16 {
+            cache.insert(MarketDataEntry::new(i * 5, i as f64));
+        }
+        assert_eq!(cache.count_range(10, 39), 6);
+        assert_eq!(cache.count_range(0, 99), 16);
+        assert_eq!(cache.count_range(11, 39), 5);
+        assert_eq!(cache.count_range(10, 34), 5);
+        assert_eq!(cache.count_range(10, 19), 2);
+        assert_eq!(cache.count_range(10, 14), 1);
+    }
+
+    #[test]
+    fn test_min_spread() {
+        let mut cache = MarketDataCache::new(10, 10);
+        let entries: Vec<MarketDataEntry> = (0..100)
+            .map(|i| MarketDataEntry {
+                utc_epoch_ns: i,
+                spread: i as f64,
+                ..Default::default()
+            })
+            .collect();
+        for entry in entries {
+            cache.insert(entry);
+        }
+        let min_spread = cache.min_spread(30, 70);
+        assert_eq!(min_spread, 30.0);
+    }
+
+    #[test]
+    fn test_max_spread() {
+        let mut cache = MarketDataCache::new(10, 10);
+        let entries: Vec<MarketDataEntry> = (0..100)
+            .map(|i| MarketDataEntry {
+                utc_epoch_ns: i,
+                spread: i as f64,
+                ..Default::default()
+            })
+            .collect();
+        for entry in entries {
+            cache.insert(entry);
+        }
+        let max_spread = cache.max_spread(30, 70);
+        assert_eq!(max_spread, 70.0);
+    }
+
+    #[test]
+    fn test_spread_percentiles() {
+        let mut cache = MarketDataCache::new(10, 10);
+        let entries: Vec<MarketDataEntry> = (0..100)
+            .map(|i| MarketDataEntry {
+                utc_epoch_ns: i,
+                spread: i as f64,
+                ..Default::default()
+            })
+            .collect();
+        for entry in entries {
+            cache.insert(entry);
+        }
+        let (a, b, c) = cache.spread_percentiles(0, 99);
+
+        assert!(approx_eq(a, 9.5, 1e-9), "{a}");
+        assert!(approx_eq(b, 49.5, 1e-9), "{b}");
+        assert!(approx_eq(c, 89.5, 1e-9), "{c}");
+    }
+
+    #[test]
+    fn test_spread_percentiles_multi() {
+        let mut cache = MarketDataCache::new(10, 10);
+        let entries: Vec<MarketDataEntry> = (0..100)
+            .map(|i| MarketDataEntry::new(i, i as f64))
+            .collect();
+        for entry in entries {
+            cache.insert(entry);
+        }
+        let ranges = [(0, 99), (30, 70), (2, 7), (70, 30), (500, 600), (20, 79)];
+        let results = cache.spread_percentiles_multi(&ranges);
+        assert_eq!(results.len(), ranges.len());
+
+        // Valid ranges should match the one range version.
+        for i in [0, 1, 2, 5] {
+            let (start_time, end_time) = ranges[i];
+            assert_eq!(results[i], cache.spread_percentiles(start_time, end_time));
+        }
+
+        // Invalid ranges.
+        for i in [3, 4] {
+            let (a, b, c) = results[i];
+            assert!(a.is_nan() && b.is_nan() && c.is_nan());
+        }
+    }
+
+    #[test]
+    fn test_time_bounds() {
+        let mut cache = MarketDataCache::new(10, 10);
+        assert_eq!(cache.time_bounds(), None);
+        assert!(!cache.contains(0));
+
+        cache.insert(MarketDataEntry::new(25, 1.0));
+        assert_eq!(cache.time_bounds(), Some((20, 120)));
+        assert!(!cache.contains(19));
+        assert!(cache.contains(20));
+        assert!(cache.contains(119));
+        assert!(!cache.contains(120));
+
+        // Window moves forward after eviction.
+        cache.insert(MarketDataEntry::new(135, 1.0));
+        assert_eq!(cache.time_bounds(), Some((40, 140)));
+        assert!(!cache.contains(25));
+    }
+
+    #[test]
+    fn test_with_thread_pool() {
+        let pool = Arc::new(
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(2)
+                .build()
+                .unwrap(),
+        );
+        let entries: Vec<MarketDataEntry> = (0..1000)
+            .map(|i| MarketDataEntry::new(i, (i % 97) as f64))
+            .collect();
+        let mut global = MarketDataCache::new(100, 10).with_par_threshold(0);
+        global.bulk_insert_parallel(entries.clone());
+        let mut pooled = MarketDataCache::new(100, 10)
+            .with_par_threshold(0)
+            .with_thread_pool(Arc::clone(&pool));
+        pooled.bulk_insert_parallel(entries);
+
+        assert_eq!(pooled.count(), 1000);
+        assert_eq!(pooled.summarize(5, 994), global.summarize(5, 994));
+        assert_eq!(
+            pooled.spread_percentiles(5, 994),
+            global.spread_percentiles(5, 994)
+        );
+        assert_eq!(
+            pooled.top_buckets_by_max_spread(0, 999, 3),
+            global.top_buckets_by_max_spread(0, 999, 3)
+        );
+        assert_eq!(pooled.remove_up_to(504), global.remove_up_to(504));
+        assert_eq!(pooled.count_range(0, 999), 495);
+        // Queries run in the pool, whose threads are the only ones that know their index in it.
+        pooled.install(|| assert!(rayon::current_thread_index().is_some()));
+        assert_eq!(pool.current_num_threads(), 2);
+    }
+
+    #[test]
+    fn test_bulk_insert_parallel() {
+        // The last few entries are out of the first window, so some old data will be evicted.
+        let entries: Vec<MarketDataEntry> = (0..130)
+            .rev()
+            .map(|i| MarketDataEntry::new(i, i as f64))
+            .collect();
+
+        let mut sequential = MarketDataCache::new(10, 10);
+        let mut sorted = entries.clone();
+        sorted.sort_by_key(|entry| entry.utc_epoch_ns);
+        for entry in sorted {
+            sequential.insert(entry);
+        }
+
+        let mut parallel = MarketDataCache::new(10, 10);
+        parallel.bulk_insert_parallel(entries);
+
+        assert_eq!(parallel.time_bounds(), sequential.time_bounds());
+        assert_eq!(parallel.count(), 100);
+        assert_eq!(parallel.count_range(30, 129), 100);
+        assert_eq!(parallel.min_spread(30, 129), 30.0);
+        assert_eq!(parallel.max_spread(30, 129), 129.0);
+        assert_eq!(
+            parallel.spread_percentiles(40, 120),
+            sequential.spread_percentiles(40, 120)
+        );
+
+        // Entries older than the window are dropped, newer ones move the window forward.
+        parallel.bulk_insert_parallel(vec![
+            MarketDataEntry::new(5, 5.0),
+            MarketDataEntry::new(135, 135.0),
+        ]);
+        assert_eq!(parallel.time_bounds(), Some((40, 140)));
+        assert_eq!(parallel.count(), 90);
+    }
+
+    #[test]
+    fn test_full_depth() {
+        use crate::types::BidAsk;
+
+        let entries: Vec<MarketDataEntryFull> = (0..100)
+            .map(|i| MarketDataEntryFull {
+                utc_epoch_ns: i,
+                bids: vec![BidAsk {
+                    price: 100.0,
+                    amount: 2.0,
+                }],
+                asks: vec![BidAsk {
+                    price: 100.0 + i as f64,
+                    amount: 1.0,
+                }],
+            })
+            .collect();
+
+        let mut full = MarketDataCache::new_full_depth(10, 10);
+        let mut spread_only = MarketDataCache::new(10, 10);
+        for entry in entries {
+            full.insert_full(entry.clone());
+            spread_only.insert_full(entry);
+        }
+
+        // Statistics are the same no matter what we store.
+        assert_eq!(full.count(), 100);
+        assert_eq!(spread_only.count(), 100);
+        assert_eq!(full.min_spread(30, 70), spread_only.min_spread(30, 70));
+        assert_eq!(full.max_spread(30, 70), 70.0);
+
+        let depth = full.full_entries_in_range(25, 34);
+        assert_eq!(depth.len(), 10);
+        assert_eq!(depth[0].utc_epoch_ns, 25);
+        assert_eq!(depth[0].bids[0].amount, 2.0);
+        assert!(spread_only.full_entries_in_range(25, 34).is_empty());
+    }
+
+    #[test]
+    fn test_drain_up_to() {
+        let mut cache = MarketDataCache::new(4, 10);
+        // Insert out of order within buckets, drained entries should still come back in timestamp order.
+        let entries: Vec<MarketDataEntry> = (0..8)
+            .rev()
+            .map(|i| MarketDataEntry::new(i * 5, i as f64))
+            .collect();
+        cache.insert(MarketDataEntry::new(0, 0.0));
+        for entry in entries {
+            cache.insert(entry);
+        }
+        assert_eq!(cache.count(), 9);
+
+        let drained = cache.drain_up_to(22);
+        let drained_times: Vec<u64> = drained.iter().map(|entry| entry.utc_epoch_ns).collect();
+        assert_eq!(drained_times, vec![0, 0, 5, 10, 15, 20]);
+        assert!(drained.iter().all(|entry| entry.utc_epoch_ns <= 22));
+        assert_eq!(cache.count(), 3);
+        assert_eq!(cache.count_range(20, 59), 3);
+        assert_eq!(cache.buckets.len(), 4);
+    }
+
+    #[test]
+    fn test_remove_up_to_empty_cache() {
+        let mut cache = MarketDataCache::new(4, 10);
+        assert_eq!(cache.remove_up_to(100), 0);
+        assert!(cache.drain_up_to(100).is_empty());
+        assert!(cache.buckets.is_empty());
+        assert_eq!(cache.count(), 0);
+    }
+
+    #[test]
+    fn test_remove_up_to_everything() {
+        let mut cache = MarketDataCache::new(4, 10);
+        let entries: Vec<MarketDataEntry> = (0..8)
+            .map(|i| MarketDataEntry::new(i * 5, i as f64))
+            .collect();
+        for entry in entries.clone() {
+            cache.insert(entry);
+        }
+        assert_eq!(cache.remove_up_to(1000), 8);
+        assert_eq!(cache.count(), 0);
+        assert_eq!(cache.buckets.len(), 4);
+        // Cache starts over from the bucket containing the threshold.
+        assert_eq!(cache.time_bounds(), Some((1000, 1040)));
+
+        let mut cache = MarketDataCache::new(4, 10);
+        for entry in entries {
+            cache.insert(entry);
+        }
+        assert_eq!(cache.drain_up_to(1005).len(), 8);
+        assert_eq!(cache.count(), 0);
+        assert_eq!(cache.time_bounds(), Some((1000, 1040)));
+
+        // Inserting far in the future evicts everything as well.
+        cache.insert(MarketDataEntry::new(10_000, 1.0));
+        assert_eq!(cache.count(), 1);
+        assert_eq!(cache.time_bounds(), Some((9970, 10010)));
+    }
+
+    #[test]
+    fn test_latest_spread_zscore() {
+        let mut cache = MarketDataCache::new(10, 10);
+        assert!(cache.latest_spread_zscore(50).is_nan());
+
+        // Spreads alternate between 1 and 3 within the trailing window, so mean is 2 and std dev is 1.
+        for i in 0..40 {
+            cache.insert(MarketDataEntry::new(i, if i % 2 == 0 { 1.0 } else { 3.0 }));
+        }
+        cache.insert(MarketDataEntry::new(40, 2.0));
+        assert_eq!(cache.latest_spread_zscore(40), 0.0);
+
+        cache.insert(MarketDataEntry::new(41, 100.0));
+        assert!(cache.latest_spread_zscore(41) > 5.0);
+
+        // Zero standard deviation.
+        let mut cache = MarketDataCache::new(10, 10);
+        for i in 0..40 {
+            cache.insert(MarketDataEntry::new(i, 1.0));
+        }
+        assert!(cache.latest_spread_zscore(30).is_nan());
+    }
+
+    #[test]
+    fn test_insert_overflow() {
+        // The first bucket would start at u64::MAX - 5, the window can't fit.
+        let mut cache = MarketDataCache::new(10, 10);
+        cache.insert(MarketDataEntry::new(u64::MAX, 1.0));
+        assert!(cache.buckets.is_empty());
+        assert_eq!(cache.count(), 0);
+
+        // A window close to the top still works.
+        let start = u64::MAX - 105;
+        cache.insert(MarketDataEntry::new(start, 1.0));
+        assert_eq!(cache.time_bounds(), Some((start, start + 100)));
+
+        // Moving the window to the very top would overflow, so the entry is skipped instead of wrapping.
+        cache.insert(MarketDataEntry::new(u64::MAX, 2.0));
+        assert_eq!(cache.time_bounds(), Some((start, start + 100)));
+        assert_eq!(cache.max_spread(start, start + 99), 1.0);
+
+        // Same for bulk insert, and direct eviction.
+        cache.bulk_insert_parallel(vec![MarketDataEntry::new(u64::MAX, 2.0)]);
+        assert_eq!(cache.time_bounds(), Some((start, start + 100)));
+        cache.remove_up_to(u64::MAX);
+        assert!(cache.buckets.is_empty());
+    }
+
+    #[test]
+    fn test_expected_entries_per_bucket() {
+        let mut cache = MarketDataCache::new(4, 10).with_expected_entries_per_bucket(8);
+        cache.insert(MarketDataEntry::new(0, 1.0));
+        assert!(
+            cache
+                .buckets
+                .iter()
+                .all(|bucket| bucket.read().unwrap().entries.capacity() >= 8)
+        );
+
+        // Buckets created after eviction are pre-allocated as well.
+        cache.insert(MarketDataEntry::new(55, 1.0));
+        let last_bucket = cache.buckets.back().unwrap().read().unwrap();
+        assert_eq!(last_bucket.start_time_ns, 50);
+        assert!(last_bucket.entries.capacity() >= 8);
+    }
+
+    #[test]
+    fn test_entries_in_range() {
+        let mut cache = MarketDataCache::new(10, 10);
+        for i in [0, 12, 3, 15, 11, 40, 39] {
+            cache.insert(MarketDataEntry::new(i, i as f64));
+        }
+        let times: Vec<u64> = cache
+            .entries_in_range(3, 39)
+            .iter()
+            .map(|e| e.utc_epoch_ns)
+            .collect();
+        assert_eq!(times, vec![3, 11, 12, 15, 39]);
+        assert!(cache.entries_in_range(500, 600).is_empty());
+    }
+
+    #[test]
+    fn test_gaps() {
+        let mut cache = MarketDataCache::new(10, 10);
+        for i in [0, 2, 4, 20, 22, 60, 61] {
+            cache.insert(MarketDataEntry::new(i, 1.0));
+        }
+        assert_eq!(cache.longest_gap(0, 99), 38);
+        assert_eq!(cache.longest_gap(0, 59), 16);
+        assert_eq!(cache.gaps_exceeding(0, 99, 10), vec![(4, 20), (22, 60)]);
+        assert_eq!(cache.gaps_exceeding(0, 99, 38), vec![]);
+
+        // Zero or one entry.
+        assert_eq!(cache.longest_gap(30, 50), 0);
+        assert_eq!(cache.longest_gap(55, 60), 0);
+        assert!(cache.gaps_exceeding(55, 60, 0).is_empty());
+    }
+
+    #[test]
+    fn test_merge_bucket() {
+        let mut cache = MarketDataCache::new(10, 10);
+        for i in 0..50 {
+            cache.insert(MarketDataEntry::new(i, i as f64));
+        }
+
+        let mut other = Bucket::new(20, 30);
+        for i in 20..30 {
+            other.insert(MarketDataEntry::new(i, 100.0 + i as f64));
+        }
+        assert_eq!(cache.merge_bucket(&other), Ok(()));
+        assert_eq!(cache.count(), 60);
+        assert_eq!(cache.count_range(20, 29), 20);
+        assert_eq!(cache.min_spread(20, 29), 20.0);
+        assert_eq!(cache.max_spread(20, 29), 129.0);
+        assert_eq!(cache.max_spread(0, 99), 129.0);
+
+        // Not aligned.
+        assert_eq!(
+            cache.merge_bucket(&Bucket::new(25, 35)),
+            Err(MarketDataError::MisalignedBucket {
+                start_time_ns: 25,
+                end_time_ns: 35,
+                bucket_ns: 10
+            })
+        );
+        assert_eq!(
+            cache.merge_bucket(&Bucket::new(20, 40)).unwrap_err(),
+            MarketDataError::MisalignedBucket {
+                start_time_ns: 20,
+                end_time_ns: 40,
+                bucket_ns: 10
+            }
+        );
+
+        // Entries out of the bucket range.
+        let mut out_of_range = Bucket::new(30, 40);
+        out_of_range.entries.push(MarketDataEntry::new(45, 1.0));
+        assert_eq!(
+            cache.merge_bucket(&out_of_range),
+            Err(MarketDataError::EntryRejected(45))
+        );
+
+        // Newer than the window, moves the window forward, and then the old one is too old.
+        let mut newer = Bucket::new(100, 110);
+        newer.insert(MarketDataEntry::new(105, 1.0));
+        assert_eq!(cache.merge_bucket(&newer), Ok(()));
+        assert_eq!(cache.time_bounds(), Some((10, 110)));
+        assert_eq!(
+            cache.merge_bucket(&Bucket::new(0, 10)),
+            Err(MarketDataError::BucketTooOld {
+                start_time_ns: 0,
+                cache_start_ns: 10
+            })
+        );
+    }
+
+    #[test]
+    fn test_summarize() {
+        let mut cache = MarketDataCache::new(10, 10);
+        let entries: Vec<MarketDataEntry> = (0..100)
+            .map(|i| MarketDataEntry::new(i, i as f64))
+            .collect();
+        for entry in entries {
+            cache.insert(entry);
+        }
+
+        for (start_time, end_time) in [(0, 99), (25, 75), (31, 38)] {
+            let summary = cache.summarize(start_time, end_time);
+            let (p10, p50, p90) = cache.spread_percentiles(start_time, end_time);
+            assert_eq!(summary.count, cache.count_range(start_time, end_time));
+            assert_eq!(summary.min, cache.min_spread(start_time, end_time));
+            assert_eq!(summary.max, cache.max_spread(start_time, end_time));
+            let mean = (start_time + end_time) as f64 / 2.0;
+            assert!(approx_eq(summary.mean, mean, 1e-9), "{}", summary.mean);
+            assert_eq!((summary.p10, summary.p50, summary.p90), (p10, p50, p90));
+        }
+
+        let summary = cache.summarize(200, 300);
+        assert_eq!(summary.count, 0);
+        assert!(summary.min.is_nan() && summary.p50.is_nan() && summary.mean.is_nan());
+    }
+
+    #[test]
+    fn test_warning_limiter() {
+        let mut warnings = WarningLimiter::new(2);
+        for i in 0..5 {
+            warnings.warn(format_args!("Skipping entry {i}"));
+        }
+        assert_eq!(warnings.emitted, 2);
+        assert_eq!(warnings.suppressed, 3);
+    }
+
+    #[test]
+    fn test_with_file_options() {
+        let path = std::env::temp_dir().join("market_data_test_with_file_options.json");
+        std::fs::write(
+            &path,
+            r#"{"market_data_entries": [
+                {"utc_epoch_ns": 1731496040145982615, "bids": [{"price": 100.0, "amount": 1.0}], "asks": [{"price": 101.0, "amount": 1.0}]},
+                {"utc_epoch_ns": 1731496040145982616},
+                {"utc_epoch_ns": 1731496040145982617, "bids": []},
+                {"bids": [], "asks": []},
+                {"utc_epoch_ns": 1731496040145982618, "bids": [{"price": 100.0, "amount": 1.0}], "asks": [{"price": 102.0, "amount": 1.0}]}
+            ]}"#,
+        )
+        .unwrap();
+        let cache = MarketDataCache::with_file_options(
+            path.to_str().unwrap(),
+            LoadOptions {
+                max_warnings: 1,
+                ..Default::default()
+            },
+        );
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(cache.count(), 2);
+    }
+
+    #[test]
+    fn test_aggregate_to() {
+        let mut cache = MarketDataCache::new(10, 10);
+        for (ts, spread) in [
+            (0, 1.0),
+            (5, 3.0),
+            (12, 2.0),
+            (25, 4.0),
+            (31, 6.0),
+            (38, 8.0),
+        ] {
+            cache.insert(MarketDataEntry::new(ts, spread));
+        }
+
+        // Windows of one bucket reproduce the bucket summaries.
+        let windows = cache.aggregate_to(10, 0, 39);
+        let summaries = cache.bucket_summaries_by_index(0..4);
+        assert_eq!(windows.len(), summaries.len());
+        for (window, summary) in windows.iter().zip(&summaries) {
+            assert_eq!(window.start_time_ns, summary.start_time_ns);
+            assert_eq!(window.count, summary.count);
+            assert_eq!(window.min, summary.min_spread);
+            assert_eq!(window.max, summary.max_spread);
+            assert_eq!(window.mean, summary.mean_spread);
+        }
+
+        // Windows of two buckets, the range ends in the middle of the last bucket.
+        let windows = cache.aggregate_to(20, 0, 35);
+        assert_eq!(windows.len(), 2);
+        assert_eq!((windows[0].start_time_ns, windows[0].count), (0, 3));
+        assert_eq!(
+            (windows[0].min, windows[0].max, windows[0].mean),
+            (1.0, 3.0, 2.0)
+        );
+        assert_eq!(windows[0].p50, 2.0);
+        assert_eq!((windows[1].start_time_ns, windows[1].count), (20, 2));
+        assert_eq!(
+            (windows[1].min, windows[1].max, windows[1].mean),
+            (4.0, 6.0, 5.0)
+        );
+
+        // An empty window has NaN spreads.
+        cache.insert(MarketDataEntry::new(65, 1.0));
+        let windows = cache.aggregate_to(20, 0, 69);
+        assert_eq!(windows.len(), 4);
+        assert_eq!(windows[2].start_time_ns, 40);
+        assert_eq!(windows[2].count, 0);
+        assert!(windows[2].min.is_nan() && windows[2].mean.is_nan() && windows[2].p50.is_nan());
+
+        assert!(cache.aggregate_to(15, 0, 39).is_empty());
+        assert!(cache.aggregate_to(0, 0, 39).is_empty());
+    }
+
+    #[test]
+    fn test_validate_file() {
+        let path = std::env::temp_dir().join("market_data_test_validate_file.json");
+        std::fs::write(
+            &path,
+            r#"{"market_data_entries": [
+                {"utc_epoch_ns": 1731496040145982615, "bids": [{"price": 100.0, "amount": 1.0}], "asks": [{"price": 101.0, "amount": 1.0}]},
+                {"utc_epoch_ns": 1000, "spread": 1.0},
+                {"utc_epoch_ns": 1731496040145982617, "bids": []},
+                {"utc_epoch_ns": 1731496040145982618, "bids": [{"price": 100.0, "amount": 1.0}], "asks": []},
+                {"utc_epoch_ns": 1731496040145982619, "bids": [{"price": 100.0, "amount": 1.0}], "asks": [{"price": 110.0, "amount": 1.0}]},
+                {"utc_epoch_ns": 1731496040145982614, "spread": 2.5}
+            ]}"#,
+        )
+        .unwrap();
+        let path = path.to_str().unwrap();
+        let report = MarketDataCache::validate_file(path).unwrap();
+        let mut cache = MarketDataCache::new(36000, 100_000_000);
+        let loaded = cache.ingest(JsonFileSource::open(path).unwrap(), LoadOptions::default());
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(
+            report,
+            LoadReport {
+                inserted: 0,
+                ..loaded
+            }
+        );
+        assert_eq!(loaded.inserted, 2);
+        assert_eq!(
+            report.skipped_by_reason,
+            SkipCounts {
+                invalid_timestamp: 1,
+                missing_book: 1,
+                empty_book: 1,
+                outlier: 1,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            report.data_span,
+            Some((1731496040145982614, 1731496040145982615))
+        );
+        assert!(!report.timestamps_monotonic);
+
+        assert!(matches!(
+            MarketDataCache::validate_file("/nonexistent/market_data.json"),
+            Err(MarketDataError::Io(_))
+        ));
+    }
+
+    #[test]
+    fn test_load_timestamp_above_i64_max() {
+        // i64::MAX + 1, which used to panic in the timestamp check.
+        let cache = MarketDataCache::with_json_str(
+            r#"{"market_data_entries": [{"utc_epoch_ns": 9223372036854775808, "spread": 1.0}]}"#,
+        )
+        .unwrap();
+        assert_eq!(cache.count(), 1);
+        assert_eq!(
+            cache.latest_entry.unwrap().utc_epoch_ns,
+            i64::MAX as u64 + 1
+        );
+    }
+
+    #[test]
+    fn test_with_json_str() {
+        let cache = MarketDataCache::with_json_str(
+            r#"{"market_data_entries": [
+                {"utc_epoch_ns": 1731496040145982615, "bids": [{"price": 100.0, "amount": 1.0}], "asks": [{"price": 101.0, "amount": 1.0}]},
+                {"utc_epoch_ns": 1731496040145982616, "spread": 3.0},
+                {"utc_epoch_ns": 1731496040145982617, "bids": []}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(cache.count(), 2);
+        assert_eq!(cache.min_spread(0, u64::MAX), 1.0);
+
+        assert!(matches!(
+            MarketDataCache::with_json_str("{\"market_data_entries\": 1}"),
+            Err(MarketDataError::InvalidJson(_))
+        ));
+        assert!(matches!(
+            MarketDataCache::with_json_str("not json"),
+            Err(MarketDataError::InvalidJson(_))
+        ));
+    }
+
+    #[test]
+    fn test_with_file_spread_shapes() {
+        let book = |ts: u64, bid: f64, ask: f64| {
+            format!(
+                r#"{{"utc_epoch_ns": {ts}, "bids": [{{"price": {bid:.1}, "amount": 1.0}}], "asks": [{{"price": {ask:.1}, "amount": 1.0}}]}}"#
+            )
+        };
+        let spread =
+            |ts: u64, spread: &str| format!(r#"{{"utc_epoch_ns": {ts}, "spread": {spread}}}"#);
+        let ts = 1731496040145982615;
+        let fixtures = [
+            // The full depth shape only.
+            (
+                vec![book(ts, 100.0, 101.0), book(ts + 1, 100.0, 102.0)],
+                2,
+                1.5,
+            ),
+            // The precomputed spread shape only, a non numeric spread is invalid.
+            (
+                vec![
+                    spread(ts, "0.5"),
+                    spread(ts + 1, "2.5"),
+                    spread(ts + 2, "\"wide\""),
+                ],
+                2,
+                1.5,
+            ),
+            // Both shapes mixed, a precomputed spread wins over the book.
+            (
+                vec![
+                    book(ts, 100.0, 101.0),
+                    spread(ts + 1, "3.0"),
+                    format!(
+                        r#"{{"utc_epoch_ns": {}, "spread": 2.0, "bids": [], "asks": []}}"#,
+                        ts + 2
+                    ),
+                ],
+                3,
+                2.0,
+            ),
+        ];
+        for (i, (quotes, count, mean)) in fixtures.into_iter().enumerate() {
+            let path =
+                std::env::temp_dir().join(format!("market_data_test_spread_shapes_{i}.json"));
+            std::fs::write(
+                &path,
+                format!(r#"{{"market_data_entries": [{}]}}"#, quotes.join(",")),
+            )
+            .unwrap();
+            let cache = MarketDataCache::with_file(path.to_str().unwrap());
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!(cache.count(), count, "fixture {i}");
+            assert_eq!(cache.summarize(0, u64::MAX - 1).mean, mean, "fixture {i}");
+        }
+    }
+
+    #[test]
+    fn test_imbalance() {
+        let mut cache = MarketDataCache::new(10, 10);
+        // Imbalance is 0.5 for the first half and -0.5 for the second half, plus a few entries without amounts.
+        for i in 0..100 {
+            let entry = if i < 50 {
+                MarketDataEntry::new(i, 1.0).with_amounts(3.0, 1.0)
+            } else {
+                MarketDataEntry::new(i, 1.0).with_amounts(1.0, 3.0)
+            };
+            cache.insert(entry);
+        }
+        for i in [5, 55] {
+            cache.insert(MarketDataEntry::new(i, 1.0));
+        }
+
+        assert!(approx_eq(cache.mean_imbalance(0, 99), 0.0, 1e-9));
+        assert!(approx_eq(cache.mean_imbalance(0, 49), 0.5, 1e-9));
+        assert!(approx_eq(cache.mean_imbalance(40, 59), 0.0, 1e-9));
+        assert_eq!(cache.imbalance_percentiles(0, 49), (0.5, 0.5, 0.5));
+        let (p10, _, p90) = cache.imbalance_percentiles(0, 99);
+        assert_eq!((p10, p90), (-0.5, 0.5));
+
+        assert!(cache.mean_imbalance(200, 300).is_nan());
+    }
+
+    #[test]
+    fn test_buckets_in_range() {
+        let mut cache = MarketDataCache::new(10, 10);
+        assert_eq!(cache.buckets_in_range(0, 99), 0);
+
+        cache.insert(MarketDataEntry::new(0, 1.0));
+        assert_eq!(cache.buckets_in_range(0, 99), 10);
+        assert_eq!(cache.buckets_in_range(5, 5), 1);
+        assert_eq!(cache.buckets_in_range(5, 10), 2);
+        assert_eq!(cache.buckets_in_range(25, 1000), 8);
+        assert_eq!(cache.buckets_in_range(50, 40), 0);
+        assert_eq!(cache.buckets_in_range(100, 1000), 0);
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut cache = MarketDataCache::new(10, 10);
+        for i in 0..100 {
+            cache.insert(MarketDataEntry::new(i, i as f64));
+        }
+        cache.reset();
+        assert_eq!(cache.count(), 0);
+        assert_eq!(cache.time_bounds(), None);
+        assert_eq!(cache.count_range(0, 99), 0);
+        assert_eq!(cache.min_spread(0, 99), f64::MAX);
+        assert_eq!(cache.max_spread(0, 99), -f64::MAX);
+        assert!(cache.spread_percentiles(0, 99).0.is_nan());
+
+        cache.reset_with((1000..1050).map(|i| MarketDataEntry::new(i, 1.0)));
+        assert_eq!(cache.count(), 50);
+        assert_eq!(cache.time_bounds(), Some((1000, 1100)));
+        assert_eq!(cache.count_range(0, 2000), 50);
+        assert_eq!(cache.max_spread(1000, 1049), 1.0);
+    }
+
+    #[test]
+    fn test_bucket_at() {
+        let mut cache = MarketDataCache::new(10, 10);
+        assert_eq!(cache.bucket_at(0), None);
+
+        for i in 100..150 {
+            cache.insert(MarketDataEntry::new(i, (i % 10) as f64));
+        }
+        let summary = cache.bucket_at(125).unwrap();
+        assert_eq!((summary.start_time_ns, summary.end_time_ns), (120, 130));
+        assert_eq!(summary.count, 10);
+        assert_eq!(
+            (summary.min_spread, summary.max_spread, summary.mean_spread),
+            (0.0, 9.0, 4.5)
+        );
+
+        let empty = cache.bucket_at(199).unwrap();
+        assert_eq!(empty.count, 0);
+        assert!(empty.min_spread.is_nan());
+
+        assert_eq!(cache.bucket_at(99), None);
+        assert_eq!(cache.bucket_at(200), None);
+    }
+
+    #[test]
+    fn test_bucket_summary_by_index() {
+        let mut cache = MarketDataCache::new_sparse(10, 10);
+        assert_eq!(cache.bucket_summary_at_index(0), None);
+        assert!(cache.bucket_summaries_by_index(0..10).is_empty());
+
+        cache.insert(MarketDataEntry::new(100, 1.0));
+        cache.insert(MarketDataEntry::new(130, 2.0));
+        let oldest = cache.bucket_summary_at_index(0).unwrap();
+        assert_eq!((oldest.start_time_ns, oldest.count), (100, 1));
+        let missing = cache.bucket_summary_at_index(1).unwrap();
+        assert_eq!((missing.start_time_ns, missing.count), (110, 0));
+        let (start_time, end_time) = cache.time_bounds().unwrap();
+        let len = ((end_time - start_time) / 10) as usize;
+        assert!(cache.bucket_summary_at_index(len - 1).is_some());
+        assert_eq!(cache.bucket_summary_at_index(len), None);
+
+        let summaries = cache.bucket_summaries_by_index(1..60);
+        assert_eq!(summaries.len(), len - 1);
+        assert_eq!(summaries[2].start_time_ns, 130);
+        assert_eq!(summaries[2].count, 1);
+        assert!(cache.bucket_summaries_by_index(len..len + 5).is_empty());
+        #[allow(clippy::reversed_empty_ranges)]
+        let reversed = cache.bucket_summaries_by_index(3..1);
+        assert!(reversed.is_empty());
+    }
+
+    #[test]
+    fn test_spread_percentiles_exact_if_small() {
+        let mut cache = MarketDataCache::new(10, 10);
+        for i in 0..100 {
+            cache.insert(MarketDataEntry::new(i, i as f64));
+        }
+
+        let exact = cache.spread_percentiles_exact_if_small(0, 10, 100);
+        assert!(!exact.approximate);
+        assert_eq!((exact.p10, exact.p50, exact.p90), (1.0, 5.0, 9.0));
+
+        let approximate = cache.spread_percentiles_exact_if_small(0, 99, 50);
+        assert!(approximate.approximate);
+        assert_eq!(
+            (approximate.p10, approximate.p50, approximate.p90),
+            cache.spread_percentiles(0, 99)
+        );
+
+        let empty = cache.spread_percentiles_exact_if_small(200, 300, 100);
+        assert!(!empty.approximate);
+        assert!(empty.p50.is_nan());
+    }
+
+    #[test]
+    fn test_ingest_endless_source() {
+        // A feed that never ends on its own, stopped by the test after enough quotes.
+        struct Feed {
+            pulls: usize,
+            evicted: Arc<AtomicUsize>,
+        }
+        impl QuoteSource for Feed {
+            fn next_raw(&mut self) -> Option<RawQuote> {
+                self.pulls += 1;
+                if self.pulls > 3 * INGEST_BATCH_SIZE {
+                    // Earlier batches were inserted while reading, as later ones moved the window and evicted them.
+                    assert!(self.evicted.load(Ordering::Relaxed) > 0);
+                    return None;
+                }
+                Some(serde_json::json!({
+                    "utc_epoch_ns": 1731496040000000000u64 + self.pulls as u64,
+                    "spread": 1.0
+                }))
+            }
+        }
+
+        let evicted = Arc::new(AtomicUsize::new(0));
+        let mut cache = MarketDataCache::new(10, 100);
+        let counter = Arc::clone(&evicted);
+        cache.set_evict_handler(Box::new(move |entries: &[MarketDataEntry]| {
+            counter.fetch_add(entries.len(), Ordering::Relaxed);
+        }));
+        let feed = Feed {
+            pulls: 0,
+            evicted: Arc::clone(&evicted),
+        };
+        let report = cache.ingest(feed, LoadOptions::default());
+        assert_eq!(report.total, 3 * INGEST_BATCH_SIZE);
+        // Earlier batches count, even though later ones evicted them again.
+        assert!(report.inserted > cache.count());
+        // Only the latest window of 1000 ns is kept.
+        assert!(cache.count() <= 1000);
+        assert_eq!(
+            cache.latest_entry.unwrap().utc_epoch_ns,
+            1731496040000030000
+        );
+    }
+
+    #[test]
+    fn test_ingest_timestamps() {
+        let quote = |ts: serde_json::Value| serde_json::json!({"utc_epoch_ns": ts, "spread": 1.0});
+        let quotes = vec![
+            quote(serde_json::json!(1731496040000000000u64)),
+            quote(serde_json::json!(-1731496040000000000i64)),
+            quote(serde_json::json!(1230940800000000000u64)),
+            quote(serde_json::json!(1731496040000000001.5)),
+            // Beyond i64::MAX, but a valid u64.
+            quote(serde_json::json!(u64::MAX - 1)),
+        ];
+        let mut cache = MarketDataCache::new(10, 10);
+        let report = cache.ingest(JsonFileSource::from_values(quotes), LoadOptions::default());
+        assert_eq!(report.skipped_by_reason.invalid_timestamp, 3);
+        assert_eq!(report.data_span, Some((1731496040000000000, u64::MAX - 1)));
+    }
+
+    #[test]
+    fn test_ingest() {
+        // A source that isn't backed by a file, e.g. a websocket feed.
+        struct VecSource(Vec<RawQuote>);
+        impl QuoteSource for VecSource {
+            fn next_raw(&mut self) -> Option<RawQuote> {
+                self.0.pop()
+            }
+        }
+
+        let quote = |ts: u64, ask: f64| {
+            serde_json::json!({
+                "utc_epoch_ns": ts,
+                "bids": [{"price": 100.0, "amount": 2.0}],
+                "asks": [{"price": ask, "amount": 1.0}]
+            })
+        };
+        let source = VecSource(vec![
+            quote(1731496040000000000, 101.0),
+            quote(1731496040000000001, 200.0),
+            serde_json::json!({"utc_epoch_ns": 1731496040000000002u64}),
+            quote(1731496040000000003, 100.5),
+        ]);
+
+        let mut cache = MarketDataCache::new(10, 10);
+        let report = cache.ingest(source, LoadOptions::default());
+        assert_eq!(
+            report,
+            LoadReport {
+                total: 4,
+                skipped: 2,
+                skipped_by_reason: SkipCounts {
+                    missing_book: 1,
+                    outlier: 1,
+                    ..Default::default()
+                },
+                inserted: 2,
+                exceeds_window: false,
+                // The source pops quotes from the back, so they come in reverse order.
+                timestamps_monotonic: false,
+                out_of_order: 1,
+                data_span: Some((1731496040000000000, 1731496040000000003)),
+            }
+        );
+        assert_eq!(cache.count(), 2);
+        assert_eq!(cache.latest_entry.unwrap().bid_amount, 2.0);
+    }
+
+    #[test]
+    fn test_estimated_bytes() {
+        let mut cache = MarketDataCache::new(100, 10);
+        let empty = cache.estimated_bytes();
+        assert!(empty >= size_of::<MarketDataCache>());
+
+        for i in 0..1000 {
+            cache.insert(MarketDataEntry::new(i, 1.0));
+        }
+        // 10 entries in each of the 100 buckets, vectors grow to a capacity of 16 at most.
+        let entries = 1000 * size_of::<MarketDataEntry>();
+        let bytes = cache.estimated_bytes() - empty;
+        assert!(bytes >= entries, "{bytes} < {entries}");
+        assert!(
+            bytes <= 2 * entries + 100 * size_of::<RwLock<Bucket>>() * 2,
+            "{bytes}"
+        );
+    }
+
+    #[test]
+    fn test_ingest_one_sided() {
+        // Quotes with spreads 0.25 to 2.25, and a one sided book after each third one.
+        let quotes: Vec<RawQuote> = (0..12u64)
+            .map(|i| {
+                let asks = if i % 4 == 3 {
+                    serde_json::json!([])
+                } else {
+                    serde_json::json!([{"price": 100.0 + (i - i / 4 + 1) as f64 * 0.25, "amount": 1.0}])
+                };
+                serde_json::json!({
+                    "utc_epoch_ns": 1731496040000000000u64 + i,
+                    "bids": [{"price": 100.0, "amount": 1.0}],
+                    "asks": asks
+                })
+            })
+            .collect();
+        let ingest = |one_sided| {
+            let mut cache = MarketDataCache::new(10, 10);
+            let options = LoadOptions {
+                one_sided,
+                ..Default::default()
+            };
+            let report = cache.ingest(JsonFileSource::from_values(quotes.clone()), options);
+            (cache, report)
+        };
+
+        let (cache, report) = ingest(OneSidedPolicy::Skip);
+        assert_eq!(report.inserted, 9);
+        assert_eq!(report.skipped_by_reason.empty_book, 3);
+        let (start_time, end_time) = cache.time_bounds().unwrap();
+        assert_eq!(cache.max_spread(start_time, end_time), 2.25);
+
+        let (cache, report) = ingest(OneSidedPolicy::RecordSentinel(1000.0));
+        assert_eq!(report.inserted, 12);
+        assert_eq!(report.skipped, 0);
+        assert_eq!(cache.count_range(start_time, end_time), 12);
+        assert_eq!(cache.max_spread(start_time, end_time), 1000.0);
+
+        // NaN spreads are counted, but left out of min, max and percentiles.
+        let (cache, report) = ingest(OneSidedPolicy::RecordNaN);
+        assert_eq!(report.inserted, 12);
+        assert_eq!(cache.count_range(start_time, end_time), 12);
+        assert_eq!(cache.min_spread(start_time, end_time), 0.25);
+        assert_eq!(cache.max_spread(start_time, end_time), 2.25);
+        let (p50, p90, p99) = cache.spread_percentiles(start_time, end_time);
+        assert!(p50 > 0.25 && p90 <= 2.25 && p99 <= 2.25);
+        let (p50, p90, p99) = cache.spread_percentiles(start_time + 1, end_time - 1);
+        assert!(p50 > 0.25 && p90 <= 2.25 && p99 <= 2.25);
+        let exact = cache.spread_percentiles_exact_if_small(start_time, end_time, 100);
+        assert!(!exact.approximate);
+        assert!(exact.p10 >= 0.25 && exact.p90 <= 2.25);
+
+        // Winsorizing ignores the NaN spreads.
+        let mut cache = MarketDataCache::new(10, 10);
+        let options = LoadOptions {
+            one_sided: OneSidedPolicy::RecordNaN,
+            winsorize: Some(WinsorizePolicy {
+                lower_q: 0.0,
+                upper_q: 0.5,
+            }),
+            ..Default::default()
+        };
+        let report = cache.ingest(JsonFileSource::from_values(quotes.clone()), options);
+        assert_eq!(report.inserted, 12);
+        assert_eq!(cache.max_spread(start_time, end_time), 1.25);
+    }
+
+    #[test]
+    fn test_ingest_winsorize() {
+        let quotes: Vec<RawQuote> = (0..11u64)
+            .map(|i| {
+                // The last one is an outlier which is dropped by default.
+                let ask = if i == 10 {
+                    200.0
+                } else {
+                    100.0 + i as f64 * 0.1
+                };
+                serde_json::json!({
+                    "utc_epoch_ns": 1731496040000000000u64 + i,
+                    "bids": [{"price": 100.0, "amount": 1.0}],
+                    "asks": [{"price": ask, "amount": 1.0}]
+                })
+            })
+            .collect();
+
+        let mut cache = MarketDataCache::new(10, 10);
+        let report = cache.ingest(
+            JsonFileSource::from_values(quotes.clone()),
+            LoadOptions::default(),
+        );
+        assert_eq!(report.inserted, 10);
+
+        let mut cache = MarketDataCache::new(10, 10);
+        let options = LoadOptions {
+            winsorize: Some(WinsorizePolicy {
+                lower_q: 0.1,
+                upper_q: 0.9,
+            }),
+            ..Default::default()
+        };
+        let report = cache.ingest(JsonFileSource::from_values(quotes), options);
+        assert_eq!(report.inserted, 11);
+        assert_eq!(report.skipped, 0);
+
+        let (start_time, end_time) = cache.time_bounds().unwrap();
+        let spreads: Vec<f64> = cache
+            .entries_in_range(start_time, end_time)
+            .iter()
+            .map(|e| e.spread)
+            .collect();
+        // Exact 10th and 90th percentiles of 0.0, 0.1, ..., 0.9 and 100.0.
+        let (lower, upper) = (spreads[1], spreads[9]);
+        assert_eq!(spreads[0], lower);
+        assert_eq!(spreads[10], upper);
+        assert!((upper - 0.9).abs() < 1e-9, "{upper}");
+    }
+
+    #[test]
+    fn test_last_n_entries() {
+        let mut cache = MarketDataCache::new(10, 10);
+        assert!(cache.last_n_entries(5).is_empty());
+
+        for i in [3, 1, 2, 15, 12, 25] {
+            cache.insert(MarketDataEntry::new(i, i as f64));
+        }
+        let timestamps = |entries: Vec<MarketDataEntry>| -> Vec<u64> {
+            entries.iter().map(|e| e.utc_epoch_ns).collect()
+        };
+        assert_eq!(timestamps(cache.last_n_entries(0)), Vec::<u64>::new());
+        assert_eq!(timestamps(cache.last_n_entries(2)), vec![15, 25]);
+        assert_eq!(timestamps(cache.last_n_entries(4)), vec![3, 12, 15, 25]);
+        // Fewer than n entries present.
+        assert_eq!(
+            timestamps(cache.last_n_entries(100)),
+            vec![1, 2, 3, 12, 15, 25]
+        );
+    }
+
+    #[test]
+    fn test_concurrent_insert_and_query() {
+        let cache = Arc::new(RwLock::new(MarketDataCache::new(10, 10)));
+        let writer = {
+            let cache = Arc::clone(&cache);
+            std::thread::spawn(move || {
+                // Slides the window far enough to evict buckets many times.
+                for i in 0..20_000u64 {
+                    cache
+                        .write()
+                        .unwrap()
+                        .insert(MarketDataEntry::new(i, (i % 7) as f64));
+                }
+            })
+        };
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let cache = Arc::clone(&cache);
+                std::thread::spawn(move || {
+                    for _ in 0..2_000 {
+                        let cache = cache.read().unwrap();
+                        let Some((start_time, end_time)) = cache.time_bounds() else {
+                            continue;
+                        };
+                        assert!(cache.count_range(start_time, end_time) <= cache.count());
+                        let (p10, _, p90) = cache.spread_percentiles(start_time, end_time - 1);
+                        assert!(p10 <= p90);
+                        assert!(cache.max_spread(start_time, end_time) <= 6.0);
+                    }
+                })
+            })
+            .collect();
+
+        writer.join().unwrap();
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        let cache = cache.read().unwrap();
+        let (start_time, end_time) = cache.time_bounds().unwrap();
+        assert_eq!(cache.count_range(start_time, end_time), cache.count());
+    }
+
+    #[test]
+    fn test_concurrent_tdigest_cache() {
+        let mut cache = MarketDataCache::new(100, 10);
+        for i in 0..1000 {
+            cache.insert(MarketDataEntry::new(i, (i % 10) as f64));
+        }
+        // Readers share the same buckets, so they race to fill the cached tdigests.
+        let expected = cache.spread_percentiles(0, 999);
+        for bucket in cache.buckets.iter() {
+            bucket.write().unwrap().tdigest = std::sync::OnceLock::new();
+        }
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| assert_eq!(cache.spread_percentiles(0, 999), expected));
+            }
+        });
+    }
+
+    #[test]
+    fn test_time_in_bands() {
+        let mut cache = MarketDataCache::new(10, 10);
+        assert_eq!(cache.time_in_bands(0, 99, &[0.0, 1.0]), vec![0]);
+
+        // Spread 0.5 for 10ns, 1.5 for 30ns, exactly 2.0 for 5ns, 5.0 for 5ns, then 0.5 until the end time.
+        for (ts, spread) in [(0, 0.5), (10, 1.5), (40, 2.0), (45, 5.0), (50, 0.5)] {
+            cache.insert(MarketDataEntry::new(ts, spread));
+        }
+        assert_eq!(cache.time_in_bands(0, 60, &[0.0, 1.0, 2.0]), vec![20, 35]);
+        // The end time is clamped to the last ns of the cache.
+        assert_eq!(cache.time_in_bands(0, 1000, &[0.0, 1.0]), vec![59]);
+        // Time before the first quote in range is not counted.
+        assert_eq!(cache.time_in_bands(5, 40, &[0.0, 1.0, 2.0]), vec![0, 30]);
+
+        assert!(cache.time_in_bands(0, 60, &[1.0]).is_empty());
+        assert!(cache.time_in_bands(0, 60, &[2.0, 1.0]).is_empty());
+    }
+
+    #[test]
+    fn test_sparse_matches_dense() {
+        let mut dense = MarketDataCache::new(1000, 10);
+        let mut sparse = MarketDataCache::new_sparse(1000, 10);
+        // A quote every 97ns, so most buckets are empty, and the window slides a few times.
+        for i in 0..500u64 {
+            let entry = MarketDataEntry::new(i * 97, (i % 13) as f64);
+            dense.insert(entry.clone());
+            sparse.insert(entry);
+        }
+        let bulk: Vec<MarketDataEntry> = (0..50u64)
+            .map(|i| MarketDataEntry::new(48_500 + i * 31, (i % 5) as f64))
+            .collect();
+        dense.bulk_insert_parallel(bulk.clone());
+        sparse.bulk_insert_parallel(bulk);
+
+        let timestamps = |entries: Vec<MarketDataEntry>| -> Vec<u64> {
+            entries.iter().map(|e| e.utc_epoch_ns).collect()
+        };
+        assert_eq!(sparse.count(), dense.count());
+        assert_eq!(sparse.time_bounds(), dense.time_bounds());
+        assert_eq!(sparse.buckets.len(), dense.buckets.len());
+        let (start_time, end_time) = dense.time_bounds().unwrap();
+        for (start, end) in [
+            (start_time, end_time),
+            (start_time + 5, start_time + 7),
+            (start_time + 123, end_time - 456),
+            (0, u64::MAX),
+            (end_time, end_time + 10),
+        ] {
+            assert_eq!(
+                sparse.count_range(start, end),
+                dense.count_range(start, end)
+            );
+            assert_eq!(sparse.min_spread(start, end), dense.min_spread(start, end));
+            assert_eq!(sparse.max_spread(start, end), dense.max_spread(start, end));
+            assert_eq!(
+                format!("{:?}", sparse.spread_percentiles(start, end)),
+                format!("{:?}", dense.spread_percentiles(start, end))
+            );
+            assert_eq!(
+                format!("{:?}", sparse.summarize(start, end)),
+                format!("{:?}", dense.summarize(start, end))
+            );
+            assert_eq!(
+                timestamps(sparse.entries_in_range(start, end)),
+                timestamps(dense.entries_in_range(start, end))
+            );
+        }
+        assert_eq!(
+            timestamps(sparse.last_n_entries(20)),
+            timestamps(dense.last_n_entries(20))
+        );
+        for ts in [start_time, start_time + 55, end_time - 1, end_time] {
+            assert_eq!(
+                format!("{:?}", sparse.bucket_at(ts)),
+                format!("{:?}", dense.bucket_at(ts))
+            );
+        }
+        assert!(sparse.estimated_bytes() < dense.estimated_bytes() / 2);
+
+        let drained_time = start_time + 2_345;
+        assert_eq!(
+            timestamps(sparse.drain_up_to(drained_time)),
+            timestamps(dense.drain_up_to(drained_time))
+        );
+        assert_eq!(
+            sparse.remove_up_to(end_time - 10),
+            dense.remove_up_to(end_time - 10)
+        );
+        assert_eq!(sparse.count(), dense.count());
+        assert_eq!(sparse.time_bounds(), dense.time_bounds());
+
+        sparse.reset();
+        assert_eq!(sparse.count_range(0, u64::MAX), 0);
+        assert_eq!(sparse.time_bounds(), None);
+    }
+
+    #[test]
+    fn test_sliding_quantile() {
+        let mut cache = MarketDataCache::new(10, 10);
+        assert!(
+            cache
+                .sliding_quantile(20, 3, 0.5)
+                .iter()
+                .all(|q| q.is_nan())
+        );
+
+        // Spread is the bucket index, in a window of [0, 100).
+        for i in 0..100 {
+            cache.insert(MarketDataEntry::new(i, (i / 10) as f64));
+        }
+        assert_eq!(cache.sliding_quantile(10, 3, 0.5), vec![7.0, 8.0, 9.0]);
+        assert_eq!(cache.sliding_quantile(20, 2, 0.0), vec![6.0, 8.0]);
+
+        // The oldest windows start before the cache.
+        let quantiles = cache.sliding_quantile(40, 4, 1.0);
+        assert!(quantiles[0].is_nan());
+        assert_eq!(&quantiles[1..], &[1.0, 5.0, 9.0]);
+    }
+
+    #[test]
+    fn test_quantile_around() {
+        let mut cache = MarketDataCache::new(10, 10);
+        assert!(cache.quantile_around(&[5], 5, 0.5)[0].is_nan());
+
+        // Spread is the timestamp, in a window of [0, 100).
+        for i in 0..100 {
+            cache.insert(MarketDataEntry::new(i, i as f64));
+        }
+        let quantiles = cache.quantile_around(&[50, 10, 0, 99, 200], 2, 1.0);
+        assert_eq!(&quantiles[..4], &[52.0, 12.0, 2.0, 99.0]);
+        // Entirely after the cache.
+        assert!(quantiles[4].is_nan());
+
+        assert_eq!(cache.quantile_around(&[50], 0, 0.5), vec![50.0]);
+        assert_eq!(cache.quantile_around(&[u64::MAX], u64::MAX, 0.0), vec![0.0]);
+        assert!(cache.quantile_around(&[], 2, 0.5).is_empty());
+    }
+
+    #[test]
+    fn test_count_matches_buckets() {
+        let mut cache = MarketDataCache::new(10, 10);
+        for i in 0..250 {
+            cache.insert(MarketDataEntry::new(i, 1.0));
+        }
+        cache.bulk_insert_parallel((250..300).map(|i| MarketDataEntry::new(i, 1.0)).collect());
+        cache.remove_up_to(215);
+
+        // count() is exact whenever no update is running, as updates take &mut self.
+        let in_buckets: usize = cache
+            .buckets
+            .iter()
+            .map(|bucket| bucket.read().unwrap().count)
+            .sum();
+        assert_eq!(cache.count(), in_buckets);
+        assert_eq!(cache.count(), 84);
+    }
+
+    #[test]
+    fn test_map_buckets() {
+        let mut cache = MarketDataCache::new(5, 10);
+        assert!(cache.map_buckets(|summary| summary.count).is_empty());
+
+        for i in [1, 2, 25, 41] {
+            cache.insert(MarketDataEntry::new(i, i as f64));
+        }
+        assert_eq!(
+            cache.map_buckets(|summary| summary.count),
+            vec![2, 0, 1, 0, 1]
+        );
+        assert_eq!(
+            cache.map_buckets(|summary| summary.start_time_ns),
+            vec![0, 10, 20, 30, 40]
+        );
+
+        let mut sparse = MarketDataCache::new_sparse(5, 10);
+        for i in [1, 2, 25, 41] {
+            sparse.insert(MarketDataEntry::new(i, i as f64));
+        }
+        let mut summaries = Vec::new();
+        sparse.for_each_bucket(|summary| summaries.push((summary.start_time_ns, summary.count)));
+        assert_eq!(summaries, vec![(0, 2), (10, 0), (20, 1), (30, 0), (40, 1)]);
+    }
+
+    #[test]
+    fn test_ingest_unsorted_book() {
+        // The best bid is the second level, so the first one gives a spread of 2.0 instead of 1.0.
+        let quotes = vec![serde_json::json!({
+            "utc_epoch_ns": 1731496040000000000u64,
+            "bids": [{"price": 99.0, "amount": 1.0}, {"price": 100.0, "amount": 2.0}],
+            "asks": [{"price": 101.0, "amount": 1.0}]
+        })];
+        let load = |book_order| {
+            let mut cache = MarketDataCache::new(10, 10);
+            let options = LoadOptions {
+                book_order,
+                ..Default::default()
+            };
+            cache.ingest(JsonFileSource::from_values(quotes.clone()), options);
+            cache.latest_entry
+        };
+
+        assert_eq!(load(BookOrder::Trust).unwrap().spread, 2.0);
+        let sorted = load(BookOrder::Sort).unwrap();
+        assert_eq!((sorted.spread, sorted.bid_amount), (1.0, 2.0));
+        assert!(load(BookOrder::Skip).is_none());
+    }
+
+    #[test]
+    fn test_spread_log_returns() {
+        let mut cache = MarketDataCache::new(10, 10);
+        assert!(cache.spread_log_returns(0, 99, true).is_empty());
+
+        for (ts, spread) in [
+            (0, 1.0),
+            (5, 2.0),
+            (15, 2.0),
+            (20, 0.0),
+            (30, 4.0),
+            (42, 1.0),
+        ] {
+            cache.insert(MarketDataEntry::new(ts, spread));
+        }
+        let ln2 = 2f64.ln();
+        let returns = cache.spread_log_returns(0, 99, true);
+        assert_eq!(returns.len(), 3);
+        for (actual, expected) in returns.iter().zip([ln2, 0.0, -2.0 * ln2]) {
+            assert!(
+                approx_eq(*actual, expected, 1e-12),
+                "{actual} != {expected}"
+            );
+        }
+        let with_nan = cache.spread_log_returns(0, 99, false);
+        assert_eq!(with_nan.len(), 5);
+        assert!(with_nan[2].is_nan() && with_nan[3].is_nan());
+        assert!(approx_eq(with_nan[4], -2.0 * ln2, 1e-12));
+        assert_eq!(cache.spread_log_returns(5, 15, true), vec![0.0]);
+    }
+
+    #[test]
+    fn test_running_spread_extremes() {
+        let mut cache = MarketDataCache::new(4, 10);
+        assert_eq!(cache.running_min_spread(), f64::MAX);
+
+        for (ts, spread) in [(0, 1.0), (5, 9.0), (12, 3.0), (25, 5.0), (33, 4.0)] {
+            cache.insert(MarketDataEntry::new(ts, spread));
+        }
+        assert_eq!(
+            (cache.running_min_spread(), cache.running_max_spread()),
+            (1.0, 9.0)
+        );
+
+        // Evicts the first bucket, which holds both extremes.
+        cache.insert(MarketDataEntry::new(41, 3.5));
+        assert_eq!(
+            (cache.running_min_spread(), cache.running_max_spread()),
+            (3.0, 5.0)
+        );
+
+        // A partial removal of the bucket holding the min.
+        cache.remove_up_to(12);
+        assert_eq!(
+            (cache.running_min_spread(), cache.running_max_spread()),
+            (3.5, 5.0)
+        );
+        let drained = cache.drain_up_to(30);
+        assert_eq!(drained.len(), 1);
+        assert_eq!(
+            (cache.running_min_spread(), cache.running_max_spread()),
+            (3.5, 4.0)
+        );
+
+        cache.bulk_insert_parallel(vec![
+            MarketDataEntry::new(45, 0.5),
+            MarketDataEntry::new(46, 7.0),
+        ]);
+        assert_eq!(
+            (cache.running_min_spread(), cache.running_max_spread()),
+            (0.5, 7.0)
+        );
+        assert_eq!(cache.running_min_spread(), cache.min_spread(0, u64::MAX));
+        assert_eq!(cache.running_max_spread(), cache.max_spread(0, u64::MAX));
+
+        cache.reset();
+        assert_eq!(cache.running_max_spread(), -f64::MAX);
+    }
+
+    #[test]
+    fn test_summarize_json() {
+        let mut cache = MarketDataCache::new(10, 10);
+        for i in 0..10 {
+            cache.insert(MarketDataEntry::new(i, 1.0));
+        }
+        let json: Value = serde_json::from_str(&cache.summarize_json(0, 99)).unwrap();
+        for key in ["count", "min", "max", "mean", "p10", "p50", "p90"] {
+            assert!(json.get(key).is_some(), "{key} is missing");
+        }
+        assert_eq!(json["count"], 10);
+        assert_eq!(json["mean"], 1.0);
+
+        let empty: Value = serde_json::from_str(&cache.summarize_json(200, 300)).unwrap();
+        assert_eq!(empty["count"], 0);
+        assert!(empty["min"].is_null());
+    }
+
+    #[test]
+    fn test_spread_mode() {
+        let mut cache = MarketDataCache::new(10, 10);
+        assert!(cache.spread_mode(0, 99, 0.01).is_nan());
+
+        // Mostly 2 ticks of 0.01, spread over several buckets.
+        for i in 0..100 {
+            let spread = match i % 5 {
+                0 => 0.01,
+                1..=3 => 0.02,
+                _ => 0.03,
+            };
+            cache.insert(MarketDataEntry::new(i, spread));
+        }
+        assert!(approx_eq(cache.spread_mode(0, 99, 0.01), 0.025, 1e-12));
+        // Only 0.01 and 0.02 once each, the tie goes to the lower bin.
+        assert!(approx_eq(cache.spread_mode(0, 1, 0.01), 0.015, 1e-12));
+        assert!(approx_eq(cache.spread_mode(0, 99, 1.0), 0.5, 1e-12));
+
+        assert!(cache.spread_mode(0, 99, 0.0).is_nan());
+        assert!(cache.spread_mode(200, 300, 0.01).is_nan());
+    }
+
+    #[test]
+    fn test_ingest_out_of_order() {
+        let path = std::env::temp_dir().join("market_data_test_out_of_order.json");
+        let quote = |ts: u64| {
+            format!(
+                r#"{{"utc_epoch_ns": {}, "bids": [{{"price": 100.0, "amount": 1.0}}], "asks": [{{"price": 100.1, "amount": 1.0}}]}}"#,
+                1731496040000000000 + ts
+            )
+        };
+        // 2 comes after 3, the second 4 repeats a timestamp, and the invalid quote between 5 and 6 doesn't count.
+        let mut quotes: Vec<String> = [1, 3, 2, 4, 4, 5].into_iter().map(quote).collect();
+        quotes.push(r#"{"utc_epoch_ns": 1731496040000000000}"#.to_string());
+        quotes.push(quote(6));
+        std::fs::write(
+            &path,
+            format!(r#"{{"market_data_entries": [{}]}}"#, quotes.join(",")),
+        )
+        .unwrap();
+        let source = JsonFileSource::open(path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut cache = MarketDataCache::new(10, 10);
+        let report = cache.ingest(source, LoadOptions::default());
+        assert!(!report.timestamps_monotonic);
+        assert_eq!(report.out_of_order, 2);
+        assert_eq!((report.skipped, report.inserted), (1, 7));
+
+        let in_order: Vec<RawQuote> = [1, 2, 3]
+            .into_iter()
+            .map(|ts| serde_json::from_str(&quote(ts)).unwrap())
+            .collect();
+        let report = cache.ingest(
+            JsonFileSource::from_values(in_order),
+            LoadOptions::default(),
+        );
+        assert!(report.timestamps_monotonic);
+        assert_eq!(report.out_of_order, 0);
+    }
+
+    #[test]
+    fn test_ingest_evicts_existing() {
+        let mut cache = MarketDataCache::new(2, 10);
+        for i in 0..20 {
+            cache.insert(MarketDataEntry::new(1731496040000000000 + i, 1.0));
+        }
+        assert_eq!(cache.count(), 20);
+
+        // One quote far in the future evicts everything already in the cache.
+        let quote = serde_json::json!({"utc_epoch_ns": 1731496040000001000u64, "spread": 2.0});
+        let report = cache.ingest(
+            JsonFileSource::from_values(vec![quote]),
+            LoadOptions::default(),
+        );
+        assert_eq!(report.inserted, 1);
+        assert_eq!(cache.count(), 1);
+    }
+
+    #[test]
+    fn test_ingest_exceeds_window() {
+        // 30 quotes 1 bucket apart, but the window only has 10 buckets.
+        let quotes: Vec<RawQuote> = (0..30u64)
+            .map(|i| {
+                serde_json::json!({
+                    "utc_epoch_ns": 1731496040000000000u64 + i * 10,
+                    "bids": [{"price": 100.0, "amount": 1.0}],
+                    "asks": [{"price": 100.1, "amount": 1.0}]
+                })
+            })
+            .collect();
+
+        let mut cache = MarketDataCache::new(10, 10);
+        let report = cache.ingest(
+            JsonFileSource::from_values(quotes.clone()),
+            LoadOptions::default(),
+        );
+        assert!(report.exceeds_window);
+        assert_eq!(report.inserted, 10);
+        assert_eq!(cache.count(), 10);
+
+        let options = LoadOptions {
+            fit_window: true,
+            ..Default::default()
+        };
+        let mut cache = MarketDataCache::new(10, 10);
+        let report = cache.ingest(JsonFileSource::from_values(quotes.clone()), options.clone());
+        assert!(!report.exceeds_window);
+        assert_eq!(report.inserted, 30);
+        assert_eq!(cache.count(), 30);
+        assert_eq!(cache.num_buckets, 30);
+
+        // A non-empty cache keeps its window.
+        let report = cache.ingest(JsonFileSource::from_values(quotes[..15].to_vec()), options);
+        assert!(!report.exceeds_window);
+        let mut cache = MarketDataCache::new(10, 10);
+        cache.insert(MarketDataEntry::new(1731496040000000000, 0.1));
+        let report = cache.ingest(
+            JsonFileSource::from_values(quotes),
+            LoadOptions {
+                fit_window: true,
+                ..Default::default()
+            },
+        );
+        assert!(report.exceeds_window);
+        assert_eq!(cache.num_buckets, 10);
+
+        // The window grows up to a cap, entries beyond it still exceed the window.
+        let mut cache = MarketDataCache::new(10, 10);
+        let span = (0, 10 * MAX_FIT_WINDOW_BUCKETS as u64);
+        assert!(!cache.fit_window_to(Some(span), true));
+        assert_eq!(cache.num_buckets, MAX_FIT_WINDOW_BUCKETS);
+        assert!(cache.fit_window_to(Some((0, span.1 - 10)), true));
+    }
+
+    #[test]
+    fn test_compact() {
+        let mut cache = MarketDataCache::new(10, 1000).with_expected_entries_per_bucket(1000);
+        for i in 0..1000 {
+            cache.insert(MarketDataEntry::new(i, 1.0));
+        }
+        assert!(cache.buckets[1].read().unwrap().entries.capacity() >= 1000);
+
+        // Eviction frees the space of a mostly removed bucket.
+        cache.remove_up_to(990);
+        let first_bucket = cache.buckets[0].read().unwrap();
+        assert_eq!(first_bucket.entries.len(), 9);
+        assert!(first_bucket.entries.capacity() < 1000);
+        drop(first_bucket);
+
+        cache.compact();
+        for bucket in cache.buckets.iter() {
+            let bucket = bucket.read().unwrap();
+            assert_eq!(bucket.entries.capacity(), bucket.entries.len());
+        }
+        assert_eq!(cache.count(), 9);
+    }
+
+    #[test]
+    fn test_spread_percentiles_skip() {
+        let mut cache = MarketDataCache::new(10, 10);
+        // A noisy warmup of 10 ns, then a constant spread.
+        for i in 0..100 {
+            let spread = if i < 10 { 100.0 + i as f64 } else { 1.0 };
+            cache.insert(MarketDataEntry::new(i, spread));
+        }
+        assert_eq!(cache.spread_percentiles_skip(0, 99, 10), (1.0, 1.0, 1.0));
+        assert_eq!(
+            cache.spread_percentiles_skip(0, 99, 0),
+            cache.spread_percentiles(0, 99)
+        );
+        // Relative to start, not absolute.
+        assert_eq!(cache.spread_percentiles_skip(50, 99, 10), (1.0, 1.0, 1.0));
+
+        for (start, end, skip) in [(0, 99, 99), (0, 99, 1000), (99, 0, 0)] {
+            let (p10, p50, p90) = cache.spread_percentiles_skip(start, end, skip);
+            assert!(p10.is_nan() && p50.is_nan() && p90.is_nan());
+        }
+    }
+
+    #[test]
+    fn test_validate_invariants() {
+        let mut cache = MarketDataCache::new(10, 10);
+        assert_eq!(cache.validate_invariants(), Ok(()));
+        for i in 0..100 {
+            cache.insert(MarketDataEntry::new(i, 1.0));
+        }
+        cache.remove_up_to(35);
+        assert_eq!(cache.validate_invariants(), Ok(()));
+
+        cache.buckets[2].write().unwrap().end_time_ns += 1;
+        assert_eq!(
+            cache.validate_invariants(),
+            Err(vec![InvariantViolation::NotContiguous {
+                idx: 2,
+                end_time_ns: 61,
+                next_start_time_ns: 60
+            }])
+        );
+        cache.buckets[2].write().unwrap().end_time_ns -= 1;
+
+        cache.buckets[3]
+            .write()
+            .unwrap()
+            .entries
+            .push(MarketDataEntry::new(5, 1.0));
+        cache.count.fetch_add(1, Ordering::Relaxed);
+        assert_eq!(
+            cache.validate_invariants(),
+            Err(vec![
+                InvariantViolation::EntryOutOfBucket {
+                    idx: 3,
+                    utc_epoch_ns: 5
+                },
+                InvariantViolation::CountMismatch {
+                    count: 65,
+                    bucket_sum: 64
+                }
+            ])
+        );
+    }
+
+    #[test]
+    fn test_validate_invariants_sparse() {
+        let mut cache = MarketDataCache::new_sparse(100, 10);
+        for i in [0, 500, 510, 990] {
+            cache.insert(MarketDataEntry::new(i, 1.0));
+        }
+        assert_eq!(cache.validate_invariants(), Ok(()));
+        cache.count.fetch_sub(1, Ordering::Relaxed);
+        assert_eq!(
+            cache.validate_invariants(),
+            Err(vec![InvariantViolation::CountMismatch {
+                count: 3,
+                bucket_sum: 4
+            }])
+        );
+    }
+
+    #[test]
+    fn test_top_buckets() {
+        let mut cache = MarketDataCache::new(10, 10);
+        assert!(cache.top_buckets_by_max_spread(0, 99, 3).is_empty());
+
+        // Bucket i has i + 1 entries with max spread i % 4, and bucket 9 stays empty.
+        for i in 0..9u64 {
+            for j in 0..=i {
+                cache.insert(MarketDataEntry::new(i * 10 + j, (i % 4) as f64));
+            }
+        }
+        let starts = |summaries: Vec<BucketSummary>| -> Vec<u64> {
+            summaries.iter().map(|s| s.start_time_ns).collect()
+        };
+        // Ties of max spread 3 and 2 go to the earlier bucket.
+        assert_eq!(
+            starts(cache.top_buckets_by_max_spread(0, 99, 3)),
+            vec![30, 70, 20]
+        );
+        assert_eq!(starts(cache.top_buckets_by_count(0, 99, 2)), vec![80, 70]);
+        // Partially overlapping buckets count.
+        assert_eq!(
+            starts(cache.top_buckets_by_count(5, 25, 10)),
+            vec![20, 10, 0]
+        );
+        assert!(cache.top_buckets_by_count(0, 99, 0).is_empty());
+        assert_eq!(cache.top_buckets_by_count(0, 99, 100).len(), 9);
+    }
+
+    #[test]
+    fn test_ingest_spread_convention() {
+        let quotes: Vec<RawQuote> = (0..10u64)
+            .map(|i| {
+                serde_json::json!({
+                    "utc_epoch_ns": 1731496040000000000u64 + i,
+                    "bids": [{"price": 100.0, "amount": 1.0}],
+                    "asks": [{"price": 100.0 + (i + 1) as f64 * 0.1, "amount": 1.0}]
+                })
+            })
+            .collect();
+        let load = |spread_convention| {
+            let mut cache = MarketDataCache::new(10, 10);
+            let options = LoadOptions {
+                spread_convention,
+                ..Default::default()
+            };
+            let report = cache.ingest(JsonFileSource::from_values(quotes.clone()), options);
+            assert_eq!(report.inserted, 10);
+            cache
+        };
+        let (start_time, end_time) = (1731496040000000000, 1731496040000000009);
+
+        let normal = load(SpreadConvention::AskMinusBid);
+        let negated = load(SpreadConvention::BidMinusAsk);
+        assert!(approx_eq(
+            negated.min_spread(start_time, end_time),
+            -normal.max_spread(start_time, end_time),
+            1e-9
+        ));
+        assert!(approx_eq(
+            negated.max_spread(start_time, end_time),
+            -normal.min_spread(start_time, end_time),
+            1e-9
+        ));
+        // Exact percentiles, as tdigest interpolation is not symmetric.
+        let p = normal.spread_percentiles_exact_if_small(start_time, end_time, 100);
+        let n = negated.spread_percentiles_exact_if_small(start_time, end_time, 100);
+        assert!(approx_eq(n.p10, -p.p90, 1e-9));
+        assert!(approx_eq(n.p50, -p.p50, 1e-9));
+        assert!(approx_eq(n.p90, -p.p10, 1e-9));
+
+        let absolute = load(SpreadConvention::AbsoluteValue);
+        assert_eq!(
+            absolute.summarize(start_time, end_time).count,
+            normal.summarize(start_time, end_time).count
+        );
+        assert!(approx_eq(
+            absolute.min_spread(start_time, end_time),
+            normal.min_spread(start_time, end_time),
+            1e-9
+        ));
+    }
+
+    #[test]
+    fn test_incremental_tdigest_policy() {
+        let mut lazy = MarketDataCache::new(10, 100);
+        let mut incremental =
+            MarketDataCache::new(10, 100).with_tdigest_policy(TDigestPolicy::Incremental);
+        for cache in [&mut lazy, &mut incremental] {
+            for i in 0..1000 {
+                cache.insert(MarketDataEntry::new(i, (i % 10) as f64));
+            }
+        }
+        // Query with middle buckets cached, then keep inserting late quotes into them.
+        for i in 0..200 {
+            for cache in [&mut lazy, &mut incremental] {
+                cache.spread_percentiles(0, 999);
+                cache.insert(MarketDataEntry::new(100 + i * 4, (i % 7) as f64));
+            }
+        }
+        assert!(
+            incremental.buckets[1]
+                .read()
+                .unwrap()
+                .tdigest
+                .get()
+                .is_some()
+        );
+        let (l10, l50, l90) = lazy.spread_percentiles(0, 999);
+        let (i10, i50, i90) = incremental.spread_percentiles(0, 999);
+        assert!(approx_eq(l10, i10, 0.5));
+        assert!(approx_eq(l50, i50, 0.5));
+        assert!(approx_eq(l90, i90, 0.5));
+
+        // Full depth inserts follow the policy as well.
+        let mut full = MarketDataCache::new_full_depth(10, 100)
+            .with_tdigest_policy(TDigestPolicy::Incremental);
+        let full_entry = |i: u64| MarketDataEntryFull {
+            utc_epoch_ns: i,
+            bids: vec![BidAsk {
+                price: 100.0,
+                amount: 1.0,
+            }],
+            asks: vec![BidAsk {
+                price: 100.0 + (i % 10) as f64,
+                amount: 1.0,
+            }],
+        };
+        for i in 0..1000 {
+            full.insert_full(full_entry(i));
+        }
+        full.spread_percentiles(0, 999);
+        full.insert_full(full_entry(104));
+        let bucket = full.buckets[1].read().unwrap();
+        assert_eq!(
+            bucket.tdigest.get().map(|(_, tdigest)| tdigest.count()),
+            Some(101.0)
+        );
+    }
+
+    #[test]
+    fn test_count_spread_above_below() {
+        let mut cache = MarketDataCache::new(10, 10);
+        assert_eq!(cache.count_spread_above(0, 99, 1.0), 0);
+        // Bucket i holds spreads i, i + 0.1, ..., i + 0.9.
+        for i in 0..100 {
+            cache.insert(MarketDataEntry::new(
+                i,
+                (i / 10) as f64 + (i % 10) as f64 * 0.1,
+            ));
+        }
+        assert_eq!(cache.count_spread_above(0, 99, 4.45), 55);
+        assert_eq!(cache.count_spread_below(0, 99, 4.45), 45);
+        // Partial buckets at both ends.
+        assert_eq!(cache.count_spread_above(15, 84, 4.45), 40);
+        assert_eq!(cache.count_spread_below(15, 84, 4.45), 30);
+        // Threshold equal to a spread counts on neither side.
+        assert_eq!(
+            cache.count_spread_above(0, 99, 5.0) + cache.count_spread_below(0, 99, 5.0),
+            99
+        );
+        assert_eq!(cache.count_spread_above(200, 300, 0.0), 0);
+
+        // Change middle buckets behind the cache's back, the shortcuts only look at the cached min and max.
+        cache.buckets[2].write().unwrap().entries.spreads_mut()[0] = 100.0;
+        cache.buckets[8].write().unwrap().entries.spreads_mut()[0] = -100.0;
+        // Bucket 2 is all below 4.45, bucket 8 is all above.
+        assert_eq!(cache.count_spread_above(0, 99, 4.45), 55);
+        assert_eq!(cache.count_spread_below(0, 99, 4.45), 45);
+        // Bucket 2 is scanned with threshold 2.45, and bucket 8 is all above.
+        assert_eq!(cache.count_spread_above(0, 99, 2.45), 76);
+    }
+
+    #[test]
+    fn test_visit_entries() {
+        let mut cache = MarketDataCache::new(10, 10);
+        cache.visit_entries(0, 99, |_| panic!("empty cache has no entry"));
+        for i in (0..100).rev() {
+            cache.insert(MarketDataEntry::new(99 - (i * 7) % 100, i as f64));
+        }
+        let mut timestamps = Vec::new();
+        let mut sum = 0.0;
+        cache.visit_entries(5, 94, |entry| {
+            timestamps.push(entry.utc_epoch_ns);
+            sum += entry.spread;
+            // Querying from the callback only takes read locks.
+            assert!(cache.count_range(0, 99) > 0);
+        });
+        assert_eq!(timestamps, (5..=94).collect::<Vec<_>>());
+        let expected: f64 = cache.entries_in_range(5, 94).iter().map(|e| e.spread).sum();
+        assert_eq!(sum, expected);
+    }
+
+    #[test]
+    fn test_data_time_span() {
+        let mut cache = MarketDataCache::new(10, 10);
+        assert_eq!(cache.data_time_span(), None);
+        cache.insert(MarketDataEntry::new(25, 1.0));
+        cache.insert(MarketDataEntry::new(23, 1.0));
+        cache.insert(MarketDataEntry::new(57, 1.0));
+        cache.insert(MarketDataEntry::new(52, 1.0));
+        // Buckets start at 20, and the window has 5 empty buckets after the latest entry.
+        assert_eq!(cache.time_bounds(), Some((20, 120)));
+        assert_eq!(cache.data_time_span(), Some((23, 57)));
+
+        cache.remove_up_to(30);
+        assert_eq!(cache.data_time_span(), Some((52, 57)));
+        cache.remove_up_to(60);
+        assert_eq!(cache.data_time_span(), None);
+
+        let mut sparse = MarketDataCache::new_sparse(100, 10);
+        sparse.insert(MarketDataEntry::new(5, 1.0));
+        sparse.insert(MarketDataEntry::new(505, 1.0));
+        assert_eq!(sparse.data_time_span(), Some((5, 505)));
+    }
+
+    #[test]
+    fn test_duplicate_timestamps_at_range_bounds() {
+        let mut cache = MarketDataCache::new(10, 10);
+        // 3 duplicates right on the boundary of bucket [10, 20) and [20, 30), and 2 inside a bucket.
+        for (ts, spread) in [
+            (15, 1.0),
+            (20, 2.0),
+            (20, 3.0),
+            (20, 4.0),
+            (25, 5.0),
+            (25, 6.0),
+            (35, 7.0),
+        ] {
+            cache.insert(MarketDataEntry::new(ts, spread));
+        }
+        assert_eq!(cache.buckets[0].read().unwrap().count, 1);
+        assert_eq!(cache.buckets[1].read().unwrap().count, 5);
+
+        // All duplicates at start time and at end time are included.
+        assert_eq!(cache.count_range(20, 35), 6);
+        assert_eq!(cache.count_range(15, 20), 4);
+        assert_eq!(cache.count_range(20, 20), 3);
+        assert_eq!(cache.count_range(20, 25), 5);
+        assert_eq!(cache.count_range(21, 24), 0);
+        assert_eq!(cache.min_spread(20, 20), 2.0);
+        assert_eq!(cache.max_spread(20, 20), 4.0);
+        assert_eq!(cache.max_spread(0, 20), 4.0);
+        assert_eq!(cache.min_spread(25, 99), 5.0);
+        let spreads: Vec<f64> = cache
+            .entries_in_range(20, 20)
+            .iter()
+            .map(|e| e.spread)
+            .collect();
+        assert_eq!(spreads, vec![2.0, 3.0, 4.0]);
+
+        // Never counted twice, whichever buckets the range spans.
+        assert_eq!(cache.count_range(0, 99), 7);
+        assert_eq!(cache.count_range(0, 19) + cache.count_range(20, 99), 7);
+        assert_eq!(cache.summarize(0, 99).count, 7);
+    }
+
+    #[test]
+    fn test_spread_trimmed_mean() {
+        let mut cache = MarketDataCache::new(10, 100);
+        assert!(cache.spread_trimmed_mean(0, 999, 0.1).is_nan());
+        // Spreads 1 to 98 around a mean of 49.5, plus 2 huge outliers on top.
+        for i in 0..100 {
+            let spread = if i < 98 { (i + 1) as f64 } else { 10000.0 };
+            cache.insert(MarketDataEntry::new(i * 10, spread));
+        }
+        let plain = cache.spread_trimmed_mean(0, 999, 0.0);
+        assert!(approx_eq(plain, cache.summarize(0, 999).mean, 1e-9));
+        assert!(plain > 200.0);
+
+        let trimmed = cache.spread_trimmed_mean(0, 999, 0.05);
+        assert!(approx_eq(trimmed, 49.5, 1.0), "{trimmed}");
+
+        for trim_fraction in [-0.1, 0.5, 1.0, f64::NAN] {
+            assert!(cache.spread_trimmed_mean(0, 999, trim_fraction).is_nan());
+        }
+        assert!(cache.spread_trimmed_mean(2000, 3000, 0.1).is_nan());
+    }
+
+    #[test]
+    fn test_entry_meta_round_trip() {
+        let mut cache = MarketDataCache::new(10, 10);
+        for i in 0..50 {
+            cache.insert(MarketDataEntry::new(i, 1.0).with_meta(1000 + i));
+        }
+        cache.insert(MarketDataEntry::new(50, 1.0));
+        cache.remove_up_to(24);
+
+        let metas: Vec<Option<u64>> = cache
+            .entries_in_range(0, 99)
+            .iter()
+            .map(|e| e.meta)
+            .collect();
+        let mut expected: Vec<Option<u64>> = (25..50).map(|i| Some(1000 + i)).collect();
+        expected.push(None);
+        assert_eq!(metas, expected);
+        assert_eq!(cache.latest_entry.as_ref().unwrap().meta, None);
+    }
+
+    #[test]
+    fn test_par_threshold() {
+        let sequential = MarketDataCache::new(100, 10).with_par_threshold(usize::MAX);
+        let parallel = MarketDataCache::new(100, 10).with_par_threshold(0);
+        assert_eq!(MarketDataCache::new(100, 10).par_threshold, 16);
+        for mut cache in [sequential, parallel] {
+            for i in 0..1000 {
+                cache.insert(MarketDataEntry::new(i, (i % 37) as f64));
+            }
+            // Both paths give the same results in bucket order.
+            assert_eq!(
+                cache.map_middle_buckets(0, 5, |b| b.start_time_ns),
+                vec![10, 20, 30, 40]
+            );
+            assert!(cache.map_middle_buckets(3, 4, |b| b.count).is_empty());
+            assert_eq!(cache.count_range(5, 994), 990);
+            assert_eq!(cache.min_spread(5, 994), 0.0);
+            assert_eq!(cache.max_spread(5, 994), 36.0);
+            assert_eq!(cache.summarize(5, 994).count, 990);
+        }
+    }
+
+    #[test]
+    fn test_warm_digests() {
+        let mut cache = MarketDataCache::new(10, 10);
+        cache.warm_digests();
+        for i in 0..50 {
+            cache.insert(MarketDataEntry::new(i, i as f64));
+        }
+        let cached = |cache: &MarketDataCache| -> Vec<bool> {
+            cache
+                .buckets
+                .iter()
+                .map(|b| b.read().unwrap().tdigest.get().is_some())
+                .collect()
+        };
+        assert!(cached(&cache).iter().all(|c| !c));
+        let before = cache.spread_percentiles(5, 44);
+
+        cache.warm_digests();
+        // Empty buckets are skipped.
+        assert_eq!(cached(&cache), [[true; 5], [false; 5]].concat());
+        assert_eq!(cache.spread_percentiles(5, 44), before);
+    }
+
+    #[test]
+    fn test_spread_trend_slope() {
+        let start_ns = 1731496040000000000;
+        let mut cache = MarketDataCache::new(100, 10_000_000);
+        assert!(cache.spread_trend_slope(start_ns, start_ns + 1).is_nan());
+        // Spread = 2 + 0.5 per second, one quote every 1ms for 0.5 second.
+        for i in 0..500u64 {
+            let ts = start_ns + i * 1_000_000;
+            cache.insert(MarketDataEntry::new(ts, 2.0 + 0.5 * (i as f64 / 1000.0)));
+        }
+        let end_ns = start_ns + 499_000_000;
+        assert!(approx_eq(
+            cache.spread_trend_slope(start_ns, end_ns),
+            0.5,
+            1e-6
+        ));
+        // Partial buckets at both ends, and a single bucket.
+        let slope = cache.spread_trend_slope(start_ns + 3_500_000, end_ns - 7_500_000);
+        assert!(approx_eq(slope, 0.5, 1e-6), "{slope}");
+        let slope = cache.spread_trend_slope(start_ns + 1_000_000, start_ns + 8_000_000);
+        assert!(approx_eq(slope, 0.5, 1e-6), "{slope}");
+
+        // Tightening after eviction of the older half, which recalculates bucket sums.
+        cache.remove_up_to(start_ns + 255_000_000);
+        for i in 500..1000u64 {
+            let ts = start_ns + i * 1_000_000;
+            cache.insert(MarketDataEntry::new(
+                ts,
+                2.25 - 0.5 * ((i - 500) as f64 / 1000.0),
+            ));
+        }
+        let slope = cache.spread_trend_slope(start_ns + 500_000_000, start_ns + 999_000_000);
+        assert!(approx_eq(slope, -0.5, 1e-6), "{slope}");
+        assert!(
+            cache
+                .spread_trend_slope(start_ns + 300_000_000, start_ns + 300_000_000)
+                .is_nan()
+        );
+    }
+
+    #[test]
+    fn test_stream_range() {
+        let mut cache = MarketDataCache::new(10, 10);
+        cache.stream_range(0, 99, |_| panic!("empty cache has no bucket"));
+        for i in 0..100 {
+            cache.insert(MarketDataEntry::new(i, i as f64));
+        }
+        let mut summaries = Vec::new();
+        cache.stream_range(15, 44, |summary| {
+            // Querying from the callback only takes read locks.
+            assert_eq!(cache.count_range(15, 44), 30);
+            summaries.push(summary);
+        });
+        let starts: Vec<u64> = summaries.iter().map(|s| s.start_time_ns).collect();
+        assert_eq!(starts, vec![10, 20, 30, 40]);
+        // Partial buckets only cover the entries in range.
+        assert_eq!((summaries[0].count, summaries[0].min_spread), (5, 15.0));
+        assert_eq!((summaries[3].count, summaries[3].max_spread), (5, 44.0));
+        assert_eq!(summaries[1], cache.bucket_at(20).unwrap());
+        assert_eq!(summaries.iter().map(|s| s.count).sum::<usize>(), 30);
+
+        let mut count = 0;
+        cache.stream_range(5, 5, |summary| {
+            assert_eq!(summary.mean_spread, 5.0);
+            count += 1;
+        });
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn test_insert_before_window() {
+        let mut cache = MarketDataCache::new(10, 10);
+        for i in 100..150 {
+            assert_eq!(
+                cache.insert(MarketDataEntry::new(i, 1.0)),
+                InsertOutcome::Inserted
+            );
+        }
+        assert_eq!(
+            cache.insert(MarketDataEntry::new(50, 1.0)),
+            InsertOutcome::BeforeWindow
+        );
+        assert_eq!(cache.count(), 50);
+        assert_eq!(cache.validate_invariants(), Ok(()));
+
+        let mut cache = MarketDataCache::new(10, 10);
+        assert_eq!(
+            cache.insert(MarketDataEntry::new(u64::MAX - 5, 1.0)),
+            InsertOutcome::WindowOverflow
+        );
+        assert_eq!(cache.count(), 0);
+    }
+
+    #[test]
+    fn test_sampling_every_nth() {
+        let mut cache = MarketDataCache::new(10, 100).with_sampling(SamplingPolicy::EveryNth(3));
+        let outcomes: Vec<InsertOutcome> = (0..900)
+            .map(|i| cache.insert(MarketDataEntry::new(i, i as f64)))
+            .collect();
+        assert_eq!(cache.count(), 300);
+        assert_eq!(outcomes[0], InsertOutcome::Inserted);
+        assert_eq!(outcomes[1], InsertOutcome::Sampled);
+        assert_eq!(outcomes[3], InsertOutcome::Inserted);
+        assert_eq!(cache.min_spread(0, 899), 0.0);
+        assert_eq!(cache.max_spread(0, 899), 897.0);
+        assert_eq!(cache.validate_invariants(), Ok(()));
+
+        // Entries before the window are not offered to the policy.
+        let mut cache = MarketDataCache::new(10, 10).with_sampling(SamplingPolicy::EveryNth(2));
+        cache.insert(MarketDataEntry::new(100, 1.0));
+        cache.insert(MarketDataEntry::new(50, 1.0));
+        assert_eq!(
+            cache.insert(MarketDataEntry::new(101, 1.0)),
+            InsertOutcome::Sampled
+        );
+
+        // A sampled out entry beyond the window doesn't slide it.
+        let mut cache = MarketDataCache::new(10, 10).with_sampling(SamplingPolicy::EveryNth(2));
+        cache.insert(MarketDataEntry::new(0, 1.0));
+        assert_eq!(
+            cache.insert(MarketDataEntry::new(500, 1.0)),
+            InsertOutcome::Sampled
+        );
+        assert_eq!(cache.time_bounds(), Some((0, 100)));
+        assert_eq!(cache.count(), 1);
+        assert_eq!(
+            cache.insert(MarketDataEntry::new(505, 1.0)),
+            InsertOutcome::EvictedThenInserted { evicted: 1 }
+        );
+    }
+
+    #[test]
+    fn test_sampling_min_interval() {
+        let mut cache =
+            MarketDataCache::new_sparse(10, 100).with_sampling(SamplingPolicy::MinInterval(30));
+        for ts in [0, 10, 29, 30, 55, 60, 99, 100, 120, 131] {
+            cache.insert(MarketDataEntry::new(ts, ts as f64));
+        }
+        // The interval starts over in every bucket.
+        let kept: Vec<u64> = cache
+            .entries_in_range(0, 200)
+            .iter()
+            .map(|e| e.utc_epoch_ns)
+            .collect();
+        assert_eq!(kept, vec![0, 30, 60, 99, 100, 131]);
+        // A late entry close to the last kept one is dropped as well.
+        assert_eq!(
+            cache.insert(MarketDataEntry::new(110, 1.0)),
+            InsertOutcome::Sampled
+        );
+    }
+
+    #[test]
+    fn test_count_matches_stored_entries() {
+        let mut cache = MarketDataCache::new_full_depth(10, 10);
+        for i in 0..50 {
+            cache.insert(MarketDataEntry::new(i, 1.0));
+        }
+        // Shrink a bucket behind the cache's back, so it rejects the tail of its time range.
+        cache.buckets[6].write().unwrap().end_time_ns = 65;
+        let mut expected = 50;
+        for i in 50..100 {
+            let outcome = cache.insert(MarketDataEntry::new(i, 1.0));
+            if (65..70).contains(&i) {
+                assert_eq!(outcome, InsertOutcome::Rejected);
+            } else {
+                assert_eq!(outcome, InsertOutcome::Inserted);
+                expected += 1;
+            }
+        }
+        let level = |price| BidAsk { price, amount: 1.0 };
+        let one_sided = MarketDataEntryFull {
+            utc_epoch_ns: 99,
+            bids: vec![level(1.0)],
+            asks: vec![],
+        };
+        assert_eq!(cache.insert_full(one_sided), InsertOutcome::Rejected);
+        let rejected = MarketDataEntryFull {
+            utc_epoch_ns: 66,
+            bids: vec![level(1.0)],
+            asks: vec![level(2.0)],
+        };
+        assert_eq!(cache.insert_full(rejected), InsertOutcome::Rejected);
+
+        let bucket_sum: usize = cache.buckets.iter().map(|b| b.read().unwrap().count).sum();
+        assert_eq!(cache.count(), expected);
+        assert_eq!(cache.count(), bucket_sum);
+    }
+
+    #[test]
+    fn test_stability_score() {
+        // Tight spreads quoted every ns.
+        let mut tight = MarketDataCache::new(10, 10);
+        for i in 0..100 {
+            tight.insert(MarketDataEntry::new(i, 1.0 + (i % 2) as f64 * 0.1));
+        }
+        // Wide spreads with a long silent period in the middle.
+        let mut gappy = MarketDataCache::new(10, 10);
+        for i in (0..20).chain(80..100) {
+            gappy.insert(MarketDataEntry::new(i, 2.0 + (i % 2) as f64 * 2.0));
+        }
+
+        let components = tight.stability_components(0, 99);
+        assert!(approx_eq(components.mean_spread, 1.05, 1e-12));
+        assert!(approx_eq(components.spread_variance, 0.0025, 1e-12));
+        assert_eq!(components.longest_gap_ns, 1);
+        assert_eq!(components.gap_fraction, 0.01);
+        assert!(approx_eq(tight.spread_variance(0, 99), 0.0025, 1e-12));
+
+        let components = gappy.stability_components(0, 99);
+        assert_eq!(
+            (components.mean_spread, components.spread_variance),
+            (3.0, 1.0)
+        );
+        assert_eq!(components.longest_gap_ns, 61);
+        assert!(approx_eq(
+            gappy.stability_score(0, 99),
+            3.0 * (1.0 + 1.0 / 9.0 + 0.61),
+            1e-12
+        ));
+        assert!(tight.stability_score(0, 99) < gappy.stability_score(0, 99));
+
+        assert!(tight.stability_score(200, 300).is_nan());
+        assert!(MarketDataCache::new(10, 10).spread_variance(0, 99).is_nan());
+    }
+
+    #[test]
+    fn test_remove_up_to_many_buckets() {
+        let mut cache = MarketDataCache::new(100, 10);
+        for i in 0..1000 {
+            cache.insert(MarketDataEntry::new(i, (i % 7) as f64));
+        }
+        assert_eq!(cache.remove_up_to(854), 855);
+        assert_eq!(cache.count(), 145);
+        assert!(cache.validate_invariants().is_ok());
+        assert_eq!(
+            (cache.running_min_spread(), cache.running_max_spread()),
+            (0.0, 6.0)
+        );
+    }
+
+    #[test]
+    fn test_spread_as_of() {
+        for mut cache in [
+            MarketDataCache::new(10, 10),
+            MarketDataCache::new_sparse(10, 10),
+        ] {
+            assert_eq!(cache.spread_as_of(5), None);
+            // Quotes in buckets 0 and 5, the late quote at 3 is inserted after the one at 7.
+            for (ts, spread) in [(2, 1.0), (7, 2.0), (3, 3.0), (7, 4.0), (55, 5.0)] {
+                cache.insert(MarketDataEntry::new(ts, spread));
+            }
+            assert_eq!(cache.spread_as_of(1), None);
+            assert_eq!(cache.spread_as_of(2), Some(1.0));
+            assert_eq!(cache.spread_as_of(5), Some(3.0));
+            // Of the two quotes at 7, the last inserted one.
+            assert_eq!(cache.spread_as_of(7), Some(4.0));
+            // Walk back over the empty buckets 1 to 4, and from bucket 5 before its quote.
+            assert_eq!(cache.spread_as_of(42), Some(4.0));
+            assert_eq!(cache.spread_as_of(54), Some(4.0));
+            assert_eq!(cache.spread_as_of(55), Some(5.0));
+            // After the cache window, the latest quote.
+            assert_eq!(cache.spread_as_of(1_000), Some(5.0));
+        }
+    }
+
+    #[test]
+    fn test_spread_time_quantile() {
+        let mut cache = MarketDataCache::new(10, 10);
+        // A wide spread of 5.0 persists for 80ns, while four tight quotes last 5ns each.
+        for (ts, spread) in [(0, 1.0), (5, 2.0), (10, 5.0), (90, 1.5), (95, 3.0)] {
+            cache.insert(MarketDataEntry::new(ts, spread));
+        }
+        // Durations until 99: 1.0 -> 5, 1.5 -> 5, 2.0 -> 5, 3.0 -> 4, 5.0 -> 80, 99 in total.
+        assert_eq!(cache.spread_time_quantile(0, 99, 0.0), 1.0);
+        assert_eq!(cache.spread_time_quantile(0, 99, 0.1), 1.5);
+        assert_eq!(cache.spread_time_quantile(0, 99, 0.19), 3.0);
+        assert_eq!(cache.spread_time_quantile(0, 99, 0.5), 5.0);
+        assert_eq!(cache.spread_time_quantile(0, 99, 1.0), 5.0);
+        // By quote count, the median is only 2.0.
+        assert_eq!(cache.spread_percentiles_exact_if_small(0, 99, 10).p50, 2.0);
+
+        assert!(cache.spread_time_quantile(0, 99, 1.5).is_nan());
+        assert!(cache.spread_time_quantile(96, 80, 0.5).is_nan());
+        assert!(
+            MarketDataCache::new(10, 10)
+                .spread_time_quantile(0, 99, 0.5)
+                .is_nan()
+        );
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn test_spread_quantile_with() {
+        let mut cache = MarketDataCache::new(10, 10);
+        for i in 0..100 {
+            cache.insert(MarketDataEntry::new(i, (i + 1) as f64));
+        }
+        let p90 = cache.spread_quantile_with::<DDSketch>(0, 99, 0.9);
+        assert!((p90 - 90.0).abs() <= 90.0 * 0.01);
+        let p50 = cache.spread_quantile_with::<DDSketch>(15, 34, 0.5);
+        assert!((p50 - 25.0).abs() <= 25.0 * 0.01);
+        assert!(approx_eq(
+            cache.spread_quantile_with::<TDigest>(0, 99, 0.5),
+            50.5,
+            1.0
+        ));
+        assert!(
+            cache
+                .spread_quantile_with::<DDSketch>(200, 300, 0.5)
+                .is_nan()
+        );
+    }
 
     #[test]
-    fn test_new_market_data_cache() {
+    fn test_spread_quantile() {
+        let entries: Vec<MarketDataEntry> = (0..100)
+            .map(|i| MarketDataEntry::new(i, (i + 1) as f64))
+            .collect();
+        let mut cache =
+            MarketDataCache::new(10, 10).with_bucket_sketch(BucketSketch::DDSketch(0.01));
+        cache.bulk_insert_parallel(entries[..50].to_vec());
+        for entry in &entries[50..] {
+            cache.insert(entry.clone());
+        }
+        let mut stats_only = MarketDataCache::new_stats_only(10, 10)
+            .with_bucket_sketch(BucketSketch::DDSketch(0.01));
+        stats_only.bulk_insert_parallel(entries.clone());
+
+        // Every bucket keeps a sketch of all its entries.
+        let bucket_count = |cache: &MarketDataCache, idx: usize| {
+            cache.buckets[idx]
+                .read()
+                .unwrap()
+                .ddsketch
+                .as_ref()
+                .map(DDSketch::count)
+        };
+        assert_eq!(bucket_count(&cache, 3), Some(10));
+        assert_eq!(bucket_count(&stats_only, 3), Some(10));
+        for (start, end, q, exact) in [(0, 99, 0.9, 90.0), (15, 34, 0.5, 25.0)] {
+            let estimate = cache.spread_quantile(start, end, q);
+            assert!(
+                (estimate - exact).abs() <= exact * 0.01,
+                "{estimate} vs {exact}"
+            );
+        }
+        let p90 = stats_only.spread_quantile(0, 99, 0.9);
+        assert!((p90 - 90.0).abs() <= 90.0 * 0.01);
+
+        // A partially evicted bucket rebuilds its sketch from the remaining entries.
+        cache.remove_up_to(4);
+        assert_eq!(bucket_count(&cache, 0), Some(5));
+        assert!((cache.spread_quantile(0, 99, 0.0) - 6.0).abs() <= 6.0 * 0.01);
+        assert!(cache.spread_quantile(200, 300, 0.5).is_nan());
+
+        // Full depth inserts keep a sketch as well.
+        let mut full = MarketDataCache::new_full_depth(10, 10)
+            .with_bucket_sketch(BucketSketch::DDSketch(0.01));
+        for entry in &entries {
+            full.insert_full(MarketDataEntryFull {
+                utc_epoch_ns: entry.utc_epoch_ns,
+                bids: vec![BidAsk {
+                    price: 100.0,
+                    amount: 1.0,
+                }],
+                asks: vec![BidAsk {
+                    price: 100.0 + entry.spread,
+                    amount: 1.0,
+                }],
+            });
+        }
+        assert_eq!(bucket_count(&full, 3), Some(10));
+        let p90 = full.spread_quantile(0, 99, 0.9);
+        assert!((p90 - 90.0).abs() <= 90.0 * 0.01, "{p90}");
+
+        // Without a DDSketch, it's the cached tdigests.
+        let mut tdigest_cache = MarketDataCache::new(10, 10);
+        tdigest_cache.bulk_insert_parallel(entries);
+        assert_eq!(bucket_count(&tdigest_cache, 3), None);
+        assert!(approx_eq(
+            tdigest_cache.spread_quantile(0, 99, 0.5),
+            50.5,
+            1.0
+        ));
+    }
+
+    #[test]
+    fn test_range_digest_round_trip() {
         let mut cache = MarketDataCache::new(10, 10);
-        let entry = MarketDataEntry {
-            utc_epoch_ns: 0,
-            spread: 1.0,
+        for i in 0..100 {
+            cache.insert(MarketDataEntry::new(i, ((i * 37) % 100) as f64));
+        }
+        // As if the two halves come from different processes.
+        let ship = |digest: SerializableDigest| -> SerializableDigest {
+            serde_json::from_str(&serde_json::to_string(&digest).unwrap()).unwrap()
         };
+        let first = ship(cache.range_digest(0, 44));
+        let second = ship(cache.range_digest(45, 99));
+        assert_eq!((first.count(), second.count()), (45, 55));
 
-        cache.insert(entry);
-        assert_eq!(cache.count(), 1);
+        let merged = SerializableDigest::merge([first, second, cache.range_digest(200, 300)]);
+        let combined = cache.range_digest(0, 99);
+        assert_eq!(merged.count(), combined.count());
+        for q in [0.1, 0.5, 0.9] {
+            assert!(approx_eq(merged.quantile(q), combined.quantile(q), 1.0));
+        }
+        let (p10, p50, p90) = cache.spread_percentiles(0, 99);
+        assert!(approx_eq(merged.quantile(0.1), p10, 1.0));
+        assert!(approx_eq(merged.quantile(0.5), p50, 1.0));
+        assert!(approx_eq(merged.quantile(0.9), p90, 1.0));
 
-        for (i, bucket) in cache.buckets.iter().enumerate() {
-            let read_lock = bucket.read().unwrap();
-            assert_eq!(read_lock.start_time_ns, i as u64 * 10);
-            assert_eq!(read_lock.end_time_ns, (i + 1) as u64 * 10);
+        let empty = SerializableDigest::merge(Vec::new());
+        assert!(empty.is_empty());
+        assert!(empty.quantile(0.5).is_nan());
+        let empty = ship(cache.range_digest(200, 300));
+        assert!(empty.is_empty());
+        assert_eq!(
+            serde_json::to_string(&empty).unwrap(),
+            r#"{"tdigest":null}"#
+        );
+    }
+
+    #[test]
+    fn test_spread_alert() {
+        let fired = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut cache = MarketDataCache::new(10, 10).with_sampling(SamplingPolicy::EveryNth(2));
+        let sink = Arc::clone(&fired);
+        cache.set_spread_alert(
+            2.0,
+            Box::new(move |entry: &MarketDataEntry| sink.lock().unwrap().push(entry.utc_epoch_ns)),
+        );
+        // The entry at 3 is above the threshold but dropped by sampling, and 2.0 is not above 2.0.
+        for (ts, spread) in [
+            (0, 1.0),
+            (3, 5.0),
+            (4, 3.0),
+            (5, 1.0),
+            (6, 2.0),
+            (7, 0.5),
+            (8, 4.0),
+        ] {
+            cache.insert(MarketDataEntry::new(ts, spread));
         }
-        assert_eq!(cache.buckets.len(), 10);
+        assert_eq!(*fired.lock().unwrap(), vec![4, 8]);
+
+        fired.lock().unwrap().clear();
+        let sink = Arc::clone(&fired);
+        cache.set_spread_alert_with(
+            1.0,
+            AlertDirection::Below,
+            Box::new(move |entry: &MarketDataEntry| sink.lock().unwrap().push(entry.utc_epoch_ns)),
+        );
+        for (ts, spread) in [
+            (10, 0.5),
+            (11, 0.0),
+            (12, 0.8),
+            (13, -1.0),
+            (14, 1.0),
+            (15, 2.0),
+        ] {
+            cache.insert(MarketDataEntry::new(ts, spread));
+        }
+        // Sampling keeps every other entry, i.e. 11, 13 and 15 here.
+        assert_eq!(*fired.lock().unwrap(), vec![11, 13]);
+
+        assert!(cache.clear_spread_alert().is_some());
+        cache.insert(MarketDataEntry::new(16, -5.0));
+        assert_eq!(fired.lock().unwrap().len(), 2);
     }
 
     #[test]
-    fn test_remove_up_to() {
-        let mut cache = MarketDataCache::new(4, 10);
-        let entries: Vec<MarketDataEntry> = (0..16)
-            .map(|i| MarketDataEntry {
-                utc_epoch_ns: i * 5,
-                spread: i as f64,
-            })
+    fn test_evict_handler() {
+        let evicted = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut cache = MarketDataCache::new(5, 10);
+        let sink = Arc::clone(&evicted);
+        cache.set_evict_handler(Box::new(move |entries: &[MarketDataEntry]| {
+            sink.lock().unwrap().push(entries.to_vec());
+        }));
+        for ts in [3, 1, 12, 25, 27, 49] {
+            cache.insert(MarketDataEntry::new(ts, ts as f64));
+        }
+        assert_eq!(cache.remove_up_to(0), 0);
+        assert_eq!(cache.remove_up_to(25), 4);
+        // Sliding the window to [30, 80) evicts the entries at 27 and 49 is kept.
+        cache.insert(MarketDataEntry::new(75, 75.0));
+
+        let timestamps: Vec<Vec<u64>> = evicted
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|batch| batch.iter().map(|e| e.utc_epoch_ns).collect())
             .collect();
-        for entry in entries {
-            cache.insert(entry);
+        assert_eq!(timestamps, vec![vec![1, 3, 12, 25], vec![27]]);
+        assert_eq!(cache.count(), 2);
+        assert!(cache.validate_invariants().is_ok());
+
+        assert!(cache.clear_evict_handler().is_some());
+        assert_eq!(cache.remove_up_to(60), 1);
+        assert_eq!(evicted.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_ks_statistic() {
+        assert_eq!(ks_statistic(&[1.0, 2.0], &[1.0, 2.0]), 0.0);
+        assert_eq!(ks_statistic(&[1.0, 2.0], &[3.0, 4.0]), 1.0);
+        assert_eq!(ks_statistic(&[1.0, 2.0, 3.0, 4.0], &[3.0, 4.0]), 0.5);
+        assert!(ks_statistic(&[], &[1.0]).is_nan());
+    }
+
+    #[test]
+    fn test_divergence_from_baseline() {
+        let mut cache = MarketDataCache::new(10, 100);
+        for i in 0..1000 {
+            cache.insert(MarketDataEntry::new(i, ((i * 37) % 100) as f64));
         }
-        assert_eq!(cache.count(), 7);
-        cache.remove_up_to(60);
-        assert_eq!(cache.count(), 3);
+        let baseline =
+            TDigest::new_with_size(100).merge_unsorted((0..100).map(f64::from).collect());
+        assert!(cache.divergence_from_baseline(0, 999, &baseline) < 0.5);
+
+        let shifted =
+            TDigest::new_with_size(100).merge_unsorted((10..110).map(f64::from).collect());
+        let divergence = cache.divergence_from_baseline(0, 999, &shifted);
+        assert!(approx_eq(divergence, 10.0, 0.5), "{divergence}");
+
+        assert!(
+            cache
+                .divergence_from_baseline(0, 999, &TDigest::default())
+                .is_nan()
+        );
+        assert!(
+            cache
+                .divergence_from_baseline(2000, 3000, &baseline)
+                .is_nan()
+        );
     }
 
     #[test]
-    fn test_count_range() {
-        let mut cache = MarketDataCache::new(4, 10);
-        let entries: Vec<MarketDataEntry> = (0..16)
-            .map(|i| MarketDataEntry {
-                utc_epoch_ns: i * 5,
-                spread: i as f64,
-            })
-            .collect();
-        for entry in entries {
-            cache.insert(entry);
+    fn test_compare_windows() {
+        let mut cache = MarketDataCache::new(10, 10);
+        // Spread widens from 1.0 to 3.0 after the event at 50, with more quotes.
+        for i in 0..50 {
+            cache.insert(MarketDataEntry::new(i, 1.0));
         }
-        let count = cache.count_range(45, 60);
-        assert_eq!(count, 4);
+        for i in 50..100 {
+            cache.insert(MarketDataEntry::new(i, if i % 5 == 0 { 1.0 } else { 3.0 }));
+            cache.insert(MarketDataEntry::new(i, 3.0));
+        }
+
+        let comparison = cache.compare_windows((0, 49), (50, 99));
+        assert_eq!((comparison.a.count, comparison.b.count), (50, 100));
+        assert_eq!(comparison.count_delta, 50);
+        assert!(approx_eq(comparison.mean_delta, 2.8 - 1.0, 1e-9));
+        assert!(approx_eq(comparison.p50_delta, 2.0, 1e-6));
+        // 10% of the after window is still at 1.0.
+        assert!(approx_eq(comparison.ks_statistic, 0.9, 1e-12));
+
+        let same = cache.compare_windows((0, 49), (0, 49));
+        assert_eq!(
+            (same.count_delta, same.mean_delta, same.ks_statistic),
+            (0, 0.0, 0.0)
+        );
+
+        let empty = cache.compare_windows((0, 49), (500, 600));
+        assert_eq!(empty.count_delta, -50);
+        assert!(empty.mean_delta.is_nan() && empty.ks_statistic.is_nan());
     }
 
     #[test]
-    fn test_min_spread() {
+    fn test_try_insert() {
+        let mut cache = MarketDataCache::new(5, 10);
+        for ts in [0, 5, 12] {
+            assert_eq!(
+                cache.try_insert(MarketDataEntry::new(ts, 1.0)),
+                Ok(InsertOutcome::Inserted)
+            );
+        }
+        // Sliding the window to [10, 60) evicts the first bucket.
+        assert_eq!(
+            cache.try_insert(MarketDataEntry::new(55, 1.0)),
+            Ok(InsertOutcome::EvictedThenInserted { evicted: 2 })
+        );
+        assert_eq!(
+            cache.try_insert(MarketDataEntry::new(3, 1.0)),
+            Ok(InsertOutcome::BeforeWindow)
+        );
+        assert_eq!(
+            cache.try_insert(MarketDataEntry::new(u64::MAX, 1.0)),
+            Err(MarketDataError::WindowOverflow(u64::MAX))
+        );
+        cache.buckets[1].write().unwrap().end_time_ns = 25;
+        assert_eq!(
+            cache.try_insert(MarketDataEntry::new(27, 1.0)),
+            Err(MarketDataError::EntryRejected(27))
+        );
+        assert_eq!(cache.count(), 2);
+    }
+
+    #[test]
+    fn test_approximate_mode() {
+        let brute_force = |cache: &MarketDataCache| {
+            let mut histogram = SpreadHistogram::new(0.5);
+            cache
+                .entries_in_range(0, u64::MAX)
+                .iter()
+                .for_each(|e| histogram.add(e.spread));
+            histogram
+        };
+        let mut cache = MarketDataCache::new(10, 10).with_mode_histogram(0.5);
+        assert!(cache.approximate_mode().is_nan());
+        for i in 0..100 {
+            cache.insert(MarketDataEntry::new(i, (i % 7) as f64 * 0.3));
+        }
+        cache.insert(MarketDataEntry::new(50, f64::NAN));
+        assert_eq!(cache.mode_histogram.as_ref(), Some(&brute_force(&cache)));
+        assert_eq!(cache.approximate_mode(), cache.spread_mode(0, 99, 0.5));
+
+        // Evict by removal, by drain and by sliding the window.
+        cache.remove_up_to(33);
+        cache.drain_up_to(41);
+        for i in 120..140 {
+            cache.insert(MarketDataEntry::new(i, 2.2));
+        }
+        let mut bucket = Bucket::new(130, 140);
+        bucket.insert(MarketDataEntry::new(135, 2.2));
+        cache.merge_bucket(&bucket).unwrap();
+        cache.bulk_insert_parallel((140..150).map(|i| MarketDataEntry::new(i, 0.1)).collect());
+        assert_eq!(cache.mode_histogram.as_ref(), Some(&brute_force(&cache)));
+        assert_eq!(cache.approximate_mode(), 0.25);
+        assert_eq!(
+            cache.approximate_mode(),
+            cache.spread_mode(0, u64::MAX - 1, 0.5)
+        );
+
+        cache.reset();
+        assert!(cache.approximate_mode().is_nan());
+        assert!(MarketDataCache::new(10, 10).approximate_mode().is_nan());
+    }
+
+    #[test]
+    fn test_percentiles_of_empty_buckets() {
+        // merge_digests of nothing gives a default digest, which would estimate every quantile as 0.
+        assert_eq!(TDigest::merge_digests(vec![]).estimate_quantile(0.5), 0.0);
+        assert!(merge_digests_safe(vec![]).is_none());
+
         let mut cache = MarketDataCache::new(10, 10);
-        let entries: Vec<MarketDataEntry> = (0..100)
-            .map(|i| MarketDataEntry {
-                utc_epoch_ns: i,
-                spread: i as f64,
-            })
-            .collect();
-        for entry in entries {
-            cache.insert(entry);
+        cache.insert(MarketDataEntry::new(5, 1.0));
+        cache.insert(MarketDataEntry::new(95, 2.0));
+        let is_nan = |p: (f64, f64, f64)| p.0.is_nan() && p.1.is_nan() && p.2.is_nan();
+        // Empty middle buckets only, a single empty bucket, and two adjacent empty buckets.
+        for (start, end) in [(10, 89), (10, 19), (12, 27)] {
+            assert!(
+                is_nan(cache.spread_percentiles(start, end)),
+                "{start}, {end}"
+            );
+            assert!(is_nan(cache.spread_percentiles_multi(&[(start, end)])[0]));
+            assert!(
+                cache
+                    .spread_percentiles_exact_if_small(start, end, 0)
+                    .p50
+                    .is_nan()
+            );
+            assert_eq!(cache.min_spread(start, end), f64::MAX);
+            assert_eq!(cache.max_spread(start, end), -f64::MAX);
         }
-        let min_spread = cache.min_spread(30, 70);
-        assert_eq!(min_spread, 30.0);
+        // Empty middle buckets don't distort a range with entries at both ends.
+        assert_eq!(cache.spread_percentiles(0, 99).2, 2.0);
+        assert_eq!(cache.spread_percentiles_multi(&[(0, 99)])[0].2, 2.0);
     }
 
     #[test]
-    fn test_max_spread() {
+    fn test_infer_tick_size() {
         let mut cache = MarketDataCache::new(10, 10);
-        let entries: Vec<MarketDataEntry> = (0..100)
-            .map(|i| MarketDataEntry {
-                utc_epoch_ns: i,
-                spread: i as f64,
-            })
+        // Spreads quantized to a 0.05 tick, with float noise from the arithmetic.
+        for (i, ticks) in [2, 6, 2, 12, 4, 20, 6].into_iter().enumerate() {
+            cache.insert(MarketDataEntry::new(i as u64, 0.05 * ticks as f64 + 1e-14));
+        }
+        let tick = cache.infer_tick_size(0, 99).unwrap();
+        assert!(approx_eq(tick, 0.1, 1e-9), "{tick}");
+        // Only even multiples of the tick so far, 0.05 shows up with an odd one.
+        cache.insert(MarketDataEntry::new(20, 0.05 * 5.0));
+        let tick = cache.infer_tick_size(0, 99).unwrap();
+        assert!(approx_eq(tick, 0.05, 1e-9), "{tick}");
+
+        // Two distinct spreads are not enough.
+        assert_eq!(cache.infer_tick_size(0, 2), None);
+        assert_eq!(cache.infer_tick_size(500, 600), None);
+    }
+
+    #[test]
+    fn test_merged_tdigest_size() {
+        assert_eq!(merged_tdigest_size(1), 1000);
+        assert_eq!(merged_tdigest_size(36), 3600);
+        assert_eq!(merged_tdigest_size(usize::MAX), 10_000);
+
+        // Heavy tailed spreads over many buckets, whose tails a fixed size digest under-represents.
+        let mut cache = MarketDataCache::new(500, 100);
+        let mut spreads = Vec::new();
+        for i in 0..50_000_u64 {
+            let u = (i.wrapping_mul(2_654_435_761) % 10_007) as f64 / 10_007.0;
+            let spread = 1.0 / (1.0 - u).powi(2);
+            spreads.push(spread);
+            cache.insert(MarketDataEntry::new(i, spread));
+        }
+        spreads.sort_by(f64::total_cmp);
+        let tdigests: Vec<TDigest> = cache
+            .buckets
+            .iter()
+            .map(|b| b.read().unwrap().get_tdigest())
             .collect();
-        for entry in entries {
-            cache.insert(entry);
+        let fixed = TDigest::merge_digests(tdigests);
+        let adaptive = cache.spread_tdigest(0, 49_999).unwrap();
+        assert_eq!(adaptive.max_size(), 10_000);
+
+        let relative_error = |tdigest: &TDigest, q: f64| {
+            let exact = sorted_quantile(&spreads, q);
+            (tdigest.estimate_quantile(q) - exact).abs() / exact
+        };
+        for q in [0.001, 0.999] {
+            assert!(
+                relative_error(&adaptive, q) < relative_error(&fixed, q),
+                "{q}: {} vs {}",
+                relative_error(&adaptive, q),
+                relative_error(&fixed, q)
+            );
         }
-        let max_spread = cache.max_spread(30, 70);
-        assert_eq!(max_spread, 70.0);
     }
 
     #[test]
-    fn test_spread_percentiles() {
+    fn test_classify_regime() {
         let mut cache = MarketDataCache::new(10, 10);
-        let entries: Vec<MarketDataEntry> = (0..100)
-            .map(|i| MarketDataEntry {
-                utc_epoch_ns: i,
-                spread: i as f64,
-            })
+        // Spreads cycle through 1.0 to 2.0, except for a wide stretch in [40, 50) and a tight one in [70, 80).
+        for i in 0..100 {
+            let spread = match i {
+                40..50 => 5.0,
+                70..80 => 0.2,
+                _ => 1.0 + (i % 11) as f64 / 10.0,
+            };
+            cache.insert(MarketDataEntry::new(i, spread));
+        }
+        assert_eq!(cache.classify_regime(40, 49), Some(Regime::Wide));
+        assert_eq!(cache.classify_regime(70, 79), Some(Regime::Tight));
+        assert_eq!(cache.classify_regime(0, 39), Some(Regime::Normal));
+        // Wide enough a threshold takes the wide stretch as normal.
+        assert_eq!(
+            cache.classify_regime_with(40, 49, 10.0),
+            Some(Regime::Normal)
+        );
+
+        assert_eq!(cache.classify_regime(200, 300), None);
+        assert_eq!(cache.classify_regime_with(0, 99, f64::NAN), None);
+        assert_eq!(MarketDataCache::new(10, 10).classify_regime(0, 99), None);
+    }
+
+    #[test]
+    fn test_clock_driven_eviction() {
+        let clock = Arc::new(ManualClock::new(0));
+        let mut cache = MarketDataCache::new(10, 10).with_clock(clock.clone());
+        for i in 0..100 {
+            cache.insert(MarketDataEntry::new(i, (i / 10) as f64));
+        }
+        assert_eq!(cache.evict_older_than(50), 0);
+
+        // Keep the last 50ns at 80, i.e. [30, 80], everything before 30 goes.
+        clock.set(80);
+        assert_eq!(cache.evict_older_than(50), 30);
+        assert_eq!(cache.count(), 70);
+        assert_eq!(cache.rolling_summary(9).min, 7.0);
+        assert_eq!(cache.rolling_summary(9).count, 10);
+
+        clock.advance(20);
+        assert_eq!(cache.evict_older_than(50), 20);
+        assert_eq!(cache.data_time_span(), Some((50, 99)));
+        assert_eq!(cache.evict_older_than(u64::MAX), 0);
+
+        // Nothing is in the window once the feed goes quiet.
+        clock.advance(1000);
+        assert_eq!(cache.rolling_summary(100).count, 0);
+        assert_eq!(cache.evict_older_than(0), 50);
+        assert_eq!(cache.count(), 0);
+    }
+
+    #[test]
+    fn test_percentile_band_series() {
+        for mut cache in [
+            MarketDataCache::new(10, 10),
+            MarketDataCache::new_sparse(10, 10),
+        ] {
+            // Buckets 0, 1 and 3 have spreads 1.0 to 10.0 shifted by the bucket index, bucket 2 is empty.
+            for i in (0..20).chain(30..40) {
+                cache.insert(MarketDataEntry::new(
+                    i,
+                    (i % 10 + 1) as f64 + (i / 10) as f64,
+                ));
+            }
+            let band = cache.percentile_band_series(0, 39, 0.0, 1.0, false);
+            assert_eq!(band, vec![(0, 1.0, 10.0), (10, 2.0, 11.0), (30, 4.0, 13.0)]);
+
+            let band = cache.percentile_band_series(15, 39, 0.0, 1.0, true);
+            assert_eq!(band.len(), 3);
+            // Only the entries in range of a partial bucket.
+            assert_eq!(band[0], (10, 7.0, 11.0));
+            assert!(band[1].0 == 20 && band[1].1.is_nan() && band[1].2.is_nan());
+
+            let band = cache.percentile_band_series(0, 39, 0.1, 0.9, false);
+            assert!(band.iter().all(|(_, lower, upper)| lower < upper));
+            assert!(
+                cache
+                    .percentile_band_series(0, 39, 0.9, 0.1, false)
+                    .is_empty()
+            );
+            assert!(
+                cache
+                    .percentile_band_series(200, 300, 0.1, 0.9, true)
+                    .is_empty()
+            );
+        }
+    }
+
+    #[test]
+    fn test_spread_by_phase() {
+        let mut cache = MarketDataCache::new(10, 100);
+        // Period of 100ns in 4 phases, the spread is 1.0 in the first quarter of each period, and grows by 1.0 per
+        // quarter, the last quarter has no entry.
+        for ts in (0..1000).filter(|ts| ts % 100 < 75) {
+            cache.insert(MarketDataEntry::new(ts, (ts % 100 / 25 + 1) as f64));
+        }
+        let means = cache.spread_by_phase(0, 999, 100, 4);
+        assert_eq!(means[..3], [1.0, 2.0, 3.0]);
+        assert!(means[3].is_nan());
+
+        // Two phases, averaging the first and last two quarters.
+        assert_eq!(cache.spread_by_phase(0, 999, 100, 2)[0], 1.5);
+        assert_eq!(cache.spread_by_phase(0, 999, 100, 2)[1], 3.0);
+        assert!(cache.spread_by_phase(0, 999, 0, 4).is_empty());
+        assert!(cache.spread_by_phase(0, 999, 100, 0).is_empty());
+    }
+
+    #[test]
+    fn test_prepend_historical() {
+        for cache in [
+            MarketDataCache::new(4, 10),
+            MarketDataCache::new_sparse(4, 10),
+        ] {
+            let mut cache = cache.with_max_extra_buckets(2);
+            for i in 100..140 {
+                cache.insert(MarketDataEntry::new(i, i as f64));
+            }
+            // Dropped by a plain insert, as it's before the window.
+            assert_eq!(
+                cache.insert(MarketDataEntry::new(95, 95.0)),
+                InsertOutcome::BeforeWindow
+            );
+
+            // Two more buckets fit, the entries before them and after the window are dropped.
+            let backfill = (70..100)
+                .chain([140])
+                .map(|i| MarketDataEntry::new(i, i as f64));
+            assert_eq!(cache.prepend_historical(backfill.collect()), 20);
+            assert_eq!(cache.time_bounds(), Some((80, 140)));
+            assert_eq!(cache.count(), 60);
+            assert_eq!(cache.count_range(80, 139), 60);
+            assert_eq!(cache.min_spread(0, 139), 80.0);
+            assert_eq!(cache.latest_entry.as_ref().unwrap().utc_epoch_ns, 139);
+            assert_eq!(cache.validate_invariants(), Ok(()));
+
+            // No more room.
+            assert_eq!(
+                cache.prepend_historical(vec![MarketDataEntry::new(75, 75.0)]),
+                0
+            );
+
+            // Moving forward evicts the extra buckets first, and the window is back to num_buckets.
+            cache.insert(MarketDataEntry::new(140, 140.0));
+            assert_eq!(cache.time_bounds(), Some((110, 150)));
+            assert_eq!(cache.count(), 30);
+        }
+    }
+
+    #[test]
+    fn test_percentiles_skip_empty_middle_buckets() {
+        // The same spreads in every other bucket, and in consecutive buckets.
+        let mut gapped = MarketDataCache::new(20, 10);
+        let mut dense = MarketDataCache::new(20, 10);
+        for i in 0..100 {
+            let spread = (i * 37 % 101) as f64;
+            gapped.insert(MarketDataEntry::new(i / 10 * 20 + i % 10, spread));
+            dense.insert(MarketDataEntry::new(i, spread));
+        }
+
+        let expected = dense.spread_percentiles(0, 99);
+        assert!(!expected.0.is_nan() && !expected.2.is_nan());
+        assert_eq!(gapped.spread_percentiles(0, 199), expected);
+        assert_eq!(gapped.spread_percentiles_multi(&[(0, 199)]), vec![expected]);
+        // Only empty buckets in between.
+        assert_eq!(
+            gapped.spread_percentiles(5, 24),
+            dense.spread_percentiles(5, 14)
+        );
+    }
+
+    #[test]
+    fn test_eviction_batch() {
+        for cache in [
+            MarketDataCache::new(4, 10),
+            MarketDataCache::new_sparse(4, 10),
+        ] {
+            let mut cache = cache.with_eviction_batch(2);
+            for i in 0..40 {
+                cache.insert(MarketDataEntry::new(i, i as f64));
+            }
+            // The window grows without evicting anything.
+            assert_eq!(
+                cache.insert(MarketDataEntry::new(40, 40.0)),
+                InsertOutcome::Inserted
+            );
+            cache.insert(MarketDataEntry::new(55, 55.0));
+            assert_eq!(cache.time_bounds(), Some((0, 60)));
+            assert_eq!(cache.count(), 42);
+            assert_eq!(cache.min_spread(0, 59), 0.0);
+            assert_eq!(cache.validate_invariants(), Ok(()));
+
+            // Beyond the batch, evicted back to num_buckets at once.
+            assert_eq!(
+                cache.insert(MarketDataEntry::new(60, 60.0)),
+                InsertOutcome::EvictedThenInserted { evicted: 31 }
+            );
+            assert_eq!(cache.time_bounds(), Some((30, 70)));
+            assert_eq!(cache.count(), 12);
+            assert_eq!(cache.validate_invariants(), Ok(()));
+        }
+    }
+
+    #[test]
+    fn test_spread_runs() {
+        let mut cache = MarketDataCache::new(10, 10);
+        assert!(cache.spread_runs(0, 99, 0.0).is_empty());
+        // Runs of 1.0 x3, 2.0 x1, 1.0 x2 and 3.0 x4, one quote every 5 ns.
+        let spreads = [1.0, 1.0, 1.0, 2.0, 1.0, 1.0, 3.0, 3.0, 3.0, 3.0];
+        for (i, spread) in spreads.iter().enumerate() {
+            cache.insert(MarketDataEntry::new(i as u64 * 5, *spread));
+        }
+        assert_eq!(
+            cache.spread_runs(0, 99, 0.0),
+            vec![(1.0, 3, 15), (2.0, 1, 5), (1.0, 2, 10), (3.0, 4, 69)]
+        );
+        // The range cuts the first and last runs.
+        assert_eq!(
+            cache.spread_runs(5, 40, 0.0),
+            vec![(1.0, 2, 10), (2.0, 1, 5), (1.0, 2, 10), (3.0, 3, 10)]
+        );
+
+        // Spreads within tolerance of the first one of a run join it.
+        cache.insert(MarketDataEntry::new(50, 3.05));
+        cache.insert(MarketDataEntry::new(55, 2.95));
+        assert_eq!(cache.spread_runs(30, 99, 0.1), vec![(3.0, 6, 69)]);
+        assert_eq!(cache.spread_runs(30, 99, 0.0).len(), 3);
+    }
+
+    #[test]
+    fn test_stats_only() {
+        let mut stats_only = MarketDataCache::new_stats_only(10, 10);
+        let mut spread_only = MarketDataCache::new(10, 10);
+        let entries: Vec<MarketDataEntry> = (0..50)
+            .map(|i| MarketDataEntry::new(i, (i * 7 % 11) as f64))
             .collect();
-        for entry in entries {
-            cache.insert(entry);
+        stats_only.bulk_insert_parallel(entries[..30].to_vec());
+        spread_only.bulk_insert_parallel(entries[..30].to_vec());
+        for entry in &entries[30..] {
+            stats_only.insert(entry.clone());
+            spread_only.insert(entry.clone());
         }
-        let (a, b, c) = cache.spread_percentiles(0, 99);
 
-        assert_eq!(a, 9.5);
-        assert_eq!(b, 49.5);
-        assert_eq!(c, 89.5);
+        // Ranges aligned to buckets are exact.
+        for (start, end) in [(0, 49), (10, 29), (20, 29)] {
+            assert_eq!(
+                stats_only.count_range(start, end),
+                spread_only.count_range(start, end)
+            );
+            assert_eq!(
+                stats_only.min_spread(start, end),
+                spread_only.min_spread(start, end)
+            );
+            assert_eq!(
+                stats_only.max_spread(start, end),
+                spread_only.max_spread(start, end)
+            );
+            let (summary, expected) = (
+                stats_only.summarize(start, end),
+                spread_only.summarize(start, end),
+            );
+            assert_eq!(
+                (summary.count, summary.min, summary.max, summary.mean),
+                (expected.count, expected.min, expected.max, expected.mean)
+            );
+            let variance = stats_only.spread_variance(start, end);
+            assert!((variance - spread_only.spread_variance(start, end)).abs() < 1e-9);
+            let slope = stats_only.spread_trend_slope(start, end);
+            assert!((slope - spread_only.spread_trend_slope(start, end)).abs() < 1e-3);
+            // Thresholds outside of the bucket min and max are decided by the cached stats alone.
+            for threshold in [-1.0, 11.0] {
+                assert_eq!(
+                    stats_only.count_spread_above(start, end, threshold),
+                    spread_only.count_spread_above(start, end, threshold)
+                );
+                assert_eq!(
+                    stats_only.count_spread_below(start, end, threshold),
+                    spread_only.count_spread_below(start, end, threshold)
+                );
+            }
+            // Buckets straddling the threshold are estimated from their tdigests.
+            let (above, expected) = (
+                stats_only.count_spread_above(start, end, 4.5),
+                spread_only.count_spread_above(start, end, 4.5),
+            );
+            assert!(above.abs_diff(expected) <= 2);
+            // Percentiles come from the cached tdigests rather than the entries, so they are only close.
+            let (p, expected) = (
+                stats_only.spread_percentiles(start, end),
+                spread_only.spread_percentiles(start, end),
+            );
+            assert!((p.0 - expected.0).abs() < 0.5);
+            assert!((p.1 - expected.1).abs() < 0.5);
+            assert!((p.2 - expected.2).abs() < 0.5);
+        }
+        // Partial buckets count as whole ones, and no entries are kept.
+        assert_eq!(stats_only.count_range(15, 24), 20);
+        assert_eq!(
+            stats_only.spread_variance(15, 24),
+            stats_only.spread_variance(10, 29)
+        );
+        assert_eq!(stats_only.count_spread_above(15, 24, -1.0), 20);
+        assert_eq!(stats_only.spread_percentiles_multi(&[(15, 24)]).len(), 1);
+        assert!(stats_only.entries_in_range(0, 49).is_empty());
+
+        // A bucket is only evicted once all of it is.
+        stats_only.remove_up_to(15);
+        assert_eq!(stats_only.count_range(0, 49), 40);
+        stats_only.remove_up_to(19);
+        assert_eq!(stats_only.count_range(0, 49), 30);
+    }
+
+    #[test]
+    fn test_stats_only_evictions() {
+        let evicted = Arc::new(AtomicUsize::new(0));
+        let mut cache = MarketDataCache::new_stats_only(10, 10).with_mode_histogram(1.0);
+        let handler_evicted = evicted.clone();
+        cache.set_evict_handler(Box::new(move |entries: &[MarketDataEntry]| {
+            handler_evicted.fetch_add(entries.len(), Ordering::Relaxed);
+        }));
+        cache.bulk_insert_parallel(
+            (0..50)
+                .map(|i| MarketDataEntry::new(i, if i < 10 { 100.0 } else { 1.0 + i as f64 }))
+                .collect(),
+        );
+        assert_eq!(cache.running_max_spread(), 100.0);
+
+        // The bucket stats are evicted, even though there are no entries to hand over.
+        assert_eq!(cache.remove_up_to(9), 10);
+        assert_eq!(cache.count(), 40);
+        assert_eq!(cache.running_max_spread(), 50.0);
+        assert_eq!(cache.running_min_spread(), 11.0);
+        assert_eq!(evicted.load(Ordering::Relaxed), 0);
+        assert!(cache.approximate_mode().is_nan());
+
+        assert!(cache.drain_up_to(19).is_empty());
+        assert_eq!(cache.count(), 30);
+        assert_eq!(cache.running_min_spread(), 21.0);
     }
 }
@@ -8,20 +8,32 @@
 //! 2. The buckets in the middle of start to end. These are whole buckets, and their result are already calculated and
 //!    cached in themselves.
 //! 3. The bucket that contains end time. get everything in this bucket that happens before end time.
+//!
+//! Query ranges include both start and end time, so every entry with a timestamp equal to either of them is included,
+//! however many share it. A timestamp belongs to exactly one bucket, as bucket start time is inclusive and end time is
+//! exclusive, so entries are never counted twice, even if they sit right on a bucket boundary.
 
 pub mod bucket;
+pub mod columns;
+pub mod entry;
 pub mod market_data;
+pub mod query_cache;
+pub mod store;
 
 // System libraries.
-use std::cell::RefCell;
-use std::collections::VecDeque;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::sync::atomic::AtomicUsize;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
 
 // Third party libraries.
-use serde::Deserialize;
+use rayon::ThreadPool;
+use serde::{Deserialize, Serialize};
 use tdigest::TDigest;
 
+// Project libraries.
+use crate::clock::Clock;
+use crate::sketch::DDSketch;
+
 #[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
 pub struct BidAsk {
     pub price: f64,
@@ -29,34 +41,513 @@ pub struct BidAsk {
 }
 
 /// One entry can have multiple [BidAsk] record, but we only care about its spread, so no need to store [BidAsk] array.
-#[derive(Clone, Debug, Deserialize)]
+/// Amounts of the best bid and ask are kept for order book imbalance, they are 0 if unknown. Meta is an opaque tag of the
+/// caller, e.g. an exchange sequence number to reconcile with source records, it's not used by any statistics.
+#[derive(Clone, Debug, Default, Deserialize)]
 pub struct MarketDataEntry {
     pub utc_epoch_ns: u64,
     pub spread: f64,
+    #[serde(default)]
+    pub bid_amount: f64,
+    #[serde(default)]
+    pub ask_amount: f64,
+    #[serde(default)]
+    pub meta: Option<u64>,
+}
+
+/// Full order book depth of one entry, for analyses that need more than the spread, e.g. book imbalance. Bids are
+/// sorted from highest to lowest and asks from lowest to highest, so the spread is derived from asks\[0\] and bids\[0\].
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct MarketDataEntryFull {
+    pub utc_epoch_ns: u64,
+    pub bids: Vec<BidAsk>,
+    pub asks: Vec<BidAsk>,
+}
+
+/// What a [MarketDataCache] keeps for each entry, selected at construction. Spread only is the default and the memory
+/// friendly choice, full depth additionally keeps all bid/ask levels as [MarketDataEntryFull]. Stats only drops entries
+/// once they're counted in the stats and tdigest of their bucket, so queries can't look inside a bucket any more, see
+/// [MarketDataCache::new_stats_only].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EntryStorage {
+    #[default]
+    SpreadOnly,
+    FullDepth,
+    StatsOnly,
+}
+
+/// What an insert does with the cached tdigest of its [Bucket]. Lazy leaves it stale, so a bucket receiving many quotes
+/// between queries merges them all at once at the next query. Incremental merges the new spread into it, which is
+/// cheaper for a bucket that is queried about as often as it's written to, e.g. one receiving late quotes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TDigestPolicy {
+    #[default]
+    Lazy,
+    Incremental,
+}
+
+/// Which quantile sketch buckets keep for [MarketDataCache::spread_quantile], see
+/// [MarketDataCache::with_bucket_sketch]. TDigest is the tdigest every bucket caches anyway.
+/// DDSketch(relative_accuracy) keeps a [DDSketch] of the given relative accuracy in every bucket as well, for a
+/// relative error guarantee on the quantiles.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum BucketSketch {
+    #[default]
+    TDigest,
+    DDSketch(f64),
 }
 
+/// Which entries [MarketDataCache::insert] keeps when a feed bursts above what should be stored. EveryNth(n) keeps the
+/// first of every n entries offered to the cache, 0 is the same as 1. MinInterval(ns) keeps an entry only if it's at
+/// least ns away from the entry inserted last into the same bucket.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SamplingPolicy {
+    #[default]
+    KeepAll,
+    EveryNth(usize),
+    MinInterval(u64),
+}
+
+/// The entries of a [Bucket] in insertion order, stored column by column. Scans that only need timestamps or spreads,
+/// e.g. counts, min and max, read one or two tightly packed arrays instead of striding over whole entries, which is
+/// friendlier to the CPU cache and to SIMD. Amounts and meta are rarely set, so their column stays empty until an entry
+/// has any of them. [MarketDataEntry]s are constructed on demand when reading.
+#[derive(Clone, Debug, Default)]
+pub struct EntryColumns {
+    utc_epoch_ns: Vec<u64>,
+    spreads: Vec<f64>,
+    /// Bid amount, ask amount and meta of every entry, or empty if all of them are the defaults.
+    extras: Vec<EntryExtras>,
+}
+
+/// Bid amount, ask amount and meta of one entry in [EntryColumns].
+type EntryExtras = (f64, f64, Option<u64>);
+
 /// A [Bucket] will keep a record of its start and end time just for easier implementation. (I know end_time_ns is not
 /// really needed). Count is the number of data entries contained in this bucket, tdigest is a fast algorithm to help us
-/// calculate rank based statistics. min and max are our cache of each bucket.
+/// calculate rank based statistics. min and max are our cache of each bucket. Sum of spreads and sum of squared spreads
+/// are cached as well, so mean and variance of complete buckets come for free. So are sums over the time offset of each
+/// entry from the bucket start, i.e. utc_epoch_ns - start_time_ns, for the trend of spreads over time.
 #[derive(Clone, Debug, Default)]
 pub struct Bucket {
     pub start_time_ns: u64,
     pub end_time_ns: u64,
     pub count: usize,
-    pub tdigest: RefCell<Option<TDigest>>,
+    /// Bumped on every change of the entries, so a cached tdigest can tell whether it's still current.
+    pub version: u64,
+    /// The cached tdigest, with the version it's calculated at, see [Bucket::get_tdigest].
+    pub tdigest: OnceLock<(u64, TDigest)>,
+    /// The tdigest cached before the latest inserts, with its version. The next one merges the spreads inserted since
+    /// into it instead of starting over.
+    pub stale_tdigest: Option<(u64, TDigest)>,
+    pub min_spread: f64,
+    pub max_spread: f64,
+    pub sum_spread: f64,
+    pub sum_squared_spread: f64,
+    pub sum_offset: f64,
+    pub sum_squared_offset: f64,
+    pub sum_offset_spread: f64,
+    pub entries: EntryColumns,
+    /// Timestamp of the entry inserted last, in arrival order, for [SamplingPolicy::MinInterval].
+    pub last_inserted_ns: Option<u64>,
+    /// Only used with [EntryStorage::FullDepth], holds the same entries as `entries` but with all their levels.
+    pub depth: Vec<MarketDataEntryFull>,
+    /// Only kept with [BucketSketch::DDSketch], updated on every change of the entries, see [Bucket::track_ddsketch].
+    pub ddsketch: Option<DDSketch>,
+}
+
+/// A copy of the cached statistics of one [Bucket], without its entries, see [MarketDataCache::bucket_at]. Spread
+/// fields are NaN for an empty bucket.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct BucketSummary {
+    pub start_time_ns: u64,
+    pub end_time_ns: u64,
+    pub count: usize,
     pub min_spread: f64,
     pub max_spread: f64,
-    pub entries: Vec<MarketDataEntry>,
+    pub mean_spread: f64,
+}
+
+/// Spread statistics of one aggregation window, see [MarketDataCache::aggregate_to]. Spread fields are NaN for a window
+/// without any entry.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct AggBucket {
+    pub start_time_ns: u64,
+    pub count: usize,
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    pub p50: f64,
+}
+
+/// Everything about spreads in a time range, calculated in one traversal by [MarketDataCache::summarize]. All fields but
+/// count are NaN for a range without any entry.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct RangeSummary {
+    pub count: usize,
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    pub p10: f64,
+    pub p50: f64,
+    pub p90: f64,
+}
+
+/// The merged tdigest of the spreads in a time range, from [MarketDataCache::range_digest]. It can be serialized, e.g.
+/// to send to another process, and merged with the digests of other ranges or caches to estimate quantiles over all of
+/// them. The digest of a range without any entry is written as null.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SerializableDigest {
+    #[serde(with = "crate::utils::tdigest_or_null")]
+    pub tdigest: TDigest,
+}
+
+/// How wide the spreads of a time range are compared to the whole cache window, see [MarketDataCache::classify_regime].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum Regime {
+    Tight,
+    Normal,
+    Wide,
+}
+
+/// Spreads of two time ranges compared by [MarketDataCache::compare_windows], e.g. before and after an event. Deltas are
+/// b - a. ks_statistic is the two sample Kolmogorov-Smirnov statistic, the largest distance between the empirical spread
+/// distributions of both windows, from 0 for identical to 1 for disjoint ones. Deltas and ks_statistic are NaN if either
+/// window has no entry.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct WindowComparison {
+    pub a: RangeSummary,
+    pub b: RangeSummary,
+    pub count_delta: i64,
+    pub mean_delta: f64,
+    pub p50_delta: f64,
+    pub ks_statistic: f64,
+}
+
+/// The components of [MarketDataCache::stability_score] in a time range, for users to build their own score. Spread
+/// variance is the population variance, and gap_fraction is the longest quote gap over the range length. Spread fields
+/// are NaN for a range without any entry.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct StabilityComponents {
+    pub mean_spread: f64,
+    pub spread_variance: f64,
+    pub longest_gap_ns: u64,
+    pub gap_fraction: f64,
+}
+
+/// 10th, 50th and 90th percentiles of spreads, see [MarketDataCache::spread_percentiles_exact_if_small]. Approximate is
+/// false if they are calculated exactly from the sorted spreads, and true if they are estimated by TDigest.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct PercentileResult {
+    pub p10: f64,
+    pub p50: f64,
+    pub p90: f64,
+    pub approximate: bool,
+}
+
+/// Quantile bounds to winsorize spreads to, e.g. 0.01 and 0.99. Instead of dropping outliers, spreads below the lower_q
+/// quantile or above the upper_q quantile of all loaded spreads are clamped to those quantiles.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WinsorizePolicy {
+    pub lower_q: f64,
+    pub upper_q: f64,
+}
+
+/// What the loader does with an order book whose levels are not sorted, i.e. bids are not from highest to lowest price or
+/// asks are not from lowest to highest. The best prices are taken from the first levels, so they'd be wrong.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BookOrder {
+    /// Don't check, the feed is known to be sorted.
+    #[default]
+    Trust,
+    /// Sort the levels before taking the best prices.
+    Sort,
+    /// Skip the entry with a warning.
+    Skip,
+}
+
+/// What the loader does with a one sided order book, i.e. with empty bids or asks, which is valid on some venues. A
+/// recorded entry counts like any other, so the count of a range reflects all quotes.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum OneSidedPolicy {
+    /// Skip the entry with a warning.
+    #[default]
+    Skip,
+    /// Record the entry with the given spread, e.g. a large value to mark it. It's an ordinary spread to every query, and
+    /// is winsorized like the others.
+    RecordSentinel(f64),
+    /// Record the entry with a NaN spread. It's left out of min, max, percentiles and winsorizing, but turns means and
+    /// sums NaN.
+    RecordNaN,
+}
+
+/// How the loader calculates the spread from the best ask and bid prices, to stay consistent with other tooling. The
+/// outlier check works on the absolute spread, so it's the same for every convention.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SpreadConvention {
+    /// Best ask - best bid, positive for a normal book.
+    #[default]
+    AskMinusBid,
+    /// Best bid - best ask, negative for a normal book.
+    BidMinusAsk,
+    /// |best ask - best bid|.
+    AbsoluteValue,
+}
+
+/// Options for loading entries from a file, see [MarketDataCache::with_file_options].
+#[derive(Clone, Debug, PartialEq)]
+pub struct LoadOptions {
+    /// Number of per entry warnings to log for skipped entries, the rest are only counted and logged as one summary.
+    pub max_warnings: usize,
+    /// Winsorize spreads instead of dropping outliers, None (the default) drops them.
+    pub winsorize: Option<WinsorizePolicy>,
+    /// How to handle entries with unsorted bids or asks.
+    pub book_order: BookOrder,
+    /// How the spread is calculated from the best prices.
+    pub spread_convention: SpreadConvention,
+    /// Grow the window of an empty cache to hold all valid entries, instead of keeping only the latest window of them.
+    /// The window grows up to a fixed cap of buckets, entries spanning more still exceed it.
+    pub fit_window: bool,
+    /// How to handle entries with empty bids or asks.
+    pub one_sided: OneSidedPolicy,
+}
+
+impl Default for LoadOptions {
+    fn default() -> Self {
+        Self {
+            max_warnings: 100,
+            winsorize: None,
+            book_order: BookOrder::Trust,
+            spread_convention: SpreadConvention::AskMinusBid,
+            fit_window: false,
+            one_sided: OneSidedPolicy::Skip,
+        }
+    }
 }
 
-/// A [MarketDataCache] uses a deque to hold all its [Bucket]s, O(1) for indexing, pop front and push back operations.
-/// bucket_ns and num_buckets are just two helper variables to make calculations easier. Count is the total number of
-/// [MarketDataEntry] stored in this cache. The total time duration represented by [MarketDataCache] is bucket_ns *
-/// num_buckets. Note that bucket_ns and num_buckets never change.
+/// What happened to the raw quotes of one [MarketDataCache::ingest] call. Total is the number of raw quotes read, skipped
+/// ones failed validation, and inserted ones made it into the cache, the rest were valid but too old for the cache.
+/// Exceeds_window is set if the valid quotes span more than the cache window, so the older ones were evicted.
+/// Out_of_order counts valid quotes whose timestamp is not strictly after the previous valid one, and
+/// timestamps_monotonic is set if there is none. Data_span is the earliest and latest timestamps of the valid quotes,
+/// None if there is none.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LoadReport {
+    pub total: usize,
+    pub skipped: usize,
+    pub skipped_by_reason: SkipCounts,
+    /// Valid quotes stored in the cache, counted as each batch is inserted, so ones evicted again by a later batch of
+    /// the same source are included. Entries already in the cache and evicted by the quotes don't reduce it.
+    pub inserted: usize,
+    pub exceeds_window: bool,
+    pub timestamps_monotonic: bool,
+    pub out_of_order: usize,
+    pub data_span: Option<(u64, u64)>,
+}
+
+/// Why a raw quote fails validation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SkipReason {
+    /// The timestamp is missing, not a u64, or before 2009.
+    InvalidTimestamp,
+    /// Neither a spread nor both bids and asks arrays are given.
+    MissingBook,
+    /// Bids or asks have no valid level.
+    EmptyBook,
+    /// Bids or asks are not sorted, with [BookOrder::Skip].
+    UnsortedBook,
+    /// The spread is too wide compared to the prices.
+    Outlier,
+}
+
+/// Number of raw quotes skipped for each [SkipReason], see [LoadReport].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SkipCounts {
+    pub invalid_timestamp: usize,
+    pub missing_book: usize,
+    pub empty_book: usize,
+    pub unsorted_book: usize,
+    pub outlier: usize,
+}
+
+/// The [Bucket]s of a [MarketDataCache] in time order, indexed from the oldest one. Dense keeps every bucket in a deque.
+/// Sparse only keeps buckets that have been written to, keyed by their start time divided by bucket_ns, which saves
+/// most of the memory for instruments that rarely quote. Missing buckets read as the shared empty bucket.
+#[derive(Debug)]
+pub enum BucketStore {
+    Dense(VecDeque<Arc<RwLock<Bucket>>>),
+    Sparse {
+        start_time_ns: u64,
+        bucket_ns: u64,
+        len: usize,
+        buckets: BTreeMap<u64, Arc<RwLock<Bucket>>>,
+        empty: Arc<RwLock<Bucket>>,
+    },
+}
+
+/// A [MarketDataCache] uses a [BucketStore] to hold all its [Bucket]s, by default a deque, O(1) for indexing, pop front
+/// and push back operations. bucket_ns and num_buckets are just two helper variables to make calculations easier. Count
+/// is the total number of [MarketDataEntry] stored in this cache. The total time duration represented by
+/// [MarketDataCache] is bucket_ns * num_buckets, plus up to eviction_batch newer buckets waiting for the next eviction,
+/// and up to max_extra_buckets older buckets after a backfill. Bucket_ns never changes, and num_buckets only grows when
+/// an empty cache fits its window to loaded data, see [crate::LoadOptions::fit_window]. Storage decides whether full
+/// depth is kept.
+///
+/// Visitors such as [MarketDataCache::visit_entries] visit buckets one by one from the oldest, and call their function
+/// while the read lock of the current bucket is held, and no other bucket lock. So the function may query the cache,
+/// which only takes read locks, but it must not lock a bucket for writing, e.g. through a cloned [std::sync::Arc] of it,
+/// or it deadlocks.
 #[derive(Debug)]
 pub struct MarketDataCache {
-    pub buckets: VecDeque<Arc<RwLock<Bucket>>>, // for 100ms buckets
+    pub buckets: BucketStore, // for 100ms buckets
     pub bucket_ns: u64,
     pub num_buckets: usize,
+    /// Every update takes `&mut self`, so it never races with another update or a query, and it doesn't publish any
+    /// other data either, bucket contents are guarded by their own locks. Relaxed ordering is enough for all accesses.
     pub count: AtomicUsize,
+    pub storage: EntryStorage,
+    /// The most recently inserted entry, e.g. the latest quote for real time signals.
+    pub latest_entry: Option<MarketDataEntry>,
+    /// Capacity reserved for entries of each newly created bucket, 0 means no pre-allocation.
+    pub expected_entries_per_bucket: usize,
+    /// How [MarketDataCache::insert] keeps the cached bucket tdigests up to date.
+    pub tdigest_policy: TDigestPolicy,
+    /// Which entries [MarketDataCache::insert] keeps, all of them by default.
+    pub sampling: SamplingPolicy,
+    /// Number of entries offered to the sampling policy, for [SamplingPolicy::EveryNth].
+    pub sampling_offered: usize,
+    /// Queries handle fewer middle buckets than this sequentially, as rayon costs more than it saves for them.
+    pub par_threshold: usize,
+    /// Bumped on every update, see [MarketDataCache::version].
+    pub version: u64,
+    /// Running min and max spread of the whole window, see [MarketDataCache::running_min_spread].
+    pub global_min: f64,
+    pub global_max: f64,
+    /// Called with the entries evicted from the cache, see [MarketDataCache::set_evict_handler].
+    pub evict_handler: Option<EvictHandler>,
+    /// Called with the stored entries whose spread is beyond a threshold, see [MarketDataCache::set_spread_alert].
+    pub spread_alert: Option<SpreadAlert>,
+    /// Spreads of the whole window counted online, see [MarketDataCache::approximate_mode].
+    pub mode_histogram: Option<SpreadHistogram>,
+    /// What "now" is for [MarketDataCache::evict_older_than] and the rolling queries, the system clock by default.
+    pub clock: Arc<dyn Clock>,
+    /// Where parallel work runs, see [MarketDataCache::with_thread_pool]. None means the global rayon pool.
+    pub thread_pool: Option<Arc<ThreadPool>>,
+    /// Number of buckets [MarketDataCache::prepend_historical] may add before the window, beyond num_buckets.
+    pub max_extra_buckets: usize,
+    /// Number of buckets the window may grow beyond num_buckets before old ones are evicted, see
+    /// [MarketDataCache::with_eviction_batch].
+    pub eviction_batch: usize,
+    /// The quantile sketch kept in buckets, see [MarketDataCache::with_bucket_sketch].
+    pub bucket_sketch: BucketSketch,
+}
+
+/// Number of finite spreads in each bin of bin_width, keyed by the bin index, i.e. floor(spread / bin_width). Bins
+/// without any spread are not kept.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SpreadHistogram {
+    pub bin_width: f64,
+    pub counts: HashMap<i64, usize>,
+}
+
+/// A callback receiving evicted entries in timestamp order, e.g. to persist cold data to Parquet or CSV before it's
+/// dropped. It's Send and Sync, so the cache can still be shared between threads.
+pub struct EvictHandler(pub Box<EvictFn>);
+
+/// The signature of an [EvictHandler] callback.
+pub type EvictFn = dyn FnMut(&[MarketDataEntry]) + Send + Sync;
+
+/// Which side of its threshold a [SpreadAlert] fires on.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AlertDirection {
+    /// Spread strictly greater than the threshold, e.g. the market is getting too wide.
+    #[default]
+    Above,
+    /// Spread strictly less than the threshold, e.g. a crossed or locked market.
+    Below,
+}
+
+/// A callback receiving every entry stored by [MarketDataCache::insert] with a spread beyond the threshold, e.g. as a
+/// circuit breaker for live risk. It's Send and Sync, so the cache can still be shared between threads.
+pub struct SpreadAlert {
+    pub threshold: f64,
+    pub direction: AlertDirection,
+    pub callback: Box<AlertFn>,
+}
+
+/// The signature of a [SpreadAlert] callback.
+pub type AlertFn = dyn FnMut(&MarketDataEntry) + Send + Sync;
+
+/// Which query a result memoized by [QueryCache] belongs to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum QueryKind {
+    CountRange,
+    SpreadPercentiles,
+    MinSpread,
+    MaxSpread,
+    Summarize,
+}
+
+/// What [MarketDataCache::insert] or [MarketDataCache::insert_full] did with an entry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InsertOutcome {
+    Inserted,
+    /// Stored after evicting old entries, as the timestamp is after the cache window and the window slid forward.
+    EvictedThenInserted {
+        evicted: usize,
+    },
+    /// Dropped, as the timestamp is before the cache window.
+    BeforeWindow,
+    /// Dropped, as the cache window needed for the timestamp overflows u64.
+    WindowOverflow,
+    /// Dropped by the [SamplingPolicy] of the cache. Sampling is decided before eviction, so nothing is evicted either.
+    Sampled,
+    /// Dropped by the bucket, i.e. the timestamp is not in its time range, or a full depth entry has no spread.
+    Rejected,
+}
+
+/// A broken internal invariant of a [MarketDataCache], reported by [MarketDataCache::validate_invariants]. Bucket
+/// indices count from the oldest bucket.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InvariantViolation {
+    /// Bucket idx doesn't end where the next one starts, i.e. they overlap or leave a gap.
+    NotContiguous {
+        idx: usize,
+        end_time_ns: u64,
+        next_start_time_ns: u64,
+    },
+    /// The cache count differs from the sum of the bucket counts.
+    CountMismatch { count: usize, bucket_sum: usize },
+    /// An entry of bucket idx is outside of the bucket's time range.
+    EntryOutOfBucket { idx: usize, utc_epoch_ns: u64 },
+}
+
+/// A query result memoized by [QueryCache], one variant for each return type of the queries.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QueryResult {
+    Count(usize),
+    Percentiles(f64, f64, f64),
+    Spread(f64),
+    Summary(RangeSummary),
+}
+
+/// A small LRU map from (start time, end time, kind) of a query to its result, for one version of a [MarketDataCache].
+/// All results are dropped once the version changes. Each result remembers the tick it was last used at, and the least
+/// recently used one is evicted when there are more than capacity results. Hits and misses are counted for monitoring.
+#[derive(Debug, Default)]
+pub struct QueryCache {
+    pub capacity: usize,
+    pub version: u64,
+    pub results: HashMap<(u64, u64, QueryKind), (QueryResult, u64)>,
+    pub tick: u64,
+    pub hits: usize,
+    pub misses: usize,
+}
+
+/// A [MarketDataCache] that memoizes the results of its range queries in a [QueryCache], for services that answer the
+/// same few queries again and again. Queries are behind a [Mutex] so they can still be answered through `&self`.
+#[derive(Debug)]
+pub struct CachedMarketDataCache {
+    pub cache: MarketDataCache,
+    pub queries: Mutex<QueryCache>,
 }
@@ -0,0 +1,213 @@
+//! Memoization of range query results on top of a [MarketDataCache]. Results are only valid for the version of the cache
+//! they were calculated from, see [MarketDataCache::version], so the whole [QueryCache] is dropped on any update.
+
+// System libraries.
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+// Project libraries.
+use crate::types::{
+    CachedMarketDataCache, MarketDataCache, QueryCache, QueryKind, QueryResult, RangeSummary,
+};
+
+impl QueryCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            results: HashMap::with_capacity(capacity + 1),
+            ..Default::default()
+        }
+    }
+
+    /// Get the memoized result of a query, if it's calculated from the given version of the cache.
+    pub fn get(&mut self, key: (u64, u64, QueryKind), version: u64) -> Option<QueryResult> {
+        if version != self.version {
+            self.results.clear();
+            self.version = version;
+        }
+        self.tick += 1;
+        match self.results.get_mut(&key) {
+            Some((result, last_used)) => {
+                *last_used = self.tick;
+                self.hits += 1;
+                Some(*result)
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Memoize the result of a query calculated from the given version of the cache, evicting the least recently used
+    /// result if it's full. A result of an outdated version is ignored.
+    pub fn put(&mut self, key: (u64, u64, QueryKind), version: u64, result: QueryResult) {
+        if version != self.version || self.capacity == 0 {
+            return;
+        }
+        self.tick += 1;
+        self.results.insert(key, (result, self.tick));
+        if self.results.len() > self.capacity {
+            let oldest = self
+                .results
+                .iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(key, _)| *key);
+            if let Some(oldest) = oldest {
+                self.results.remove(&oldest);
+            }
+        }
+    }
+}
+
+impl CachedMarketDataCache {
+    /// Memoize up to capacity query results of the given cache.
+    pub fn new(cache: MarketDataCache, capacity: usize) -> Self {
+        Self {
+            cache,
+            queries: Mutex::new(QueryCache::new(capacity)),
+        }
+    }
+
+    pub fn inner(&self) -> &MarketDataCache {
+        &self.cache
+    }
+
+    /// Get the cache for updates, memoized results are dropped by the next query if anything changes.
+    pub fn inner_mut(&mut self) -> &mut MarketDataCache {
+        &mut self.cache
+    }
+
+    pub fn into_inner(self) -> MarketDataCache {
+        self.cache
+    }
+
+    /// Number of queries answered from memoized results.
+    pub fn hits(&self) -> usize {
+        self.queries.lock().unwrap().hits
+    }
+
+    /// Number of queries calculated from the cache.
+    pub fn misses(&self) -> usize {
+        self.queries.lock().unwrap().misses
+    }
+
+    /// Same as [MarketDataCache::count_range], memoized.
+    pub fn count_range(&self, start_time: u64, end_time: u64) -> usize {
+        let key = (start_time, end_time, QueryKind::CountRange);
+        match self.memoize(key, |cache| {
+            QueryResult::Count(cache.count_range(start_time, end_time))
+        }) {
+            QueryResult::Count(count) => count,
+            result => unreachable!("unexpected {result:?} for {key:?}"),
+        }
+    }
+
+    /// Same as [MarketDataCache::spread_percentiles], memoized.
+    pub fn spread_percentiles(&self, start_time: u64, end_time: u64) -> (f64, f64, f64) {
+        let key = (start_time, end_time, QueryKind::SpreadPercentiles);
+        match self.memoize(key, |cache| {
+            let (p10, p50, p90) = cache.spread_percentiles(start_time, end_time);
+            QueryResult::Percentiles(p10, p50, p90)
+        }) {
+            QueryResult::Percentiles(p10, p50, p90) => (p10, p50, p90),
+            result => unreachable!("unexpected {result:?} for {key:?}"),
+        }
+    }
+
+    /// Same as [MarketDataCache::min_spread], memoized.
+    pub fn min_spread(&self, start_time: u64, end_time: u64) -> f64 {
+        let key = (start_time, end_time, QueryKind::MinSpread);
+        match self.memoize(key, |cache| {
+            QueryResult::Spread(cache.min_spread(start_time, end_time))
+        }) {
+            QueryResult::Spread(spread) => spread,
+            result => unreachable!("unexpected {result:?} for {key:?}"),
+        }
+    }
+
+    /// Same as [MarketDataCache::max_spread], memoized.
+    pub fn max_spread(&self, start_time: u64, end_time: u64) -> f64 {
+        let key = (start_time, end_time, QueryKind::MaxSpread);
+        match self.memoize(key, |cache| {
+            QueryResult::Spread(cache.max_spread(start_time, end_time))
+        }) {
+            QueryResult::Spread(spread) => spread,
+            result => unreachable!("unexpected {result:?} for {key:?}"),
+        }
+    }
+
+    /// Same as [MarketDataCache::summarize], memoized.
+    pub fn summarize(&self, start_time: u64, end_time: u64) -> RangeSummary {
+        let key = (start_time, end_time, QueryKind::Summarize);
+        match self.memoize(key, |cache| {
+            QueryResult::Summary(cache.summarize(start_time, end_time))
+        }) {
+            QueryResult::Summary(summary) => summary,
+            result => unreachable!("unexpected {result:?} for {key:?}"),
+        }
+    }
+
+    /// Get the memoized result of a query, or calculate and memoize it. The lock is not held while calculating, so
+    /// different queries still run in parallel.
+    fn memoize<F>(&self, key: (u64, u64, QueryKind), calculate: F) -> QueryResult
+    where
+        F: FnOnce(&MarketDataCache) -> QueryResult,
+    {
+        let version = self.cache.version();
+        if let Some(result) = self.queries.lock().unwrap().get(key, version) {
+            return result;
+        }
+        let result = calculate(&self.cache);
+        self.queries.lock().unwrap().put(key, version, result);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::MarketDataEntry;
+
+    #[test]
+    fn test_repeated_query_is_memoized() {
+        let mut cache = MarketDataCache::new(10, 10);
+        for i in 0..100 {
+            cache.insert(MarketDataEntry::new(i, i as f64));
+        }
+        let mut cached = CachedMarketDataCache::new(cache, 4);
+        assert_eq!(cached.count_range(0, 99), 100);
+        assert_eq!(cached.max_spread(0, 99), 99.0);
+        assert_eq!(cached.misses(), 2);
+
+        // Change a bucket behind the cache's back, a memoized result doesn't see it as buckets are not traversed again.
+        cached.inner().buckets[0].write().unwrap().entries.clear();
+        assert_eq!(cached.count_range(0, 99), 100);
+        assert_eq!(cached.max_spread(0, 99), 99.0);
+        assert_eq!((cached.hits(), cached.misses()), (2, 2));
+
+        // Any update through the cache drops memoized results.
+        cached.inner_mut().insert(MarketDataEntry::new(100, 200.0));
+        assert_eq!(cached.max_spread(0, 200), 200.0);
+        assert_eq!(cached.max_spread(0, 200), 200.0);
+        assert_eq!((cached.hits(), cached.misses()), (3, 3));
+    }
+
+    #[test]
+    fn test_query_cache_lru() {
+        let mut queries = QueryCache::new(2);
+        let key = |start| (start, 10, QueryKind::CountRange);
+        queries.put(key(0), 0, QueryResult::Count(0));
+        queries.put(key(1), 0, QueryResult::Count(1));
+        // Key 0 is used more recently than key 1, so key 1 is evicted.
+        assert_eq!(queries.get(key(0), 0), Some(QueryResult::Count(0)));
+        queries.put(key(2), 0, QueryResult::Count(2));
+        assert_eq!(queries.get(key(1), 0), None);
+        assert_eq!(queries.get(key(2), 0), Some(QueryResult::Count(2)));
+
+        // A new version drops everything, and results of the old version are ignored.
+        assert_eq!(queries.get(key(0), 1), None);
+        queries.put(key(0), 0, QueryResult::Count(0));
+        assert!(queries.results.is_empty());
+    }
+}
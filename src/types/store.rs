@@ -0,0 +1,295 @@
+//! [BucketStore] hides whether a cache keeps all of its buckets or only the non-empty ones. Reading works the same for
+//! both, indexing with a missing bucket gives an empty one. Writing goes through [BucketStore::materialize], which
+//! creates the bucket in sparse mode.
+
+// System libraries.
+use std::collections::{BTreeMap, VecDeque};
+use std::ops::Index;
+use std::sync::{Arc, RwLock};
+
+// Project libraries.
+use crate::types::{Bucket, BucketStore};
+
+impl BucketStore {
+    pub fn new_dense(capacity: usize) -> Self {
+        Self::Dense(VecDeque::with_capacity(capacity))
+    }
+
+    pub fn new_sparse(bucket_ns: u64) -> Self {
+        Self::Sparse {
+            start_time_ns: 0,
+            bucket_ns,
+            len: 0,
+            buckets: BTreeMap::new(),
+            empty: Arc::new(RwLock::new(Bucket::default())),
+        }
+    }
+
+    pub fn is_sparse(&self) -> bool {
+        matches!(self, Self::Sparse { .. })
+    }
+
+    /// Number of buckets in the cache window, including the missing ones in sparse mode.
+    pub fn len(&self) -> usize {
+        match self {
+            Self::Dense(buckets) => buckets.len(),
+            Self::Sparse { len, .. } => *len,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Drop all buckets, a dense store keeps its allocation.
+    pub fn clear(&mut self) {
+        match self {
+            Self::Dense(buckets) => buckets.clear(),
+            Self::Sparse { len, buckets, .. } => {
+                buckets.clear();
+                *len = 0;
+            }
+        }
+    }
+
+    /// Get the bucket at the given index, a missing bucket in sparse mode reads as the shared empty bucket. Only use it
+    /// for reading, as writes to the shared empty bucket are visible to every missing bucket.
+    pub fn get(&self, idx: usize) -> Option<&Arc<RwLock<Bucket>>> {
+        if idx >= self.len() {
+            return None;
+        }
+        match self {
+            Self::Dense(buckets) => buckets.get(idx),
+            Self::Sparse { .. } => Some(self.stored(idx).unwrap_or_else(|| self.empty())),
+        }
+    }
+
+    /// Same as [BucketStore::get], but None for a missing bucket in sparse mode.
+    pub fn stored(&self, idx: usize) -> Option<&Arc<RwLock<Bucket>>> {
+        match self {
+            Self::Dense(buckets) => buckets.get(idx),
+            Self::Sparse {
+                start_time_ns,
+                bucket_ns,
+                len,
+                buckets,
+                ..
+            } => {
+                if idx >= *len {
+                    return None;
+                }
+                buckets.get(&(start_time_ns / bucket_ns + idx as u64))
+            }
+        }
+    }
+
+    pub fn front(&self) -> Option<&Arc<RwLock<Bucket>>> {
+        self.get(0)
+    }
+
+    pub fn back(&self) -> Option<&Arc<RwLock<Bucket>>> {
+        self.get(self.len().checked_sub(1)?)
+    }
+
+    /// Get the bucket at the given index for writing, sparse mode creates it with the given capacity if it's missing.
+    pub fn materialize(&mut self, idx: usize, capacity: usize) -> &Arc<RwLock<Bucket>> {
+        match self {
+            Self::Dense(buckets) => &buckets[idx],
+            Self::Sparse {
+                start_time_ns,
+                bucket_ns,
+                len,
+                buckets,
+                ..
+            } => {
+                assert!(idx < *len, "bucket index {idx} out of range {len}");
+                let bucket_start_ns = *start_time_ns + *bucket_ns * idx as u64;
+                buckets
+                    .entry(bucket_start_ns / *bucket_ns)
+                    .or_insert_with(|| {
+                        Arc::new(RwLock::new(Bucket::with_capacity(
+                            bucket_start_ns,
+                            bucket_start_ns + *bucket_ns,
+                            capacity,
+                        )))
+                    })
+            }
+        }
+    }
+
+    /// Get the time span covered by all buckets, start inclusive and end exclusive. None if there is no bucket.
+    pub fn time_bounds(&self) -> Option<(u64, u64)> {
+        match self {
+            Self::Dense(buckets) => {
+                let first_bucket = buckets.front()?.read().unwrap();
+                let last_bucket = buckets.back()?.read().unwrap();
+                Some((first_bucket.start_time_ns, last_bucket.end_time_ns))
+            }
+            Self::Sparse {
+                start_time_ns,
+                bucket_ns,
+                len,
+                ..
+            } => (*len > 0).then(|| (*start_time_ns, *start_time_ns + *bucket_ns * *len as u64)),
+        }
+    }
+
+    /// Append an empty bucket covering [start_time_ns, end_time_ns) to the window, sparse mode doesn't create it until
+    /// it's written to.
+    pub fn push_empty(&mut self, start_time_ns: u64, end_time_ns: u64, capacity: usize) {
+        match self {
+            Self::Dense(buckets) => buckets.push_back(Arc::new(RwLock::new(
+                Bucket::with_capacity(start_time_ns, end_time_ns, capacity),
+            ))),
+            Self::Sparse {
+                start_time_ns: first_start_ns,
+                len,
+                ..
+            } => {
+                if *len == 0 {
+                    *first_start_ns = start_time_ns;
+                }
+                *len += 1;
+            }
+        }
+    }
+
+    /// Prepend an empty bucket covering [start_time_ns, end_time_ns) to the window, which must end where the window starts.
+    /// Sparse mode doesn't create it until it's written to.
+    pub fn push_empty_front(&mut self, start_time_ns: u64, end_time_ns: u64, capacity: usize) {
+        match self {
+            Self::Dense(buckets) => buckets.push_front(Arc::new(RwLock::new(
+                Bucket::with_capacity(start_time_ns, end_time_ns, capacity),
+            ))),
+            Self::Sparse {
+                start_time_ns: first_start_ns,
+                len,
+                ..
+            } => {
+                *first_start_ns = start_time_ns;
+                *len += 1;
+            }
+        }
+    }
+
+    /// Remove all buckets that end before or at the specified time from the front of the window, and return the stored
+    /// ones.
+    pub fn pop_front_up_to(&mut self, time: u64) -> Vec<Arc<RwLock<Bucket>>> {
+        match self {
+            Self::Dense(buckets) => {
+                let mut popped = Vec::new();
+                while let Some(first_bucket) = buckets.front() {
+                    if first_bucket.read().unwrap().end_time_ns > time {
+                        break;
+                    }
+                    popped.push(buckets.pop_front().unwrap());
+                }
+                popped
+            }
+            Self::Sparse {
+                start_time_ns,
+                bucket_ns,
+                len,
+                buckets,
+                ..
+            } => {
+                let num_popped =
+                    ((time.saturating_sub(*start_time_ns) / *bucket_ns) as usize).min(*len);
+                *start_time_ns += *bucket_ns * num_popped as u64;
+                *len -= num_popped;
+                let kept = buckets.split_off(&(*start_time_ns / *bucket_ns));
+                std::mem::replace(buckets, kept).into_values().collect()
+            }
+        }
+    }
+
+    /// Iterate over the stored buckets in time order, i.e. every bucket in dense mode, and the non-missing ones in
+    /// sparse mode.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &Arc<RwLock<Bucket>>> {
+        let (dense, sparse) = match self {
+            Self::Dense(buckets) => (Some(buckets.iter()), None),
+            Self::Sparse { buckets, .. } => (None, Some(buckets.values())),
+        };
+        dense
+            .into_iter()
+            .flatten()
+            .chain(sparse.into_iter().flatten())
+    }
+
+    /// Estimate the memory used by the store in bytes, see [crate::MarketDataCache::estimated_bytes].
+    pub fn estimated_bytes(&self) -> usize {
+        // Strong and weak counters of an Arc live next to its value.
+        let per_bucket = size_of::<RwLock<Bucket>>() + 2 * size_of::<usize>();
+        let buckets: usize = self
+            .iter()
+            .map(|bucket| per_bucket + bucket.read().unwrap().heap_bytes())
+            .sum();
+        let container = match self {
+            Self::Dense(buckets) => buckets.capacity() * size_of::<Arc<RwLock<Bucket>>>(),
+            // Ignore the internal nodes of the tree, plus the shared empty bucket.
+            Self::Sparse { buckets, .. } => {
+                buckets.len() * (size_of::<u64>() + size_of::<Arc<RwLock<Bucket>>>()) + per_bucket
+            }
+        };
+        container + buckets
+    }
+
+    fn empty(&self) -> &Arc<RwLock<Bucket>> {
+        match self {
+            Self::Dense(_) => unreachable!("dense store has no shared empty bucket"),
+            Self::Sparse { empty, .. } => empty,
+        }
+    }
+}
+
+impl Index<usize> for BucketStore {
+    type Output = Arc<RwLock<Bucket>>;
+
+    fn index(&self, idx: usize) -> &Self::Output {
+        self.get(idx)
+            .unwrap_or_else(|| panic!("bucket index {idx} out of range {}", self.len()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sparse_store() {
+        let mut store = BucketStore::new_sparse(10);
+        assert!(store.is_empty());
+        assert_eq!(store.time_bounds(), None);
+
+        for i in 0..5 {
+            store.push_empty(100 + i * 10, 110 + i * 10, 0);
+        }
+        assert_eq!(store.len(), 5);
+        assert_eq!(store.time_bounds(), Some((100, 150)));
+        assert!(store.stored(2).is_none());
+        assert_eq!(store[2].read().unwrap().count, 0);
+
+        let bucket = store.materialize(2, 0);
+        assert_eq!(bucket.read().unwrap().start_time_ns, 120);
+        assert!(store.stored(2).is_some());
+        assert_eq!(store.iter().count(), 1);
+
+        // Pops the first 3 buckets, including the stored one.
+        assert_eq!(store.pop_front_up_to(135).len(), 1);
+        assert_eq!(store.time_bounds(), Some((130, 150)));
+        assert_eq!(store.iter().count(), 0);
+    }
+
+    #[test]
+    fn test_dense_store() {
+        let mut store = BucketStore::new_dense(4);
+        for i in 0..4 {
+            store.push_empty(i * 10, (i + 1) * 10, 0);
+        }
+        assert_eq!(store.time_bounds(), Some((0, 40)));
+        assert!(store.stored(3).is_some());
+        assert_eq!(store.pop_front_up_to(20).len(), 2);
+        assert_eq!(store[0].read().unwrap().start_time_ns, 20);
+        assert_eq!(store.iter().count(), 2);
+    }
+}
@@ -6,10 +6,15 @@ use log::warn;
 
 // Third party libraries.
 use serde_json::Value;
+use tdigest::TDigest;
 
 // Project libraries.
+use crate::error::MarketDataError;
 use crate::types::BidAsk;
 
+// The std independent helpers live in the no_std core, re-exported here for the rest of the crate.
+pub use crate::bucketing::{approx_eq, f64_max, f64_min, find_bucket_index, sorted_quantile};
+
 /// Parse the bid/ask array from json string to Rust structure.
 pub fn parse_bid_ask_array(arr: &[Value]) -> Vec<BidAsk> {
     let mut result = Vec::new();
@@ -41,21 +46,15 @@ pub fn parse_bid_ask_array(arr: &[Value]) -> Vec<BidAsk> {
     result
 }
 
-/// Find bucket index based on first bucket start ns, the query ns, and the duration of each bucket.
-/// Return None if the query ns is less than first bucket start ns, as it's impossible to find such index.
-pub fn find_bucket_index(
-    first_bucket_start_ns: u64,
-    query_ns: u64,
-    bucket_duration_ns: u64,
-) -> Option<usize> {
-    if query_ns < first_bucket_start_ns {
-        // Query time is before the first bucket
-        return None;
-    }
+/// Check that bids are sorted from highest to lowest price, and asks from lowest to highest.
+pub fn is_book_sorted(bids: &[BidAsk], asks: &[BidAsk]) -> bool {
+    bids.is_sorted_by(|a, b| a.price >= b.price) && asks.is_sorted_by(|a, b| a.price <= b.price)
+}
 
-    let elapsed_ns = query_ns - first_bucket_start_ns;
-    let index = (elapsed_ns / bucket_duration_ns) as usize;
-    Some(index)
+/// Sort bids from highest to lowest price, and asks from lowest to highest.
+pub fn sort_book(bids: &mut [BidAsk], asks: &mut [BidAsk]) {
+    bids.sort_by(|a, b| b.price.total_cmp(&a.price));
+    asks.sort_by(|a, b| a.price.total_cmp(&b.price));
 }
 
 /// Calculate the average price in a given bid/ask array.
@@ -68,20 +67,75 @@ pub fn calculate_ave_price(bidask: &[BidAsk]) -> Option<f64> {
     Some(sum / num as f64)
 }
 
-/// Find min value in an f64 array. Return None if the input array is empty.
-pub fn f64_min(array: &[f64]) -> Option<&f64> {
-    array.iter().min_by(|a, b| a.partial_cmp(b).unwrap())
+/// Convert a signed nanosecond timestamp, as emitted by some upstream systems, to the unsigned one used everywhere in
+/// this crate. Negative timestamps, i.e. before the epoch, are rejected.
+pub fn try_from_signed(ts: i64) -> Result<u64, MarketDataError> {
+    u64::try_from(ts).map_err(|_| MarketDataError::NegativeTimestamp(ts))
 }
 
-/// Find max value in an f64 array. Return None if the input array is empty.
-pub fn f64_max(array: &[f64]) -> Option<&f64> {
-    array.iter().max_by(|a, b| a.partial_cmp(b).unwrap())
+/// Greatest common divisor of two non negative floats by the Euclidean algorithm, where remainders within tolerance of 0
+/// or of the divisor count as exact divisions, so float noise like 0.1 + 0.2 != 0.3 doesn't break it down to tiny
+/// values.
+pub fn float_gcd(a: f64, b: f64, tolerance: f64) -> f64 {
+    let (mut a, mut b) = (a.max(b), a.min(b));
+    while b > tolerance {
+        let mut remainder = a % b;
+        if remainder < tolerance || b - remainder < tolerance {
+            remainder = 0.0;
+        }
+        (a, b) = (b, remainder);
+    }
+    a
+}
+
+/// Merge tdigests, or None if there's none, as [TDigest::merge_digests] gives a default digest then, which estimates
+/// every quantile as 0. A single digest is returned unchanged instead of being compressed again.
+pub(crate) fn merge_digests_safe(mut tdigests: Vec<TDigest>) -> Option<TDigest> {
+    match tdigests.len() {
+        0 => None,
+        1 => tdigests.pop(),
+        _ => Some(TDigest::merge_digests(tdigests)),
+    }
+}
+
+/// Merge spreads into a tdigest. NaN spreads are left out, as they sort above every other spread and would turn the
+/// upper quantiles into NaN.
+pub(crate) fn merge_spreads(tdigest: &TDigest, mut spreads: Vec<f64>) -> TDigest {
+    spreads.retain(|spread| !spread.is_nan());
+    tdigest.merge_unsorted(spreads)
+}
+
+/// Serde of a [TDigest] for `#[serde(with = "crate::utils::tdigest_or_null")]`, writing an empty one as null. An empty
+/// tdigest has NaN min and max, which serde_json writes as null as well, but can't read back as numbers.
+pub(crate) mod tdigest_or_null {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use tdigest::TDigest;
+
+    pub fn serialize<S: Serializer>(tdigest: &TDigest, serializer: S) -> Result<S::Ok, S::Error> {
+        (!tdigest.is_empty())
+            .then_some(tdigest)
+            .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<TDigest, D::Error> {
+        Ok(Option::<TDigest>::deserialize(deserializer)?.unwrap_or_default())
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_try_from_signed() {
+        assert_eq!(try_from_signed(0), Ok(0));
+        assert_eq!(try_from_signed(i64::MAX), Ok(i64::MAX as u64));
+        assert_eq!(
+            try_from_signed(-1),
+            Err(MarketDataError::NegativeTimestamp(-1))
+        );
+    }
+
     #[test]
     fn test_parse_bid_ask_array() {
         let input_str = r#"
@@ -117,18 +171,7 @@ mod tests {
     }
 
     #[test]
-    fn test_find_bucket_index() {
-        let first_bucket_start_ns = 10;
-        let bucket_duration_ns = 10;
-        let inputs = vec![0_u64, 5, 10, 15, 20, 25, 30];
-        let expected_outputs = vec![None, None, Some(0), Some(0), Some(1), Some(1), Some(2)];
-        for (input, expected) in inputs.into_iter().zip(expected_outputs.into_iter()) {
-            let output = find_bucket_index(first_bucket_start_ns, input, bucket_duration_ns);
-            assert_eq!(output, expected);
-        }
-    }
-
-    #[test]
+    #[allow(clippy::useless_vec)]
     fn test_calculate_ave_price() {
         let input: Vec<BidAsk> = (1..=10)
             .map(|price| BidAsk {
@@ -142,24 +185,37 @@ mod tests {
     }
 
     #[test]
-    fn test_f64_max() {
-        let input = vec![1.0, 2.0, 3.0];
-        let max = f64_max(&input);
-        assert_eq!(max, Some(&3.0));
-
-        let input = vec![];
-        let max = f64_max(&input);
-        assert_eq!(max, None);
+    fn test_book_order() {
+        let level = |price| BidAsk { price, amount: 1.0 };
+        let mut bids = vec![level(99.0), level(100.0), level(98.0)];
+        let mut asks = vec![level(101.0), level(101.0), level(102.0)];
+        assert!(!is_book_sorted(&bids, &asks));
+
+        sort_book(&mut bids, &mut asks);
+        assert!(is_book_sorted(&bids, &asks));
+        assert_eq!(bids[0].price, 100.0);
+        assert_eq!(asks[0].price, 101.0);
     }
 
     #[test]
-    fn test_f64_min() {
-        let input = vec![1.0, 2.0, 3.0];
-        let min = f64_min(&input);
-        assert_eq!(min, Some(&1.0));
-
-        let input = vec![];
-        let min = f64_min(&input);
-        assert_eq!(min, None);
+    fn test_float_gcd() {
+        assert!(approx_eq(float_gcd(0.3, 0.2, 1e-9), 0.1, 1e-9));
+        assert!(approx_eq(float_gcd(0.1 + 0.2, 0.5, 1e-9), 0.1, 1e-9));
+        assert_eq!(float_gcd(6.0, 4.0, 1e-9), 2.0);
+        assert_eq!(float_gcd(5.0, 0.0, 1e-9), 5.0);
+    }
+
+    #[test]
+    fn test_merge_digests_safe() {
+        assert!(merge_digests_safe(vec![]).is_none());
+
+        let a = TDigest::new_with_size(100).merge_unsorted(vec![1.0, 2.0, 3.0]);
+        assert_eq!(merge_digests_safe(vec![a.clone()]), Some(a.clone()));
+
+        let b = TDigest::new_with_size(100).merge_unsorted(vec![4.0, 5.0]);
+        let merged = merge_digests_safe(vec![a, b]).unwrap();
+        assert_eq!(merged.count(), 5.0);
+        assert_eq!(merged.min(), 1.0);
+        assert_eq!(merged.max(), 5.0);
     }
 }